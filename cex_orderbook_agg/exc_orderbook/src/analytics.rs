@@ -0,0 +1,196 @@
+use crate::consolidated_book::{ConsolidatedBook, PriceLevel, Side};
+
+/// One point of a cumulative depth curve: everything from the best price up to and
+/// including `price`. `distance_bps` is how far `price` is from the mid.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CurvePoint {
+    pub price: f64,
+    pub distance_bps: f64,
+    pub size: f64,
+    pub notional: f64,
+}
+
+/// How many levels one side of the book has and how large they are. `mean_venues` is
+/// the average number of exchanges quoting a level.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LevelStats {
+    pub levels: usize,
+    pub total_size: f64,
+    pub mean_size: f64,
+    pub max_size: f64,
+    pub mean_venues: f64,
+}
+
+/// Features of a book computed over its top `top` levels per side, except the level
+/// statistics, which cover whole sides. Features that need both sides are `None` when
+/// one is empty.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BookFeatures {
+    pub top: usize,
+    pub mid: Option<f64>,
+    pub spread: Option<f64>,
+    pub microprice: Option<f64>,
+    /// The mid of the size-weighted average bid and ask prices.
+    pub weighted_mid: Option<f64>,
+    /// Bid size minus ask size over their sum, from -1 (all asks) to 1 (all bids).
+    pub imbalance: Option<f64>,
+    /// Least-squares slope of cumulative size against distance from the mid, in size
+    /// per basis point. `None` with fewer than two levels.
+    pub bid_slope: Option<f64>,
+    pub ask_slope: Option<f64>,
+    pub bid_curve: Vec<CurvePoint>,
+    pub ask_curve: Vec<CurvePoint>,
+    pub bid_stats: LevelStats,
+    pub ask_stats: LevelStats,
+}
+
+impl ConsolidatedBook {
+    /// The book's features over its top `top` levels per side.
+    pub fn features(&self, top: usize) -> BookFeatures {
+        let mid = self.mid();
+        let bids = &self.bids[..top.min(self.bids.len())];
+        let asks = &self.asks[..top.min(self.asks.len())];
+
+        let bid_size: f64 = bids.iter().map(|level| level.size).sum();
+        let ask_size: f64 = asks.iter().map(|level| level.size).sum();
+        let total = bid_size + ask_size;
+        let imbalance = (!bids.is_empty() && !asks.is_empty() && total > 0.0)
+            .then(|| (bid_size - ask_size) / total);
+        let weighted_mid = match (average_price(bids), average_price(asks)) {
+            (Some(bid), Some(ask)) => Some((bid + ask) / 2.0),
+            _ => None,
+        };
+
+        let bid_curve = mid.map_or_else(Vec::new, |mid| depth_curve(bids, mid));
+        let ask_curve = mid.map_or_else(Vec::new, |mid| depth_curve(asks, mid));
+
+        BookFeatures {
+            top,
+            mid,
+            spread: self.spread(),
+            microprice: self.microprice(),
+            weighted_mid,
+            imbalance,
+            bid_slope: slope(&bid_curve),
+            ask_slope: slope(&ask_curve),
+            bid_curve,
+            ask_curve,
+            bid_stats: level_stats(self.levels(Side::Bid)),
+            ask_stats: level_stats(self.levels(Side::Ask)),
+        }
+    }
+}
+
+/// The size-weighted average price of `levels`.
+fn average_price(levels: &[PriceLevel]) -> Option<f64> {
+    let size: f64 = levels.iter().map(|level| level.size).sum();
+    let notional: f64 = levels.iter().map(|level| level.price * level.size).sum();
+    (size > 0.0).then(|| notional / size)
+}
+
+fn depth_curve(levels: &[PriceLevel], mid: f64) -> Vec<CurvePoint> {
+    let (mut size, mut notional) = (0.0, 0.0);
+    levels.iter()
+        .map(|level| {
+            size += level.size;
+            notional += level.price * level.size;
+            CurvePoint {
+                price: level.price,
+                distance_bps: (level.price - mid).abs() / mid * 10_000.0,
+                size,
+                notional,
+            }
+        })
+        .collect()
+}
+
+fn slope(curve: &[CurvePoint]) -> Option<f64> {
+    if curve.len() < 2 {
+        return None;
+    }
+    let n = curve.len() as f64;
+    let mean_x = curve.iter().map(|point| point.distance_bps).sum::<f64>() / n;
+    let mean_y = curve.iter().map(|point| point.size).sum::<f64>() / n;
+    let (mut covariance, mut variance) = (0.0, 0.0);
+    for point in curve {
+        covariance += (point.distance_bps - mean_x) * (point.size - mean_y);
+        variance += (point.distance_bps - mean_x).powi(2);
+    }
+    (variance > 0.0).then(|| covariance / variance)
+}
+
+fn level_stats(levels: &[PriceLevel]) -> LevelStats {
+    if levels.is_empty() {
+        return LevelStats::default();
+    }
+    let count = levels.len() as f64;
+    let total_size: f64 = levels.iter().map(|level| level.size).sum();
+    LevelStats {
+        levels: levels.len(),
+        total_size,
+        mean_size: total_size / count,
+        max_size: levels.iter().map(|level| level.size).fold(0.0, f64::max),
+        mean_venues: levels.iter().map(|level| level.venues.len()).sum::<usize>() as f64 / count,
+    }
+}
+
+/* ------------
+    TEST CASES
+   ------------*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::pair_order;
+
+    fn book() -> ConsolidatedBook {
+        let binance = (
+            vec![pair_order("binance", "btcusd", 99.0, 3.0), pair_order("binance", "btcusd", 98.0, 1.0)],
+            vec![pair_order("binance", "btcusd", 101.0, 1.0), pair_order("binance", "btcusd", 102.0, 2.0)],
+        );
+        let bitstamp = (vec![pair_order("bitstamp", "btcusd", 99.0, 1.0)], vec![pair_order("bitstamp", "btcusd", 103.0, 4.0)]);
+        ConsolidatedBook::new(vec![binance, bitstamp], "btcusd")
+    }
+
+    #[test]
+    fn test_top_of_book_features() {
+        let features = book().features(1);
+        assert_eq!(features.mid, Some(100.0));
+        assert_eq!(features.spread, Some(2.0));
+        // 4 bid against 1 ask at the top.
+        assert_eq!(features.imbalance, Some(0.6));
+        assert_eq!(features.microprice, Some((99.0 * 1.0 + 101.0 * 4.0) / 5.0));
+        assert_eq!(features.weighted_mid, Some(100.0));
+        assert!(features.bid_slope.is_none());
+
+        let features = book().features(2);
+        assert_eq!(features.imbalance, Some((5.0 - 3.0) / 8.0));
+        assert_eq!(features.weighted_mid, Some(((99.0 * 4.0 + 98.0) / 5.0 + (101.0 + 204.0) / 3.0) / 2.0));
+    }
+
+    #[test]
+    fn test_depth_curves_and_slope() {
+        let features = book().features(10);
+        let sizes: Vec<f64> = features.ask_curve.iter().map(|point| point.size).collect();
+        assert_eq!(sizes, vec![1.0, 3.0, 7.0]);
+        assert_eq!(features.ask_curve[2].distance_bps, 300.0);
+        assert_eq!(features.ask_curve[2].notional, 101.0 + 204.0 + 412.0);
+
+        // Bids: 4 at 100 bps and 5 at 200 bps.
+        assert_eq!(features.bid_slope, Some(0.01));
+        // Asks: 1, 3 and 7 at 100, 200 and 300 bps.
+        assert_eq!(features.ask_slope, Some(0.03));
+    }
+
+    #[test]
+    fn test_level_stats() {
+        let features = book().features(1);
+        assert_eq!(features.bid_stats, LevelStats { levels: 2, total_size: 5.0, mean_size: 2.5, max_size: 4.0, mean_venues: 1.5 });
+        assert_eq!(features.ask_stats.levels, 3);
+
+        let empty = ConsolidatedBook::default().features(5);
+        assert!(empty.mid.is_none() && empty.imbalance.is_none() && empty.weighted_mid.is_none());
+        assert!(empty.bid_curve.is_empty());
+        assert_eq!(empty.ask_stats, LevelStats::default());
+    }
+}
@@ -0,0 +1,267 @@
+use crate::consolidated_book::{ConsolidatedBook, Side};
+use crate::fees::FeeSchedule;
+use std::collections::BTreeMap;
+
+/// Buying on one venue and selling on another while the first's asks are below the
+/// second's bids. `gross_profit` ignores fees; `net_profit` pays the taker fee on both legs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Opportunity {
+    pub pair: String,
+    pub buy_exchange: String,
+    pub sell_exchange: String,
+    pub buy_price: f64,
+    pub sell_price: f64,
+    pub size: f64,
+    pub gross_profit: f64,
+    pub net_profit: f64,
+}
+
+/// The `(price, size)` levels one venue has on `side`, best first.
+fn venue_levels(book: &ConsolidatedBook, side: Side, exchange: &str) -> Vec<(f64, f64)> {
+    book.levels(side).iter()
+        .filter_map(|level| {
+            let venue = level.venues.iter().find(|venue| venue.exchange == exchange)?;
+            Some((level.price, venue.size))
+        })
+        .collect()
+}
+
+/// Every pair of venues whose books cross in `book` after taker fees. The executable size
+/// is what can be bought on one and sold on the other while the buy price, fees included,
+/// stays below the sell price net of fees.
+pub fn find_opportunities(book: &ConsolidatedBook, fees: &FeeSchedule) -> Vec<Opportunity> {
+    let mut exchanges: Vec<&str> = book.bids.iter().chain(&book.asks)
+        .flat_map(|level| level.venues.iter().map(|venue| venue.exchange.as_str()))
+        .collect();
+    exchanges.sort_unstable();
+    exchanges.dedup();
+
+    let mut opportunities = Vec::new();
+    for buy_exchange in &exchanges {
+        for sell_exchange in &exchanges {
+            if buy_exchange == sell_exchange {
+                continue;
+            }
+            let asks = venue_levels(book, Side::Ask, buy_exchange);
+            let bids = venue_levels(book, Side::Bid, sell_exchange);
+            let buy_fees = fees.fees(buy_exchange);
+            let sell_fees = fees.fees(sell_exchange);
+
+            let mut opportunity = Opportunity {
+                pair: book.pair.clone(),
+                buy_exchange: buy_exchange.to_string(),
+                sell_exchange: sell_exchange.to_string(),
+                buy_price: 0.0,
+                sell_price: 0.0,
+                size: 0.0,
+                gross_profit: 0.0,
+                net_profit: 0.0,
+            };
+            // What is left of the current ask and bid level as they get used up.
+            let (mut ask, mut bid) = (0, 0);
+            let mut ask_left = asks.first().map_or(0.0, |level| level.1);
+            let mut bid_left = bids.first().map_or(0.0, |level| level.1);
+            while ask < asks.len() && bid < bids.len() {
+                let ask_price = asks[ask].0;
+                let bid_price = bids[bid].0;
+                let net_edge = sell_fees.effective_price(bid_price, Side::Bid) - buy_fees.effective_price(ask_price, Side::Ask);
+                if net_edge <= 0.0 {
+                    break;
+                }
+                if opportunity.size == 0.0 {
+                    opportunity.buy_price = ask_price;
+                    opportunity.sell_price = bid_price;
+                }
+
+                let size = f64::min(ask_left, bid_left);
+                opportunity.size += size;
+                opportunity.gross_profit += (bid_price - ask_price) * size;
+                opportunity.net_profit += net_edge * size;
+
+                ask_left -= size;
+                bid_left -= size;
+                if ask_left <= 0.0 {
+                    ask += 1;
+                    ask_left = asks.get(ask).map_or(0.0, |level| level.1);
+                }
+                if bid_left <= 0.0 {
+                    bid += 1;
+                    bid_left = bids.get(bid).map_or(0.0, |level| level.1);
+                }
+            }
+
+            if opportunity.size > 0.0 {
+                opportunities.push(opportunity);
+            }
+        }
+    }
+    opportunities
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Opened,
+    Updated,
+    Closed,
+}
+
+/// A change in an opportunity. `duration_ms` is how long it has been open so far, or
+/// was open in total for `Closed` events, which repeat its last known figures.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArbitrageEvent {
+    pub kind: EventKind,
+    pub opportunity: Opportunity,
+    pub opened_at: u64,
+    pub duration_ms: u64,
+}
+
+/// Follows opportunities across successive looks at the books, so that each one is
+/// reported when it opens, changes and closes rather than on every look.
+#[derive(Debug, Default)]
+pub struct ArbitrageTracker {
+    open: BTreeMap<(String, String, String), (Opportunity, u64)>,
+}
+
+impl ArbitrageTracker {
+    pub fn new() -> Self {
+        ArbitrageTracker::default()
+    }
+
+    /// Takes the opportunities found in `pair` at `now` (milliseconds) and returns what changed.
+    pub fn update(&mut self, pair: &str, opportunities: Vec<Opportunity>, now: u64) -> Vec<ArbitrageEvent> {
+        let mut events = Vec::new();
+        let mut seen = Vec::new();
+
+        for opportunity in opportunities {
+            let key = (pair.to_string(), opportunity.buy_exchange.clone(), opportunity.sell_exchange.clone());
+            seen.push(key.clone());
+            match self.open.get_mut(&key) {
+                Some((previous, opened_at)) => {
+                    if *previous != opportunity {
+                        *previous = opportunity.clone();
+                        events.push(ArbitrageEvent {
+                            kind: EventKind::Updated,
+                            opportunity,
+                            opened_at: *opened_at,
+                            duration_ms: now.saturating_sub(*opened_at),
+                        });
+                    }
+                }
+                None => {
+                    self.open.insert(key, (opportunity.clone(), now));
+                    events.push(ArbitrageEvent { kind: EventKind::Opened, opportunity, opened_at: now, duration_ms: 0 });
+                }
+            }
+        }
+
+        let closed: Vec<_> = self.open.keys()
+            .filter(|key| key.0 == pair && !seen.contains(key))
+            .cloned()
+            .collect();
+        for key in closed {
+            if let Some((opportunity, opened_at)) = self.open.remove(&key) {
+                events.push(ArbitrageEvent {
+                    kind: EventKind::Closed,
+                    opportunity,
+                    opened_at,
+                    duration_ms: now.saturating_sub(opened_at),
+                });
+            }
+        }
+        events
+    }
+}
+
+/* ------------
+    TEST CASES
+   ------------*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fees::Fees;
+    use crate::test_util::order;
+
+    // Bitstamp bids above Binance asks for 1.5 at a 1.0 to 3.0 edge.
+    fn crossed_book() -> ConsolidatedBook {
+        let binance = (
+            vec![order("binance", 99.0, 1.0)],
+            vec![order("binance", 100.0, 1.0), order("binance", 101.0, 1.0), order("binance", 104.0, 1.0)],
+        );
+        let bitstamp = (
+            vec![order("bitstamp", 103.0, 0.5), order("bitstamp", 102.0, 1.0)],
+            vec![order("bitstamp", 105.0, 1.0)],
+        );
+        ConsolidatedBook::new(vec![binance, bitstamp], "ethbtc")
+    }
+
+    #[test]
+    fn test_find_opportunities() {
+        let opportunities = find_opportunities(&crossed_book(), &FeeSchedule::new());
+        assert_eq!(opportunities.len(), 1);
+
+        let opportunity = &opportunities[0];
+        assert_eq!(opportunity.buy_exchange, "binance");
+        assert_eq!(opportunity.sell_exchange, "bitstamp");
+        assert_eq!(opportunity.buy_price, 100.0);
+        assert_eq!(opportunity.sell_price, 103.0);
+        assert_eq!(opportunity.size, 1.5);
+        assert_eq!(opportunity.gross_profit, 0.5 * 3.0 + 0.5 * 2.0 + 0.5 * 1.0);
+        assert_eq!(opportunity.net_profit, opportunity.gross_profit);
+
+        let mut fees = FeeSchedule::new();
+        fees.set("binance", Fees { maker_bps: 0.0, taker_bps: 100.0 });
+        let opportunities = find_opportunities(&crossed_book(), &fees);
+        assert!(opportunities[0].net_profit < opportunities[0].gross_profit);
+
+        let uncrossed = ConsolidatedBook::new(vec![(vec![order("binance", 99.0, 1.0)], vec![order("bitstamp", 100.0, 1.0)])], "ethbtc");
+        assert!(find_opportunities(&uncrossed, &FeeSchedule::new()).is_empty());
+    }
+
+    #[test]
+    fn test_levels_crossed_only_gross_are_left_out() {
+        // A 1% taker fee on Binance lifts its 100.0 ask to 101.0 and its 101.0 ask to
+        // 102.01, which no longer crosses the 102.0 Bitstamp bid.
+        let mut fees = FeeSchedule::new();
+        fees.set("binance", Fees { maker_bps: 0.0, taker_bps: 100.0 });
+        let opportunities = find_opportunities(&crossed_book(), &fees);
+        assert_eq!(opportunities.len(), 1);
+
+        let opportunity = &opportunities[0];
+        assert_eq!(opportunity.size, 1.0);
+        assert!((opportunity.gross_profit - (0.5 * 3.0 + 0.5 * 2.0)).abs() < 1e-9);
+        assert!((opportunity.net_profit - (0.5 * 2.0 + 0.5 * 1.0)).abs() < 1e-9);
+
+        // Fees that eat the whole edge leave no opportunity.
+        fees.set("bitstamp", Fees { maker_bps: 0.0, taker_bps: 300.0 });
+        assert!(find_opportunities(&crossed_book(), &fees).is_empty());
+    }
+
+    #[test]
+    fn test_tracker_reports_open_update_and_close() {
+        let mut tracker = ArbitrageTracker::new();
+        let opportunities = find_opportunities(&crossed_book(), &FeeSchedule::new());
+
+        let events = tracker.update("ethbtc", opportunities.clone(), 1_000);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, EventKind::Opened);
+
+        // Nothing changed, nothing to report.
+        assert!(tracker.update("ethbtc", opportunities.clone(), 1_100).is_empty());
+
+        let mut smaller = opportunities.clone();
+        smaller[0].size = 1.0;
+        let events = tracker.update("ethbtc", smaller, 1_200);
+        assert_eq!(events[0].kind, EventKind::Updated);
+        assert_eq!(events[0].duration_ms, 200);
+
+        // Other pairs do not close it.
+        assert!(tracker.update("btcusd", Vec::new(), 1_300).is_empty());
+
+        let events = tracker.update("ethbtc", Vec::new(), 1_500);
+        assert_eq!(events[0].kind, EventKind::Closed);
+        assert_eq!(events[0].opened_at, 1_000);
+        assert_eq!(events[0].duration_ms, 500);
+        assert_eq!(events[0].opportunity.size, 1.0);
+    }
+}
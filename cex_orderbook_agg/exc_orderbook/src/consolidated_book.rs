@@ -0,0 +1,171 @@
+use crate::combine_orderbook::Order;
+use std::cmp::Ordering;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Bid,
+    Ask,
+}
+
+/// How much of a price level one exchange provides.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VenueSize {
+    pub exchange: String,
+    pub size: f64,
+}
+
+/// Every order at one price across all venues.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PriceLevel {
+    pub price: f64,
+    pub size: f64,
+    pub venues: Vec<VenueSize>,
+}
+
+/// The merged book of one pair: bids best (highest) first, asks best (lowest) first.
+#[derive(Debug, Clone, Default)]
+pub struct ConsolidatedBook {
+    pub pair: String,
+    pub bids: Vec<PriceLevel>,
+    pub asks: Vec<PriceLevel>,
+}
+
+impl ConsolidatedBook {
+    pub fn new<T>(order_books: T, pair: &str) -> Self
+    where
+        T: IntoIterator<Item = (Vec<Order>, Vec<Order>)>,
+    {
+        let mut bids: Vec<Order> = Vec::new();
+        let mut asks: Vec<Order> = Vec::new();
+        for (venue_bids, venue_asks) in order_books {
+            bids.extend(venue_bids);
+            asks.extend(venue_asks);
+        }
+
+        ConsolidatedBook {
+            pair: pair.to_string(),
+            bids: merge_levels(bids, Side::Bid),
+            asks: merge_levels(asks, Side::Ask),
+        }
+    }
+
+    pub fn levels(&self, side: Side) -> &[PriceLevel] {
+        match side {
+            Side::Bid => &self.bids,
+            Side::Ask => &self.asks,
+        }
+    }
+
+    pub fn best_bid(&self) -> Option<&PriceLevel> {
+        self.bids.first()
+    }
+
+    pub fn best_ask(&self) -> Option<&PriceLevel> {
+        self.asks.first()
+    }
+
+    pub fn mid(&self) -> Option<f64> {
+        match (self.best_bid(), self.best_ask()) {
+            (Some(bid), Some(ask)) => Some((bid.price + ask.price) / 2.0),
+            _ => None,
+        }
+    }
+
+    /// The mid weighted towards the side with less size at the top, i.e. the side
+    /// more likely to be taken out next.
+    pub fn microprice(&self) -> Option<f64> {
+        match (self.best_bid(), self.best_ask()) {
+            (Some(bid), Some(ask)) if bid.size + ask.size > 0.0 => {
+                Some((bid.price * ask.size + ask.price * bid.size) / (bid.size + ask.size))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn spread(&self) -> Option<f64> {
+        match (self.best_bid(), self.best_ask()) {
+            (Some(bid), Some(ask)) => Some(ask.price - bid.price),
+            _ => None,
+        }
+    }
+}
+
+/// Groups `orders` by price, best price first for `side`.
+pub fn merge_levels(mut orders: Vec<Order>, side: Side) -> Vec<PriceLevel> {
+    orders.sort_by(|a, b| compare_prices(a.price, b.price, side));
+
+    let mut levels: Vec<PriceLevel> = Vec::new();
+    for order in orders {
+        let venue = VenueSize { exchange: order.exchange, size: order.size };
+        match levels.last_mut() {
+            Some(level) if level.price == order.price => {
+                level.size += venue.size;
+                match level.venues.iter_mut().find(|v| v.exchange == venue.exchange) {
+                    Some(existing) => existing.size += venue.size,
+                    None => level.venues.push(venue),
+                }
+            }
+            _ => levels.push(PriceLevel { price: order.price, size: venue.size, venues: vec![venue] }),
+        }
+    }
+    levels
+}
+
+/// Orders prices best first for `side`.
+pub fn compare_prices(a: f64, b: f64, side: Side) -> Ordering {
+    match side {
+        Side::Bid => b.total_cmp(&a),
+        Side::Ask => a.total_cmp(&b),
+    }
+}
+
+/* ------------
+    TEST CASES
+   ------------*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::order;
+
+    #[test]
+    fn test_consolidated_book() {
+        let binance = (
+            vec![order("binance", 0.0633, 1.0), order("binance", 0.0634, 2.0)],
+            vec![order("binance", 0.0636, 1.5), order("binance", 0.0635, 0.5)],
+        );
+        let bitstamp = (
+            vec![order("Bitstamp", 0.0634, 3.0)],
+            vec![order("Bitstamp", 0.0635, 0.25)],
+        );
+
+        let book = ConsolidatedBook::new(vec![binance, bitstamp], "ethbtc");
+
+        assert_eq!(book.bids.len(), 2);
+        assert_eq!(book.bids[0].price, 0.0634);
+        assert_eq!(book.bids[0].size, 5.0);
+        assert_eq!(book.bids[0].venues, vec![
+            VenueSize { exchange: "binance".to_string(), size: 2.0 },
+            VenueSize { exchange: "Bitstamp".to_string(), size: 3.0 },
+        ]);
+        assert_eq!(book.bids[1].price, 0.0633);
+
+        assert_eq!(book.asks[0].price, 0.0635);
+        assert_eq!(book.asks[0].size, 0.75);
+        assert_eq!(book.asks[1].price, 0.0636);
+
+        assert!((book.spread().unwrap() - 0.0001).abs() < 1e-12);
+        assert!((book.mid().unwrap() - 0.06345).abs() < 1e-12);
+        // 5.0 bid against 0.75 offered pulls the microprice towards the ask.
+        assert!((book.microprice().unwrap() - (0.0634 * 0.75 + 0.0635 * 5.0) / 5.75).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_empty_book() {
+        let book = ConsolidatedBook::new(Vec::new(), "ethbtc");
+        assert!(book.best_bid().is_none());
+        assert!(book.mid().is_none());
+        assert!(book.microprice().is_none());
+        assert!(book.spread().is_none());
+    }
+}
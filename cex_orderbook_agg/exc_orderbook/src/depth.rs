@@ -0,0 +1,162 @@
+use crate::consolidated_book::{ConsolidatedBook, PriceLevel, Side};
+
+/// Size and notional (price times size) one exchange contributes to a `Depth`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VenueDepth {
+    pub exchange: String,
+    pub size: f64,
+    pub notional: f64,
+}
+
+/// Cumulative size and notional over a run of levels on one side of the book.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Depth {
+    pub size: f64,
+    pub notional: f64,
+    pub levels: usize,
+    pub venues: Vec<VenueDepth>,
+}
+
+impl Depth {
+    fn add_level(&mut self, level: &PriceLevel) {
+        self.size += level.size;
+        self.notional += level.price * level.size;
+        self.levels += 1;
+        for venue in &level.venues {
+            match self.venues.iter_mut().find(|v| v.exchange == venue.exchange) {
+                Some(existing) => {
+                    existing.size += venue.size;
+                    existing.notional += level.price * venue.size;
+                }
+                None => self.venues.push(VenueDepth {
+                    exchange: venue.exchange.clone(),
+                    size: venue.size,
+                    notional: level.price * venue.size,
+                }),
+            }
+        }
+    }
+}
+
+/// How much of the book a query has to reach.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    Quantity(f64),
+    Notional(f64),
+}
+
+/// The levels needed to reach a `Target`. `reached` is false when the whole side
+/// was used up before the target was, in which case `depth` covers every level.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LevelsToFill {
+    pub depth: Depth,
+    pub worst_price: Option<f64>,
+    pub reached: bool,
+}
+
+impl ConsolidatedBook {
+    /// Everything on `side` priced within `bps` basis points of the mid, or `None`
+    /// without a mid to measure from.
+    pub fn depth_within_bps(&self, side: Side, bps: f64) -> Option<Depth> {
+        let mid = self.mid()?;
+        let band = mid * bps / 10_000.0;
+
+        let mut depth = Depth::default();
+        for level in self.levels(side) {
+            let inside = match side {
+                Side::Bid => level.price >= mid - band,
+                Side::Ask => level.price <= mid + band,
+            };
+            if !inside {
+                break;
+            }
+            depth.add_level(level);
+        }
+        Some(depth)
+    }
+
+    /// The levels of `side`, best first, needed to reach `target`. The last level
+    /// counts in full even if only part of it is needed.
+    pub fn levels_to_fill(&self, side: Side, target: Target) -> LevelsToFill {
+        let mut depth = Depth::default();
+        let mut worst_price = None;
+        let reached = |depth: &Depth| match target {
+            Target::Quantity(quantity) => depth.size >= quantity,
+            Target::Notional(notional) => depth.notional >= notional,
+        };
+
+        for level in self.levels(side) {
+            if reached(&depth) {
+                break;
+            }
+            depth.add_level(level);
+            worst_price = Some(level.price);
+        }
+
+        LevelsToFill { reached: reached(&depth), depth, worst_price }
+    }
+}
+
+/* ------------
+    TEST CASES
+   ------------*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::order;
+
+    fn book() -> ConsolidatedBook {
+        let binance = (
+            vec![order("binance", 99.0, 1.0), order("binance", 98.0, 2.0)],
+            vec![order("binance", 101.0, 1.0), order("binance", 102.0, 2.0)],
+        );
+        let bitstamp = (
+            vec![order("bitstamp", 99.0, 0.5)],
+            vec![order("bitstamp", 101.0, 3.0), order("bitstamp", 110.0, 5.0)],
+        );
+        ConsolidatedBook::new(vec![binance, bitstamp], "ethbtc")
+    }
+
+    #[test]
+    fn test_depth_within_bps() {
+        let book = book();
+
+        // Mid is 100, so 200 bps reaches up to 102 on the ask side.
+        let asks = book.depth_within_bps(Side::Ask, 200.0).unwrap();
+        assert_eq!(asks.levels, 2);
+        assert_eq!(asks.size, 6.0);
+        assert_eq!(asks.notional, 101.0 * 4.0 + 102.0 * 2.0);
+        assert_eq!(asks.venues, vec![
+            VenueDepth { exchange: "binance".to_string(), size: 3.0, notional: 101.0 + 204.0 },
+            VenueDepth { exchange: "bitstamp".to_string(), size: 3.0, notional: 303.0 },
+        ]);
+
+        let bids = book.depth_within_bps(Side::Bid, 100.0).unwrap();
+        assert_eq!(bids.levels, 1);
+        assert_eq!(bids.size, 1.5);
+
+        assert!(ConsolidatedBook::default().depth_within_bps(Side::Bid, 10.0).is_none());
+    }
+
+    #[test]
+    fn test_levels_to_fill() {
+        let book = book();
+
+        let fill = book.levels_to_fill(Side::Ask, Target::Quantity(5.0));
+        assert!(fill.reached);
+        assert_eq!(fill.depth.levels, 2);
+        assert_eq!(fill.worst_price, Some(102.0));
+
+        let fill = book.levels_to_fill(Side::Bid, Target::Notional(100.0));
+        assert!(fill.reached);
+        assert_eq!(fill.depth.levels, 1);
+        assert_eq!(fill.depth.notional, 148.5);
+
+        let fill = book.levels_to_fill(Side::Ask, Target::Quantity(100.0));
+        assert!(!fill.reached);
+        assert_eq!(fill.depth.levels, 3);
+        assert_eq!(fill.depth.size, 11.0);
+        assert_eq!(fill.worst_price, Some(110.0));
+    }
+}
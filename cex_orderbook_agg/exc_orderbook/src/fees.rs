@@ -0,0 +1,140 @@
+use crate::combine_orderbook::Order;
+use crate::consolidated_book::{merge_levels, ConsolidatedBook, Side};
+use crate::sweep::{MarketOrderSimulation, OrderSide};
+use std::collections::HashMap;
+
+/// What a venue charges, in basis points of the notional.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Fees {
+    pub maker_bps: f64,
+    pub taker_bps: f64,
+}
+
+impl Fees {
+    /// The price a taker effectively pays (asks) or receives (bids) at `price`.
+    pub fn effective_price(&self, price: f64, side: Side) -> f64 {
+        match side {
+            Side::Bid => price * (1.0 - self.taker_bps / 10_000.0),
+            Side::Ask => price * (1.0 + self.taker_bps / 10_000.0),
+        }
+    }
+}
+
+/// Fees per venue. Venues without an entry are free.
+#[derive(Debug, Clone, Default)]
+pub struct FeeSchedule {
+    venues: HashMap<String, Fees>,
+}
+
+impl FeeSchedule {
+    pub fn new() -> Self {
+        FeeSchedule::default()
+    }
+
+    pub fn set(&mut self, exchange: &str, fees: Fees) {
+        self.venues.insert(exchange.to_lowercase(), fees);
+    }
+
+    pub fn fees(&self, exchange: &str) -> Fees {
+        self.venues.get(&exchange.to_lowercase()).copied().unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.venues.is_empty()
+    }
+}
+
+impl ConsolidatedBook {
+    /// The book as a taker sees it: every venue's prices include its taker fee, so
+    /// orders at the same nominal price on venues with different fees end up on
+    /// different levels.
+    pub fn fee_adjusted(&self, fees: &FeeSchedule) -> ConsolidatedBook {
+        let adjust = |side: Side| {
+            let orders = self.levels(side).iter()
+                .flat_map(|level| level.venues.iter().map(move |venue| (level.price, venue)))
+                .map(|(price, venue)| Order {
+                    exchange: venue.exchange.clone(),
+                    pair: self.pair.clone(),
+                    price: fees.fees(&venue.exchange).effective_price(price, side),
+                    size: venue.size,
+                })
+                .collect();
+            merge_levels(orders, side)
+        };
+
+        ConsolidatedBook {
+            pair: self.pair.clone(),
+            bids: adjust(Side::Bid),
+            asks: adjust(Side::Ask),
+        }
+    }
+
+    /// The spread once taker fees are paid on both sides.
+    pub fn effective_spread(&self, fees: &FeeSchedule) -> Option<f64> {
+        self.fee_adjusted(fees).spread()
+    }
+
+    /// Like `simulate_market_order`, but routed by fee-adjusted prices. The VWAP and
+    /// worst price include the fees, and slippage is measured against the raw mid.
+    pub fn simulate_market_order_with_fees(&self, side: OrderSide, quantity: f64, fees: &FeeSchedule) -> MarketOrderSimulation {
+        let mut simulation = self.fee_adjusted(fees).simulate_market_order(side, quantity);
+        simulation.mid = self.mid();
+        simulation.slippage_bps = match (simulation.vwap, simulation.mid) {
+            (Some(vwap), Some(mid)) => Some(side.slippage_bps(vwap, mid)),
+            _ => None,
+        };
+        simulation
+    }
+}
+
+/* ------------
+    TEST CASES
+   ------------*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::order;
+
+    fn fees() -> FeeSchedule {
+        let mut fees = FeeSchedule::new();
+        fees.set("binance", Fees { maker_bps: 0.0, taker_bps: 10.0 });
+        fees.set("Bitstamp", Fees { maker_bps: 0.0, taker_bps: 50.0 });
+        fees
+    }
+
+    fn book() -> ConsolidatedBook {
+        let binance = (vec![order("binance", 100.0, 1.0)], vec![order("binance", 101.0, 1.0)]);
+        let bitstamp = (vec![order("Bitstamp", 100.0, 1.0)], vec![order("Bitstamp", 101.0, 1.0)]);
+        ConsolidatedBook::new(vec![binance, bitstamp], "ethbtc")
+    }
+
+    #[test]
+    fn test_fee_adjusted_book() {
+        let book = book();
+        let adjusted = book.fee_adjusted(&fees());
+
+        // The shared nominal level splits in two, the cheaper venue first.
+        assert_eq!(adjusted.asks.len(), 2);
+        assert_eq!(adjusted.asks[0].venues[0].exchange, "binance");
+        assert!((adjusted.asks[0].price - 101.101).abs() < 1e-9);
+        assert!((adjusted.asks[1].price - 101.505).abs() < 1e-9);
+        assert_eq!(adjusted.bids[0].venues[0].exchange, "binance");
+        assert!((adjusted.bids[0].price - 99.9).abs() < 1e-9);
+
+        assert_eq!(book.spread(), Some(1.0));
+        assert!((book.effective_spread(&fees()).unwrap() - 1.201).abs() < 1e-9);
+        assert_eq!(book.effective_spread(&FeeSchedule::new()), Some(1.0));
+    }
+
+    #[test]
+    fn test_sweep_routes_by_effective_price() {
+        let simulation = book().simulate_market_order_with_fees(OrderSide::Buy, 1.0, &fees());
+
+        assert_eq!(simulation.fills.len(), 1);
+        assert_eq!(simulation.fills[0].exchange, "binance");
+        assert!((simulation.vwap.unwrap() - 101.101).abs() < 1e-9);
+        assert_eq!(simulation.mid, Some(100.5));
+        assert!((simulation.slippage_bps.unwrap() - 0.601 / 100.5 * 10_000.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,176 @@
+use crate::combine_orderbook::Order;
+use crate::consolidated_book::ConsolidatedBook;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// A venue's name and its `(bids, asks)`.
+pub type VenueOrders = (String, (Vec<Order>, Vec<Order>));
+
+/// How the reference price of a pair is put together.
+#[derive(Debug, Clone)]
+pub struct IndexConfig {
+    /// Per-venue weights; venues without one weigh 1.0, and a weight of 0 leaves a venue out.
+    pub weights: HashMap<String, f64>,
+    /// Venue mids further than this from the weighted median are left out of the mean.
+    pub max_deviation_bps: f64,
+    /// Venues whose book is older than this are left out entirely.
+    pub max_age: Duration,
+}
+
+impl Default for IndexConfig {
+    fn default() -> Self {
+        IndexConfig {
+            weights: HashMap::new(),
+            max_deviation_bps: 50.0,
+            max_age: Duration::from_secs(10),
+        }
+    }
+}
+
+impl IndexConfig {
+    pub fn weight(&self, exchange: &str) -> f64 {
+        self.weights.get(&exchange.to_lowercase()).copied().unwrap_or(1.0)
+    }
+}
+
+/// One venue's contribution to a reference price. `weight` is its configured weight
+/// times the size at its top of book.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VenueMid {
+    pub exchange: String,
+    pub mid: f64,
+    pub weight: f64,
+    pub outlier: bool,
+}
+
+/// Fair-price estimates of one pair across venues.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReferencePrice {
+    pub pair: String,
+    /// Mid and microprice of the consolidated book of the venues that are not outliers.
+    pub mid: f64,
+    pub microprice: f64,
+    /// The volume-weighted median of the venue mids.
+    pub weighted_median: f64,
+    /// The weighted mean of the venue mids that are not outliers.
+    pub mean: f64,
+    pub venues: Vec<VenueMid>,
+    /// Venues left out because their book is too old; filled in by the caller that knows.
+    pub stale: Vec<String>,
+}
+
+/// The reference price of `pair` from each venue's `(bids, asks)`, or `None` if no venue
+/// with a weight has a two-sided book.
+pub fn reference_price(books: Vec<VenueOrders>, pair: &str, config: &IndexConfig) -> Option<ReferencePrice> {
+    let mut venues = Vec::new();
+    let mut venue_books = Vec::new();
+    for (exchange, book) in books {
+        let weight = config.weight(&exchange);
+        if weight <= 0.0 {
+            continue;
+        }
+        let venue_book = ConsolidatedBook::new(vec![book.clone()], pair);
+        if let (Some(mid), Some(bid), Some(ask)) = (venue_book.mid(), venue_book.best_bid(), venue_book.best_ask()) {
+            venues.push(VenueMid { exchange, mid, weight: weight * (bid.size + ask.size), outlier: false });
+            venue_books.push(book);
+        }
+    }
+    if venues.is_empty() {
+        return None;
+    }
+
+    let weighted_median = weighted_median(&venues);
+
+    let max_deviation = weighted_median * config.max_deviation_bps / 10_000.0;
+    for venue in &mut venues {
+        venue.outlier = (venue.mid - weighted_median).abs() > max_deviation;
+    }
+    let (sum, weights) = venues.iter()
+        .filter(|venue| !venue.outlier)
+        .fold((0.0, 0.0), |(sum, weights), venue| (sum + venue.mid * venue.weight, weights + venue.weight));
+
+    let inliers = venues.iter().zip(venue_books)
+        .filter(|(venue, _)| !venue.outlier)
+        .map(|(_, book)| book);
+    let consolidated = ConsolidatedBook::new(inliers, pair);
+
+    Some(ReferencePrice {
+        pair: pair.to_string(),
+        mid: consolidated.mid()?,
+        microprice: consolidated.microprice()?,
+        weighted_median,
+        // The median itself is never an outlier, so something is always left.
+        mean: if weights > 0.0 { sum / weights } else { weighted_median },
+        venues,
+        stale: Vec::new(),
+    })
+}
+
+/// The mid at which half of the total weight lies below.
+fn weighted_median(venues: &[VenueMid]) -> f64 {
+    let mut sorted: Vec<&VenueMid> = venues.iter().collect();
+    sorted.sort_by(|a, b| a.mid.total_cmp(&b.mid));
+
+    let half = sorted.iter().map(|venue| venue.weight).sum::<f64>() / 2.0;
+    let mut cumulative = 0.0;
+    for venue in &sorted {
+        cumulative += venue.weight;
+        if cumulative >= half {
+            return venue.mid;
+        }
+    }
+    sorted.last().map_or(0.0, |venue| venue.mid)
+}
+
+/* ------------
+    TEST CASES
+   ------------*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::pair_order;
+
+    fn book(exchange: &str, bid: f64, ask: f64, size: f64) -> VenueOrders {
+        let order = |price| pair_order(exchange, "btcusd", price, size);
+        (exchange.to_string(), (vec![order(bid)], vec![order(ask)]))
+    }
+
+    #[test]
+    fn test_reference_price() {
+        let books = vec![
+            book("binance", 99.0, 101.0, 3.0),
+            book("bitstamp", 100.0, 102.0, 1.0),
+            book("kraken", 109.0, 111.0, 1.0),
+        ];
+        let config = IndexConfig { max_deviation_bps: 200.0, ..Default::default() };
+        let price = reference_price(books, "btcusd", &config).unwrap();
+
+        // Binance carries 6 of the 10 units of weight, and Kraken is too far off to count.
+        assert_eq!(price.weighted_median, 100.0);
+        assert!(!price.venues[1].outlier);
+        assert!(price.venues[2].outlier);
+        assert_eq!(price.mid, 100.5);
+        assert_eq!(price.mean, (100.0 * 6.0 + 101.0 * 2.0) / 8.0);
+        // Bitstamp's 1.0 bid against Binance's 3.0 ask.
+        assert_eq!(price.microprice, (100.0 * 3.0 + 101.0 * 1.0) / 4.0);
+    }
+
+    #[test]
+    fn test_venue_weights() {
+        let books = vec![book("binance", 99.0, 101.0, 3.0), book("bitstamp", 101.0, 103.0, 1.0)];
+        let mut config = IndexConfig { max_deviation_bps: 1_000.0, ..Default::default() };
+        config.weights.insert("binance".to_string(), 0.1);
+
+        let price = reference_price(books.clone(), "btcusd", &config).unwrap();
+        assert_eq!(price.weighted_median, 102.0);
+        assert!((price.mean - (100.0 * 0.6 + 102.0 * 2.0) / 2.6).abs() < 1e-9);
+
+        config.weights.insert("bitstamp".to_string(), 0.0);
+        let price = reference_price(books, "btcusd", &config).unwrap();
+        assert_eq!(price.venues.len(), 1);
+        assert_eq!(price.mid, 100.0);
+
+        assert!(reference_price(Vec::new(), "btcusd", &config).is_none());
+    }
+}
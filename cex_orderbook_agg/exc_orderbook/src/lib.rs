@@ -1 +1,12 @@
-pub mod combine_orderbook;
\ No newline at end of file
+pub mod analytics;
+pub mod arbitrage;
+pub mod combine_orderbook;
+pub mod consolidated_book;
+pub mod depth;
+pub mod fees;
+pub mod index;
+pub mod quote_equivalence;
+pub mod sweep;
+pub mod synthetic;
+#[cfg(test)]
+pub(crate) mod test_util;
@@ -0,0 +1,147 @@
+use crate::combine_orderbook::Order;
+use std::collections::BTreeMap;
+
+/// Quote currencies treated as one, e.g. USD, USDT and USDC. The first currency is the
+/// reference the others are valued in. `rate_pairs` names, per currency, a pair
+/// between it and the reference whose mid is its live rate; currencies without
+/// one are taken at par.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QuoteGroup {
+    pub currencies: Vec<String>,
+    pub rate_pairs: BTreeMap<String, String>,
+}
+
+impl QuoteGroup {
+    pub fn new(currencies: &[&str]) -> Self {
+        QuoteGroup {
+            currencies: currencies.iter().map(|currency| currency.to_lowercase()).collect(),
+            rate_pairs: BTreeMap::new(),
+        }
+    }
+
+    /// Takes the live rate of `currency` from the mid of `pair`, which trades it against
+    /// the reference currency in either direction (`usdtusd` or `usdusdt`).
+    pub fn with_rate_pair(mut self, currency: &str, pair: &str) -> Self {
+        self.rate_pairs.insert(currency.to_lowercase(), pair.to_lowercase());
+        self
+    }
+
+    /// The base and quote currency of `pair`, if it is quoted in a currency of the group.
+    pub fn split_pair<'a>(&self, pair: &'a str) -> Option<(&'a str, &'a str)> {
+        self.currencies.iter()
+            .filter(|currency| pair.len() > currency.len() && pair.ends_with(currency.as_str()))
+            .max_by_key(|currency| currency.len())
+            .map(|currency| pair.split_at(pair.len() - currency.len()))
+    }
+
+    /// `pair` and the pairs with the same base quoted in the other currencies of the group,
+    /// with `pair` first. Empty if `pair` is not quoted in the group.
+    pub fn equivalent_pairs(&self, pair: &str) -> Vec<String> {
+        let pair = pair.to_lowercase();
+        let Some((base, quote)) = self.split_pair(&pair) else {
+            return Vec::new();
+        };
+
+        let mut pairs = vec![pair.clone()];
+        pairs.extend(self.currencies.iter()
+            .filter(|currency| currency.as_str() != quote)
+            .map(|currency| format!("{}{}", base, currency)));
+        pairs
+    }
+
+    /// The pairs whose mids the live rates come from.
+    pub fn live_rate_pairs(&self) -> Vec<&str> {
+        self.rate_pairs.values().map(String::as_str).collect()
+    }
+
+    /// The value of one unit of `currency` in the reference currency. `mid` gives the
+    /// current mid of a pair, if there is one. `None` as well when the rate pair is not
+    /// `currency` and the reference joined in either order.
+    fn reference_rate(&self, currency: &str, mid: &impl Fn(&str) -> Option<f64>) -> Option<f64> {
+        let reference = self.currencies.first()?;
+        if currency == reference {
+            return Some(1.0);
+        }
+        let Some(pair) = self.rate_pairs.get(currency) else {
+            return Some(1.0);
+        };
+
+        let mid = mid(pair).filter(|mid| *mid > 0.0)?;
+        if *pair == format!("{}{}", currency, reference) {
+            Some(mid)
+        } else if *pair == format!("{}{}", reference, currency) {
+            Some(1.0 / mid)
+        } else {
+            None
+        }
+    }
+
+    /// The price in `to` of one unit of `from`, both currencies of the group. `None`
+    /// when a live rate is configured but its pair has no mid yet.
+    pub fn rate(&self, from: &str, to: &str, mid: impl Fn(&str) -> Option<f64>) -> Option<f64> {
+        Some(self.reference_rate(from, &mid)? / self.reference_rate(to, &mid)?)
+    }
+}
+
+/// `orders` with their prices multiplied by `rate`.
+pub fn convert(orders: Vec<Order>, rate: f64) -> Vec<Order> {
+    orders.into_iter()
+        .map(|order| Order { price: order.price * rate, ..order })
+        .collect()
+}
+
+/* ------------
+    TEST CASES
+   ------------*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::pair_order;
+
+    fn usd() -> QuoteGroup {
+        QuoteGroup::new(&["USD", "USDT", "USDC"]).with_rate_pair("usdt", "usdtusd")
+    }
+
+    #[test]
+    fn test_equivalent_pairs() {
+        let group = usd();
+        assert_eq!(group.split_pair("btcusdt"), Some(("btc", "usdt")));
+        assert_eq!(group.split_pair("btcusd"), Some(("btc", "usd")));
+        assert_eq!(group.split_pair("ethbtc"), None);
+
+        assert_eq!(group.equivalent_pairs("BTCUSD"), vec!["btcusd", "btcusdt", "btcusdc"]);
+        assert_eq!(group.equivalent_pairs("btcusdc"), vec!["btcusdc", "btcusd", "btcusdt"]);
+        assert!(group.equivalent_pairs("ethbtc").is_empty());
+    }
+
+    #[test]
+    fn test_rates() {
+        let group = usd();
+        let mids = |pair: &str| (pair == "usdtusd").then_some(0.998);
+
+        assert_eq!(group.rate("usdt", "usd", mids), Some(0.998));
+        assert_eq!(group.rate("usd", "usdt", mids), Some(1.0 / 0.998));
+        // No live rate configured for USDC, so it is at par with USD.
+        assert_eq!(group.rate("usdc", "usdt", mids), Some(1.0 / 0.998));
+        assert_eq!(group.rate("usdt", "usd", |_| None), None);
+
+        let inverted = QuoteGroup::new(&["usd", "usdt"]).with_rate_pair("usdt", "usdusdt");
+        assert_eq!(inverted.rate("usdt", "usd", |_| Some(1.25)), Some(0.8));
+
+        // "usdtusd" starts with "usd" but quotes USDT in USD, so it is inverted for USD in USDT.
+        let usdt = QuoteGroup::new(&["usdt", "usd"]).with_rate_pair("usd", "usdtusd");
+        assert_eq!(usdt.rate("usd", "usdt", |_| Some(1.25)), Some(0.8));
+        let unrelated = QuoteGroup::new(&["usd", "usdt"]).with_rate_pair("usdt", "eurusd");
+        assert_eq!(unrelated.rate("usdt", "usd", |_| Some(1.25)), None);
+    }
+
+    #[test]
+    fn test_convert() {
+        let orders = vec![pair_order("binance", "btcusdt", 40000.0, 1.0)];
+        let converted = convert(orders, 0.998);
+        assert!((converted[0].price - 39920.0).abs() < 1e-9);
+        assert_eq!(converted[0].pair, "btcusdt");
+        assert_eq!(converted[0].size, 1.0);
+    }
+}
@@ -0,0 +1,161 @@
+use crate::consolidated_book::{ConsolidatedBook, Side};
+use crate::depth::VenueDepth;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderSide {
+    Buy,
+    Sell,
+}
+
+impl OrderSide {
+    /// The side of the book a market order on this side takes liquidity from.
+    pub fn book_side(self) -> Side {
+        match self {
+            OrderSide::Buy => Side::Ask,
+            OrderSide::Sell => Side::Bid,
+        }
+    }
+
+    /// How much worse than `mid` a fill at `vwap` is, in basis points.
+    pub fn slippage_bps(self, vwap: f64, mid: f64) -> f64 {
+        let slippage = match self {
+            OrderSide::Buy => vwap - mid,
+            OrderSide::Sell => mid - vwap,
+        };
+        slippage / mid * 10_000.0
+    }
+}
+
+/// What a market order would have got from the book. `fills` holds the size and
+/// notional taken from each exchange.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MarketOrderSimulation {
+    pub fills: Vec<VenueDepth>,
+    pub filled: f64,
+    pub notional: f64,
+    pub unfilled: f64,
+    pub vwap: Option<f64>,
+    pub worst_price: Option<f64>,
+    pub mid: Option<f64>,
+    /// How much worse than the mid the VWAP is, in basis points.
+    pub slippage_bps: Option<f64>,
+}
+
+impl ConsolidatedBook {
+    /// Walks the book best level first as a market order of `quantity` would. A level
+    /// that is only partly needed is taken from its venues in proportion to their size.
+    /// Levels without a positive size are passed over.
+    pub fn simulate_market_order(&self, side: OrderSide, quantity: f64) -> MarketOrderSimulation {
+        let mut simulation = MarketOrderSimulation { mid: self.mid(), ..Default::default() };
+        let mut remaining = quantity.max(0.0);
+
+        for level in self.levels(side.book_side()) {
+            if remaining <= 0.0 {
+                break;
+            }
+            if level.size.is_nan() || level.size <= 0.0 {
+                continue;
+            }
+            let take = remaining.min(level.size);
+            let share = take / level.size;
+
+            for venue in &level.venues {
+                let size = venue.size * share;
+                match simulation.fills.iter_mut().find(|fill| fill.exchange == venue.exchange) {
+                    Some(fill) => {
+                        fill.size += size;
+                        fill.notional += size * level.price;
+                    }
+                    None => simulation.fills.push(VenueDepth {
+                        exchange: venue.exchange.clone(),
+                        size,
+                        notional: size * level.price,
+                    }),
+                }
+            }
+
+            simulation.filled += take;
+            simulation.notional += take * level.price;
+            simulation.worst_price = Some(level.price);
+            remaining -= take;
+        }
+
+        simulation.unfilled = remaining;
+        if simulation.filled > 0.0 {
+            let vwap = simulation.notional / simulation.filled;
+            simulation.vwap = Some(vwap);
+            simulation.slippage_bps = simulation.mid.map(|mid| side.slippage_bps(vwap, mid));
+        }
+        simulation
+    }
+}
+
+/* ------------
+    TEST CASES
+   ------------*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::order;
+
+    fn book() -> ConsolidatedBook {
+        let binance = (
+            vec![order("binance", 99.0, 1.0)],
+            vec![order("binance", 101.0, 1.0), order("binance", 102.0, 2.0)],
+        );
+        let bitstamp = (
+            vec![order("bitstamp", 98.0, 1.0)],
+            vec![order("bitstamp", 101.0, 3.0)],
+        );
+        ConsolidatedBook::new(vec![binance, bitstamp], "ethbtc")
+    }
+
+    #[test]
+    fn test_buy_sweeps_asks() {
+        let simulation = book().simulate_market_order(OrderSide::Buy, 5.0);
+
+        assert_eq!(simulation.filled, 5.0);
+        assert_eq!(simulation.unfilled, 0.0);
+        assert_eq!(simulation.worst_price, Some(102.0));
+        assert_eq!(simulation.vwap, Some((4.0 * 101.0 + 102.0) / 5.0));
+        assert_eq!(simulation.fills, vec![
+            VenueDepth { exchange: "binance".to_string(), size: 2.0, notional: 203.0 },
+            VenueDepth { exchange: "bitstamp".to_string(), size: 3.0, notional: 303.0 },
+        ]);
+        assert!((simulation.slippage_bps.unwrap() - 120.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_partial_level_is_split_by_size() {
+        let simulation = book().simulate_market_order(OrderSide::Buy, 2.0);
+        assert_eq!(simulation.fills[0].size, 0.5);
+        assert_eq!(simulation.fills[1].size, 1.5);
+        assert_eq!(simulation.worst_price, Some(101.0));
+    }
+
+    #[test]
+    fn test_sell_larger_than_book() {
+        let simulation = book().simulate_market_order(OrderSide::Sell, 3.0);
+        assert_eq!(simulation.filled, 2.0);
+        assert_eq!(simulation.unfilled, 1.0);
+        assert_eq!(simulation.worst_price, Some(98.0));
+        assert_eq!(simulation.vwap, Some(98.5));
+        assert!((simulation.slippage_bps.unwrap() - 150.0).abs() < 1e-9);
+
+        let empty = ConsolidatedBook::default().simulate_market_order(OrderSide::Sell, 1.0);
+        assert_eq!(empty.unfilled, 1.0);
+        assert!(empty.vwap.is_none());
+        assert!(empty.slippage_bps.is_none());
+    }
+
+    #[test]
+    fn test_empty_levels_are_passed_over() {
+        let binance = (vec![], vec![order("binance", 100.0, 0.0), order("binance", 101.0, 1.0)]);
+        let book = ConsolidatedBook::new(vec![binance], "ethbtc");
+        let simulation = book.simulate_market_order(OrderSide::Buy, 1.0);
+        assert_eq!(simulation.filled, 1.0);
+        assert_eq!(simulation.vwap, Some(101.0));
+        assert_eq!(simulation.fills[0].notional, 101.0);
+    }
+}
@@ -0,0 +1,142 @@
+use crate::combine_orderbook::Order;
+use crate::consolidated_book::{ConsolidatedBook, PriceLevel};
+
+/// The exchange name of synthetic orders starts with this, so they can be told apart
+/// from orders a venue actually quotes once merged into a book.
+pub static SYNTHETIC_PREFIX: &str = "synthetic:";
+
+/// Whether orders from `exchange` were implied from other books.
+pub fn is_synthetic(exchange: &str) -> bool {
+    exchange.starts_with(SYNTHETIC_PREFIX)
+}
+
+/// A/B implied from A/C (`base_leg`) and B/C (`quote_leg`), e.g. `ethbtc` from `ethusd`
+/// and `btcusd`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntheticPair {
+    pub pair: String,
+    pub base_leg: String,
+    pub quote_leg: String,
+}
+
+impl SyntheticPair {
+    /// The exchange name its orders carry.
+    pub fn exchange(&self) -> String {
+        format!("{}{}/{}", SYNTHETIC_PREFIX, self.base_leg, self.quote_leg)
+    }
+
+    /// The implied bids and asks of the pair, in the same shape as a venue's book.
+    ///
+    /// Selling A for B means selling A on the base leg's bids and buying B with the proceeds
+    /// on the quote leg's asks; buying A works the other way round. Each implied level is
+    /// as large as the smaller of the two legs allows, measured in A.
+    pub fn implied_book(&self, base_leg: &ConsolidatedBook, quote_leg: &ConsolidatedBook) -> (Vec<Order>, Vec<Order>) {
+        let exchange = self.exchange();
+        let to_orders = |levels: Vec<(f64, f64)>| {
+            levels.into_iter()
+                .map(|(price, size)| Order { exchange: exchange.clone(), pair: self.pair.clone(), price, size })
+                .collect()
+        };
+
+        (
+            to_orders(cross_levels(&base_leg.bids, &quote_leg.asks)),
+            to_orders(cross_levels(&base_leg.asks, &quote_leg.bids)),
+        )
+    }
+}
+
+/// Walks A/C levels against B/C levels, best first, and returns the implied A/B
+/// `(price, size)` levels. Sizes are in A; the B/C side is used up at `size * price`
+/// of C per level.
+fn cross_levels(base: &[PriceLevel], quote: &[PriceLevel]) -> Vec<(f64, f64)> {
+    let mut levels = Vec::new();
+    let (mut b, mut q) = (0, 0);
+    let mut base_left = base.first().map_or(0.0, |level| level.size);
+    let mut quote_left = quote.first().map_or(0.0, |level| level.size);
+
+    while b < base.len() && q < quote.len() {
+        let base_price = base[b].price;
+        let quote_price = quote[q].price;
+        if base_price <= 0.0 || quote_price <= 0.0 {
+            break;
+        }
+
+        // What is left of the quote level, in A.
+        let size = base_left.min(quote_left * quote_price / base_price);
+        if size > 0.0 {
+            levels.push((base_price / quote_price, size));
+        }
+
+        base_left -= size;
+        quote_left -= size * base_price / quote_price;
+        if base_left <= 1e-12 {
+            b += 1;
+            base_left = base.get(b).map_or(0.0, |level| level.size);
+        }
+        if quote_left <= 1e-12 {
+            q += 1;
+            quote_left = quote.get(q).map_or(0.0, |level| level.size);
+        }
+    }
+    levels
+}
+
+/* ------------
+    TEST CASES
+   ------------*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{order, pair_order};
+
+    fn ethbtc() -> SyntheticPair {
+        SyntheticPair { pair: "ethbtc".to_string(), base_leg: "ethusd".to_string(), quote_leg: "btcusd".to_string() }
+    }
+
+    #[test]
+    fn test_implied_book() {
+        let ethusd = ConsolidatedBook::new(vec![(
+            vec![pair_order("Bitstamp", "ethusd", 2000.0, 10.0)],
+            vec![pair_order("Bitstamp", "ethusd", 2010.0, 1.0), pair_order("Bitstamp", "ethusd", 2020.0, 10.0)],
+        )], "ethusd");
+        let btcusd = ConsolidatedBook::new(vec![(
+            vec![pair_order("Bitstamp", "btcusd", 40000.0, 1.0)],
+            vec![pair_order("Bitstamp", "btcusd", 40100.0, 0.1), pair_order("Bitstamp", "btcusd", 40200.0, 1.0)],
+        )], "btcusd");
+
+        let (bids, asks) = ethbtc().implied_book(&ethusd, &btcusd);
+
+        // 10 ETH sell for 20000 USD; the first 0.1 BTC costs 4010 USD, i.e. 2.005 ETH.
+        assert_eq!(bids.len(), 2);
+        assert_eq!(bids[0].exchange, "synthetic:ethusd/btcusd");
+        assert_eq!(bids[0].price, 2000.0 / 40100.0);
+        assert!((bids[0].size - 2.005).abs() < 1e-9);
+        assert_eq!(bids[1].price, 2000.0 / 40200.0);
+        assert!((bids[1].size - 7.995).abs() < 1e-9);
+
+        // Buying all 11 ETH costs 22210 USD, which selling part of the 1 BTC bid covers.
+        assert_eq!(asks.len(), 2);
+        assert_eq!(asks[0].price, 2010.0 / 40000.0);
+        assert_eq!(asks[0].size, 1.0);
+        assert_eq!(asks[1].price, 2020.0 / 40000.0);
+        assert_eq!(asks[1].size, 10.0);
+
+        let (bids, asks) = ethbtc().implied_book(&ethusd, &ConsolidatedBook::default());
+        assert!(bids.is_empty() && asks.is_empty());
+    }
+
+    #[test]
+    fn test_synthetic_depth_merges_flagged() {
+        let ethusd = ConsolidatedBook::new(vec![(vec![pair_order("Bitstamp", "ethusd", 2000.0, 1.0)], vec![])], "ethusd");
+        let btcusd = ConsolidatedBook::new(vec![(vec![], vec![pair_order("Bitstamp", "btcusd", 40000.0, 1.0)])], "btcusd");
+        let synthetic = ethbtc().implied_book(&ethusd, &btcusd);
+        let binance = (vec![order("binance", 0.05, 2.0)], vec![]);
+
+        let book = ConsolidatedBook::new(vec![binance, synthetic], "ethbtc");
+        assert_eq!(book.bids.len(), 1);
+        assert_eq!(book.bids[0].size, 3.0);
+        assert!(!is_synthetic(&book.bids[0].venues[0].exchange));
+        assert!(is_synthetic(&book.bids[0].venues[1].exchange));
+    }
+}
@@ -0,0 +1,13 @@
+//! Builders shared by the unit tests of this crate.
+
+use crate::combine_orderbook::Order;
+
+/// A level of `size` at `price` in the "ethbtc" book of `exchange`.
+pub fn order(exchange: &str, price: f64, size: f64) -> Order {
+    pair_order(exchange, "ethbtc", price, size)
+}
+
+/// A level of `size` at `price` in the `pair` book of `exchange`.
+pub fn pair_order(exchange: &str, pair: &str, price: f64, size: f64) -> Order {
+    Order { exchange: exchange.to_string(), pair: pair.to_string(), price, size }
+}
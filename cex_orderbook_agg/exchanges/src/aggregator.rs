@@ -0,0 +1,978 @@
+use crate::bbo::{BboBook, Nbbo};
+use crate::binance::{get_binance_bbo, get_binance_order_book, get_binance_trades};
+use crate::bitstamp::{get_bitstamp_l3, get_bitstamp_order_book, get_bitstamp_trades};
+use crate::l3::{L3Book, SharedL3Book};
+use crate::recorder::Recorder;
+use crate::replay::Replay;
+use crate::status::{Clock, FeedStatus, SharedFeedStatus};
+use crate::trade::TradeTape;
+use crate::validation::{self, RejectReason};
+use exc_orderbook::combine_orderbook::Order;
+use exc_orderbook::index::{self, IndexConfig, ReferencePrice};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+pub type SharedOrderBook = Arc<Mutex<(Vec<Order>, Vec<Order>)>>;
+
+// How long a feed waits before reconnecting after its stream ended or failed.
+static RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Exchange {
+    Binance,
+    Bitstamp,
+}
+
+impl Exchange {
+    pub const ALL: [Exchange; 2] = [Exchange::Binance, Exchange::Bitstamp];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Exchange::Binance => "binance",
+            Exchange::Bitstamp => "bitstamp",
+        }
+    }
+}
+
+impl fmt::Display for Exchange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Exchange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "binance" => Ok(Exchange::Binance),
+            "bitstamp" => Ok(Exchange::Bitstamp),
+            _ => Err(format!("Unknown exchange: {}", s)),
+        }
+    }
+}
+
+struct TradeFeed {
+    status: SharedFeedStatus,
+    handle: JoinHandle<()>,
+}
+
+/// A top-of-book feed, with how many users it has.
+struct BboFeed {
+    status: SharedFeedStatus,
+    handle: JoinHandle<()>,
+    users: usize,
+}
+
+struct Subscription {
+    order_book: SharedOrderBook,
+    status: SharedFeedStatus,
+    handle: JoinHandle<()>,
+    /// The trade feed, which replayed subscriptions do not have.
+    trades: Option<TradeFeed>,
+}
+
+impl Subscription {
+    fn abort(&self) {
+        self.handle.abort();
+        if let Some(trades) = &self.trades {
+            trades.handle.abort();
+        }
+    }
+}
+
+/// The latest book of one venue, as reported by [`Aggregator::order_books`]. Times are
+/// milliseconds since the Unix epoch; `event_time` is only known for venues that send one.
+#[derive(Debug, Clone, Default)]
+pub struct VenueBook {
+    pub bids: Vec<Order>,
+    pub asks: Vec<Order>,
+    pub event_time: Option<u64>,
+    pub received_at: Option<u64>,
+}
+
+/// The books of one pair as of one moment, as reported by [`Aggregator::pair_books`].
+#[derive(Debug, Clone, Default)]
+pub struct PairBooks {
+    /// The latest book of every enabled venue that is not stale.
+    pub books: BTreeMap<Exchange, VenueBook>,
+    /// The enabled venues left out for being stale.
+    pub stale: Vec<Exchange>,
+    /// When the books were read, by the aggregator's clock.
+    pub at: u64,
+}
+
+impl PairBooks {
+    /// The venues holding a book that was updated within `max_age`.
+    pub fn fresh(&self, max_age: Duration) -> Vec<Exchange> {
+        self.books.iter()
+            .filter(|(_, book)| !book.bids.is_empty() || !book.asks.is_empty())
+            .filter(|(_, book)| book.received_at.is_some_and(|at| self.at.saturating_sub(at) <= max_age.as_millis() as u64))
+            .map(|(exchange, _)| *exchange)
+            .collect()
+    }
+}
+
+/// The status of one feed, as reported by [`Aggregator::statuses`].
+#[derive(Debug, Clone)]
+pub struct VenueStatus {
+    pub exchange: Exchange,
+    pub pair: String,
+    pub enabled: bool,
+    pub status: FeedStatus,
+    /// The status of the trade feed, which replayed subscriptions do not have.
+    pub trades: Option<FeedStatus>,
+    /// The status of the top-of-book feed, while one runs apart from the depth feed.
+    pub bbo: Option<FeedStatus>,
+    /// Quotes of the top-of-book feed that were left out, by reason.
+    pub quote_rejections: BTreeMap<RejectReason, u64>,
+}
+
+#[derive(Default)]
+struct State {
+    subscriptions: BTreeMap<(String, Exchange), Subscription>,
+    /// Top-of-book feeds, which run apart from the depth feeds, with how many users each has.
+    bbo_feeds: BTreeMap<(String, Exchange), BboFeed>,
+    /// Bitstamp order-by-order books, by pair.
+    l3_feeds: BTreeMap<String, (SharedL3Book, JoinHandle<()>)>,
+    /// The last reference price each pair's price band was anchored on, and when.
+    band_references: BTreeMap<String, (f64, u64)>,
+    disabled: BTreeSet<Exchange>,
+}
+
+/// Keeps one live feed per subscribed (pair, exchange) and serves their latest
+/// books to the consolidated view. Feeds reconnect on their own until unsubscribed.
+#[derive(Default)]
+pub struct Aggregator {
+    state: Mutex<State>,
+    recorder: Option<Recorder>,
+    replay: Option<Replay>,
+    clock: Clock,
+    stale_after: Option<Duration>,
+    price_band_bps: Option<f64>,
+    index: IndexConfig,
+    trades: TradeTape,
+    bbo: BboBook,
+}
+
+impl Aggregator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Plays feeds back from recordings instead of connecting to the venues. A feed
+    /// stops at the end of its recording and keeps its last book. Freshness is then
+    /// judged by the replay clock.
+    pub fn with_replay(mut self, replay: Replay) -> Self {
+        self.clock = Clock::Replay(replay.clock());
+        self.replay = Some(replay);
+        self
+    }
+
+    /// The clock the books are stamped with and judged by.
+    pub fn clock(&self) -> Clock {
+        self.clock.clone()
+    }
+
+    /// Milliseconds since the Unix epoch, by the aggregator's clock.
+    pub fn now(&self) -> u64 {
+        self.clock.now()
+    }
+
+    /// Records the frames and books of every feed started from now on.
+    pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Leaves a venue out of consolidated views while its book has not been updated
+    /// for `ttl`, and takes it back once it is.
+    pub fn with_stale_after(mut self, ttl: Duration) -> Self {
+        self.stale_after = Some(ttl);
+        self
+    }
+
+    /// Leaves out of consolidated views the levels priced more than `band_bps` basis points
+    /// away from the reference price of their pair: the weighted median of the venue mids,
+    /// once most venues, and at least two, are within the band of it. Until then the last
+    /// such reference anchors the band while it is younger than the index's `max_age`, and
+    /// without one no level is left out.
+    pub fn with_price_band(mut self, band_bps: f64) -> Self {
+        self.price_band_bps = Some(band_bps);
+        self
+    }
+
+    /// Weighs the venue mids the price band is anchored on by `config`.
+    pub fn with_index(mut self, config: IndexConfig) -> Self {
+        self.index = config;
+        self
+    }
+
+    /// Whether the book of `subscription` is stale at `now`.
+    fn is_stale(&self, subscription: &Subscription, now: u64) -> bool {
+        match self.stale_after {
+            Some(ttl) => subscription.status.lock().unwrap().is_stale(now, ttl.as_millis() as u64),
+            None => false,
+        }
+    }
+
+    /// Records in the status of every feed whether its book is stale now, and since when.
+    /// Call it regularly, so that `stale_since` and `stale_count` follow the feeds whether
+    /// or not anything reads their books. Returns how many books are stale.
+    pub fn check_staleness(&self) -> usize {
+        let Some(ttl) = self.stale_after else {
+            return 0;
+        };
+        let now = self.now();
+        let state = self.state.lock().unwrap();
+        state.subscriptions.values()
+            .filter(|subscription| subscription.status.lock().unwrap().check_stale(now, ttl.as_millis() as u64))
+            .count()
+    }
+
+    /// The consolidated tape of the trades of every subscribed (pair, exchange).
+    pub fn trade_tape(&self) -> TradeTape {
+        self.trades.clone()
+    }
+
+    /// The latest top-of-book quote of every BBO feed.
+    pub fn bbo_book(&self) -> BboBook {
+        self.bbo.clone()
+    }
+
+    /// Starts a top-of-book feed for `pair` on `exchange`, apart from its depth feed, or
+    /// counts one more user of the one already running. Returns false if it was already
+    /// running. Bitstamp has no top-of-book channel, so its quotes are the best levels of
+    /// its depth feeds and nothing is started for it. Feeds are not replayed.
+    pub fn subscribe_bbo(&self, exchange: Exchange, pair: &str) -> bool {
+        if exchange == Exchange::Bitstamp || self.replay.is_some() {
+            return false;
+        }
+        let mut state = self.state.lock().unwrap();
+        let key = (pair.to_lowercase(), exchange);
+        if let Some(feed) = state.bbo_feeds.get_mut(&key) {
+            feed.users += 1;
+            return false;
+        }
+        let status = FeedStatus::new_shared();
+        let handle = spawn_bbo(key.0.clone(), self.bbo.clone(), Arc::clone(&status));
+        state.bbo_feeds.insert(key, BboFeed { status, handle, users: 1 });
+        true
+    }
+
+    /// Starts a top-of-book feed for `pair` on `exchange` that connects nowhere: its status
+    /// only changes when a test changes it. Returns false if one was already running.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn subscribe_bbo_without_feed(&self, exchange: Exchange, pair: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let key = (pair.to_lowercase(), exchange);
+        if state.bbo_feeds.contains_key(&key) {
+            return false;
+        }
+        state.bbo_feeds.insert(key, BboFeed { status: FeedStatus::new_shared(), handle: tokio::spawn(async {}), users: 1 });
+        true
+    }
+
+    /// The status of the top-of-book feed for `pair` on `exchange`, if one runs.
+    pub fn bbo_status(&self, exchange: Exchange, pair: &str) -> Option<SharedFeedStatus> {
+        let state = self.state.lock().unwrap();
+        state.bbo_feeds.get(&(pair.to_lowercase(), exchange)).map(|feed| Arc::clone(&feed.status))
+    }
+
+    /// Counts one user less of the top-of-book feed for `pair` on `exchange`, and stops it
+    /// once none is left. Returns false if there was none.
+    pub fn unsubscribe_bbo(&self, exchange: Exchange, pair: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let key = (pair.to_lowercase(), exchange);
+        let Some(feed) = state.bbo_feeds.get_mut(&key) else {
+            return false;
+        };
+        feed.users -= 1;
+        if feed.users == 0 {
+            if let Some(feed) = state.bbo_feeds.remove(&key) {
+                feed.handle.abort();
+            }
+            self.bbo.remove(exchange, pair);
+        }
+        true
+    }
+
+    /// Starts tracking the Bitstamp orders of `pair` one by one. Returns false if they
+    /// already were. Feeds are not replayed.
+    pub fn subscribe_l3(&self, pair: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let pair = pair.to_lowercase();
+        if state.l3_feeds.contains_key(&pair) || self.replay.is_some() {
+            return false;
+        }
+        let book: SharedL3Book = Arc::new(Mutex::new(L3Book::new(Exchange::Bitstamp.name(), &pair)));
+        let handle = spawn_l3(pair.clone(), Arc::clone(&book));
+        state.l3_feeds.insert(pair, (book, handle));
+        true
+    }
+
+    /// Stops tracking the Bitstamp orders of `pair`. Returns false if they were not.
+    pub fn unsubscribe_l3(&self, pair: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.l3_feeds.remove(&pair.to_lowercase()) {
+            Some((_, handle)) => {
+                handle.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The pairs whose Bitstamp orders are tracked one by one.
+    pub fn l3_pairs(&self) -> Vec<String> {
+        self.state.lock().unwrap().l3_feeds.keys().cloned().collect()
+    }
+
+    /// The Bitstamp order-by-order book of `pair`, if it is tracked.
+    pub fn l3_book(&self, pair: &str) -> Option<SharedL3Book> {
+        let state = self.state.lock().unwrap();
+        state.l3_feeds.get(&pair.to_lowercase()).map(|(book, _)| Arc::clone(book))
+    }
+
+    /// The NBBO of `pair` across the enabled venues with a top-of-book feed, leaving out
+    /// quotes older than the staleness limit.
+    pub fn nbbo(&self, pair: &str) -> Nbbo {
+        let disabled = self.state.lock().unwrap().disabled.clone();
+        let now = self.now();
+        let fresh = |quote: &&crate::bbo::Quote| {
+            self.stale_after.is_none_or(|ttl| now.saturating_sub(quote.received_at) <= ttl.as_millis() as u64)
+        };
+        let quotes = self.bbo.quotes(pair);
+        Nbbo::of(pair, quotes.iter().filter(|quote| !disabled.contains(&quote.exchange)).filter(fresh))
+    }
+
+    /// Starts a feed for `pair` on `exchange`, and its trade feed unless feeds are
+    /// replayed. Returns false if it was already running.
+    pub fn subscribe(&self, exchange: Exchange, pair: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let key = (pair.to_lowercase(), exchange);
+        if state.subscriptions.contains_key(&key) {
+            return false;
+        }
+
+        let order_book: SharedOrderBook = Arc::new(Mutex::new((Vec::new(), Vec::new())));
+        let status = FeedStatus::shared_with_clock(self.clock());
+        let handle = spawn_feed(exchange, key.0.clone(), Arc::clone(&order_book), Arc::clone(&status), self.recorder.clone(), self.replay.clone(), self.bbo.clone());
+        let trades = self.replay.is_none().then(|| {
+            let status = FeedStatus::new_shared();
+            let handle = spawn_trades(exchange, key.0.clone(), self.trades.clone(), Arc::clone(&status));
+            TradeFeed { status, handle }
+        });
+        state.subscriptions.insert(key, Subscription { order_book, status, handle, trades });
+        true
+    }
+
+    /// Subscribes `pair` on `exchange` without connecting anywhere: its book and statuses
+    /// only change when a test changes them. Returns false if it was already subscribed.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn subscribe_without_feed(&self, exchange: Exchange, pair: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let key = (pair.to_lowercase(), exchange);
+        if state.subscriptions.contains_key(&key) {
+            return false;
+        }
+
+        let trades = TradeFeed { status: FeedStatus::new_shared(), handle: tokio::spawn(async {}) };
+        let subscription = Subscription {
+            order_book: Arc::default(),
+            status: FeedStatus::shared_with_clock(self.clock()),
+            handle: tokio::spawn(async {}),
+            trades: Some(trades),
+        };
+        state.subscriptions.insert(key, subscription);
+        true
+    }
+
+    /// Stops the feed for `pair` on `exchange`. Returns false if there was none.
+    pub fn unsubscribe(&self, exchange: Exchange, pair: &str) -> bool {
+        let pair = pair.to_lowercase();
+        let mut state = self.state.lock().unwrap();
+        match state.subscriptions.remove(&(pair.clone(), exchange)) {
+            Some(subscription) => {
+                subscription.abort();
+                if exchange == Exchange::Bitstamp {
+                    // Its quotes came from this feed.
+                    self.bbo.remove(exchange, &pair);
+                }
+                if !state.subscriptions.keys().any(|(p, _)| *p == pair) {
+                    state.band_references.remove(&pair);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Starts playing the replay back to the feeds subscribed so far, and to those
+    /// subscribed later from the frame then being played. Returns false without a replay
+    /// or once it was started.
+    pub fn start_replay(&self) -> bool {
+        self.replay.as_ref().is_some_and(Replay::start)
+    }
+
+    /// Drops every book held for `exchange` and reconnects its feeds, trade feeds
+    /// included, from scratch. Returns the number of subscriptions that were restarted.
+    /// Replayed feeds are left alone, as playing them again would start their recordings
+    /// over.
+    pub fn resync(&self, exchange: Exchange) -> usize {
+        if self.replay.is_some() {
+            println!("Replayed feeds cannot be resynced; {} is left as it is", exchange);
+            return 0;
+        }
+        let mut state = self.state.lock().unwrap();
+        let mut restarted = 0;
+
+        for ((pair, venue), subscription) in state.subscriptions.iter_mut() {
+            if *venue != exchange {
+                continue;
+            }
+            subscription.handle.abort();
+            *subscription.order_book.lock().unwrap() = (Vec::new(), Vec::new());
+            {
+                let mut status = subscription.status.lock().unwrap();
+                status.disconnected(None);
+                status.record_resync();
+            }
+            subscription.handle = spawn_feed(
+                exchange,
+                pair.clone(),
+                Arc::clone(&subscription.order_book),
+                Arc::clone(&subscription.status),
+                self.recorder.clone(),
+                self.replay.clone(),
+                self.bbo.clone(),
+            );
+            if let Some(trades) = &mut subscription.trades {
+                trades.handle.abort();
+                {
+                    let mut status = trades.status.lock().unwrap();
+                    status.disconnected(None);
+                    status.record_resync();
+                }
+                trades.handle = spawn_trades(exchange, pair.clone(), self.trades.clone(), Arc::clone(&trades.status));
+            }
+            restarted += 1;
+        }
+
+        restarted
+    }
+
+    /// Includes or excludes `exchange` from the consolidated view. Its feeds keep running.
+    pub fn set_venue_enabled(&self, exchange: Exchange, enabled: bool) {
+        let mut state = self.state.lock().unwrap();
+        if enabled {
+            state.disabled.remove(&exchange);
+        } else {
+            state.disabled.insert(exchange);
+        }
+    }
+
+    pub fn is_venue_enabled(&self, exchange: Exchange) -> bool {
+        !self.state.lock().unwrap().disabled.contains(&exchange)
+    }
+
+    /// Every subscribed pair with the exchanges it is subscribed on.
+    pub fn subscriptions(&self) -> BTreeMap<String, Vec<Exchange>> {
+        let state = self.state.lock().unwrap();
+        let mut pairs: BTreeMap<String, Vec<Exchange>> = BTreeMap::new();
+        for (pair, exchange) in state.subscriptions.keys() {
+            pairs.entry(pair.clone()).or_default().push(*exchange);
+        }
+        pairs
+    }
+
+    pub fn is_subscribed(&self, pair: &str) -> bool {
+        let pair = pair.to_lowercase();
+        let state = self.state.lock().unwrap();
+        state.subscriptions.keys().any(|(p, _)| *p == pair)
+    }
+
+    /// The shared book of a single feed, if `pair` is subscribed on `exchange`.
+    pub fn order_book(&self, exchange: Exchange, pair: &str) -> Option<SharedOrderBook> {
+        let state = self.state.lock().unwrap();
+        state
+            .subscriptions
+            .get(&(pair.to_lowercase(), exchange))
+            .map(|subscription| Arc::clone(&subscription.order_book))
+    }
+
+    /// The status of a single feed, if `pair` is subscribed on `exchange`.
+    pub fn status(&self, exchange: Exchange, pair: &str) -> Option<SharedFeedStatus> {
+        let state = self.state.lock().unwrap();
+        state
+            .subscriptions
+            .get(&(pair.to_lowercase(), exchange))
+            .map(|subscription| Arc::clone(&subscription.status))
+    }
+
+    /// Enabled venues of `pair` holding a book that was updated within `max_age`. Stale
+    /// venues are never fresh, whatever `max_age` is.
+    pub fn fresh_venues(&self, pair: &str, max_age: Duration) -> Vec<Exchange> {
+        self.snapshot(pair).fresh(max_age)
+    }
+
+    /// Latest book of every enabled venue subscribed to `pair`, leaving out stale ones
+    /// and levels outside the price band.
+    pub fn order_books(&self, pair: &str) -> BTreeMap<Exchange, VenueBook> {
+        self.pair_books(pair).books
+    }
+
+    /// The same books as [`Aggregator::order_books`], with the stale venues left out of
+    /// them, all read at once.
+    pub fn pair_books(&self, pair: &str) -> PairBooks {
+        let mut snapshot = self.snapshot(pair);
+        if let Some(band_bps) = self.price_band_bps {
+            self.drop_outside_band(pair, &mut snapshot.books, band_bps, snapshot.at);
+        }
+        snapshot
+    }
+
+    /// Drops the levels of `books` priced more than `band_bps` away from the reference
+    /// price, and counts them against their venue.
+    fn drop_outside_band(&self, pair: &str, books: &mut BTreeMap<Exchange, VenueBook>, band_bps: f64, now: u64) {
+        let Some(reference) = self.band_reference(pair, books, band_bps, now) else {
+            return;
+        };
+        for (exchange, book) in books.iter_mut() {
+            let dropped = validation::drop_outside_band(&mut book.bids, reference, band_bps)
+                + validation::drop_outside_band(&mut book.asks, reference, band_bps);
+            if let Some(status) = self.status(*exchange, pair) {
+                status.lock().unwrap().record_outside_band(dropped as u64);
+            }
+        }
+    }
+
+    /// The price the band of `pair` is anchored on at `now`: the weighted median of the
+    /// mids of `books` if most of them are within the band of it, or else the last one that
+    /// was while it is younger than the index's `max_age`.
+    fn band_reference(&self, pair: &str, books: &BTreeMap<Exchange, VenueBook>, band_bps: f64, now: u64) -> Option<f64> {
+        let venues = books.iter()
+            .map(|(exchange, book)| (exchange.to_string(), (book.bids.clone(), book.asks.clone())))
+            .collect();
+        let candidate = index::reference_price(venues, pair, &self.index)
+            .map(|reference| (reference.weighted_median, reference.venues.iter().map(|venue| venue.mid).collect::<Vec<_>>()))
+            .filter(|(median, mids)| validation::has_band_quorum(mids, *median, band_bps));
+
+        let pair = pair.to_lowercase();
+        let mut state = self.state.lock().unwrap();
+        if let Some((median, _)) = candidate {
+            state.band_references.insert(pair, (median, now));
+            return Some(median);
+        }
+        state.band_references.get(&pair)
+            .filter(|(_, at)| now.saturating_sub(*at) <= self.index.max_age.as_millis() as u64)
+            .map(|(reference, _)| *reference)
+    }
+
+    /// The books of the enabled venues of `pair`, and which were stale, under one lock.
+    fn snapshot(&self, pair: &str) -> PairBooks {
+        let pair = pair.to_lowercase();
+        let now = self.now();
+        let state = self.state.lock().unwrap();
+        let mut snapshot = PairBooks { at: now, ..PairBooks::default() };
+        for ((p, exchange), subscription) in &state.subscriptions {
+            if *p != pair || state.disabled.contains(exchange) {
+                continue;
+            }
+            if self.is_stale(subscription, now) {
+                snapshot.stale.push(*exchange);
+                continue;
+            }
+            let (bids, asks) = subscription.order_book.lock().unwrap().clone();
+            let status = subscription.status.lock().unwrap();
+            let book = VenueBook {
+                bids,
+                asks,
+                event_time: status.last_event_time,
+                received_at: status.last_message_at,
+            };
+            snapshot.books.insert(*exchange, book);
+        }
+        snapshot
+    }
+
+    /// Enabled venues of `pair` left out of consolidated views because their book is stale.
+    pub fn stale_venues(&self, pair: &str) -> Vec<Exchange> {
+        self.snapshot(pair).stale
+    }
+
+    /// The reference price of `pair` across its fresh venues. Enabled venues whose book
+    /// is stale or older than `config.max_age` are left out and listed in `stale`.
+    pub fn reference_price(&self, pair: &str, config: &IndexConfig) -> Option<ReferencePrice> {
+        let snapshot = self.pair_books(pair);
+        let fresh = snapshot.fresh(config.max_age);
+        let mut stale: Vec<String> = snapshot.stale.iter().map(Exchange::to_string).collect();
+        let mut books = Vec::new();
+        for (exchange, book) in snapshot.books {
+            if fresh.contains(&exchange) {
+                books.push((exchange.to_string(), (book.bids, book.asks)));
+            } else {
+                stale.push(exchange.to_string());
+            }
+        }
+
+        let mut price = index::reference_price(books, pair, config)?;
+        stale.sort();
+        price.stale = stale;
+        Some(price)
+    }
+
+    /// Status of every feed, or only of the feeds for `pair` if one is given.
+    pub fn statuses(&self, pair: Option<&str>) -> Vec<VenueStatus> {
+        let pair = pair.map(|pair| pair.to_lowercase());
+        let state = self.state.lock().unwrap();
+        state
+            .subscriptions
+            .iter()
+            .filter(|((p, _), _)| pair.as_ref().is_none_or(|pair| p == pair))
+            .map(|((p, exchange), subscription)| VenueStatus {
+                exchange: *exchange,
+                pair: p.clone(),
+                enabled: !state.disabled.contains(exchange),
+                status: subscription.status.lock().unwrap().clone(),
+                trades: subscription.trades.as_ref().map(|trades| trades.status.lock().unwrap().clone()),
+                bbo: state.bbo_feeds.get(&(p.clone(), *exchange)).map(|feed| feed.status.lock().unwrap().clone()),
+                quote_rejections: self.bbo.rejections(*exchange, p),
+            })
+            .collect()
+    }
+}
+
+impl Drop for Aggregator {
+    fn drop(&mut self) {
+        let state = self.state.lock().unwrap();
+        for subscription in state.subscriptions.values() {
+            subscription.abort();
+        }
+        for feed in state.bbo_feeds.values() {
+            feed.handle.abort();
+        }
+        for (_, handle) in state.l3_feeds.values() {
+            handle.abort();
+        }
+    }
+}
+
+/// Keeps `order_book` up to date with `pair` on `exchange`, reconnecting whenever the
+/// stream ends or fails. Bitstamp feeds also quote their best levels to `bbo`.
+fn spawn_feed(exchange: Exchange, pair: String, order_book: SharedOrderBook, status: SharedFeedStatus, recorder: Option<Recorder>, replay: Option<Replay>, bbo: BboBook) -> JoinHandle<()> {
+    if let Some(replay) = replay {
+        // Attached right away, so that a replay started next plays it from its first frame.
+        let mut feed = replay.attach(exchange, &pair, order_book, Arc::clone(&status), recorder);
+        return tokio::spawn(async move {
+            let result = feed.finished().await;
+            status.lock().unwrap().disconnected(result.err());
+        });
+    }
+
+    tokio::spawn(async move {
+        loop {
+            // The error is turned into a String right away, since `Box<dyn Error>` is not `Send`.
+            let result = match exchange {
+                Exchange::Binance => get_binance_order_book(Arc::clone(&order_book), Arc::clone(&status), &pair, recorder.clone()).await.map_err(|e| e.to_string()),
+                Exchange::Bitstamp => get_bitstamp_order_book(Arc::clone(&order_book), Arc::clone(&status), &pair, recorder.clone(), Some(bbo.clone())).await.map_err(|e| e.to_string()),
+            };
+            if let Err(e) = &result {
+                println!("{} {} feed failed: {}", exchange, pair, e);
+            }
+            status.lock().unwrap().disconnected(result.err());
+
+            sleep(RECONNECT_DELAY).await;
+            status.lock().unwrap().record_reconnect();
+        }
+    })
+}
+
+/// Publishes the trades of `pair` on `exchange` to `tape`, reconnecting whenever the
+/// trade stream ends or fails.
+fn spawn_trades(exchange: Exchange, pair: String, tape: TradeTape, status: SharedFeedStatus) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let result = match exchange {
+                Exchange::Binance => get_binance_trades(tape.clone(), Arc::clone(&status), &pair).await.map_err(|e| e.to_string()),
+                Exchange::Bitstamp => get_bitstamp_trades(tape.clone(), Arc::clone(&status), &pair).await.map_err(|e| e.to_string()),
+            };
+            if let Err(e) = &result {
+                println!("{} {} trade feed failed: {}", exchange, pair, e);
+            }
+            status.lock().unwrap().disconnected(result.err());
+
+            sleep(RECONNECT_DELAY).await;
+            status.lock().unwrap().record_reconnect();
+        }
+    })
+}
+
+/// Keeps the Binance quote of `pair` in `bbo` up to date, reconnecting whenever the
+/// stream ends or fails.
+fn spawn_bbo(pair: String, bbo: BboBook, status: SharedFeedStatus) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let result = get_binance_bbo(bbo.clone(), Arc::clone(&status), &pair).await.map_err(|e| e.to_string());
+            if let Err(e) = &result {
+                println!("binance {} top of book feed failed: {}", pair, e);
+            }
+            status.lock().unwrap().disconnected(result.err());
+
+            sleep(RECONNECT_DELAY).await;
+            status.lock().unwrap().record_reconnect();
+        }
+    })
+}
+
+/// Keeps `book` up to date with the Bitstamp orders of `pair`, reconnecting whenever the
+/// stream ends or fails. Events missed in between would leave orders behind, so the
+/// book starts over on every connection.
+fn spawn_l3(pair: String, book: SharedL3Book) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            book.lock().unwrap().restart();
+            if let Err(e) = get_bitstamp_l3(Arc::clone(&book), &pair).await.map_err(|e| e.to_string()) {
+                println!("bitstamp {} order feed failed: {}", pair, e);
+            }
+            sleep(RECONNECT_DELAY).await;
+        }
+    })
+}
+
+/* ------------
+    TEST CASES
+   ------------*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bbo::Quote;
+    use crate::status::now_millis;
+    use crate::test_util::set_book;
+
+    #[test]
+    fn test_exchange_from_str() {
+        assert_eq!("Binance".parse::<Exchange>().unwrap(), Exchange::Binance);
+        assert_eq!("bitstamp".parse::<Exchange>().unwrap(), Exchange::Bitstamp);
+        assert!("kraken".parse::<Exchange>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_and_unsubscribe() {
+        let aggregator = Aggregator::new();
+
+        assert!(aggregator.subscribe(Exchange::Binance, "ETHBTC"));
+        assert!(!aggregator.subscribe(Exchange::Binance, "ethbtc"));
+        assert!(aggregator.subscribe(Exchange::Bitstamp, "ethbtc"));
+        assert!(aggregator.is_subscribed("ethbtc"));
+
+        let subscriptions = aggregator.subscriptions();
+        assert_eq!(subscriptions["ethbtc"], vec![Exchange::Binance, Exchange::Bitstamp]);
+
+        assert!(aggregator.unsubscribe(Exchange::Binance, "ethbtc"));
+        assert!(!aggregator.unsubscribe(Exchange::Binance, "ethbtc"));
+        assert_eq!(aggregator.subscriptions()["ethbtc"], vec![Exchange::Bitstamp]);
+    }
+
+    #[tokio::test]
+    async fn test_disabled_venue_is_left_out() {
+        let aggregator = Aggregator::new();
+        set_book(&aggregator, Exchange::Binance, "ethbtc", &[(0.06, 1.0)], &[(0.07, 1.0)]);
+        set_book(&aggregator, Exchange::Bitstamp, "ethbtc", &[(0.05, 1.0)], &[(0.08, 1.0)]);
+
+        assert_eq!(aggregator.order_books("ethbtc").len(), 2);
+
+        aggregator.set_venue_enabled(Exchange::Bitstamp, false);
+        assert!(!aggregator.is_venue_enabled(Exchange::Bitstamp));
+        let books = aggregator.order_books("ethbtc");
+        assert_eq!(books.len(), 1);
+        assert_eq!(books[&Exchange::Binance].bids[0].exchange, "binance");
+
+        aggregator.set_venue_enabled(Exchange::Bitstamp, true);
+        assert_eq!(aggregator.order_books("ethbtc").len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_fresh_venues() {
+        let aggregator = Aggregator::new();
+        set_book(&aggregator, Exchange::Binance, "ethbtc", &[], &[]);
+        set_book(&aggregator, Exchange::Bitstamp, "ethbtc", &[], &[]);
+        let max_age = Duration::from_secs(10);
+
+        // Nothing was received yet.
+        assert!(aggregator.fresh_venues("ethbtc", max_age).is_empty());
+
+        for exchange in Exchange::ALL {
+            set_book(&aggregator, exchange, "ethbtc", &[(0.06, 1.0)], &[]);
+        }
+        aggregator.status(Exchange::Binance, "ethbtc").unwrap().lock().unwrap().record_message(now_millis(), None, None);
+        aggregator.status(Exchange::Bitstamp, "ethbtc").unwrap().lock().unwrap().record_message(now_millis() - 60_000, None, None);
+        assert_eq!(aggregator.fresh_venues("ethbtc", max_age), vec![Exchange::Binance]);
+
+        aggregator.set_venue_enabled(Exchange::Binance, false);
+        assert!(aggregator.fresh_venues("ethbtc", max_age).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reference_price_leaves_out_stale_venues() {
+        let aggregator = Aggregator::new();
+        set_book(&aggregator, Exchange::Binance, "ethbtc", &[], &[]);
+        set_book(&aggregator, Exchange::Bitstamp, "ethbtc", &[], &[]);
+        let config = IndexConfig::default();
+        assert!(aggregator.reference_price("ethbtc", &config).is_none());
+
+        set_book(&aggregator, Exchange::Binance, "ethbtc", &[(0.06, 1.0)], &[(0.0602, 1.0)]);
+        set_book(&aggregator, Exchange::Bitstamp, "ethbtc", &[(0.05, 1.0)], &[(0.08, 1.0)]);
+        aggregator.status(Exchange::Binance, "ethbtc").unwrap().lock().unwrap().record_message(now_millis(), None, None);
+
+        let price = aggregator.reference_price("ethbtc", &config).unwrap();
+        assert_eq!(price.venues.len(), 1);
+        assert!((price.mid - 0.0601).abs() < 1e-12);
+        assert_eq!(price.stale, vec!["bitstamp"]);
+    }
+
+    #[tokio::test]
+    async fn test_resync_clears_venue_book() {
+        let aggregator = Aggregator::new();
+        set_book(&aggregator, Exchange::Bitstamp, "ethbtc", &[(0.05, 1.0)], &[]);
+        set_book(&aggregator, Exchange::Bitstamp, "btcusd", &[], &[]);
+        set_book(&aggregator, Exchange::Binance, "ethbtc", &[], &[]);
+
+        let book = aggregator.order_book(Exchange::Bitstamp, "ethbtc").unwrap();
+
+        assert_eq!(aggregator.resync(Exchange::Bitstamp), 2);
+        assert!(book.lock().unwrap().0.is_empty());
+
+        let statuses = aggregator.statuses(Some("ethbtc"));
+        assert_eq!(statuses.len(), 2);
+        let bitstamp = statuses.iter().find(|status| status.exchange == Exchange::Bitstamp).unwrap();
+        let binance = statuses.iter().find(|status| status.exchange == Exchange::Binance).unwrap();
+        assert_eq!(bitstamp.status.resync_count, 1);
+        assert_eq!(binance.status.resync_count, 0);
+        assert_eq!(bitstamp.trades.as_ref().unwrap().resync_count, 1);
+        assert_eq!(binance.trades.as_ref().unwrap().resync_count, 0);
+        assert_eq!(aggregator.statuses(None).len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_nbbo_leaves_out_disabled_venues() {
+        let aggregator = Aggregator::new();
+        assert!(aggregator.subscribe_bbo(Exchange::Binance, "ETHBTC"));
+        assert!(!aggregator.subscribe_bbo(Exchange::Binance, "ethbtc"));
+        assert!(!aggregator.subscribe_bbo(Exchange::Bitstamp, "ethbtc"));
+
+        let quote = |exchange, bid| Quote { exchange, pair: "ethbtc".to_string(), bid: Some((bid, 1.0)), ask: None, update_id: None, event_time: None, received_at: 1 };
+        aggregator.bbo_book().update(quote(Exchange::Binance, 0.06)).unwrap();
+        aggregator.bbo_book().update(quote(Exchange::Bitstamp, 0.061)).unwrap();
+        assert_eq!(aggregator.nbbo("ethbtc").bid.unwrap().venues, vec![(Exchange::Bitstamp, 1.0)]);
+
+        aggregator.set_venue_enabled(Exchange::Bitstamp, false);
+        assert_eq!(aggregator.nbbo("ethbtc").bid.unwrap().price, 0.06);
+
+        // The feed runs until its last user lets it go.
+        assert!(aggregator.unsubscribe_bbo(Exchange::Binance, "ethbtc"));
+        assert!(aggregator.nbbo("ethbtc").bid.is_some());
+        assert!(aggregator.unsubscribe_bbo(Exchange::Binance, "ethbtc"));
+        assert!(aggregator.nbbo("ethbtc").bid.is_none());
+        assert!(!aggregator.unsubscribe_bbo(Exchange::Binance, "ethbtc"));
+    }
+
+    #[tokio::test]
+    async fn test_stale_venue_is_left_out_until_fresh() {
+        let aggregator = Aggregator::new().with_stale_after(Duration::from_secs(30));
+        set_book(&aggregator, Exchange::Binance, "ethbtc", &[(0.06, 1.0)], &[(0.07, 1.0)]);
+        set_book(&aggregator, Exchange::Bitstamp, "ethbtc", &[(0.08, 1.0)], &[(0.05, 1.0)]);
+        let now = now_millis();
+        aggregator.status(Exchange::Binance, "ethbtc").unwrap().lock().unwrap().record_message(now, None, None);
+        // Bitstamp's frozen book would cross Binance's.
+        aggregator.status(Exchange::Bitstamp, "ethbtc").unwrap().lock().unwrap().record_message(now - 60_000, None, None);
+
+        let books = aggregator.pair_books("ethbtc");
+        assert_eq!(books.books.into_keys().collect::<Vec<_>>(), vec![Exchange::Binance]);
+        assert_eq!(books.stale, vec![Exchange::Bitstamp]);
+        let price = aggregator.reference_price("ethbtc", &IndexConfig::default()).unwrap();
+        assert_eq!(price.stale, vec!["bitstamp"]);
+
+        // Only the regular check records it, however often the books are read.
+        let status = |aggregator: &Aggregator| aggregator.statuses(Some("ethbtc")).into_iter().find(|venue| venue.exchange == Exchange::Bitstamp).unwrap().status;
+        assert_eq!(status(&aggregator).stale_count, 0);
+        assert_eq!(aggregator.check_staleness(), 1);
+        assert_eq!(aggregator.check_staleness(), 1);
+        assert_eq!(status(&aggregator).stale_count, 1);
+        assert!(status(&aggregator).stale_since.is_some());
+
+        aggregator.status(Exchange::Bitstamp, "ethbtc").unwrap().lock().unwrap().record_message(now_millis(), None, None);
+        assert_eq!(aggregator.order_books("ethbtc").len(), 2);
+        assert!(aggregator.stale_venues("ethbtc").is_empty());
+        assert_eq!(aggregator.check_staleness(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_levels_outside_price_band_are_left_out() {
+        let aggregator = Aggregator::new().with_price_band(1_000.0);
+        set_book(&aggregator, Exchange::Binance, "ethbtc", &[(0.06, 5.0), (0.03, 1.0)], &[(0.061, 5.0)]);
+        set_book(&aggregator, Exchange::Bitstamp, "ethbtc", &[(0.0601, 1.0)], &[(0.0609, 1.0)]);
+        assert_eq!(aggregator.order_books("ethbtc")[&Exchange::Binance].bids.len(), 1);
+
+        // Half the market's price, with less size at the top than Binance.
+        set_book(&aggregator, Exchange::Bitstamp, "ethbtc", &[(0.03, 1.0)], &[(0.031, 1.0), (0.0605, 1.0)]);
+        aggregator.status(Exchange::Bitstamp, "ethbtc").unwrap().lock().unwrap().record_message(now_millis(), None, None);
+        let books = aggregator.order_books("ethbtc");
+        assert_eq!(books[&Exchange::Binance].bids.len(), 1);
+        assert!(books[&Exchange::Bitstamp].bids.is_empty());
+        assert_eq!(books[&Exchange::Bitstamp].asks.iter().map(|order| order.price).collect::<Vec<_>>(), vec![0.0605]);
+
+        // Reading the same books again does not count them again.
+        aggregator.order_books("ethbtc");
+        let rejections = |exchange| aggregator.status(exchange, "ethbtc").unwrap().lock().unwrap().rejections.clone();
+        assert_eq!(rejections(Exchange::Binance)[&RejectReason::OutsideBand], 1);
+        assert_eq!(rejections(Exchange::Bitstamp)[&RejectReason::OutsideBand], 2);
+    }
+
+    #[tokio::test]
+    async fn test_price_band_is_not_drawn_to_an_outlier() {
+        let aggregator = Aggregator::new().with_price_band(1_000.0);
+        let set_book = |exchange, bid, ask| set_book(&aggregator, exchange, "ethbtc", &[(bid, 1.0)], &[(ask, 1.0)]);
+        let prices = |books: &BTreeMap<Exchange, VenueBook>, exchange| books[&exchange].bids.iter().chain(&books[&exchange].asks).map(|order| order.price).collect::<Vec<_>>();
+
+        // With equal sizes the median lands on the lower mid, Bitstamp's here. Neither venue
+        // can be told right, so nothing is left out.
+        set_book(Exchange::Binance, 0.06, 0.061);
+        set_book(Exchange::Bitstamp, 0.03, 0.031);
+        let books = aggregator.order_books("ethbtc");
+        assert_eq!((prices(&books, Exchange::Binance).len(), prices(&books, Exchange::Bitstamp).len()), (2, 2));
+
+        // Once both agree, their median anchors the band, and stays its anchor when
+        // Bitstamp drifts off again.
+        set_book(Exchange::Bitstamp, 0.0601, 0.0609);
+        assert_eq!(prices(&aggregator.order_books("ethbtc"), Exchange::Bitstamp).len(), 2);
+        set_book(Exchange::Bitstamp, 0.03, 0.031);
+        let books = aggregator.order_books("ethbtc");
+        assert_eq!(prices(&books, Exchange::Binance), vec![0.06, 0.061]);
+        assert!(prices(&books, Exchange::Bitstamp).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_l3_subscriptions() {
+        let aggregator = Aggregator::new();
+        assert!(aggregator.l3_book("ethbtc").is_none());
+        assert!(aggregator.subscribe_l3("ETHBTC"));
+        assert!(!aggregator.subscribe_l3("ethbtc"));
+
+        let book = aggregator.l3_book("ethbtc").unwrap();
+        let book = book.lock().unwrap();
+        assert_eq!((book.exchange.as_str(), book.pair.as_str()), ("bitstamp", "ethbtc"));
+        drop(book);
+
+        assert_eq!(aggregator.l3_pairs(), vec!["ethbtc"]);
+        assert!(aggregator.unsubscribe_l3("ethbtc"));
+        assert!(!aggregator.unsubscribe_l3("ethbtc"));
+        assert!(aggregator.l3_book("ethbtc").is_none());
+    }
+}
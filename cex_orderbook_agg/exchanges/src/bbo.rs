@@ -0,0 +1,213 @@
+use crate::aggregator::Exchange;
+use crate::validation::{self, RejectReason};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+// How many quotes a slow reader can fall behind before it misses some.
+static QUOTE_CAPACITY: usize = 4096;
+// Prices this close, relative to their size, are the same price quoted by several venues.
+static PRICE_TOLERANCE: f64 = 1e-9;
+
+/// The best bid and ask of one venue, as `(price, size)`. A side is `None` while the
+/// venue has no orders on it. Times are milliseconds since the Unix epoch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Quote {
+    pub exchange: Exchange,
+    pub pair: String,
+    pub bid: Option<(f64, f64)>,
+    pub ask: Option<(f64, f64)>,
+    pub update_id: Option<u64>,
+    pub event_time: Option<u64>,
+    pub received_at: u64,
+}
+
+/// The best price on one side across venues, the size quoted at it, and how much of
+/// it each venue quotes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BestPrice {
+    pub price: f64,
+    pub size: f64,
+    pub venues: Vec<(Exchange, f64)>,
+}
+
+/// The national best bid and offer of a pair across venues. `updated_at` is when the
+/// latest quote it was made from arrived.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Nbbo {
+    pub pair: String,
+    pub bid: Option<BestPrice>,
+    pub ask: Option<BestPrice>,
+    pub updated_at: Option<u64>,
+}
+
+impl Nbbo {
+    /// The NBBO of `quotes`, all of one pair.
+    pub fn of<'a>(pair: &str, quotes: impl IntoIterator<Item = &'a Quote>) -> Self {
+        let mut nbbo = Nbbo { pair: pair.to_lowercase(), ..Default::default() };
+        for quote in quotes {
+            nbbo.updated_at = nbbo.updated_at.max(Some(quote.received_at));
+            if let Some(bid) = quote.bid {
+                best(&mut nbbo.bid, quote.exchange, bid, |price, best| price > best);
+            }
+            if let Some(ask) = quote.ask {
+                best(&mut nbbo.ask, quote.exchange, ask, |price, best| price < best);
+            }
+        }
+        nbbo
+    }
+
+    /// The same prices and sizes from the same venues, whenever they were quoted.
+    pub fn same_prices(&self, other: &Nbbo) -> bool {
+        self.bid == other.bid && self.ask == other.ask
+    }
+}
+
+fn same_price(a: f64, b: f64) -> bool {
+    (a - b).abs() <= PRICE_TOLERANCE * a.abs().max(b.abs())
+}
+
+fn best(side: &mut Option<BestPrice>, exchange: Exchange, (price, size): (f64, f64), better: fn(f64, f64) -> bool) {
+    match side {
+        Some(best) if same_price(best.price, price) => {
+            best.size += size;
+            best.venues.push((exchange, size));
+        }
+        Some(best) if !better(price, best.price) => {}
+        _ => *side = Some(BestPrice { price, size, venues: vec![(exchange, size)] }),
+    }
+}
+
+type Rejections = BTreeMap<(String, Exchange), BTreeMap<RejectReason, u64>>;
+
+/// The latest quote of every top-of-book feed. Every quote is also published to
+/// subscribers. Clones share the same quotes.
+#[derive(Clone)]
+pub struct BboBook {
+    quotes: Arc<Mutex<BTreeMap<(String, Exchange), Quote>>>,
+    rejections: Arc<Mutex<Rejections>>,
+    updates: broadcast::Sender<Quote>,
+}
+
+impl Default for BboBook {
+    fn default() -> Self {
+        BboBook { quotes: Arc::default(), rejections: Arc::default(), updates: broadcast::channel(QUOTE_CAPACITY).0 }
+    }
+}
+
+impl BboBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes `quote` the latest of its venue. A quote with a malformed side, or a bid at or
+    /// above its ask, is counted and left out, and the venue keeps its previous quote.
+    pub fn update(&self, quote: Quote) -> Result<(), RejectReason> {
+        let key = (quote.pair.to_lowercase(), quote.exchange);
+        if let Err(reason) = validation::check_quote(&quote) {
+            *self.rejections.lock().unwrap().entry(key).or_default().entry(reason).or_default() += 1;
+            return Err(reason);
+        }
+        self.quotes.lock().unwrap().insert(key, quote.clone());
+        let _ = self.updates.send(quote);
+        Ok(())
+    }
+
+    /// How many quotes of `pair` on `exchange` were left out, by reason.
+    pub fn rejections(&self, exchange: Exchange, pair: &str) -> BTreeMap<RejectReason, u64> {
+        self.rejections.lock().unwrap().get(&(pair.to_lowercase(), exchange)).cloned().unwrap_or_default()
+    }
+
+    /// Forgets the quote of `pair` on `exchange`, once its feed stops.
+    pub fn remove(&self, exchange: Exchange, pair: &str) {
+        self.quotes.lock().unwrap().remove(&(pair.to_lowercase(), exchange));
+    }
+
+    /// The latest quote of every venue of `pair`.
+    pub fn quotes(&self, pair: &str) -> Vec<Quote> {
+        let pair = pair.to_lowercase();
+        self.quotes.lock().unwrap().iter()
+            .filter(|((p, _), _)| *p == pair)
+            .map(|(_, quote)| quote.clone())
+            .collect()
+    }
+
+    /// A receiver of every quote from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Quote> {
+        self.updates.subscribe()
+    }
+}
+
+/* ------------
+    TEST CASES
+   ------------*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quote(exchange: Exchange, bid: Option<(f64, f64)>, ask: Option<(f64, f64)>, received_at: u64) -> Quote {
+        Quote { exchange, pair: "ethbtc".to_string(), bid, ask, update_id: None, event_time: None, received_at }
+    }
+
+    #[test]
+    fn test_nbbo_attributes_venues() {
+        let quotes = [
+            quote(Exchange::Binance, Some((0.060, 1.0)), Some((0.062, 2.0)), 1_000),
+            quote(Exchange::Bitstamp, Some((0.060, 3.0)), Some((0.061, 1.5)), 1_200),
+        ];
+        let nbbo = Nbbo::of("ETHBTC", &quotes);
+        assert_eq!(nbbo.pair, "ethbtc");
+        assert_eq!(nbbo.bid, Some(BestPrice { price: 0.060, size: 4.0, venues: vec![(Exchange::Binance, 1.0), (Exchange::Bitstamp, 3.0)] }));
+        assert_eq!(nbbo.ask, Some(BestPrice { price: 0.061, size: 1.5, venues: vec![(Exchange::Bitstamp, 1.5)] }));
+        assert_eq!(nbbo.updated_at, Some(1_200));
+
+        let one_sided = Nbbo::of("ethbtc", &[quote(Exchange::Binance, None, Some((0.07, 1.0)), 5)]);
+        assert!(one_sided.bid.is_none());
+        assert!(!one_sided.same_prices(&nbbo));
+        assert!(Nbbo::of("ethbtc", &[]).ask.is_none());
+    }
+
+    #[test]
+    fn test_nbbo_merges_nearly_equal_prices() {
+        let quotes = [
+            quote(Exchange::Binance, Some((0.1 + 0.2, 1.0)), None, 1),
+            quote(Exchange::Bitstamp, Some((0.3, 2.0)), None, 2),
+        ];
+        let bid = Nbbo::of("ethbtc", &quotes).bid.unwrap();
+        assert_eq!((bid.size, bid.venues.len()), (3.0, 2));
+
+        let quotes = [quote(Exchange::Binance, Some((0.3, 1.0)), None, 1), quote(Exchange::Bitstamp, Some((0.3001, 2.0)), None, 2)];
+        assert_eq!(Nbbo::of("ethbtc", &quotes).bid.unwrap().venues, vec![(Exchange::Bitstamp, 2.0)]);
+    }
+
+    #[test]
+    fn test_bbo_book_keeps_latest_quote() {
+        let book = BboBook::new();
+        let mut updates = book.subscribe();
+        book.update(quote(Exchange::Binance, Some((0.06, 1.0)), None, 1)).unwrap();
+        book.update(quote(Exchange::Binance, Some((0.061, 1.0)), None, 2)).unwrap();
+        book.update(quote(Exchange::Bitstamp, Some((0.05, 1.0)), None, 3)).unwrap();
+
+        let quotes = book.quotes("ETHBTC");
+        assert_eq!(quotes.len(), 2);
+        assert_eq!(quotes[0].bid, Some((0.061, 1.0)));
+        assert_eq!(updates.try_recv().unwrap().received_at, 1);
+
+        book.remove(Exchange::Bitstamp, "ethbtc");
+        assert_eq!(book.quotes("ethbtc").len(), 1);
+    }
+
+    #[test]
+    fn test_bbo_book_leaves_out_bad_quotes() {
+        let book = BboBook::new();
+        book.update(quote(Exchange::Binance, Some((0.06, 1.0)), Some((0.061, 1.0)), 1)).unwrap();
+        assert_eq!(book.update(quote(Exchange::Binance, Some((0.062, 1.0)), Some((0.061, 1.0)), 2)), Err(RejectReason::CrossedBook));
+        assert_eq!(book.update(quote(Exchange::Binance, Some((f64::NAN, 1.0)), None, 3)), Err(RejectReason::InvalidPrice));
+
+        assert_eq!(book.quotes("ethbtc")[0].received_at, 1);
+        let rejections = book.rejections(Exchange::Binance, "ETHBTC");
+        assert_eq!((rejections[&RejectReason::CrossedBook], rejections[&RejectReason::InvalidPrice]), (1, 1));
+        assert!(book.rejections(Exchange::Bitstamp, "ethbtc").is_empty());
+    }
+}
@@ -1,3 +1,10 @@
+use crate::aggregator::Exchange;
+use crate::bbo::{BboBook, Quote};
+use crate::feed::{apply_update, publish_trade, FeedUpdate};
+use crate::recorder::Recorder;
+use crate::status::{now_millis, SharedFeedStatus};
+use crate::trade::{Trade, TradeSide, TradeTape};
+use crate::validation::RejectReason;
 use exc_orderbook::combine_orderbook::Order;
 use url::Url;
 use serde::Deserialize;
@@ -8,6 +15,8 @@ use futures_util::StreamExt;
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct DepthStreamData {
+    #[serde(default)]
+    pub last_update_id: Option<u64>,
     pub bids: Vec<BidOrAsk>,
     pub asks: Vec<BidOrAsk>,
 }
@@ -24,13 +33,169 @@ pub struct DepthStreamWrapper {
     pub data: DepthStreamData,
 }
 
+/// A `@trade` event.
+#[derive(Debug, Deserialize)]
+pub struct TradeStreamData {
+    #[serde(rename = "e")]
+    pub event: String,
+    #[serde(rename = "t")]
+    pub trade_id: u64,
+    #[serde(rename = "p")]
+    pub price: String,
+    #[serde(rename = "q")]
+    pub size: String,
+    #[serde(rename = "T")]
+    pub trade_time: u64,
+    #[serde(rename = "m")]
+    pub buyer_is_maker: bool,
+}
+
+/// A `@bookTicker` event: the best bid and ask, and the book update they are as of.
+#[derive(Debug, Deserialize)]
+pub struct BookTickerData {
+    #[serde(rename = "u")]
+    pub update_id: u64,
+    #[serde(rename = "b")]
+    pub bid_price: String,
+    #[serde(rename = "B")]
+    pub bid_size: String,
+    #[serde(rename = "a")]
+    pub ask_price: String,
+    #[serde(rename = "A")]
+    pub ask_size: String,
+}
+
 static BINANCE_WS_API: &str = "wss://stream.binance.com:9443";
 
+/// The book in one partial depth frame. These carry no event time.
+pub fn parse_depth(text: &str, pair: &str) -> Result<FeedUpdate, Box<dyn std::error::Error>> {
+    let parsed: DepthStreamData = serde_json::from_str(text)?;
+    let to_orders = |levels: Vec<BidOrAsk>| -> Result<Vec<Order>, std::num::ParseFloatError> {
+        levels.into_iter()
+            .map(|level| Ok(Order {
+                exchange: "binance".to_string(),
+                pair: pair.to_string(),
+                price: level.price.parse()?,
+                size: level.size.parse()?,
+            }))
+            .collect()
+    };
+
+    Ok(FeedUpdate {
+        bids: to_orders(parsed.bids)?,
+        asks: to_orders(parsed.asks)?,
+        event_time: None,
+        sequence: parsed.last_update_id,
+    })
+}
+
+/// The trade in one `@trade` frame.
+pub fn parse_trade(text: &str, pair: &str, received_at: u64) -> Result<Trade, Box<dyn std::error::Error>> {
+    let parsed: TradeStreamData = serde_json::from_str(text)?;
+    if parsed.event != "trade" {
+        return Err(format!("Unexpected trade event: {}", parsed.event).into());
+    }
+
+    Ok(Trade {
+        exchange: Exchange::Binance,
+        pair: pair.to_string(),
+        price: parsed.price.parse()?,
+        size: parsed.size.parse()?,
+        // When the buyer was resting on the book, the seller took liquidity.
+        aggressor: if parsed.buyer_is_maker { TradeSide::Sell } else { TradeSide::Buy },
+        trade_id: parsed.trade_id,
+        trade_time: Some(parsed.trade_time),
+        received_at,
+    })
+}
+
+/// Publishes every trade of `pair` to `tape` until the stream ends, keeping `status`
+/// up to date.
+pub async fn get_binance_trades(tape: TradeTape, status: SharedFeedStatus, pair: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let binance_url = format!("{}/ws/{}@trade", BINANCE_WS_API, pair);
+    status.lock().unwrap().connecting();
+    let (mut socket, _) = connect_async(Url::parse(&binance_url)?).await?;
+    status.lock().unwrap().connected();
+    println!("Connected to {} binance trade stream.", pair);
+
+    while let Some(msg) = socket.next().await {
+        match msg {
+            Ok(Message::Text(text)) => {
+                publish_trade("binance", pair, &text, parse_trade(&text, pair, now_millis()).map(Some), &tape, &status);
+            }
+            Err(e) => return Err(e.into()),
+            _ => continue,
+        }
+    }
+
+    Ok(())
+}
+
+/// The best bid and ask in one `@bookTicker` frame. Binance sends a zero size for an
+/// empty side. A price or size that is NaN or infinite is an error.
+pub fn parse_book_ticker(text: &str, pair: &str, received_at: u64) -> Result<Quote, Box<dyn std::error::Error>> {
+    let parsed: BookTickerData = serde_json::from_str(text)?;
+    let level = |price: String, size: String| -> Result<Option<(f64, f64)>, Box<dyn std::error::Error>> {
+        let (price, size) = (price.parse::<f64>()?, size.parse::<f64>()?);
+        if !price.is_finite() || !size.is_finite() {
+            return Err(format!("level of {} at {} is not a number", size, price).into());
+        }
+        Ok((size > 0.0).then_some((price, size)))
+    };
+
+    Ok(Quote {
+        exchange: Exchange::Binance,
+        pair: pair.to_string(),
+        bid: level(parsed.bid_price, parsed.bid_size)?,
+        ask: level(parsed.ask_price, parsed.ask_size)?,
+        update_id: Some(parsed.update_id),
+        event_time: None,
+        received_at,
+    })
+}
+
+/// Keeps the Binance quote of `pair` in `bbo` up to date from `@bookTicker`, which sends
+/// every change of the best bid or ask as it happens, until the stream ends, keeping
+/// `status` up to date. Frames that cannot be parsed are counted in `status` and quotes
+/// `bbo` rejects are counted there; both are skipped.
+pub async fn get_binance_bbo(bbo: BboBook, status: SharedFeedStatus, pair: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let binance_url = format!("{}/ws/{}@bookTicker", BINANCE_WS_API, pair);
+    status.lock().unwrap().connecting();
+    let (mut socket, _) = connect_async(Url::parse(&binance_url)?).await?;
+    status.lock().unwrap().connected();
+    println!("Connected to {} binance book ticker stream.", pair);
+
+    while let Some(msg) = socket.next().await {
+        match msg {
+            Ok(Message::Text(text)) => {
+                let quote = match parse_book_ticker(&text, pair, now_millis()) {
+                    Ok(quote) => quote,
+                    Err(e) => {
+                        status.lock().unwrap().record_rejections(RejectReason::MalformedFrame, 1);
+                        println!("Skipped a binance {} quote frame: {}: {}", pair, e, text);
+                        continue;
+                    }
+                };
+                status.lock().unwrap().record_message(quote.received_at, quote.event_time, quote.update_id);
+                if let Err(reason) = bbo.update(quote) {
+                    println!("Skipped a binance {} quote: {}", pair, reason);
+                }
+            }
+            Err(e) => return Err(e.into()),
+            _ => continue,
+        }
+    }
+
+    Ok(())
+}
+
 // Added `pair` argument
-pub async fn get_binance_order_book(order_book: Arc<Mutex<(Vec<Order>, Vec<Order>)>>, pair: &str) -> Result<(), Box<dyn std::error::Error>> {
+pub async fn get_binance_order_book(order_book: Arc<Mutex<(Vec<Order>, Vec<Order>)>>, status: SharedFeedStatus, pair: &str, recorder: Option<Recorder>) -> Result<(), Box<dyn std::error::Error>> {
     let binance_url = format!("{}/ws/{}@depth20@100ms", BINANCE_WS_API, pair);
 
-    let (mut socket, _) = connect_async(Url::parse(&binance_url).unwrap()).await.expect("Can't connect");
+    status.lock().unwrap().connecting();
+    let (mut socket, _) = connect_async(Url::parse(&binance_url)?).await?;
+    status.lock().unwrap().connected();
 
     println!("Connected to {} binance stream.", pair);
 
@@ -38,34 +203,16 @@ pub async fn get_binance_order_book(order_book: Arc<Mutex<(Vec<Order>, Vec<Order
         let msg = socket.next().await;
         match msg {
             Some(Ok(Message::Text(text))) => {
-                let parsed: DepthStreamData = serde_json::from_str(&text).expect("Can't parse");
-
-                let mut bids: Vec<Order> = Vec::new();
-                let mut asks: Vec<Order> = Vec::new();
-
-                for bid in parsed.bids {
-                    bids.push(Order {
-                        exchange: "binance".to_string(),
-                        pair: pair.to_string(),
-                        price: bid.price.parse().unwrap(),
-                        size: bid.size.parse().unwrap(),
-                    });
-                }
-
-                for ask in parsed.asks {
-                    asks.push(Order {
-                        exchange: "binance".to_string(),
-                        pair: pair.to_string(),
-                        price: ask.price.parse().unwrap(),
-                        size: ask.size.parse().unwrap(),
-                    });
+                let received_at = now_millis();
+                if let Some(recorder) = &recorder {
+                    recorder.record_frame("binance", pair, received_at, &text);
                 }
-
-                let mut shared_order_book = order_book.lock().unwrap();
-                *shared_order_book = (bids, asks);
+                let update = parse_depth(&text, pair)?;
+                apply_update("binance", pair, received_at, update, &order_book, &status, recorder.as_ref())?;
             }
             Some(Err(e)) => {
                 println!("Error in WebSocket communication: {:?}", e);
+                status.lock().unwrap().disconnected(Some(e.to_string()));
                 break;
             }
             None => break,
@@ -83,16 +230,46 @@ pub async fn get_binance_order_book(order_book: Arc<Mutex<(Vec<Order>, Vec<Order
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::status::FeedStatus;
     use std::sync::{Arc, Mutex};
     use tokio::time::{sleep, Duration};
 
+    #[test]
+    fn test_parse_trade() {
+        let text = r#"{"e":"trade","E":1672515782136,"s":"ETHBTC","t":12345,"p":"0.06010","q":"1.5","T":1672515782130,"m":true,"M":true}"#;
+        let trade = parse_trade(text, "ethbtc", 1672515782200).unwrap();
+        assert_eq!(trade.exchange, Exchange::Binance);
+        assert_eq!((trade.price, trade.size), (0.0601, 1.5));
+        assert_eq!(trade.aggressor, TradeSide::Sell);
+        assert_eq!(trade.trade_id, 12345);
+        assert_eq!(trade.trade_time, Some(1672515782130));
+        assert_eq!(trade.received_at, 1672515782200);
+
+        assert!(parse_trade(r#"{"e":"kline","t":1,"p":"1","q":"1","T":0,"m":false}"#, "ethbtc", 0).is_err());
+    }
+
+    #[test]
+    fn test_parse_book_ticker() {
+        let text = r#"{"u":400900217,"s":"ETHBTC","b":"0.06010","B":"31.2","a":"0.06020","A":"0.00000000"}"#;
+        let quote = parse_book_ticker(text, "ethbtc", 1_000).unwrap();
+        assert_eq!(quote.bid, Some((0.0601, 31.2)));
+        assert_eq!(quote.ask, None);
+        assert_eq!(quote.update_id, Some(400900217));
+        assert_eq!(quote.received_at, 1_000);
+
+        let text = r#"{"u":400900218,"s":"ETHBTC","b":"0.06010","B":"NaN","a":"0.06020","A":"1.0"}"#;
+        assert!(parse_book_ticker(text, "ethbtc", 1_000).is_err());
+        let text = r#"{"u":400900219,"s":"ETHBTC","b":"0.06010","B":"1.0","a":"inf","A":"1.0"}"#;
+        assert!(parse_book_ticker(text, "ethbtc", 1_000).is_err());
+    }
+
     #[tokio::test]
     async fn test_get_binance_order_book() {
         let order_book = Arc::new(Mutex::new((Vec::new(), Vec::new())));
         let order_book_clone = Arc::clone(&order_book);  // clone the Arc
 
         let handle = tokio::spawn(async move {
-            get_binance_order_book(order_book_clone, "ethbtc").await.unwrap();
+            get_binance_order_book(order_book_clone, FeedStatus::new_shared(), "ethbtc", None).await.unwrap();
         });
 
         // Wait for some seconds to collect some data.
@@ -104,7 +281,7 @@ mod tests {
         let (bids, asks) = order_book.lock().unwrap().clone();
 
         assert!(!bids.is_empty() || !asks.is_empty(), "Binance orders are empty");
-        println!("{} order book (first 5 bids and asks):", "ethbtc");
+        println!("ethbtc order book (first 5 bids and asks):");
         println!("Bids: {:?}", bids.iter().take(5).collect::<Vec<_>>());
         println!("Asks: {:?}", asks.iter().take(5).collect::<Vec<_>>());
     }
@@ -1,20 +1,202 @@
+use crate::aggregator::Exchange;
+use crate::bbo::{BboBook, Quote};
+use crate::feed::{apply_update, publish_trade, FeedUpdate};
+use crate::l3::{OrderEvent, OrderEventKind, SharedL3Book};
+use crate::recorder::Recorder;
+use crate::status::{now_millis, SharedFeedStatus};
+use crate::trade::{Trade, TradeSide, TradeTape};
+use crate::validation;
 use exc_orderbook::combine_orderbook::Order;
+use exc_orderbook::consolidated_book::Side;
 use tokio_tungstenite::tungstenite::Message;
 use tokio_tungstenite::connect_async;
 use futures_util::StreamExt;
 use url::Url;
 use serde_json::{json, Value};
 use std::sync::{Arc, Mutex};
-use tokio::time::sleep;
 use std::time::Duration;
 
 static BITSTAMP_WS_API: &str = "wss://ws.bitstamp.net";
 
-// Added `pair` argument
-pub async fn get_bitstamp_order_book(order_book: Arc<Mutex<(Vec<Order>, Vec<Order>)>>, pair: &str) -> Result<(), Box<dyn std::error::Error>> {
+/// The book in one frame, or `None` for frames that carry none, such as the
+/// subscription acknowledgement. Only the top 20 levels per side are kept.
+pub fn parse_order_book(text: &str, pair: &str) -> Result<Option<FeedUpdate>, Box<dyn std::error::Error>> {
+    let Ok(data) = serde_json::from_str::<Value>(text) else {
+        return Ok(None);
+    };
+    if data["event"].as_str() != Some("data") {
+        return Ok(None);
+    }
+
+    let to_orders = |levels: &Value| -> Result<Vec<Order>, Box<dyn std::error::Error>> {
+        let mut orders = Vec::new();
+        for level in levels.as_array().ok_or("levels are not an array")?.iter().take(20) {
+            let price = level[0].as_str().ok_or("price is not a string")?.parse::<f64>()?;
+            let size = level[1].as_str().ok_or("size is not a string")?.parse::<f64>()?;
+            orders.push(Order { exchange: "Bitstamp".into(), pair: pair.into(), price, size });
+        }
+        Ok(orders)
+    };
+
+    // Bitstamp books carry their event time in microseconds, but no sequence number.
+    let event_time = data["data"]["microtimestamp"].as_str()
+        .and_then(|micros| micros.parse::<u64>().ok())
+        .map(|micros| micros / 1000);
+
+    Ok(Some(FeedUpdate {
+        bids: to_orders(&data["data"]["bids"])?,
+        asks: to_orders(&data["data"]["asks"])?,
+        event_time,
+        sequence: None,
+    }))
+}
+
+/// The trade in one `live_trades` frame, or `None` for frames that carry none.
+pub fn parse_trade(text: &str, pair: &str, received_at: u64) -> Result<Option<Trade>, Box<dyn std::error::Error>> {
+    let Ok(data) = serde_json::from_str::<Value>(text) else {
+        return Ok(None);
+    };
+    if data["event"].as_str() != Some("trade") {
+        return Ok(None);
+    }
+
+    let trade = &data["data"];
+    let aggressor = match trade["type"].as_u64() {
+        Some(0) => TradeSide::Buy,
+        Some(1) => TradeSide::Sell,
+        _ => return Err("trade type is neither buy nor sell".into()),
+    };
+    let trade_time = trade["microtimestamp"].as_str()
+        .and_then(|micros| micros.parse::<u64>().ok())
+        .map(|micros| micros / 1000);
+
+    Ok(Some(Trade {
+        exchange: Exchange::Bitstamp,
+        pair: pair.to_string(),
+        price: trade["price_str"].as_str().ok_or("price is not a string")?.parse()?,
+        size: trade["amount_str"].as_str().ok_or("amount is not a string")?.parse()?,
+        aggressor,
+        trade_id: trade["id"].as_u64().ok_or("trade id is not a number")?,
+        trade_time,
+        received_at,
+    }))
+}
+
+/// Publishes every trade of `pair` to `tape` until the stream ends, keeping `status`
+/// up to date.
+pub async fn get_bitstamp_trades(tape: TradeTape, status: SharedFeedStatus, pair: &str) -> Result<(), Box<dyn std::error::Error>> {
+    status.lock().unwrap().connecting();
+    let (ws_stream, _response) = connect_async(Url::parse(BITSTAMP_WS_API)?).await?;
+    status.lock().unwrap().connected();
+    println!("Connected to {} bitstamp trade stream.", pair);
+    let (mut socket, mut receiver) = ws_stream.split();
+
+    let subscribe_msg = json!({
+        "event": "bts:subscribe",
+        "data": {
+            "channel": format!("live_trades_{}", pair)
+        }
+    });
+    futures_util::SinkExt::send(&mut socket, Message::Text(subscribe_msg.to_string())).await?;
+
+    while let Some(msg) = receiver.next().await {
+        if let Message::Text(text) = msg? {
+            publish_trade("bitstamp", pair, &text, parse_trade(&text, pair, now_millis()), &tape, &status);
+        }
+    }
+
+    Ok(())
+}
+
+/// The order event in one `live_orders` frame, or `None` for frames that carry none.
+/// An event priced at or below zero, or with a size that is NaN, infinite or negative,
+/// is an error.
+pub fn parse_order_event(text: &str) -> Result<Option<OrderEvent>, Box<dyn std::error::Error>> {
+    let Ok(data) = serde_json::from_str::<Value>(text) else {
+        return Ok(None);
+    };
+    let kind = match data["event"].as_str() {
+        Some("order_created") => OrderEventKind::Created,
+        Some("order_changed") => OrderEventKind::Changed,
+        Some("order_deleted") => OrderEventKind::Deleted,
+        _ => return Ok(None),
+    };
+
+    let order = &data["data"];
+    let side = match order["order_type"].as_u64() {
+        Some(0) => Side::Bid,
+        Some(1) => Side::Ask,
+        _ => return Err("order type is neither buy nor sell".into()),
+    };
+    let time = order["microtimestamp"].as_str()
+        .and_then(|micros| micros.parse::<u64>().ok())
+        .map(|micros| micros / 1000);
+
+    let event = OrderEvent {
+        kind,
+        id: order["id"].as_u64().ok_or("order id is not a number")?,
+        side,
+        price: order["price_str"].as_str().ok_or("price is not a string")?.parse()?,
+        size: order["amount_str"].as_str().ok_or("amount is not a string")?.parse()?,
+        time,
+    };
+    validation::check_order_event(&event).map_err(|reason| format!("order {} rejected: {}", event.id, reason))?;
+    Ok(Some(event))
+}
+
+/// Applies every order event of `pair` to `book` until the stream ends. The channel
+/// sends no snapshot, so the book starts empty and only holds orders seen since. Events
+/// that cannot be read or are invalid are skipped and counted, since dropping the
+/// connection would lose every order seen so far.
+pub async fn get_bitstamp_l3(book: SharedL3Book, pair: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let (ws_stream, _response) = connect_async(Url::parse(BITSTAMP_WS_API)?).await?;
+    book.lock().unwrap().connected_at = Some(now_millis());
+    println!("Connected to {} bitstamp order stream.", pair);
+    let (mut socket, mut receiver) = ws_stream.split();
+
+    let subscribe_msg = json!({
+        "event": "bts:subscribe",
+        "data": {
+            "channel": format!("live_orders_{}", pair)
+        }
+    });
+    futures_util::SinkExt::send(&mut socket, Message::Text(subscribe_msg.to_string())).await?;
+
+    while let Some(msg) = receiver.next().await {
+        if let Message::Text(text) = msg? {
+            match parse_order_event(&text) {
+                Ok(Some(event)) => book.lock().unwrap().apply(&event),
+                Ok(None) => {}
+                Err(e) => {
+                    println!("Skipped a bitstamp {} order event: {}", pair, e);
+                    book.lock().unwrap().skipped_events += 1;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Quotes the best levels of `order_book` to `bbo` as the Bitstamp quote of `pair`.
+/// Bitstamp has no top-of-book channel, so this is its top-of-book feed.
+fn quote_best_levels(bbo: &BboBook, pair: &str, order_book: &Mutex<(Vec<Order>, Vec<Order>)>, event_time: Option<u64>, received_at: u64) {
+    let best = |orders: &[Order]| orders.first().map(|order| (order.price, order.size));
+    let (bid, ask) = {
+        let (bids, asks) = &*order_book.lock().unwrap();
+        (best(bids), best(asks))
+    };
+    // The book was validated as it was applied, so its best levels always make a valid quote.
+    let _ = bbo.update(Quote { exchange: Exchange::Bitstamp, pair: pair.to_string(), bid, ask, update_id: None, event_time, received_at });
+}
+
+// Added `pair` argument. With `bbo`, the best levels of every book are quoted to it.
+pub async fn get_bitstamp_order_book(order_book: Arc<Mutex<(Vec<Order>, Vec<Order>)>>, status: SharedFeedStatus, pair: &str, recorder: Option<Recorder>, bbo: Option<BboBook>) -> Result<(), Box<dyn std::error::Error>> {
     let url = Url::parse(BITSTAMP_WS_API)?;
 
+    status.lock().unwrap().connecting();
     let (ws_stream, _response) = connect_async(url).await?;
+    status.lock().unwrap().connected();
     println!("Connected to {} bitstamp stream.", pair);
 
     let (mut socket, mut receiver) = ws_stream.split();
@@ -28,46 +210,20 @@ pub async fn get_bitstamp_order_book(order_book: Arc<Mutex<(Vec<Order>, Vec<Orde
 
     futures_util::SinkExt::send(&mut socket, Message::Text(subscribe_msg.to_string())).await?;
 
-    let mut message_count = 0; // Add this line
-
-    loop {
-        match receiver.next().await {
-            Some(Ok(Message::Text(text))) => {
-                if let Ok(data) = serde_json::from_str::<Value>(&text) {
-                    if data["event"].as_str() == Some("data") {
-                        let mut bids: Vec<Order> = Vec::new();
-                        let mut asks: Vec<Order> = Vec::new();
-                        let bids_data = data["data"]["bids"].as_array().unwrap();
-                        let asks_data = data["data"]["asks"].as_array().unwrap();
-
-                        for bid in bids_data.iter().take(20) {
-                            let bid = bid.as_array().unwrap();
-                            let price = bid[0].as_str().unwrap().parse::<f64>()?;
-                            let size = bid[1].as_str().unwrap().parse::<f64>()?;
-                            bids.push(Order { exchange: "Bitstamp".into(), pair: pair.into(), price, size });  // Changed to use `pair`
-                        }
-
-                        for ask in asks_data.iter().take(20) {
-                            let ask = ask.as_array().unwrap();
-                            let price = ask[0].as_str().unwrap().parse::<f64>()?;
-                            let size = ask[1].as_str().unwrap().parse::<f64>()?;
-                            asks.push(Order { exchange: "Bitstamp".into(), pair: pair.into(), price, size });  // Changed to use `pair`
-                        }
-
-                        let mut shared_order_book = order_book.lock().unwrap();
-                        *shared_order_book = (bids, asks);
-
-                        message_count += 1; // Update the counter after processing the message
-
-                        if message_count >= 10 {
-                            break; // This will break the loop after processing 10 messages
-                        }
-                    }
+    while let Some(msg) = receiver.next().await {
+        if let Ok(Message::Text(text)) = msg {
+            let received_at = now_millis();
+            if let Some(recorder) = &recorder {
+                recorder.record_frame("bitstamp", pair, received_at, &text);
+            }
+            if let Some(update) = parse_order_book(&text, pair)? {
+                let event_time = update.event_time;
+                apply_update("bitstamp", pair, received_at, update, &order_book, &status, recorder.as_ref())?;
+                if let Some(bbo) = &bbo {
+                    quote_best_levels(bbo, pair, &order_book, event_time, received_at);
                 }
-            },
-            _ => (),
+            }
         }
-        sleep(Duration::from_millis(200)).await;
     }
 
     Ok(())
@@ -146,3 +302,49 @@ pub async fn get_bitstamp_order_book(order_book: Arc<Mutex<(Vec<Order>, Vec<Orde
    }
    
 
+   #[test]
+   fn test_parse_trade() {
+       let text = r#"{"data": {"id": 271406391, "timestamp": "1672515782", "amount": 0.5, "amount_str": "0.50000000", "price": 0.0601, "price_str": "0.06010", "type": 1, "microtimestamp": "1672515782136512", "buy_order_id": 1, "sell_order_id": 2}, "channel": "live_trades_ethbtc", "event": "trade"}"#;
+       let trade = parse_trade(text, "ethbtc", 1672515782200).unwrap().unwrap();
+       assert_eq!(trade.exchange, Exchange::Bitstamp);
+       assert_eq!((trade.price, trade.size), (0.0601, 0.5));
+       assert_eq!(trade.aggressor, TradeSide::Sell);
+       assert_eq!(trade.trade_id, 271406391);
+       assert_eq!(trade.trade_time, Some(1672515782136));
+
+       let subscribed = r#"{"event": "bts:subscription_succeeded", "channel": "live_trades_ethbtc", "data": {}}"#;
+       assert!(parse_trade(subscribed, "ethbtc", 0).unwrap().is_none());
+   }
+
+   #[test]
+   fn test_quote_best_levels() {
+       let bbo = BboBook::new();
+       let order = |price| Order { exchange: "bitstamp".to_string(), pair: "ethbtc".to_string(), price, size: 2.0 };
+       let order_book = Mutex::new((vec![order(0.06), order(0.059)], vec![]));
+       quote_best_levels(&bbo, "ethbtc", &order_book, Some(900), 1_000);
+       let quotes = bbo.quotes("ethbtc");
+       assert_eq!(quotes.len(), 1);
+       assert_eq!((quotes[0].exchange, quotes[0].bid, quotes[0].ask), (Exchange::Bitstamp, Some((0.06, 2.0)), None));
+       assert_eq!((quotes[0].event_time, quotes[0].received_at), (Some(900), 1_000));
+   }
+
+   #[test]
+   fn test_parse_order_event() {
+       let text = r#"{"data": {"id": 1585410389401600, "id_str": "1585410389401600", "order_type": 0, "datetime": "1672515782", "microtimestamp": "1672515782123456", "amount": 0.5, "amount_str": "0.50000000", "price": 0.0601, "price_str": "0.06010"}, "channel": "live_orders_ethbtc", "event": "order_created"}"#;
+       let event = parse_order_event(text).unwrap().unwrap();
+       assert_eq!(event.kind, OrderEventKind::Created);
+       assert_eq!((event.id, event.side), (1585410389401600, Side::Bid));
+       assert_eq!((event.price, event.size, event.time), (0.0601, 0.5, Some(1672515782123)));
+
+       let deleted = text.replace("order_created", "order_deleted").replace(r#""order_type": 0"#, r#""order_type": 1"#);
+       let event = parse_order_event(&deleted).unwrap().unwrap();
+       assert_eq!((event.kind, event.side), (OrderEventKind::Deleted, Side::Ask));
+
+       let subscribed = r#"{"event": "bts:subscription_succeeded", "channel": "live_orders_ethbtc", "data": {}}"#;
+       assert!(parse_order_event(subscribed).unwrap().is_none());
+
+       let negative_price = text.replace(r#""price_str": "0.06010""#, r#""price_str": "-0.06010""#);
+       assert!(parse_order_event(&negative_price).is_err());
+       let nan_size = text.replace(r#""amount_str": "0.50000000""#, r#""amount_str": "NaN""#);
+       assert!(parse_order_event(&nan_size).is_err());
+   }
@@ -0,0 +1,272 @@
+use crate::aggregator::Exchange;
+use crate::trade::Trade;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+
+// How many closed candles are kept per series.
+static HISTORY_LENGTH: usize = 1_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Interval {
+    OneSecond,
+    OneMinute,
+    FiveMinutes,
+    OneHour,
+}
+
+impl Interval {
+    pub const ALL: [Interval; 4] = [Interval::OneSecond, Interval::OneMinute, Interval::FiveMinutes, Interval::OneHour];
+
+    pub fn millis(&self) -> u64 {
+        match self {
+            Interval::OneSecond => 1_000,
+            Interval::OneMinute => 60_000,
+            Interval::FiveMinutes => 300_000,
+            Interval::OneHour => 3_600_000,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Interval::OneSecond => "1s",
+            Interval::OneMinute => "1m",
+            Interval::FiveMinutes => "5m",
+            Interval::OneHour => "1h",
+        }
+    }
+
+    /// The start of the interval `time` falls in.
+    pub fn start(&self, time: u64) -> u64 {
+        time - time % self.millis()
+    }
+}
+
+impl fmt::Display for Interval {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Interval {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Interval::ALL.into_iter()
+            .find(|interval| interval.name() == s.to_lowercase())
+            .ok_or_else(|| format!("Unknown candle interval: {}", s))
+    }
+}
+
+/// The trades of one venue, or of every venue when `exchange` is `None`, from
+/// `open_time` up to but excluding `close_time`, in milliseconds since the Unix epoch.
+/// `closed` is false while the interval is still running.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candle {
+    pub exchange: Option<Exchange>,
+    pub pair: String,
+    pub interval: Interval,
+    pub open_time: u64,
+    pub close_time: u64,
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    pub volume: f64,
+    /// The traded notional, in the quote currency.
+    pub quote_volume: f64,
+    pub vwap: f64,
+    pub trades: u64,
+    pub closed: bool,
+}
+
+impl Candle {
+    fn open(exchange: Option<Exchange>, interval: Interval, trade: &Trade, open_time: u64) -> Self {
+        Candle {
+            exchange,
+            pair: trade.pair.clone(),
+            interval,
+            open_time,
+            close_time: open_time + interval.millis(),
+            open: trade.price,
+            high: trade.price,
+            low: trade.price,
+            close: trade.price,
+            volume: trade.size,
+            quote_volume: trade.price * trade.size,
+            vwap: trade.price,
+            trades: 1,
+            closed: false,
+        }
+    }
+
+    fn add(&mut self, trade: &Trade) {
+        self.high = self.high.max(trade.price);
+        self.low = self.low.min(trade.price);
+        self.close = trade.price;
+        self.volume += trade.size;
+        self.quote_volume += trade.price * trade.size;
+        if self.volume > 0.0 {
+            self.vwap = self.quote_volume / self.volume;
+        }
+        self.trades += 1;
+    }
+}
+
+type SeriesKey = (Option<Exchange>, String, Interval);
+
+#[derive(Default)]
+struct Series {
+    current: Option<Candle>,
+    closed: VecDeque<Candle>,
+}
+
+impl Series {
+    fn close(&mut self) -> Option<Candle> {
+        let mut candle = self.current.take()?;
+        candle.closed = true;
+        if self.closed.len() == HISTORY_LENGTH {
+            self.closed.pop_front();
+        }
+        self.closed.push_back(candle.clone());
+        Some(candle)
+    }
+}
+
+/// Builds candles of every interval for each venue and pair, and for each pair across
+/// all venues, from the trade tape. Intervals without trades have no candle.
+#[derive(Default)]
+pub struct CandleBuilder {
+    series: BTreeMap<SeriesKey, Series>,
+}
+
+impl CandleBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `trade`, timed by when the venue matched it if it says, to the candles of its
+    /// venue and the consolidated ones. Returns the candles it closed, followed by the
+    /// ones it updated. A trade from an interval that was already closed, as a late one
+    /// from another venue can be, is counted in the interval after it.
+    pub fn add(&mut self, trade: &Trade) -> Vec<Candle> {
+        let trade_time = trade.trade_time.unwrap_or(trade.received_at);
+        let mut closed = Vec::new();
+        let mut updated = Vec::new();
+        for exchange in [Some(trade.exchange), None] {
+            for interval in Interval::ALL {
+                let series = self.series.entry((exchange, trade.pair.clone(), interval)).or_default();
+                let time = trade_time.max(series.closed.back().map_or(0, |candle| candle.close_time));
+                if series.current.as_ref().is_some_and(|candle| time >= candle.close_time) {
+                    closed.extend(series.close());
+                }
+                match &mut series.current {
+                    Some(candle) => candle.add(trade),
+                    None => series.current = Some(Candle::open(exchange, interval, trade, interval.start(time))),
+                }
+                updated.extend(series.current.clone());
+            }
+        }
+        closed.extend(updated);
+        closed
+    }
+
+    /// Closes every candle whose interval ended by `now`, and returns them.
+    pub fn close_elapsed(&mut self, now: u64) -> Vec<Candle> {
+        self.series.values_mut()
+            .filter(|series| series.current.as_ref().is_some_and(|candle| now >= candle.close_time))
+            .filter_map(Series::close)
+            .collect()
+    }
+
+    /// Up to `limit` of the latest candles of `pair` on `exchange`, or consolidated when
+    /// it is `None`, oldest first. The running candle comes last if there is one.
+    pub fn history(&self, exchange: Option<Exchange>, pair: &str, interval: Interval, limit: usize) -> Vec<Candle> {
+        let Some(series) = self.series.get(&(exchange, pair.to_lowercase(), interval)) else {
+            return Vec::new();
+        };
+        let candles: Vec<Candle> = series.closed.iter().chain(series.current.iter()).cloned().collect();
+        candles[candles.len().saturating_sub(limit)..].to_vec()
+    }
+}
+
+/* ------------
+    TEST CASES
+   ------------*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trade::TradeSide;
+
+    fn trade(exchange: Exchange, time: u64, price: f64, size: f64) -> Trade {
+        Trade {
+            exchange,
+            pair: "ethbtc".to_string(),
+            price,
+            size,
+            aggressor: TradeSide::Buy,
+            trade_id: time,
+            trade_time: Some(time),
+            received_at: time + 5,
+        }
+    }
+
+    #[test]
+    fn test_interval_names() {
+        assert_eq!("5M".parse::<Interval>().unwrap(), Interval::FiveMinutes);
+        assert!("2m".parse::<Interval>().is_err());
+        assert_eq!(Interval::OneMinute.start(125_000), 120_000);
+    }
+
+    #[test]
+    fn test_candles_per_venue_and_consolidated() {
+        let mut builder = CandleBuilder::new();
+        builder.add(&trade(Exchange::Binance, 1_000, 10.0, 1.0));
+        builder.add(&trade(Exchange::Bitstamp, 1_200, 12.0, 1.0));
+        let updated = builder.add(&trade(Exchange::Binance, 1_900, 9.0, 2.0));
+        // The Binance and consolidated candle of every interval.
+        assert_eq!(updated.len(), 8);
+        assert!(updated.iter().all(|candle| !candle.closed));
+
+        let consolidated = &builder.history(None, "ETHBTC", Interval::OneSecond, 10)[0];
+        assert_eq!((consolidated.open_time, consolidated.close_time), (1_000, 2_000));
+        assert_eq!((consolidated.open, consolidated.high, consolidated.low, consolidated.close), (10.0, 12.0, 9.0, 9.0));
+        assert_eq!((consolidated.volume, consolidated.trades), (4.0, 3));
+        assert_eq!(consolidated.vwap, 10.0);
+        let binance = &builder.history(Some(Exchange::Binance), "ethbtc", Interval::OneSecond, 10)[0];
+        assert_eq!((binance.volume, binance.high, binance.trades), (3.0, 10.0, 2));
+
+        // A trade in the next second closes the running 1s candles.
+        let changed = builder.add(&trade(Exchange::Bitstamp, 2_100, 11.0, 1.0));
+        let closed: Vec<&Candle> = changed.iter().filter(|candle| candle.closed).collect();
+        assert_eq!(closed.len(), 2);
+        assert!(closed.iter().all(|candle| candle.interval == Interval::OneSecond && candle.open_time == 1_000));
+        let history = builder.history(None, "ethbtc", Interval::OneSecond, 10);
+        assert_eq!(history.len(), 2);
+        assert!(history[0].closed && !history[1].closed);
+        assert_eq!(builder.history(None, "ethbtc", Interval::OneMinute, 10).len(), 1);
+        assert_eq!(builder.history(None, "ethbtc", Interval::OneSecond, 1)[0].open_time, 2_000);
+    }
+
+    #[test]
+    fn test_candles_close_on_interval_boundaries() {
+        let mut builder = CandleBuilder::new();
+        builder.add(&trade(Exchange::Binance, 59_500, 10.0, 1.0));
+        assert!(builder.close_elapsed(59_999).is_empty());
+
+        let closed = builder.close_elapsed(60_000);
+        assert_eq!(closed.len(), 4);
+        assert!(closed.iter().all(|candle| candle.closed && candle.close_time <= 60_000));
+        assert!(builder.close_elapsed(60_000).is_empty());
+
+        // A late trade goes into the next minute rather than reopening the closed one, and
+        // into the 5m candle still running.
+        builder.add(&trade(Exchange::Bitstamp, 59_900, 11.0, 1.0));
+        let minutes = builder.history(None, "ethbtc", Interval::OneMinute, 10);
+        assert_eq!(minutes.len(), 2);
+        assert_eq!((minutes[0].trades, minutes[1].open_time), (1, 60_000));
+        let five_minutes = builder.history(None, "ethbtc", Interval::FiveMinutes, 10);
+        assert_eq!((five_minutes.len(), five_minutes[0].trades), (1, 2));
+    }
+}
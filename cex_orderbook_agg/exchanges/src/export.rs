@@ -0,0 +1,517 @@
+use crate::aggregator::VenueBook;
+use crate::recorder::{recordings, Levels, Record, RecordReader};
+use exc_orderbook::combine_orderbook::Order;
+use exc_orderbook::consolidated_book::{ConsolidatedBook, PriceLevel};
+use parquet::basic::Compression;
+use parquet::data_type::{ByteArray, ByteArrayType, DataType, DoubleType, Int32Type, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::{SerializedFileWriter, SerializedRowGroupWriter};
+use parquet::schema::parser::parse_message_type;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// What `exchange` says in the rows of a consolidated book.
+pub static CONSOLIDATED: &str = "consolidated";
+
+static CSV_FILE: &str = "snapshots.csv";
+static CSV_HEADER: &str = "time,pair,exchange,side,level,price,size,event_time,received_at";
+
+static PARQUET_SCHEMA: &str = "
+message snapshot {
+    REQUIRED INT64 time (TIMESTAMP(MILLIS, true));
+    REQUIRED BYTE_ARRAY pair (UTF8);
+    REQUIRED BYTE_ARRAY exchange (UTF8);
+    REQUIRED BYTE_ARRAY side (UTF8);
+    REQUIRED INT32 level;
+    REQUIRED DOUBLE price;
+    REQUIRED DOUBLE size;
+    OPTIONAL INT64 event_time (TIMESTAMP(MILLIS, true));
+    OPTIONAL INT64 received_at (TIMESTAMP(MILLIS, true));
+}";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Parquet,
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "parquet" => Ok(Format::Parquet),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("Unknown export format: {}", s)),
+        }
+    }
+}
+
+/// Where snapshots are exported to. Files are partitioned as
+/// `{dir}/pair={pair}/date={YYYY-MM-DD}/`. CSV rows are appended as they come; Parquet
+/// rows are buffered per partition and written as a new part file once there are
+/// `rows_per_file` of them or they span `max_age`.
+#[derive(Debug, Clone)]
+pub struct ExportConfig {
+    pub dir: PathBuf,
+    pub format: Format,
+    pub top: usize,
+    pub rows_per_file: usize,
+    pub max_age: Option<Duration>,
+}
+
+impl Default for ExportConfig {
+    fn default() -> Self {
+        ExportConfig { dir: PathBuf::from("export"), format: Format::Parquet, top: 10, rows_per_file: 100_000, max_age: None }
+    }
+}
+
+/// One level of one side of an exported book. `level` is 0 for the best price. Times
+/// are milliseconds since the Unix epoch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportRow {
+    pub time: u64,
+    pub pair: String,
+    pub exchange: String,
+    pub side: &'static str,
+    pub level: u32,
+    pub price: f64,
+    pub size: f64,
+    pub event_time: Option<u64>,
+    pub received_at: Option<u64>,
+}
+
+/// A venue's book, or the consolidated one, as it stood at `time`.
+#[derive(Debug, Clone, Default)]
+pub struct BookSnapshot {
+    pub time: u64,
+    pub pair: String,
+    pub exchange: String,
+    pub event_time: Option<u64>,
+    pub received_at: Option<u64>,
+    pub bids: Levels,
+    pub asks: Levels,
+}
+
+impl BookSnapshot {
+    pub fn of_venue(exchange: &str, pair: &str, book: &VenueBook, time: u64) -> Self {
+        let levels = |orders: &[Order]| orders.iter().map(|order| (order.price, order.size)).collect();
+        BookSnapshot {
+            time,
+            pair: pair.to_lowercase(),
+            exchange: exchange.to_lowercase(),
+            event_time: book.event_time,
+            received_at: book.received_at,
+            bids: levels(&book.bids),
+            asks: levels(&book.asks),
+        }
+    }
+
+    /// The consolidated book of `venues`, all of one pair. Its times are the latest of
+    /// the venues'.
+    pub fn consolidated(pair: &str, venues: &[BookSnapshot], time: u64) -> Self {
+        let orders = |venue: &BookSnapshot, levels: &Levels| -> Vec<Order> {
+            levels.iter()
+                .map(|&(price, size)| Order { exchange: venue.exchange.clone(), pair: pair.to_lowercase(), price, size })
+                .collect()
+        };
+        let book = ConsolidatedBook::new(venues.iter().map(|venue| (orders(venue, &venue.bids), orders(venue, &venue.asks))), pair);
+        let levels = |levels: &[PriceLevel]| levels.iter().map(|level| (level.price, level.size)).collect();
+        BookSnapshot {
+            time,
+            pair: pair.to_lowercase(),
+            exchange: CONSOLIDATED.to_string(),
+            event_time: venues.iter().filter_map(|venue| venue.event_time).max(),
+            received_at: venues.iter().filter_map(|venue| venue.received_at).max(),
+            bids: levels(&book.bids),
+            asks: levels(&book.asks),
+        }
+    }
+
+    /// The top `top` levels of each side, bids first.
+    pub fn rows(&self, top: usize) -> Vec<ExportRow> {
+        let side = |name: &'static str, levels: &Levels| -> Vec<ExportRow> {
+            levels.iter().take(top).enumerate()
+                .map(|(level, &(price, size))| ExportRow {
+                    time: self.time,
+                    pair: self.pair.clone(),
+                    exchange: self.exchange.clone(),
+                    side: name,
+                    level: level as u32,
+                    price,
+                    size,
+                    event_time: self.event_time,
+                    received_at: self.received_at,
+                })
+                .collect()
+        };
+        let mut rows = side("bid", &self.bids);
+        rows.extend(side("ask", &self.asks));
+        rows
+    }
+}
+
+/// Writes snapshots to partitioned CSV or Parquet files. Call [`Exporter::flush_due`]
+/// regularly, so that partitions that stop getting rows are still written, and
+/// [`Exporter::flush`] before dropping it, or buffered Parquet rows are lost.
+pub struct Exporter {
+    config: ExportConfig,
+    buffers: BTreeMap<PathBuf, Vec<ExportRow>>,
+    files_written: u64,
+}
+
+impl Exporter {
+    pub fn new(config: ExportConfig) -> Self {
+        Exporter { config, buffers: BTreeMap::new(), files_written: 0 }
+    }
+
+    pub fn write(&mut self, snapshot: &BookSnapshot) -> Result<(), Box<dyn Error>> {
+        let rows = snapshot.rows(self.config.top);
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let partition = self.config.dir
+            .join(format!("pair={}", snapshot.pair))
+            .join(format!("date={}", date(snapshot.time)));
+
+        match self.config.format {
+            Format::Csv => append_csv(&partition, &rows)?,
+            Format::Parquet => {
+                let buffer = self.buffers.entry(partition.clone()).or_default();
+                buffer.extend(rows);
+                let span = buffer.last().unwrap().time.saturating_sub(buffer[0].time);
+                let full = buffer.len() >= self.config.rows_per_file
+                    || self.config.max_age.is_some_and(|max| span >= max.as_millis() as u64);
+                if full {
+                    let rows = self.buffers.remove(&partition).unwrap_or_default();
+                    self.write_parquet(&partition, &rows)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes out the partitions that will get no more rows, or that span `max_age`, by
+    /// `now`: those of days before the day of `now`, and those whose first row is at
+    /// least `max_age` old.
+    pub fn flush_due(&mut self, now: u64) -> Result<(), Box<dyn Error>> {
+        let today = date(now);
+        let max_age = self.config.max_age;
+        let due: Vec<PathBuf> = self.buffers.iter()
+            .filter(|(_, rows)| {
+                let first = rows[0].time;
+                date(first) < today || max_age.is_some_and(|max| now.saturating_sub(first) >= max.as_millis() as u64)
+            })
+            .map(|(partition, _)| partition.clone())
+            .collect();
+        for partition in due {
+            let rows = self.buffers.remove(&partition).unwrap_or_default();
+            self.write_parquet(&partition, &rows)?;
+        }
+        Ok(())
+    }
+
+    /// Writes out every buffered row.
+    pub fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        for (partition, rows) in std::mem::take(&mut self.buffers) {
+            self.write_parquet(&partition, &rows)?;
+        }
+        Ok(())
+    }
+
+    fn write_parquet(&mut self, partition: &Path, rows: &[ExportRow]) -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(partition)?;
+        self.files_written += 1;
+        let path = partition.join(format!("part-{}-{}.parquet", rows[0].time, self.files_written));
+        write_parquet(&path, rows)
+    }
+}
+
+fn append_csv(partition: &Path, rows: &[ExportRow]) -> io::Result<()> {
+    fs::create_dir_all(partition)?;
+    let path = partition.join(CSV_FILE);
+    let new = !path.exists();
+    let mut file = BufWriter::new(OpenOptions::new().create(true).append(true).open(path)?);
+    if new {
+        writeln!(file, "{}", CSV_HEADER)?;
+    }
+    let optional = |time: Option<u64>| time.map(|time| time.to_string()).unwrap_or_default();
+    for row in rows {
+        writeln!(
+            file,
+            "{},{},{},{},{},{},{},{},{}",
+            row.time, row.pair, row.exchange, row.side, row.level, row.price, row.size,
+            optional(row.event_time), optional(row.received_at),
+        )?;
+    }
+    file.flush()
+}
+
+fn write_parquet(path: &Path, rows: &[ExportRow]) -> Result<(), Box<dyn Error>> {
+    let schema = Arc::new(parse_message_type(PARQUET_SCHEMA)?);
+    let properties = Arc::new(WriterProperties::builder().set_compression(Compression::SNAPPY).build());
+    let mut writer = SerializedFileWriter::new(File::create(path)?, schema, properties)?;
+    let mut row_group = writer.next_row_group()?;
+
+    let text = |field: fn(&ExportRow) -> &str| -> Vec<ByteArray> { rows.iter().map(|row| ByteArray::from(field(row))).collect() };
+    write_column::<Int64Type>(&mut row_group, &rows.iter().map(|row| row.time as i64).collect::<Vec<_>>(), None)?;
+    write_column::<ByteArrayType>(&mut row_group, &text(|row| &row.pair), None)?;
+    write_column::<ByteArrayType>(&mut row_group, &text(|row| &row.exchange), None)?;
+    write_column::<ByteArrayType>(&mut row_group, &text(|row| row.side), None)?;
+    write_column::<Int32Type>(&mut row_group, &rows.iter().map(|row| row.level as i32).collect::<Vec<_>>(), None)?;
+    write_column::<DoubleType>(&mut row_group, &rows.iter().map(|row| row.price).collect::<Vec<_>>(), None)?;
+    write_column::<DoubleType>(&mut row_group, &rows.iter().map(|row| row.size).collect::<Vec<_>>(), None)?;
+    for time in [|row: &ExportRow| row.event_time, |row: &ExportRow| row.received_at] {
+        // Missing times are nulls: only present values are written, and a definition
+        // level of 0 marks the rest.
+        let values: Vec<i64> = rows.iter().filter_map(time).map(|time| time as i64).collect();
+        let definitions: Vec<i16> = rows.iter().map(|row| time(row).is_some() as i16).collect();
+        write_column::<Int64Type>(&mut row_group, &values, Some(&definitions))?;
+    }
+
+    row_group.close()?;
+    writer.close()?;
+    Ok(())
+}
+
+fn write_column<T: DataType>(row_group: &mut SerializedRowGroupWriter<'_, File>, values: &[T::T], definitions: Option<&[i16]>) -> Result<(), Box<dyn Error>> {
+    let mut column = row_group.next_column()?.ok_or("Parquet schema has fewer columns than rows")?;
+    column.typed::<T>().write_batch(values, definitions, None)?;
+    column.close()?;
+    Ok(())
+}
+
+/// The UTC date of `millis` since the Unix epoch, as `YYYY-MM-DD`.
+fn date(millis: u64) -> String {
+    // Howard Hinnant's days-to-civil algorithm.
+    let days = (millis / 86_400_000) as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+type BookRecords = Peekable<Box<dyn Iterator<Item = io::Result<Record>>>>;
+
+/// The recorded books of one feed, across all of its files.
+struct RecordedFeed {
+    exchange: String,
+    pair: String,
+    books: BookRecords,
+}
+
+/// Exports every book recorded in `dir`: each feed's books as they were recorded, and
+/// after each of them the consolidated book of its pair. Feeds are merged in the order
+/// their books were received. Returns how many snapshots were written.
+pub fn export_recordings(dir: impl AsRef<Path>, exporter: &mut Exporter) -> Result<usize, Box<dyn Error>> {
+    let mut files: BTreeMap<(String, String), Vec<PathBuf>> = BTreeMap::new();
+    for path in recordings(dir)? {
+        if let Some(Ok(Record::Start { exchange, pair, .. })) = RecordReader::open(&path)?.next() {
+            files.entry((exchange, pair)).or_default().push(path);
+        }
+    }
+    let mut feeds: Vec<RecordedFeed> = files.into_iter()
+        .map(|((exchange, pair), paths)| {
+            let records = paths.into_iter()
+                .flat_map(|path| -> Box<dyn Iterator<Item = io::Result<Record>>> {
+                    match RecordReader::open(path) {
+                        Ok(reader) => Box::new(reader),
+                        Err(e) => Box::new(std::iter::once(Err(e))),
+                    }
+                })
+                .filter(|record| !matches!(record, Ok(Record::Start { .. }) | Ok(Record::Frame { .. })));
+            let records: Box<dyn Iterator<Item = io::Result<Record>>> = Box::new(records);
+            RecordedFeed { exchange, pair, books: records.peekable() }
+        })
+        .collect();
+
+    let mut latest: BTreeMap<String, BTreeMap<String, BookSnapshot>> = BTreeMap::new();
+    let mut written = 0;
+    loop {
+        // The feed whose next book was received first. Errors sort first so they surface.
+        let next = feeds.iter_mut()
+            .filter_map(|feed| match feed.books.peek()? {
+                Ok(Record::Book { received_at, .. }) => Some((*received_at, feed)),
+                _ => Some((0, feed)),
+            })
+            .min_by_key(|(received_at, _)| *received_at);
+        let Some((_, feed)) = next else { break };
+        let Some(Record::Book { received_at, event_time, bids, asks, .. }) = feed.books.next().transpose()? else { continue };
+
+        let snapshot = BookSnapshot {
+            time: received_at,
+            pair: feed.pair.clone(),
+            exchange: feed.exchange.clone(),
+            event_time,
+            received_at: Some(received_at),
+            bids,
+            asks,
+        };
+        exporter.write(&snapshot)?;
+        let venues = latest.entry(feed.pair.clone()).or_default();
+        venues.insert(feed.exchange.clone(), snapshot);
+        let venues: Vec<BookSnapshot> = venues.values().cloned().collect();
+        exporter.write(&BookSnapshot::consolidated(&feed.pair, &venues, received_at))?;
+        written += 2;
+    }
+    exporter.flush()?;
+    Ok(written)
+}
+
+/* ------------
+    TEST CASES
+   ------------*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recorder::{Recorder, RecorderConfig};
+    use parquet::file::reader::SerializedFileReader;
+    use parquet::record::RowAccessor;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("export-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn files(dir: &Path, extension: &str) -> Vec<PathBuf> {
+        let mut found = Vec::new();
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                found.extend(files(&path, extension));
+            } else if path.extension().is_some_and(|e| e == extension) {
+                found.push(path);
+            }
+        }
+        found.sort();
+        found
+    }
+
+    fn snapshot(exchange: &str, time: u64, bid: f64, ask: f64) -> BookSnapshot {
+        BookSnapshot {
+            time,
+            pair: "ethbtc".to_string(),
+            exchange: exchange.to_string(),
+            event_time: None,
+            received_at: Some(time),
+            bids: vec![(bid, 1.0), (bid - 0.001, 2.0)],
+            asks: vec![(ask, 3.0)],
+        }
+    }
+
+    #[test]
+    fn test_rows_and_consolidated() {
+        let binance = snapshot("binance", 1_000, 0.060, 0.062);
+        let bitstamp = BookSnapshot { event_time: Some(990), ..snapshot("bitstamp", 1_005, 0.060, 0.061) };
+        let rows = binance.rows(1);
+        assert_eq!(rows.len(), 2);
+        assert_eq!((rows[0].side, rows[0].level, rows[0].price), ("bid", 0, 0.060));
+        assert_eq!((rows[1].side, rows[1].size), ("ask", 3.0));
+
+        let consolidated = BookSnapshot::consolidated("ETHBTC", &[binance, bitstamp], 2_000);
+        assert_eq!(consolidated.exchange, CONSOLIDATED);
+        assert_eq!((consolidated.event_time, consolidated.received_at), (Some(990), Some(1_005)));
+        assert_eq!(consolidated.bids[0], (0.060, 2.0));
+        assert_eq!(consolidated.asks, vec![(0.061, 3.0), (0.062, 3.0)]);
+        assert_eq!(date(0), "1970-01-01");
+        assert_eq!(date(1_709_251_199_000), "2024-02-29");
+    }
+
+    #[test]
+    fn test_csv_and_parquet_files() {
+        let dir = temp_dir("formats");
+        let mut csv = Exporter::new(ExportConfig { dir: dir.join("csv"), format: Format::Csv, top: 5, ..Default::default() });
+        let mut parquet = Exporter::new(ExportConfig { dir: dir.join("parquet"), rows_per_file: 4, ..Default::default() });
+        for time in [1_000, 86_401_000] {
+            csv.write(&snapshot("binance", time, 0.06, 0.07)).unwrap();
+            parquet.write(&snapshot("binance", time, 0.06, 0.07)).unwrap();
+        }
+        parquet.write(&snapshot("bitstamp", 86_402_000, 0.06, 0.07)).unwrap();
+        parquet.flush().unwrap();
+
+        let csv_files = files(&dir.join("csv"), "csv");
+        assert_eq!(csv_files.len(), 2);
+        assert!(csv_files[0].ends_with("pair=ethbtc/date=1970-01-01/snapshots.csv"));
+        let lines: Vec<String> = fs::read_to_string(&csv_files[0]).unwrap().lines().map(String::from).collect();
+        assert_eq!(lines[0], CSV_HEADER);
+        assert_eq!(lines[1], "1000,ethbtc,binance,bid,0,0.06,1,,1000");
+        assert_eq!(lines.len(), 4);
+
+        // Three rows on the first day, written on flush; six on the second, written as
+        // soon as there were at least four.
+        let parquet_files = files(&dir.join("parquet"), "parquet");
+        assert_eq!(parquet_files.len(), 2);
+        let rows: Vec<_> = parquet_files.iter()
+            .flat_map(|path| SerializedFileReader::new(File::open(path).unwrap()).unwrap().into_iter().map(Result::unwrap).collect::<Vec<_>>())
+            .collect();
+        assert_eq!(rows.len(), 9);
+        assert_eq!(rows[0].get_timestamp_millis(0).unwrap(), 1_000);
+        assert_eq!(rows[0].get_string(2).unwrap(), "binance");
+        assert_eq!(rows[1].get_int(4).unwrap(), 1);
+        assert_eq!(rows[2].get_double(6).unwrap(), 3.0);
+        assert!(rows[0].get_timestamp_millis(7).is_err());
+        assert_eq!(rows[0].get_timestamp_millis(8).unwrap(), 1_000);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_flush_due() {
+        let dir = temp_dir("due");
+        let mut exporter = Exporter::new(ExportConfig { dir: dir.clone(), ..Default::default() });
+        exporter.write(&snapshot("binance", 1_000, 0.06, 0.07)).unwrap();
+        exporter.flush_due(86_399_999).unwrap();
+        assert!(!dir.exists());
+        // The next day no more rows can come for the first.
+        exporter.flush_due(86_400_000).unwrap();
+        assert_eq!(files(&dir, "parquet").len(), 1);
+
+        let aged = dir.join("aged");
+        let mut exporter = Exporter::new(ExportConfig { dir: aged.clone(), max_age: Some(Duration::from_secs(60)), ..Default::default() });
+        exporter.write(&snapshot("binance", 1_000, 0.06, 0.07)).unwrap();
+        exporter.flush_due(60_999).unwrap();
+        assert!(!aged.exists());
+        exporter.flush_due(61_000).unwrap();
+        assert_eq!(files(&aged, "parquet").len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_export_recordings() {
+        let dir = temp_dir("recordings");
+        let recorder = Recorder::new(RecorderConfig { dir: dir.join("in"), ..Default::default() });
+        let order = |exchange: &str, price| Order { exchange: exchange.to_string(), pair: "ethbtc".to_string(), price, size: 1.0 };
+        recorder.record_frame("binance", "ethbtc", 1_000, "{}");
+        recorder.record_book("binance", "ethbtc", 1_000, Some(999), None, &(vec![order("binance", 0.06)], vec![order("binance", 0.07)]));
+        recorder.record_book("bitstamp", "ethbtc", 1_500, None, None, &(vec![order("bitstamp", 0.06)], vec![]));
+        recorder.flush().unwrap();
+
+        let mut exporter = Exporter::new(ExportConfig { dir: dir.join("out"), format: Format::Csv, ..Default::default() });
+        assert_eq!(export_recordings(dir.join("in"), &mut exporter).unwrap(), 4);
+        let csv = fs::read_to_string(dir.join("out/pair=ethbtc/date=1970-01-01").join(CSV_FILE)).unwrap();
+        let lines: Vec<&str> = csv.lines().skip(1).collect();
+        assert_eq!(lines, vec![
+            "1000,ethbtc,binance,bid,0,0.06,1,999,1000",
+            "1000,ethbtc,binance,ask,0,0.07,1,999,1000",
+            "1000,ethbtc,consolidated,bid,0,0.06,1,999,1000",
+            "1000,ethbtc,consolidated,ask,0,0.07,1,999,1000",
+            "1500,ethbtc,bitstamp,bid,0,0.06,1,,1500",
+            "1500,ethbtc,consolidated,bid,0,0.06,2,999,1500",
+            "1500,ethbtc,consolidated,ask,0,0.07,1,999,1500",
+        ]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
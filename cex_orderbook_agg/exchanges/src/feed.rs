@@ -0,0 +1,165 @@
+use crate::recorder::Recorder;
+use crate::status::SharedFeedStatus;
+use crate::trade::{Trade, TradeTape};
+use crate::validation::{self, RejectReason};
+use exc_orderbook::combine_orderbook::Order;
+use std::error::Error;
+use std::sync::Mutex;
+
+/// The book of one venue as a single frame left it. `event_time` and `sequence` are
+/// only set by venues that send them.
+#[derive(Debug, Clone, Default)]
+pub struct FeedUpdate {
+    pub bids: Vec<Order>,
+    pub asks: Vec<Order>,
+    pub event_time: Option<u64>,
+    pub sequence: Option<u64>,
+}
+
+/// Makes `update`, received at `received_at`, the current book of a live feed, and
+/// records it if the feed is being recorded.
+///
+/// Levels with a price or size that is not a positive number are dropped. A book whose
+/// best bid is at or above its best ask is dropped too, the feed's book is emptied, and
+/// an error is returned so the feed can start over from a fresh snapshot. Every
+/// rejection is counted in `status`.
+pub fn apply_update(exchange: &str, pair: &str, received_at: u64, mut update: FeedUpdate, order_book: &Mutex<(Vec<Order>, Vec<Order>)>, status: &SharedFeedStatus, recorder: Option<&Recorder>) -> Result<(), Box<dyn Error>> {
+    if let Err(e) = validate_update(exchange, pair, &mut update, status) {
+        *order_book.lock().unwrap() = (Vec::new(), Vec::new());
+        status.lock().unwrap().record_resync();
+        return Err(e.into());
+    }
+    store_update(exchange, pair, received_at, update, order_book, status, recorder);
+    Ok(())
+}
+
+/// Publishes the trade, if any, that a trade feed parsed from the frame `text` to `tape`.
+/// A frame that could not be parsed and a malformed trade are both printed, counted in
+/// `status` and skipped, so that neither ends the feed.
+pub fn publish_trade(exchange: &str, pair: &str, text: &str, parsed: Result<Option<Trade>, Box<dyn Error>>, tape: &TradeTape, status: &SharedFeedStatus) {
+    let trade = match parsed {
+        Ok(Some(trade)) => trade,
+        Ok(None) => return,
+        Err(e) => {
+            status.lock().unwrap().record_rejections(RejectReason::MalformedFrame, 1);
+            println!("Skipped a {} {} trade frame: {}: {}", exchange, pair, e, text);
+            return;
+        }
+    };
+
+    let mut feed_status = status.lock().unwrap();
+    feed_status.record_message(trade.received_at, trade.trade_time, Some(trade.trade_id));
+    if let Err(reason) = validation::check_trade(&trade) {
+        feed_status.record_rejections(reason, 1);
+        println!("Skipped a {} {} trade: {}", exchange, pair, reason);
+        return;
+    }
+    drop(feed_status);
+    tape.publish(trade);
+}
+
+/// [`apply_update`] for a replayed feed. A recording cannot be resynced, so a crossed
+/// book is only counted and skipped, and the feed keeps its previous book.
+pub fn replay_update(exchange: &str, pair: &str, received_at: u64, mut update: FeedUpdate, order_book: &Mutex<(Vec<Order>, Vec<Order>)>, status: &SharedFeedStatus, recorder: Option<&Recorder>) -> Result<(), Box<dyn Error>> {
+    validate_update(exchange, pair, &mut update, status)?;
+    store_update(exchange, pair, received_at, update, order_book, status, recorder);
+    Ok(())
+}
+
+/// Drops the malformed levels of `update`, and fails if what is left is crossed.
+fn validate_update(exchange: &str, pair: &str, update: &mut FeedUpdate, status: &SharedFeedStatus) -> Result<(), String> {
+    let mut rejected = validation::drop_malformed(&mut update.bids);
+    rejected.extend(validation::drop_malformed(&mut update.asks));
+    let mut status = status.lock().unwrap();
+    for reason in rejected {
+        status.record_rejections(reason, 1);
+    }
+    if let Some((best_bid, best_ask)) = validation::crossed(&update.bids, &update.asks) {
+        status.record_rejections(RejectReason::CrossedBook, 1);
+        return Err(format!("{} {} book crossed itself: best bid {} at or above best ask {}", exchange, pair, best_bid, best_ask));
+    }
+    Ok(())
+}
+
+fn store_update(exchange: &str, pair: &str, received_at: u64, update: FeedUpdate, order_book: &Mutex<(Vec<Order>, Vec<Order>)>, status: &SharedFeedStatus, recorder: Option<&Recorder>) {
+    let book = (update.bids, update.asks);
+    if let Some(recorder) = recorder {
+        recorder.record_book(exchange, pair, received_at, update.event_time, update.sequence, &book);
+    }
+    *order_book.lock().unwrap() = book;
+    status.lock().unwrap().record_message(received_at, update.event_time, update.sequence);
+}
+
+/* ------------
+    TEST CASES
+   ------------*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::FeedStatus;
+    use crate::test_util::order;
+
+    #[test]
+    fn test_apply_update_validates_book() {
+        let order_book = Mutex::new((Vec::new(), Vec::new()));
+        let status = FeedStatus::new_shared();
+        let update = |bids, asks| FeedUpdate { bids, asks, ..Default::default() };
+
+        let malformed = update(vec![order(0.06, 1.0), order(f64::NAN, 1.0), order(0.059, 0.0)], vec![order(0.061, -1.0), order(0.062, 1.0)]);
+        apply_update("binance", "ethbtc", 1_000, malformed, &order_book, &status, None).unwrap();
+        let prices = |orders: &Vec<Order>| orders.iter().map(|order| order.price).collect::<Vec<_>>();
+        let (bids, asks) = order_book.lock().unwrap().clone();
+        assert_eq!((prices(&bids), prices(&asks)), (vec![0.06], vec![0.062]));
+        assert_eq!(status.lock().unwrap().rejections[&RejectReason::InvalidSize], 2);
+        assert_eq!(status.lock().unwrap().rejections[&RejectReason::InvalidPrice], 1);
+
+        // A crossed book empties the feed's book rather than replacing it.
+        let crossed = update(vec![order(0.063, 1.0)], vec![order(0.062, 1.0)]);
+        assert!(apply_update("binance", "ethbtc", 2_000, crossed, &order_book, &status, None).is_err());
+        let (bids, asks) = order_book.lock().unwrap().clone();
+        assert!(bids.is_empty() && asks.is_empty());
+        let status = status.lock().unwrap();
+        assert_eq!((status.rejections[&RejectReason::CrossedBook], status.resync_count), (1, 1));
+        assert_eq!((status.messages, status.last_message_at), (1, Some(1_000)));
+    }
+
+    #[test]
+    fn test_replayed_crossed_book_keeps_previous_book() {
+        let order_book = Mutex::new((Vec::new(), Vec::new()));
+        let status = FeedStatus::new_shared();
+        let update = |bids, asks| FeedUpdate { bids, asks, ..Default::default() };
+
+        replay_update("binance", "ethbtc", 1_000, update(vec![order(0.06, 1.0)], vec![order(0.061, 1.0)]), &order_book, &status, None).unwrap();
+        let crossed = update(vec![order(0.063, 1.0)], vec![order(0.062, 1.0)]);
+        assert!(replay_update("binance", "ethbtc", 2_000, crossed, &order_book, &status, None).is_err());
+
+        let (bids, asks) = order_book.lock().unwrap().clone();
+        assert_eq!((bids[0].price, asks[0].price), (0.06, 0.061));
+        let status = status.lock().unwrap();
+        assert_eq!((status.rejections[&RejectReason::CrossedBook], status.resync_count), (1, 0));
+        assert_eq!(status.last_message_at, Some(1_000));
+    }
+
+    #[test]
+    fn test_publish_trade_skips_bad_frames() {
+        let tape = TradeTape::new();
+        let mut trades = tape.subscribe();
+        let status = FeedStatus::new_shared();
+        let parse = |text: &str| crate::binance::parse_trade(text, "ethbtc", 1_000).map(Some);
+
+        let text = "not json";
+        publish_trade("binance", "ethbtc", text, parse(text), &tape, &status);
+        let text = r#"{"e":"trade","E":1,"s":"ETHBTC","t":7,"p":"0.06","q":"-1.0","T":1,"m":true}"#;
+        publish_trade("binance", "ethbtc", text, parse(text), &tape, &status);
+        let text = r#"{"e":"trade","E":1,"s":"ETHBTC","t":8,"p":"0.06","q":"1.0","T":1,"m":true}"#;
+        publish_trade("binance", "ethbtc", text, parse(text), &tape, &status);
+
+        assert_eq!(trades.try_recv().unwrap().trade_id, 8);
+        assert!(trades.try_recv().is_err());
+        let status = status.lock().unwrap();
+        assert_eq!(status.rejections[&RejectReason::MalformedFrame], 1);
+        assert_eq!(status.rejections[&RejectReason::InvalidSize], 1);
+        assert_eq!(status.messages, 2);
+    }
+}
@@ -0,0 +1,203 @@
+use exc_orderbook::combine_orderbook::Order;
+use exc_orderbook::consolidated_book::{compare_prices, Side};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+pub type SharedL3Book = Arc<Mutex<L3Book>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderEventKind {
+    Created,
+    Changed,
+    Deleted,
+}
+
+/// What happened to one resting order. `time` is when the venue says it happened, in
+/// milliseconds since the Unix epoch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderEvent {
+    pub kind: OrderEventKind,
+    pub id: u64,
+    pub side: Side,
+    pub price: f64,
+    pub size: f64,
+    pub time: Option<u64>,
+}
+
+/// One resting order. Orders at the same price are queued by `priority`, lowest first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct L3Order {
+    pub id: u64,
+    pub side: Side,
+    pub price: f64,
+    pub size: f64,
+    pub priority: u64,
+}
+
+/// Every order at one price, and the largest of them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct L3Level {
+    pub price: f64,
+    pub size: f64,
+    pub orders: usize,
+    pub largest_order: f64,
+}
+
+/// An order-by-order book of one venue and pair. Venues without a snapshot only send
+/// the orders placed or changed after `connected_at`, so until then the book is partial.
+#[derive(Debug, Clone, Default)]
+pub struct L3Book {
+    pub exchange: String,
+    pub pair: String,
+    orders: HashMap<u64, L3Order>,
+    next_priority: u64,
+    pub last_event_time: Option<u64>,
+    /// When the feed connected, in milliseconds since the Unix epoch.
+    pub connected_at: Option<u64>,
+    /// Order events that could not be read or were invalid, and were left out.
+    pub skipped_events: u64,
+}
+
+impl L3Book {
+    pub fn new(exchange: &str, pair: &str) -> Self {
+        L3Book { exchange: exchange.to_string(), pair: pair.to_lowercase(), ..Default::default() }
+    }
+
+    /// Applies `event`. An order keeps its place in the queue when only its size
+    /// changes, and goes to the back when its price does. Changes to orders never seen
+    /// created are taken as creations.
+    pub fn apply(&mut self, event: &OrderEvent) {
+        self.last_event_time = event.time.or(self.last_event_time);
+        if event.kind == OrderEventKind::Deleted || event.size <= 0.0 {
+            self.orders.remove(&event.id);
+            return;
+        }
+
+        let keeps_place = self.orders.get(&event.id)
+            .filter(|order| order.side == event.side && order.price == event.price)
+            .map(|order| order.priority);
+        let priority = keeps_place.unwrap_or_else(|| {
+            self.next_priority += 1;
+            self.next_priority
+        });
+        self.orders.insert(event.id, L3Order { id: event.id, side: event.side, price: event.price, size: event.size, priority });
+    }
+
+    /// Forgets every order, for a feed that connects again. Skipped events stay counted.
+    pub fn restart(&mut self) {
+        *self = L3Book { skipped_events: self.skipped_events, ..L3Book::new(&self.exchange, &self.pair) };
+    }
+
+    pub fn order(&self, id: u64) -> Option<&L3Order> {
+        self.orders.get(&id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.orders.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orders.is_empty()
+    }
+
+    /// The orders of one side, best price first, then in queue order.
+    pub fn orders(&self, side: Side) -> Vec<&L3Order> {
+        let mut orders: Vec<&L3Order> = self.orders.values().filter(|order| order.side == side).collect();
+        orders.sort_by(|a, b| compare_prices(a.price, b.price, side).then(a.priority.cmp(&b.priority)));
+        orders
+    }
+
+    /// The orders of one side aggregated into price levels, best first.
+    pub fn levels(&self, side: Side) -> Vec<L3Level> {
+        let mut levels: Vec<L3Level> = Vec::new();
+        for order in self.orders(side) {
+            match levels.last_mut() {
+                Some(level) if level.price == order.price => {
+                    level.size += order.size;
+                    level.orders += 1;
+                    level.largest_order = level.largest_order.max(order.size);
+                }
+                _ => levels.push(L3Level { price: order.price, size: order.size, orders: 1, largest_order: order.size }),
+            }
+        }
+        levels
+    }
+
+    /// The levels as the `(bids, asks)` every other feed produces.
+    pub fn order_book(&self) -> (Vec<Order>, Vec<Order>) {
+        let orders = |side| -> Vec<Order> {
+            self.levels(side).into_iter()
+                .map(|level| Order { exchange: self.exchange.clone(), pair: self.pair.clone(), price: level.price, size: level.size })
+                .collect()
+        };
+        (orders(Side::Bid), orders(Side::Ask))
+    }
+
+    /// How much is queued ahead of order `id` at its price.
+    pub fn queue_ahead(&self, id: u64) -> Option<f64> {
+        let order = self.orders.get(&id)?;
+        Some(self.orders.values()
+            .filter(|other| other.side == order.side && other.price == order.price && other.priority < order.priority)
+            .map(|other| other.size)
+            .sum())
+    }
+}
+
+/* ------------
+    TEST CASES
+   ------------*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(kind: OrderEventKind, id: u64, side: Side, price: f64, size: f64) -> OrderEvent {
+        OrderEvent { kind, id, side, price, size, time: Some(id * 10) }
+    }
+
+    fn book() -> L3Book {
+        let mut book = L3Book::new("bitstamp", "ETHBTC");
+        book.apply(&event(OrderEventKind::Created, 1, Side::Bid, 0.060, 1.0));
+        book.apply(&event(OrderEventKind::Created, 2, Side::Bid, 0.060, 5.0));
+        book.apply(&event(OrderEventKind::Created, 3, Side::Bid, 0.061, 0.5));
+        book.apply(&event(OrderEventKind::Created, 4, Side::Ask, 0.062, 2.0));
+        book
+    }
+
+    #[test]
+    fn test_levels_and_order_book() {
+        let book = book();
+        assert_eq!(book.levels(Side::Bid), vec![
+            L3Level { price: 0.061, size: 0.5, orders: 1, largest_order: 0.5 },
+            L3Level { price: 0.060, size: 6.0, orders: 2, largest_order: 5.0 },
+        ]);
+        let (bids, asks) = book.order_book();
+        assert_eq!(bids.len(), 2);
+        assert_eq!((bids[1].exchange.as_str(), bids[1].pair.as_str(), bids[1].size), ("bitstamp", "ethbtc", 6.0));
+        assert_eq!(asks[0].price, 0.062);
+        assert_eq!(book.last_event_time, Some(40));
+    }
+
+    #[test]
+    fn test_changes_and_queue_position() {
+        let mut book = book();
+        assert_eq!(book.queue_ahead(2), Some(1.0));
+
+        // A smaller size keeps the order's place; a new price sends it to the back.
+        book.apply(&event(OrderEventKind::Changed, 1, Side::Bid, 0.060, 0.4));
+        assert_eq!(book.queue_ahead(2), Some(0.4));
+        book.apply(&event(OrderEventKind::Changed, 3, Side::Bid, 0.060, 0.5));
+        assert_eq!(book.queue_ahead(3), Some(5.4));
+        assert_eq!(book.levels(Side::Bid).len(), 1);
+
+        book.apply(&event(OrderEventKind::Deleted, 1, Side::Bid, 0.060, 0.0));
+        assert!(book.order(1).is_none());
+        assert_eq!(book.queue_ahead(2), Some(0.0));
+        assert_eq!(book.levels(Side::Bid)[0].orders, 2);
+
+        // A change to an order we never saw created adds it.
+        book.apply(&event(OrderEventKind::Changed, 9, Side::Ask, 0.063, 1.0));
+        assert_eq!(book.len(), 4);
+        assert!(book.queue_ahead(42).is_none());
+    }
+}
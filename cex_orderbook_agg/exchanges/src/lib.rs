@@ -1,2 +1,15 @@
+pub mod aggregator;
+pub mod bbo;
 pub mod binance;
 pub mod bitstamp;
+pub mod candle;
+pub mod export;
+pub mod feed;
+pub mod l3;
+pub mod recorder;
+pub mod replay;
+pub mod status;
+pub mod trade;
+pub mod validation;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
@@ -0,0 +1,417 @@
+use crate::status::now_millis;
+use exc_orderbook::combine_orderbook::Order;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::{Entry, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+static EXTENSION: &str = "rec";
+static COMPRESSED_EXTENSION: &str = "rec.gz";
+// How many records can wait for the writer before feeds start dropping them.
+static QUEUE_CAPACITY: usize = 4096;
+// How long records written to an uncompressed file can sit in its buffer.
+static FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+/// The longest record written or read back. A longer length prefix means a corrupt file.
+pub static MAX_RECORD_LEN: usize = 64 * 1024 * 1024;
+
+/// `(price, size)` levels of one side of a book.
+pub type Levels = Vec<(f64, f64)>;
+
+/// One entry of a recording. Every file starts with `Start`, naming the feed the
+/// rest of it belongs to. Times are milliseconds since the Unix epoch.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Record {
+    Start { exchange: String, pair: String, started_at: u64 },
+    /// A text frame exactly as the venue sent it.
+    Frame { received_at: u64, text: String },
+    /// The book the feed made of it, as `(price, size)` levels.
+    Book {
+        received_at: u64,
+        event_time: Option<u64>,
+        sequence: Option<u64>,
+        bids: Levels,
+        asks: Levels,
+    },
+}
+
+/// Where recordings go and when a feed moves on to a new file. `max_bytes` counts
+/// bytes before compression.
+#[derive(Debug, Clone, Default)]
+pub struct RecorderConfig {
+    pub dir: PathBuf,
+    pub max_bytes: Option<u64>,
+    pub max_age: Option<Duration>,
+    pub compress: bool,
+}
+
+enum Sink {
+    Plain(BufWriter<File>),
+    Compressed(GzEncoder<BufWriter<File>>),
+}
+
+/// The file one feed is currently recording to.
+struct RecordFile {
+    sink: Sink,
+    started_at: u64,
+    bytes: u64,
+}
+
+impl RecordFile {
+    /// Creates `{exchange}-{pair}-{now}-{sequence}.rec`, taking sequence numbers from
+    /// `sequence` until one names a file that does not exist yet, so that two files started
+    /// in the same millisecond never share one.
+    fn create(config: &RecorderConfig, exchange: &str, pair: &str, now: u64, sequence: &mut u64) -> io::Result<Self> {
+        fs::create_dir_all(&config.dir)?;
+        let extension = if config.compress { COMPRESSED_EXTENSION } else { EXTENSION };
+        let file = loop {
+            let path = config.dir.join(format!("{}-{}-{}-{}.{}", exchange, pair, now, sequence, extension));
+            *sequence += 1;
+            match OpenOptions::new().write(true).create_new(true).open(path) {
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                file => break BufWriter::new(file?),
+            }
+        };
+        let sink = if config.compress {
+            Sink::Compressed(GzEncoder::new(file, Compression::default()))
+        } else {
+            Sink::Plain(file)
+        };
+
+        let mut record_file = RecordFile { sink, started_at: now, bytes: 0 };
+        record_file.write(&Record::Start { exchange: exchange.to_string(), pair: pair.to_string(), started_at: now })?;
+        Ok(record_file)
+    }
+
+    /// Appends `record` as its length followed by its bincode encoding.
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        let encoded = bincode::serialize(record).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if encoded.len() > MAX_RECORD_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("record of {} bytes is longer than {}", encoded.len(), MAX_RECORD_LEN)));
+        }
+        let length = (encoded.len() as u32).to_le_bytes();
+        match &mut self.sink {
+            Sink::Plain(file) => {
+                file.write_all(&length)?;
+                file.write_all(&encoded)?;
+            }
+            Sink::Compressed(encoder) => {
+                encoder.write_all(&length)?;
+                encoder.write_all(&encoded)?;
+            }
+        }
+        self.bytes += (length.len() + encoded.len()) as u64;
+        Ok(())
+    }
+
+    /// Writes out what an uncompressed file has buffered, so a crash loses little.
+    /// Compressed files are left alone, as they cannot be read before `finish` anyway.
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.sink {
+            Sink::Plain(file) => file.flush(),
+            Sink::Compressed(_) => Ok(()),
+        }
+    }
+
+    fn is_full(&self, config: &RecorderConfig, now: u64) -> bool {
+        config.max_bytes.is_some_and(|max| self.bytes >= max)
+            || config.max_age.is_some_and(|max| now.saturating_sub(self.started_at) >= max.as_millis() as u64)
+    }
+
+    fn finish(self) -> io::Result<()> {
+        match self.sink {
+            Sink::Plain(mut file) => file.flush(),
+            Sink::Compressed(encoder) => encoder.finish()?.flush(),
+        }
+    }
+}
+
+enum Command {
+    Append { exchange: String, pair: String, record: Record },
+    Finish(SyncSender<io::Result<()>>),
+}
+
+/// Owns the open files, on a thread of its own so that feeds never wait on the disk.
+struct Writer {
+    config: RecorderConfig,
+    files: HashMap<(String, String), RecordFile>,
+    sequence: u64,
+}
+
+impl Writer {
+    /// Handles commands until every `Recorder` is dropped, then finishes the open files.
+    fn run(mut self, commands: Receiver<Command>) {
+        let mut flushed_at = Instant::now();
+        loop {
+            match commands.recv_timeout(FLUSH_INTERVAL.saturating_sub(flushed_at.elapsed())) {
+                Ok(Command::Append { exchange, pair, record }) => {
+                    if let Err(e) = self.append(exchange, pair, &record) {
+                        println!("Could not record: {}", e);
+                    }
+                }
+                Ok(Command::Finish(done)) => {
+                    let _ = done.send(self.finish());
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    if let Err(e) = self.finish() {
+                        println!("Could not finish the recording: {}", e);
+                    }
+                    return;
+                }
+            }
+
+            if flushed_at.elapsed() >= FLUSH_INTERVAL {
+                for file in self.files.values_mut() {
+                    if let Err(e) = file.flush() {
+                        println!("Could not flush the recording: {}", e);
+                    }
+                }
+                flushed_at = Instant::now();
+            }
+        }
+    }
+
+    fn append(&mut self, exchange: String, pair: String, record: &Record) -> io::Result<()> {
+        let now = now_millis();
+        let key = (exchange, pair);
+
+        if self.files.get(&key).is_some_and(|file| file.is_full(&self.config, now)) {
+            if let Some(file) = self.files.remove(&key) {
+                file.finish()?;
+            }
+        }
+        let file = match self.files.entry(key) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let file = RecordFile::create(&self.config, &entry.key().0, &entry.key().1, now, &mut self.sequence)?;
+                entry.insert(file)
+            }
+        };
+        file.write(record)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        for (_, file) in self.files.drain() {
+            file.finish()?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the frames and books of every feed to disk, one file per (exchange, pair) at
+/// a time. Records are queued for a writer thread; once it falls `QUEUE_CAPACITY` records
+/// behind, further ones are dropped rather than holding up the feeds. Clones share the
+/// same writer, which finishes its files once the last clone is dropped.
+#[derive(Clone)]
+pub struct Recorder {
+    commands: SyncSender<Command>,
+}
+
+impl Recorder {
+    pub fn new(config: RecorderConfig) -> Self {
+        let (commands, receiver) = mpsc::sync_channel(QUEUE_CAPACITY);
+        let writer = Writer { config, files: HashMap::new(), sequence: 0 };
+        thread::Builder::new()
+            .name("recorder".to_string())
+            .spawn(move || writer.run(receiver))
+            .expect("Can't start the recorder thread");
+        Recorder { commands }
+    }
+
+    pub fn record_frame(&self, exchange: &str, pair: &str, received_at: u64, text: &str) {
+        self.record(exchange, pair, Record::Frame { received_at, text: text.to_string() });
+    }
+
+    /// Records the `(bids, asks)` a feed made of the frame it received at `received_at`.
+    pub fn record_book(&self, exchange: &str, pair: &str, received_at: u64, event_time: Option<u64>, sequence: Option<u64>, book: &(Vec<Order>, Vec<Order>)) {
+        let levels = |orders: &[Order]| orders.iter().map(|order| (order.price, order.size)).collect();
+        self.record(exchange, pair, Record::Book { received_at, event_time, sequence, bids: levels(&book.0), asks: levels(&book.1) });
+    }
+
+    /// Recording problems are printed rather than returned, so they never stop a feed.
+    fn record(&self, exchange: &str, pair: &str, record: Record) {
+        let command = Command::Append { exchange: exchange.to_lowercase(), pair: pair.to_lowercase(), record };
+        match self.commands.try_send(command) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => println!("Recorder is behind, dropped a record of {} {}", exchange, pair),
+            Err(TrySendError::Disconnected(_)) => println!("Could not record {} {}: the recorder has stopped", exchange, pair),
+        }
+    }
+
+    /// Completes every open file, once everything recorded so far is written. Compressed
+    /// files cannot be read to the end until then.
+    pub fn flush(&self) -> io::Result<()> {
+        let stopped = || io::Error::other("the recorder has stopped");
+        let (done, finished) = mpsc::sync_channel(1);
+        self.commands.send(Command::Finish(done)).map_err(|_| stopped())?;
+        finished.recv().map_err(|_| stopped())?
+    }
+}
+
+/// Reads a recording back, record by record. A record cut short at the end of the file,
+/// as a crash would leave it, ends the recording rather than failing it.
+pub struct RecordReader {
+    reader: Box<dyn Read + Send>,
+}
+
+impl RecordReader {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let file = BufReader::new(File::open(path)?);
+        let reader: Box<dyn Read + Send> = if path.to_string_lossy().ends_with(".gz") {
+            Box::new(MultiGzDecoder::new(file))
+        } else {
+            Box::new(file)
+        };
+        Ok(RecordReader { reader })
+    }
+}
+
+impl Iterator for RecordReader {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut length = [0u8; 4];
+        match self.reader.read_exact(&mut length) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return None,
+            Err(e) => return Some(Err(e)),
+        }
+
+        let length = u32::from_le_bytes(length) as usize;
+        if length > MAX_RECORD_LEN {
+            let message = format!("record of {} bytes is longer than {}", length, MAX_RECORD_LEN);
+            return Some(Err(io::Error::new(io::ErrorKind::InvalidData, message)));
+        }
+        let mut encoded = vec![0u8; length];
+        match self.reader.read_exact(&mut encoded) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return None,
+            Err(e) => return Some(Err(e)),
+        }
+        Some(bincode::deserialize(&encoded).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)))
+    }
+}
+
+/// The recordings in `dir`, oldest first within each feed.
+pub fn recordings(dir: impl AsRef<Path>) -> io::Result<Vec<PathBuf>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            let name = path.to_string_lossy();
+            name.ends_with(&format!(".{}", EXTENSION)) || name.ends_with(&format!(".{}", COMPRESSED_EXTENSION))
+        })
+        .collect();
+    paths.sort_by_key(|path| {
+        let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        // `{exchange}-{pair}-{started_at}-{sequence}.rec`, or without the sequence number
+        // in older recordings: sort by feed, then by start time and sequence number.
+        let stem = name.split('.').next().unwrap_or_default();
+        let parts: Vec<&str> = stem.split('-').collect();
+        let number = |index: usize| parts.get(index).and_then(|part| part.parse::<u64>().ok()).unwrap_or_default();
+        (parts.iter().take(2).copied().collect::<Vec<_>>().join("-"), number(2), number(3))
+    });
+    Ok(paths)
+}
+
+/* ------------
+    TEST CASES
+   ------------*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("recorder-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn read_all(path: &Path) -> Vec<Record> {
+        RecordReader::open(path).unwrap().map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn test_record_and_read_back() {
+        for compress in [false, true] {
+            let dir = temp_dir(if compress { "compressed" } else { "plain" });
+            let recorder = Recorder::new(RecorderConfig { dir: dir.clone(), compress, ..Default::default() });
+            let order = |price| Order { exchange: "binance".to_string(), pair: "ethbtc".to_string(), price, size: 2.0 };
+
+            recorder.record_frame("binance", "ETHBTC", 1_000, r#"{"bids":[]}"#);
+            recorder.record_book("binance", "ethbtc", 1_000, None, Some(7), &(vec![order(0.06)], vec![order(0.07)]));
+            recorder.record_frame("Bitstamp", "ethbtc", 1_001, "{}");
+            recorder.flush().unwrap();
+
+            let paths = recordings(&dir).unwrap();
+            assert_eq!(paths.len(), 2);
+            let records = read_all(&paths[0]);
+            assert!(matches!(&records[0], Record::Start { exchange, pair, .. } if exchange == "binance" && pair == "ethbtc"));
+            assert_eq!(records[1], Record::Frame { received_at: 1_000, text: r#"{"bids":[]}"#.to_string() });
+            assert_eq!(records[2], Record::Book {
+                received_at: 1_000,
+                event_time: None,
+                sequence: Some(7),
+                bids: vec![(0.06, 2.0)],
+                asks: vec![(0.07, 2.0)],
+            });
+            assert_eq!(read_all(&paths[1]).len(), 2);
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_rotates_by_size() {
+        let dir = temp_dir("rotation");
+        let recorder = Recorder::new(RecorderConfig { dir: dir.clone(), max_bytes: Some(1), ..Default::default() });
+
+        // Rotations within the same millisecond still get a file each.
+        for (i, text) in ["first", "second", "third"].into_iter().enumerate() {
+            recorder.record_frame("binance", "ethbtc", 1_000 + i as u64, text);
+        }
+        recorder.flush().unwrap();
+
+        let paths = recordings(&dir).unwrap();
+        assert_eq!(paths.len(), 3);
+        for (path, text) in paths.iter().zip(["first", "second", "third"]) {
+            let records = read_all(path);
+            assert_eq!(records.len(), 2);
+            assert!(matches!(&records[1], Record::Frame { text: recorded, .. } if recorded == text));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_truncated_record_ends_recording() {
+        let dir = temp_dir("truncated");
+        let recorder = Recorder::new(RecorderConfig { dir: dir.clone(), ..Default::default() });
+        recorder.record_frame("binance", "ethbtc", 1_000, "frame");
+        recorder.flush().unwrap();
+
+        let path = recordings(&dir).unwrap().remove(0);
+        let length = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(length - 3).unwrap();
+        assert_eq!(read_all(&path).len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_oversized_length_is_rejected() {
+        let dir = temp_dir("oversized");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("binance-ethbtc-1000-0.rec");
+        fs::write(&path, u32::MAX.to_le_bytes()).unwrap();
+
+        let mut reader = RecordReader::open(&path).unwrap();
+        assert_eq!(reader.next().unwrap().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,543 @@
+use crate::aggregator::Exchange;
+use crate::binance::parse_depth;
+use crate::bitstamp::parse_order_book;
+use crate::feed::{replay_update, FeedUpdate};
+use crate::recorder::{recordings, Levels, Record, RecordReader, Recorder};
+use crate::status::{now_millis, SharedFeedStatus};
+use exc_orderbook::combine_orderbook::Order;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::error::Error;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+use tokio::time::{sleep_until, Duration, Instant};
+
+/// How fast a recording is played back.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Speed {
+    Realtime,
+    /// This many times faster than it was recorded.
+    Accelerated(f64),
+    AsFastAsPossible,
+}
+
+/// The time of a replay. Recorded times are shifted so that the recording starts at
+/// `start`; `now` is the time of the latest frame replayed so far.
+#[derive(Debug, Clone)]
+pub struct SimulatedClock {
+    origin: u64,
+    start: u64,
+    now: Arc<AtomicU64>,
+}
+
+impl SimulatedClock {
+    fn new(origin: u64, start: u64) -> Self {
+        SimulatedClock { origin, start, now: Arc::new(AtomicU64::new(start)) }
+    }
+
+    pub fn now(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+
+    /// The replay time of something recorded at `recorded_at`.
+    pub fn simulated(&self, recorded_at: u64) -> u64 {
+        self.start + recorded_at.saturating_sub(self.origin)
+    }
+
+    /// Frames are played in the order they were recorded, so the clock never goes back.
+    fn advance(&self, recorded_at: u64) -> u64 {
+        let simulated = self.simulated(recorded_at);
+        self.now.store(simulated, Ordering::SeqCst);
+        simulated
+    }
+}
+
+/// Which recordings to play and how. The replay clock starts at `start`, or at the
+/// time the replay was opened; a fixed `start` makes every timestamp reproducible.
+#[derive(Debug, Clone)]
+pub struct ReplayConfig {
+    pub dir: PathBuf,
+    pub speed: Speed,
+    pub start: Option<u64>,
+}
+
+/// Where the frames of one attached feed go.
+struct Target {
+    id: u64,
+    exchange: Exchange,
+    order_book: Arc<Mutex<(Vec<Order>, Vec<Order>)>>,
+    status: SharedFeedStatus,
+    recorder: Option<Recorder>,
+}
+
+/// How a replay ended, once it has.
+type Outcome = Option<Result<(), String>>;
+
+/// Plays recorded frames back into feeds as if the venues were sending them again.
+/// A single driver plays the frames of every recording in the order they were
+/// recorded, so venues stay in step with each other and every run of the same
+/// recordings updates the books in the same order.
+#[derive(Clone)]
+pub struct Replay {
+    files: Arc<BTreeMap<(String, String), Vec<PathBuf>>>,
+    speed: Speed,
+    clock: SimulatedClock,
+    opened_at: Instant,
+    targets: Arc<Mutex<HashMap<(String, String), Target>>>,
+    attached: Arc<AtomicU64>,
+    started: Arc<AtomicBool>,
+    outcome: Arc<watch::Sender<Outcome>>,
+}
+
+impl Replay {
+    /// Fails when there is nothing to replay, or when an accelerated speed is not a
+    /// positive number.
+    pub fn open(config: ReplayConfig) -> io::Result<Self> {
+        if let Speed::Accelerated(factor) = config.speed {
+            if !factor.is_finite() || factor <= 0.0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("A replay speed must be a positive number, got {}", factor)));
+            }
+        }
+        let mut files: BTreeMap<(String, String), Vec<PathBuf>> = BTreeMap::new();
+        let mut origin = u64::MAX;
+        for path in recordings(&config.dir)? {
+            let mut records = RecordReader::open(&path)?;
+            if let Some(Ok(Record::Start { exchange, pair, .. })) = records.next() {
+                // The replay starts with the earliest frame of any feed.
+                let first_frame = records.find_map(|record| match record {
+                    Ok(Record::Frame { received_at, .. }) => Some(received_at),
+                    _ => None,
+                });
+                origin = origin.min(first_frame.unwrap_or(u64::MAX));
+                files.entry((exchange, pair)).or_default().push(path);
+            }
+        }
+        if files.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("No recordings in {}", config.dir.display())));
+        }
+
+        let start = config.start.unwrap_or_else(now_millis);
+        Ok(Replay {
+            files: Arc::new(files),
+            speed: config.speed,
+            clock: SimulatedClock::new(origin, start),
+            opened_at: Instant::now(),
+            targets: Arc::default(),
+            attached: Arc::default(),
+            started: Arc::default(),
+            outcome: Arc::new(watch::channel(None).0),
+        })
+    }
+
+    pub fn clock(&self) -> SimulatedClock {
+        self.clock.clone()
+    }
+
+    /// The (exchange, pair) feeds there are recordings of.
+    pub fn feeds(&self) -> Vec<(String, String)> {
+        self.files.keys().cloned().collect()
+    }
+
+    /// Has the recorded frames of `pair` on `exchange` fed through the venue's own parser,
+    /// in the same way and with the same arguments as its live connector, until the
+    /// returned feed is dropped. Books are stamped with the replay clock. A feed attached
+    /// after `start` picks up from the frame being played.
+    pub fn attach(&self, exchange: Exchange, pair: &str, order_book: Arc<Mutex<(Vec<Order>, Vec<Order>)>>, status: SharedFeedStatus, recorder: Option<Recorder>) -> ReplayFeed {
+        let key = (exchange.to_string(), pair.to_lowercase());
+        let recordings = self.files.get(&key).map_or(0, Vec::len);
+        println!("Replaying {} {} from {} recordings.", exchange, key.1, recordings);
+
+        status.lock().unwrap().connecting();
+        status.lock().unwrap().connected();
+        let id = self.attached.fetch_add(1, Ordering::SeqCst);
+        self.targets.lock().unwrap().insert(key.clone(), Target { id, exchange, order_book, status, recorder });
+        ReplayFeed { key, id, targets: Arc::clone(&self.targets), outcome: self.outcome.subscribe() }
+    }
+
+    /// Starts playing the recordings back to the attached feeds, unless that was done
+    /// already. Returns false if it was.
+    pub fn start(&self) -> bool {
+        if self.started.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        let replay = self.clone();
+        tokio::spawn(async move {
+            let outcome = replay.run(|| {}).await;
+            if let Err(e) = &outcome {
+                println!("Replay failed: {}", e);
+            }
+            replay.outcome.send_replace(Some(outcome));
+        });
+        true
+    }
+
+    /// Plays every recorded frame in the order it was recorded, calling `played` after
+    /// each one. Frames recorded in the same millisecond go in the order of their feeds.
+    async fn run(&self, mut played: impl FnMut()) -> Result<(), String> {
+        let mut frames = Frames::new(&self.files).map_err(|e| e.to_string())?;
+        while let Some(frame) = frames.next().map_err(|e| e.to_string())? {
+            self.wait_for(frame.recorded_at).await;
+            self.play(frame);
+            played();
+        }
+        Ok(())
+    }
+
+    /// Waits until a frame recorded at `recorded_at` is due.
+    async fn wait_for(&self, recorded_at: u64) {
+        let elapsed = recorded_at.saturating_sub(self.clock.origin) as f64;
+        let factor = match self.speed {
+            Speed::Realtime => 1.0,
+            Speed::Accelerated(factor) => factor,
+            Speed::AsFastAsPossible => {
+                tokio::task::yield_now().await;
+                return;
+            }
+        };
+        sleep_until(self.opened_at + Duration::from_secs_f64(elapsed / factor / 1000.0)).await;
+    }
+
+    /// Moves the clock on to `frame`, and hands it to its feed if one is attached.
+    fn play(&self, frame: Frame) {
+        let received_at = self.clock.advance(frame.recorded_at);
+        let targets = self.targets.lock().unwrap();
+        let Some(target) = targets.get(&frame.feed) else {
+            return;
+        };
+
+        let (exchange, pair) = (target.exchange, &frame.feed.1);
+        if let Some(recorder) = &target.recorder {
+            recorder.record_frame(exchange.name(), pair, received_at, &frame.text);
+        }
+        match parse_frame(exchange, &frame.text, pair) {
+            Ok(Some(update)) => {
+                if let Err(e) = replay_update(exchange.name(), pair, received_at, update, &target.order_book, &target.status, target.recorder.as_ref()) {
+                    println!("Skipped a replayed book: {}", e);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => println!("Skipped a replayed {} {} frame: {}", exchange, pair, e),
+        }
+    }
+}
+
+/// A feed attached to a replay. Dropping it detaches the feed.
+pub struct ReplayFeed {
+    key: (String, String),
+    id: u64,
+    targets: Arc<Mutex<HashMap<(String, String), Target>>>,
+    outcome: watch::Receiver<Outcome>,
+}
+
+impl ReplayFeed {
+    /// Resolves once the replay has played its last frame, or failed.
+    pub async fn finished(&mut self) -> Result<(), String> {
+        match self.outcome.wait_for(Option::is_some).await {
+            Ok(outcome) => outcome.clone().unwrap_or(Ok(())),
+            Err(_) => Err("the replay stopped".to_string()),
+        }
+    }
+}
+
+impl Drop for ReplayFeed {
+    fn drop(&mut self) {
+        let mut targets = self.targets.lock().unwrap();
+        // Unless the feed was attached again since.
+        if targets.get(&self.key).is_some_and(|target| target.id == self.id) {
+            targets.remove(&self.key);
+        }
+    }
+}
+
+/// A recorded frame of the feed of an (exchange, pair).
+struct Frame {
+    feed: (String, String),
+    recorded_at: u64,
+    text: String,
+}
+
+/// The frames of one feed, across its files, in the order they were recorded.
+struct FeedFrames {
+    paths: std::vec::IntoIter<PathBuf>,
+    reader: Option<RecordReader>,
+}
+
+impl FeedFrames {
+    fn next(&mut self) -> io::Result<Option<(u64, String)>> {
+        loop {
+            let reader = match &mut self.reader {
+                Some(reader) => reader,
+                None => match self.paths.next() {
+                    Some(path) => self.reader.insert(RecordReader::open(path)?),
+                    None => return Ok(None),
+                },
+            };
+            match reader.next().transpose()? {
+                Some(Record::Frame { received_at, text }) => return Ok(Some((received_at, text))),
+                Some(_) => {}
+                None => self.reader = None,
+            }
+        }
+    }
+}
+
+/// The frames of every feed merged into one sequence, ordered by when they were
+/// recorded, and then by feed.
+struct Frames {
+    feeds: Vec<((String, String), FeedFrames)>,
+    pending: Vec<Option<String>>,
+    next: BinaryHeap<Reverse<(u64, usize)>>,
+}
+
+impl Frames {
+    fn new(files: &BTreeMap<(String, String), Vec<PathBuf>>) -> io::Result<Self> {
+        let feeds: Vec<_> = files.iter()
+            .map(|(feed, paths)| (feed.clone(), FeedFrames { paths: paths.clone().into_iter(), reader: None }))
+            .collect();
+        let mut frames = Frames { pending: vec![None; feeds.len()], feeds, next: BinaryHeap::new() };
+        for index in 0..frames.feeds.len() {
+            frames.read(index)?;
+        }
+        Ok(frames)
+    }
+
+    /// Reads the next frame of the feed at `index`, if it has one left.
+    fn read(&mut self, index: usize) -> io::Result<()> {
+        if let Some((recorded_at, text)) = self.feeds[index].1.next()? {
+            self.pending[index] = Some(text);
+            self.next.push(Reverse((recorded_at, index)));
+        }
+        Ok(())
+    }
+
+    fn next(&mut self) -> io::Result<Option<Frame>> {
+        let Some(Reverse((recorded_at, index))) = self.next.pop() else {
+            return Ok(None);
+        };
+        let text = self.pending[index].take().unwrap_or_default();
+        self.read(index)?;
+        Ok(Some(Frame { feed: self.feeds[index].0.clone(), recorded_at, text }))
+    }
+}
+
+fn parse_frame(exchange: Exchange, text: &str, pair: &str) -> Result<Option<FeedUpdate>, Box<dyn Error>> {
+    match exchange {
+        Exchange::Binance => parse_depth(text, pair).map(Some),
+        Exchange::Bitstamp => parse_order_book(text, pair),
+    }
+}
+
+/// The books a recording says its feed made, in order, for checking a replay against.
+pub fn recorded_books(path: impl AsRef<Path>) -> io::Result<Vec<(Levels, Levels)>> {
+    let mut books = Vec::new();
+    for record in RecordReader::open(path)? {
+        if let Record::Book { bids, asks, .. } = record? {
+            books.push((bids, asks));
+        }
+    }
+    Ok(books)
+}
+
+/* ------------
+    TEST CASES
+   ------------*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recorder::RecorderConfig;
+    use crate::status::{ConnectionState, FeedStatus};
+    use exc_orderbook::consolidated_book::ConsolidatedBook;
+    use std::fs;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("replay-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn binance_frame(id: u64, bid: &str, ask: &str) -> String {
+        format!(r#"{{"lastUpdateId":{},"bids":[["{}","1.5"]],"asks":[["{}","2.0"]]}}"#, id, bid, ask)
+    }
+
+    // Two Binance frames 200ms apart, and a Bitstamp subscription acknowledgement followed by a book.
+    fn record_fixture(dir: &Path) {
+        let recorder = Recorder::new(RecorderConfig { dir: dir.to_path_buf(), ..Default::default() });
+        recorder.record_frame("binance", "ethbtc", 10_000, &binance_frame(1, "0.0600", "0.0601"));
+        recorder.record_frame("binance", "ethbtc", 10_200, &binance_frame(2, "0.0602", "0.0603"));
+        recorder.record_frame("bitstamp", "ethbtc", 10_050, r#"{"event":"bts:subscription_succeeded","channel":"order_book_ethbtc","data":{}}"#);
+        recorder.record_frame("bitstamp", "ethbtc", 10_100, r#"{"event":"data","data":{"microtimestamp":"10090000","bids":[["0.0599","3.0"]],"asks":[["0.0604","1.0"]]}}"#);
+        recorder.flush().unwrap();
+    }
+
+    fn open(dir: &Path, speed: Speed) -> Replay {
+        Replay::open(ReplayConfig { dir: dir.to_path_buf(), speed, start: Some(1_000_000) }).unwrap()
+    }
+
+    /// Plays the recording of `pair` on `exchange` alone, to the end.
+    async fn replay_alone(replay: &Replay, exchange: Exchange, order_book: Arc<Mutex<(Vec<Order>, Vec<Order>)>>, status: SharedFeedStatus, recorder: Option<Recorder>) {
+        let mut feed = replay.attach(exchange, "ETHBTC", order_book, status, recorder);
+        assert!(replay.start());
+        feed.finished().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replay_is_deterministic() {
+        let dir = temp_dir("deterministic");
+        record_fixture(&dir);
+        let replay = open(&dir, Speed::AsFastAsPossible);
+        assert_eq!(replay.feeds().len(), 2);
+
+        let order_book = Arc::new(Mutex::new((Vec::new(), Vec::new())));
+        let status = FeedStatus::new_shared();
+        replay_alone(&replay, Exchange::Binance, Arc::clone(&order_book), Arc::clone(&status), None).await;
+
+        let (bids, asks) = order_book.lock().unwrap().clone();
+        assert_eq!((bids[0].price, bids[0].size), (0.0602, 1.5));
+        assert_eq!((asks[0].price, asks[0].size), (0.0603, 2.0));
+        let status = status.lock().unwrap();
+        assert_eq!(status.messages, 2);
+        assert_eq!(status.last_sequence, Some(2));
+        // Recorded times are shifted to start at the configured start.
+        assert_eq!(status.last_message_at, Some(1_000_200));
+        assert_eq!(replay.clock().now(), 1_000_200);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_venues_replay_in_recorded_order() {
+        let dir = temp_dir("interleaved");
+        record_fixture(&dir);
+
+        let mut runs = Vec::new();
+        for _ in 0..2 {
+            let replay = open(&dir, Speed::AsFastAsPossible);
+            let books: Vec<_> = [Exchange::Binance, Exchange::Bitstamp].into_iter()
+                .map(|exchange| {
+                    let order_book = Arc::new(Mutex::new((Vec::new(), Vec::new())));
+                    let feed = replay.attach(exchange, "ethbtc", Arc::clone(&order_book), FeedStatus::new_shared(), None);
+                    (order_book, feed)
+                })
+                .collect();
+
+            // The consolidated book and replay time after every frame.
+            let mut snapshots = Vec::new();
+            replay.run(|| {
+                let book = ConsolidatedBook::new(books.iter().map(|(order_book, _)| order_book.lock().unwrap().clone()), "ethbtc");
+                snapshots.push((replay.clock().now(), book.bids, book.asks));
+            }).await.unwrap();
+            runs.push(snapshots);
+        }
+
+        assert_eq!(runs[0], runs[1]);
+        let times: Vec<u64> = runs[0].iter().map(|(now, _, _)| *now).collect();
+        assert_eq!(times, vec![1_000_000, 1_000_050, 1_000_100, 1_000_200]);
+        // Bitstamp's book joins Binance's first one before Binance's second frame.
+        let best_bids: Vec<Option<f64>> = runs[0].iter().map(|(_, bids, _)| bids.first().map(|level| level.price)).collect();
+        assert_eq!(best_bids, vec![Some(0.0600), Some(0.0600), Some(0.0600), Some(0.0602)]);
+        assert_eq!(runs[0][2].1.len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replayed_books_match_recording() {
+        let dir = temp_dir("rerecord");
+        let rerecorded = temp_dir("rerecorded");
+        record_fixture(&dir);
+        let replay = open(&dir, Speed::AsFastAsPossible);
+
+        let recorder = Recorder::new(RecorderConfig { dir: rerecorded.clone(), ..Default::default() });
+        let order_book = Arc::new(Mutex::new((Vec::new(), Vec::new())));
+        replay_alone(&replay, Exchange::Bitstamp, Arc::clone(&order_book), FeedStatus::new_shared(), Some(recorder.clone())).await;
+        recorder.flush().unwrap();
+
+        // The acknowledgement is recorded as a frame, but only the book makes one.
+        let books = recorded_books(&recordings(&rerecorded).unwrap()[0]).unwrap();
+        assert_eq!(books, vec![(vec![(0.0599, 3.0)], vec![(0.0604, 1.0)])]);
+        assert_eq!(order_book.lock().unwrap().0[0].exchange, "Bitstamp");
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&rerecorded).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_accelerated_replay_keeps_pace() {
+        let dir = temp_dir("accelerated");
+        record_fixture(&dir);
+        let replay = open(&dir, Speed::Accelerated(2.0));
+
+        let started = Instant::now();
+        let order_book = Arc::new(Mutex::new((Vec::new(), Vec::new())));
+        replay_alone(&replay, Exchange::Binance, order_book, FeedStatus::new_shared(), None).await;
+        // The last frame was recorded at least 200ms after the first.
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_aggregator_runs_from_replay() {
+        let dir = temp_dir("aggregator");
+        record_fixture(&dir);
+        let aggregator = crate::aggregator::Aggregator::new().with_replay(open(&dir, Speed::AsFastAsPossible));
+        aggregator.subscribe(Exchange::Binance, "ethbtc");
+        aggregator.subscribe(Exchange::Bitstamp, "ethbtc");
+        assert!(aggregator.start_replay());
+        assert!(!aggregator.start_replay());
+
+        // Both feeds disconnect once their recording is used up.
+        let replaying = |venue: &crate::aggregator::VenueStatus| venue.status.messages == 0 || venue.status.state != ConnectionState::Disconnected;
+        while aggregator.statuses(None).iter().any(replaying) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let books = aggregator.order_books("ethbtc");
+        assert_eq!(books[&Exchange::Binance].bids[0].price, 0.0602);
+        assert_eq!(books[&Exchange::Bitstamp].event_time, Some(10_090));
+        assert_eq!(books[&Exchange::Bitstamp].received_at, Some(1_000_100));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replayed_feeds_go_stale_by_replay_time() {
+        let dir = temp_dir("stale");
+        record_fixture(&dir);
+        let aggregator = crate::aggregator::Aggregator::new()
+            .with_replay(open(&dir, Speed::AsFastAsPossible))
+            .with_stale_after(Duration::from_millis(50));
+        aggregator.subscribe(Exchange::Binance, "ethbtc");
+        aggregator.subscribe(Exchange::Bitstamp, "ethbtc");
+        aggregator.start_replay();
+        while aggregator.statuses(None).iter().any(|venue| venue.status.messages == 0 || venue.status.state != ConnectionState::Disconnected) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // The replay ends on Binance's last frame, 100ms of replay time after Bitstamp's,
+        // however long ago that was on the wall clock.
+        assert_eq!(aggregator.now(), 1_000_200);
+        assert_eq!(aggregator.stale_venues("ethbtc"), vec![Exchange::Bitstamp]);
+        assert_eq!(aggregator.order_books("ethbtc").into_keys().collect::<Vec<_>>(), vec![Exchange::Binance]);
+        assert_eq!(aggregator.fresh_venues("ethbtc", Duration::from_secs(1)), vec![Exchange::Binance]);
+        // A recording cannot be resynced, and is not played again from the start.
+        assert_eq!(aggregator.resync(Exchange::Binance), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_open_without_recordings() {
+        let dir = temp_dir("empty");
+        fs::create_dir_all(&dir).unwrap();
+        assert!(Replay::open(ReplayConfig { dir: dir.clone(), speed: Speed::Realtime, start: None }).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_open_rejects_bad_speed() {
+        let dir = temp_dir("speed");
+        record_fixture(&dir);
+        for factor in [f64::NAN, -1.0, 0.0, f64::INFINITY] {
+            assert!(Replay::open(ReplayConfig { dir: dir.clone(), speed: Speed::Accelerated(factor), start: None }).is_err());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
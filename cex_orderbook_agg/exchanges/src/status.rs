@@ -0,0 +1,250 @@
+use crate::replay::SimulatedClock;
+use crate::validation::RejectReason;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub type SharedFeedStatus = Arc<Mutex<FeedStatus>>;
+
+// The message rate is recomputed once this many milliseconds worth of messages were seen.
+static RATE_WINDOW_MS: u64 = 5_000;
+
+/// Milliseconds since the Unix epoch.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Where the time feeds are judged by comes from: the wall clock, or the clock of the
+/// replay the feeds are played back from.
+#[derive(Debug, Clone, Default)]
+pub enum Clock {
+    #[default]
+    Wall,
+    Replay(SimulatedClock),
+}
+
+impl Clock {
+    /// Milliseconds since the Unix epoch, by this clock.
+    pub fn now(&self) -> u64 {
+        match self {
+            Clock::Wall => now_millis(),
+            Clock::Replay(clock) => clock.now(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConnectionState {
+    #[default]
+    Disconnected,
+    Connecting,
+    Connected,
+}
+
+/// What is known about the health of a single (exchange, pair) feed.
+#[derive(Debug, Clone, Default)]
+pub struct FeedStatus {
+    pub state: ConnectionState,
+    pub last_message_at: Option<u64>,
+    pub last_event_time: Option<u64>,
+    pub messages: u64,
+    pub message_rate: f64,
+    pub last_sequence: Option<u64>,
+    /// Books dropped and rebuilt from a fresh snapshot on purpose.
+    pub resync_count: u64,
+    /// Connections that ended or failed and were opened again.
+    pub reconnect_count: u64,
+    pub last_error: Option<String>,
+    /// When the book was found to have stopped updating, while it is left out of
+    /// consolidated views.
+    pub stale_since: Option<u64>,
+    /// How many times the book was left out for being stale.
+    pub stale_count: u64,
+    /// Levels, or whole books for crossed ones, kept out of the consolidated view.
+    pub rejections: BTreeMap<RejectReason, u64>,
+    // The `messages` count of the last book whose levels outside the price band were counted.
+    band_checked: Option<u64>,
+    clock: Clock,
+    window_start: u64,
+    window_messages: u64,
+}
+
+impl FeedStatus {
+    pub fn new_shared() -> SharedFeedStatus {
+        Arc::new(Mutex::new(FeedStatus::default()))
+    }
+
+    /// A status whose message rate is measured by `clock`, which should be the clock the
+    /// feed stamps its books with.
+    pub fn shared_with_clock(clock: Clock) -> SharedFeedStatus {
+        Arc::new(Mutex::new(FeedStatus { clock, ..FeedStatus::default() }))
+    }
+
+    pub fn connecting(&mut self) {
+        self.state = ConnectionState::Connecting;
+    }
+
+    pub fn connected(&mut self) {
+        self.state = ConnectionState::Connected;
+        self.window_start = self.clock.now();
+        self.window_messages = 0;
+    }
+
+    pub fn disconnected(&mut self, error: Option<String>) {
+        self.state = ConnectionState::Disconnected;
+        self.message_rate = 0.0;
+        if error.is_some() {
+            self.last_error = error;
+        }
+    }
+
+    /// Records a book update received at `received_at`. `event_time` is when the venue
+    /// produced it and `sequence` its sequence number, for venues that send them.
+    pub fn record_message(&mut self, received_at: u64, event_time: Option<u64>, sequence: Option<u64>) {
+        self.messages += 1;
+        self.window_messages += 1;
+        self.last_message_at = Some(received_at);
+        self.last_event_time = event_time;
+        self.stale_since = None;
+        if sequence.is_some() {
+            self.last_sequence = sequence;
+        }
+
+        let elapsed = received_at.saturating_sub(self.window_start);
+        if elapsed >= RATE_WINDOW_MS {
+            self.message_rate = self.window_messages as f64 * 1000.0 / elapsed as f64;
+            self.window_start = received_at;
+            self.window_messages = 0;
+        }
+    }
+
+    pub fn record_resync(&mut self) {
+        self.resync_count += 1;
+    }
+
+    pub fn record_reconnect(&mut self) {
+        self.reconnect_count += 1;
+    }
+
+    pub fn record_rejections(&mut self, reason: RejectReason, count: u64) {
+        if count > 0 {
+            *self.rejections.entry(reason).or_default() += count;
+        }
+    }
+
+    /// Counts `count` levels of the current book as outside the price band. The same book
+    /// is read many times but only counted once.
+    pub fn record_outside_band(&mut self, count: u64) {
+        if self.band_checked != Some(self.messages) {
+            self.band_checked = Some(self.messages);
+            self.record_rejections(RejectReason::OutsideBand, count);
+        }
+    }
+
+    /// Whether the book is stale at `now`: its last update is more than `ttl` milliseconds
+    /// old.
+    pub fn is_stale(&self, now: u64, ttl: u64) -> bool {
+        self.last_message_at.is_some_and(|at| now.saturating_sub(at) > ttl)
+    }
+
+    /// Like [`FeedStatus::is_stale`], also recording since when it is. The first check that
+    /// finds it stale counts one more exclusion.
+    pub fn check_stale(&mut self, now: u64, ttl: u64) -> bool {
+        let stale = self.is_stale(now, ttl);
+        match (stale, self.stale_since) {
+            (true, None) => {
+                self.stale_since = Some(now);
+                self.stale_count += 1;
+            }
+            (false, Some(_)) => self.stale_since = None,
+            _ => {}
+        }
+        stale
+    }
+}
+
+/* ------------
+    TEST CASES
+   ------------*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_message() {
+        let mut status = FeedStatus::default();
+        status.connected();
+        let start = status.window_start;
+
+        for i in 0..10 {
+            status.record_message(start + i * 100, Some(start + i * 100 - 5), Some(i));
+        }
+        assert_eq!(status.messages, 10);
+        assert_eq!(status.last_sequence, Some(9));
+        assert_eq!(status.last_message_at, Some(start + 900));
+        assert_eq!(status.last_event_time, Some(start + 895));
+        assert_eq!(status.message_rate, 0.0);
+
+        // Closing the window turns the 11 messages seen over 5 seconds into a rate.
+        status.record_message(start + 5_000, None, None);
+        assert_eq!(status.message_rate, 2.2);
+        assert_eq!(status.last_sequence, Some(9));
+    }
+
+    #[test]
+    fn test_disconnected_keeps_last_error() {
+        let mut status = FeedStatus::default();
+        status.connecting();
+        assert_eq!(status.state, ConnectionState::Connecting);
+
+        status.disconnected(Some("connection reset".to_string()));
+        status.record_resync();
+        status.connecting();
+        status.connected();
+        status.disconnected(None);
+        status.record_reconnect();
+        status.record_reconnect();
+
+        assert_eq!(status.state, ConnectionState::Disconnected);
+        assert_eq!(status.last_error.as_deref(), Some("connection reset"));
+        assert_eq!((status.resync_count, status.reconnect_count), (1, 2));
+    }
+
+    #[test]
+    fn test_rejections() {
+        let mut status = FeedStatus::default();
+        status.record_rejections(RejectReason::InvalidSize, 2);
+        status.record_rejections(RejectReason::InvalidPrice, 0);
+        status.record_outside_band(3);
+        status.record_outside_band(3);
+        assert_eq!(status.rejections, BTreeMap::from([(RejectReason::InvalidSize, 2), (RejectReason::OutsideBand, 3)]));
+
+        status.record_message(1_000, None, None);
+        status.record_outside_band(1);
+        assert_eq!(status.rejections[&RejectReason::OutsideBand], 4);
+    }
+
+    #[test]
+    fn test_check_stale() {
+        let mut status = FeedStatus::default();
+        // Nothing received yet: there is no book to leave out.
+        assert!(!status.check_stale(100_000, 1_000));
+
+        status.record_message(10_000, None, None);
+        assert!(!status.check_stale(11_000, 1_000));
+        assert!(status.check_stale(11_001, 1_000));
+        assert!(status.check_stale(12_000, 1_000));
+        assert_eq!((status.stale_since, status.stale_count), (Some(11_001), 1));
+
+        // A new update makes it fresh again, until it goes quiet once more.
+        status.record_message(12_500, None, None);
+        assert!(status.stale_since.is_none());
+        assert!(!status.check_stale(13_000, 1_000));
+        assert!(status.check_stale(14_000, 1_000));
+        assert_eq!(status.stale_count, 2);
+    }
+}
@@ -0,0 +1,23 @@
+//! Builders shared by the unit tests of this crate and, with the `test-util` feature,
+//! of the crates depending on it.
+
+use crate::aggregator::{Aggregator, Exchange};
+use exc_orderbook::combine_orderbook::Order;
+
+/// A level of `size` at `price` in the Binance "ethbtc" book.
+pub fn order(price: f64, size: f64) -> Order {
+    venue_order(Exchange::Binance, "ethbtc", price, size)
+}
+
+/// A level of `size` at `price` in the `pair` book of `exchange`.
+pub fn venue_order(exchange: Exchange, pair: &str, price: f64, size: f64) -> Order {
+    Order { exchange: exchange.to_string(), pair: pair.to_string(), price, size }
+}
+
+/// Makes `bids` and `asks`, as `(price, size)`, the book of `pair` on `exchange`, which
+/// is subscribed without a feed if it is not yet, so that nothing else changes the book.
+pub fn set_book(aggregator: &Aggregator, exchange: Exchange, pair: &str, bids: &[(f64, f64)], asks: &[(f64, f64)]) {
+    aggregator.subscribe_without_feed(exchange, pair);
+    let levels = |levels: &[(f64, f64)]| levels.iter().map(|(price, size)| venue_order(exchange, pair, *price, *size)).collect();
+    *aggregator.order_book(exchange, pair).unwrap().lock().unwrap() = (levels(bids), levels(asks));
+}
@@ -0,0 +1,122 @@
+use crate::aggregator::Exchange;
+use futures_util::stream::{self, Stream};
+use tokio::sync::broadcast;
+
+// How many trades a slow tape reader can fall behind before it misses some.
+static TAPE_CAPACITY: usize = 4096;
+
+/// The side of the order that took liquidity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TradeSide {
+    /// A buyer lifted an ask.
+    Buy,
+    /// A seller hit a bid.
+    Sell,
+}
+
+impl TradeSide {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TradeSide::Buy => "buy",
+            TradeSide::Sell => "sell",
+        }
+    }
+}
+
+/// One trade on one venue. `trade_time` is when the venue matched it, if it says;
+/// `received_at` when it reached us. Both are milliseconds since the Unix epoch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trade {
+    pub exchange: Exchange,
+    pub pair: String,
+    pub price: f64,
+    pub size: f64,
+    pub aggressor: TradeSide,
+    pub trade_id: u64,
+    pub trade_time: Option<u64>,
+    pub received_at: u64,
+}
+
+/// The consolidated trade tape: every trade of every venue's trade feed, in the order
+/// they were received. Clones publish to and read from the same tape.
+#[derive(Clone)]
+pub struct TradeTape {
+    sender: broadcast::Sender<Trade>,
+}
+
+impl Default for TradeTape {
+    fn default() -> Self {
+        TradeTape { sender: broadcast::channel(TAPE_CAPACITY).0 }
+    }
+}
+
+impl TradeTape {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn publish(&self, trade: Trade) {
+        // Nobody reading the tape is not an error.
+        let _ = self.sender.send(trade);
+    }
+
+    /// A receiver of every trade published from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Trade> {
+        self.sender.subscribe()
+    }
+
+    /// The trades published from now on, as a stream. A reader that falls behind skips
+    /// the trades it missed rather than ending.
+    pub fn stream(&self) -> impl Stream<Item = Trade> {
+        stream::unfold(self.subscribe(), |mut trades| async move {
+            loop {
+                match trades.recv().await {
+                    Ok(trade) => return Some((trade, trades)),
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        println!("A trade tape reader fell behind and missed {} trades", missed);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+}
+
+/* ------------
+    TEST CASES
+   ------------*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+
+    fn trade(exchange: Exchange, trade_id: u64) -> Trade {
+        Trade {
+            exchange,
+            pair: "ethbtc".to_string(),
+            price: 0.06,
+            size: 1.0,
+            aggressor: TradeSide::Buy,
+            trade_id,
+            trade_time: None,
+            received_at: 1_000,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tape_stream() {
+        let tape = TradeTape::new();
+        // Published before anyone reads: not on the tape for later readers.
+        tape.publish(trade(Exchange::Binance, 1));
+
+        let stream = tape.stream();
+        tape.clone().publish(trade(Exchange::Binance, 2));
+        tape.publish(trade(Exchange::Bitstamp, 3));
+        drop(tape);
+
+        let trades: Vec<Trade> = stream.collect().await;
+        assert_eq!(trades.iter().map(|trade| trade.trade_id).collect::<Vec<_>>(), vec![2, 3]);
+        assert_eq!(trades[1].exchange, Exchange::Bitstamp);
+    }
+}
@@ -0,0 +1,201 @@
+use crate::bbo::Quote;
+use crate::l3::OrderEvent;
+use crate::trade::Trade;
+use exc_orderbook::combine_orderbook::Order;
+use std::fmt;
+
+// The fewest venue mids a reference price must be checked against before it anchors
+// the price band.
+static MIN_BAND_VENUES: usize = 2;
+
+/// Why a level, a whole book, a quote, a trade or an order event was left out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RejectReason {
+    /// A price that is NaN, infinite, zero or negative.
+    InvalidPrice,
+    /// A size that is NaN, infinite, zero or negative.
+    InvalidSize,
+    /// The venue's best bid is at or above its own best ask, in its book or its quote.
+    CrossedBook,
+    /// A price too far from the consolidated reference price.
+    OutsideBand,
+    /// A frame that could not be parsed, skipped whole.
+    MalformedFrame,
+}
+
+impl RejectReason {
+    pub const ALL: [RejectReason; 5] = [
+        RejectReason::InvalidPrice,
+        RejectReason::InvalidSize,
+        RejectReason::CrossedBook,
+        RejectReason::OutsideBand,
+        RejectReason::MalformedFrame,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RejectReason::InvalidPrice => "invalid_price",
+            RejectReason::InvalidSize => "invalid_size",
+            RejectReason::CrossedBook => "crossed_book",
+            RejectReason::OutsideBand => "outside_band",
+            RejectReason::MalformedFrame => "malformed_frame",
+        }
+    }
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+fn is_positive(value: f64) -> bool {
+    value.is_finite() && value > 0.0
+}
+
+/// Why a level of `price` and `size` is malformed, if it is: either is not a positive
+/// number.
+fn check_level(price: f64, size: f64) -> Result<(), RejectReason> {
+    if !is_positive(price) {
+        Err(RejectReason::InvalidPrice)
+    } else if !is_positive(size) {
+        Err(RejectReason::InvalidSize)
+    } else {
+        Ok(())
+    }
+}
+
+/// Drops the levels whose price or size is not a positive number, and returns why each
+/// one was dropped.
+pub fn drop_malformed(orders: &mut Vec<Order>) -> Vec<RejectReason> {
+    let mut rejected = Vec::new();
+    orders.retain(|order| match check_level(order.price, order.size) {
+        Ok(()) => true,
+        Err(reason) => {
+            rejected.push(reason);
+            false
+        }
+    });
+    rejected
+}
+
+/// Why `quote` is unfit to be quoted, if it is: a side that is malformed, or a bid at or
+/// above the ask.
+pub fn check_quote(quote: &Quote) -> Result<(), RejectReason> {
+    for (price, size) in quote.bid.iter().chain(&quote.ask) {
+        check_level(*price, *size)?;
+    }
+    match (quote.bid, quote.ask) {
+        (Some((bid, _)), Some((ask, _))) if bid >= ask => Err(RejectReason::CrossedBook),
+        _ => Ok(()),
+    }
+}
+
+/// Why `trade` is unfit for the tape and candles, if it is.
+pub fn check_trade(trade: &Trade) -> Result<(), RejectReason> {
+    check_level(trade.price, trade.size)
+}
+
+/// Why `event` is unfit for an order-by-order book, if it is. A size of 0 is fine: it
+/// takes the order off the book.
+pub fn check_order_event(event: &OrderEvent) -> Result<(), RejectReason> {
+    if !is_positive(event.price) {
+        Err(RejectReason::InvalidPrice)
+    } else if !event.size.is_finite() || event.size < 0.0 {
+        Err(RejectReason::InvalidSize)
+    } else {
+        Ok(())
+    }
+}
+
+/// The best bid and ask of a venue book whose best bid is at or above its best ask.
+pub fn crossed(bids: &[Order], asks: &[Order]) -> Option<(f64, f64)> {
+    let best_bid = bids.iter().map(|order| order.price).max_by(f64::total_cmp)?;
+    let best_ask = asks.iter().map(|order| order.price).min_by(f64::total_cmp)?;
+    (best_bid >= best_ask).then_some((best_bid, best_ask))
+}
+
+fn within_band(price: f64, reference: f64, band_bps: f64) -> bool {
+    (price - reference).abs() <= reference * band_bps / 10_000.0
+}
+
+/// Whether `reference` may anchor a price band of `band_bps` around it: it was drawn from
+/// at least two venue `mids`, and most of them lie within the band. With two venues both
+/// must, so a single venue far off the market cannot draw the band to itself.
+pub fn has_band_quorum(mids: &[f64], reference: f64, band_bps: f64) -> bool {
+    let within = mids.iter().filter(|mid| within_band(**mid, reference, band_bps)).count();
+    mids.len() >= MIN_BAND_VENUES && within * 2 > mids.len()
+}
+
+/// Drops the levels priced more than `band_bps` basis points away from `reference`, and
+/// returns how many were dropped.
+pub fn drop_outside_band(orders: &mut Vec<Order>, reference: f64, band_bps: f64) -> usize {
+    let before = orders.len();
+    orders.retain(|order| within_band(order.price, reference, band_bps));
+    before - orders.len()
+}
+
+/* ------------
+    TEST CASES
+   ------------*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregator::Exchange;
+    use crate::l3::OrderEventKind;
+    use crate::test_util::order;
+    use exc_orderbook::consolidated_book::Side;
+    use crate::trade::TradeSide;
+
+    #[test]
+    fn test_drop_malformed() {
+        let mut orders = vec![order(0.06, 1.0), order(f64::NAN, 1.0), order(0.059, 0.0), order(-0.05, 1.0), order(0.058, f64::INFINITY)];
+        let rejected = drop_malformed(&mut orders);
+        assert_eq!(orders.iter().map(|order| (order.price, order.size)).collect::<Vec<_>>(), vec![(0.06, 1.0)]);
+        assert_eq!(rejected, vec![RejectReason::InvalidPrice, RejectReason::InvalidSize, RejectReason::InvalidPrice, RejectReason::InvalidSize]);
+    }
+
+    #[test]
+    fn test_crossed_and_band() {
+        assert_eq!(crossed(&[order(0.06, 1.0), order(0.062, 1.0)], &[order(0.061, 1.0)]), Some((0.062, 0.061)));
+        assert_eq!(crossed(&[order(0.06, 1.0)], &[order(0.06, 1.0)]), Some((0.06, 0.06)));
+        assert!(crossed(&[order(0.06, 1.0)], &[order(0.061, 1.0)]).is_none());
+        assert!(crossed(&[], &[order(0.061, 1.0)]).is_none());
+
+        let mut orders = vec![order(100.0, 1.0), order(104.0, 1.0), order(150.0, 1.0), order(95.0, 1.0)];
+        assert_eq!(drop_outside_band(&mut orders, 100.0, 500.0), 1);
+        assert_eq!(orders.len(), 3);
+        assert_eq!(RejectReason::OutsideBand.to_string(), "outside_band");
+    }
+
+    #[test]
+    fn test_band_quorum() {
+        assert!(has_band_quorum(&[100.0, 101.0], 100.0, 500.0));
+        // One of two venues far off: neither side can tell which one is right.
+        assert!(!has_band_quorum(&[100.0, 50.0], 100.0, 500.0));
+        assert!(has_band_quorum(&[100.0, 101.0, 50.0], 100.0, 500.0));
+        assert!(!has_band_quorum(&[100.0], 100.0, 500.0));
+    }
+
+    #[test]
+    fn test_check_quote_trade_and_order_event() {
+        let quote = |bid, ask| Quote { exchange: Exchange::Binance, pair: "ethbtc".to_string(), bid, ask, update_id: None, event_time: None, received_at: 0 };
+        assert!(check_quote(&quote(Some((0.06, 1.0)), Some((0.061, 1.0)))).is_ok());
+        assert!(check_quote(&quote(None, Some((0.061, 1.0)))).is_ok());
+        assert_eq!(check_quote(&quote(Some((0.062, 1.0)), Some((0.061, 1.0)))), Err(RejectReason::CrossedBook));
+        assert_eq!(check_quote(&quote(Some((f64::NAN, 1.0)), None)), Err(RejectReason::InvalidPrice));
+        assert_eq!(check_quote(&quote(None, Some((0.061, -1.0)))), Err(RejectReason::InvalidSize));
+
+        let trade = |price, size| Trade { exchange: Exchange::Binance, pair: "ethbtc".to_string(), price, size, aggressor: TradeSide::Buy, trade_id: 1, trade_time: None, received_at: 0 };
+        assert!(check_trade(&trade(0.06, 1.0)).is_ok());
+        assert_eq!(check_trade(&trade(0.0, 1.0)), Err(RejectReason::InvalidPrice));
+        assert_eq!(check_trade(&trade(0.06, f64::INFINITY)), Err(RejectReason::InvalidSize));
+
+        let event = |price, size| OrderEvent { kind: OrderEventKind::Changed, id: 1, side: Side::Bid, price, size, time: None };
+        assert!(check_order_event(&event(0.06, 1.0)).is_ok());
+        assert!(check_order_event(&event(0.06, 0.0)).is_ok());
+        assert_eq!(check_order_event(&event(-0.06, 1.0)), Err(RejectReason::InvalidPrice));
+        assert_eq!(check_order_event(&event(0.06, f64::NAN)), Err(RejectReason::InvalidSize));
+    }
+}
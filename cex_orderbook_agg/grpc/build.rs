@@ -1,7 +1,11 @@
 // APPROACH - 1
+// The descriptor set is served by the reflection service.
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("proto/orderbook.proto")?;
+    let out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR")?);
+    tonic_build::configure()
+        .file_descriptor_set_path(out_dir.join("orderbook_descriptor.bin"))
+        .compile(&["proto/orderbook.proto"], &["proto"])?;
     Ok(())
 }
 
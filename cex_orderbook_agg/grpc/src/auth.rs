@@ -0,0 +1,241 @@
+use crate::config::ApiKeyConfig;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tonic::metadata::{errors::InvalidMetadataValue, AsciiMetadataValue};
+use tonic::service::Interceptor;
+use tonic::{Request, Status};
+
+/// The metadata entry clients put their API key in.
+pub static API_KEY_HEADER: &str = "x-api-key";
+
+struct Client {
+    config: ApiKeyConfig,
+    tokens: f64,
+    refilled_at: Instant,
+    streams: Arc<AtomicUsize>,
+}
+
+impl Client {
+    fn new(config: ApiKeyConfig) -> Self {
+        Client {
+            tokens: config.burst.unwrap_or(config.requests_per_second),
+            refilled_at: Instant::now(),
+            streams: Arc::new(AtomicUsize::new(0)),
+            config,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let burst = self.config.burst.unwrap_or(self.config.requests_per_second);
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.config.requests_per_second).min(burst);
+        self.refilled_at = now;
+    }
+}
+
+/// The client a request was authenticated as. Added to the request extensions.
+#[derive(Debug, Clone)]
+pub struct ClientName(pub String);
+
+/// The concurrent-stream quota of the client a request was authenticated as. Added to the
+/// request extensions; streaming handlers take a permit from it for as long as their
+/// stream is open, while unary calls leave it alone.
+#[derive(Debug, Clone)]
+pub struct StreamQuota {
+    name: String,
+    max_concurrent_streams: usize,
+    streams: Arc<AtomicUsize>,
+}
+
+impl StreamQuota {
+    /// A permit counting against the quota until dropped, unless it is used up.
+    pub fn acquire(&self) -> Result<StreamPermit, String> {
+        self.streams
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| (open < self.max_concurrent_streams).then_some(open + 1))
+            .map_err(|_| format!("concurrent stream quota exceeded for {}", self.name))?;
+        Ok(StreamPermit { streams: Arc::clone(&self.streams) })
+    }
+}
+
+/// Counts against its client's concurrent-stream quota until dropped.
+#[derive(Debug)]
+pub struct StreamPermit {
+    streams: Arc<AtomicUsize>,
+}
+
+impl Drop for StreamPermit {
+    fn drop(&mut self) {
+        self.streams.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Server interceptor that checks the API key of every request and enforces its quotas.
+/// With no keys configured every request is let through.
+#[derive(Clone, Default)]
+pub struct ApiKeyAuth {
+    clients: Arc<Mutex<HashMap<String, Client>>>,
+    admin_only: bool,
+}
+
+impl ApiKeyAuth {
+    pub fn new(keys: &[ApiKeyConfig]) -> Self {
+        let clients = keys.iter()
+            .map(|config| (config.key.clone(), Client::new(config.clone())))
+            .collect();
+        ApiKeyAuth { clients: Arc::new(Mutex::new(clients)), admin_only: false }
+    }
+
+    /// The same clients and quotas, but only letting admin clients through. Others get
+    /// `PERMISSION_DENIED`.
+    pub fn admin_only(&self) -> Self {
+        ApiKeyAuth { clients: Arc::clone(&self.clients), admin_only: true }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.clients.lock().unwrap().is_empty()
+    }
+}
+
+impl Interceptor for ApiKeyAuth {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.is_empty() {
+            return Ok(request);
+        }
+
+        let key = request.metadata().get(API_KEY_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| Status::unauthenticated("missing API key"))?;
+        let client = clients.get_mut(key)
+            .ok_or_else(|| Status::unauthenticated("invalid API key"))?;
+        if self.admin_only && !client.config.admin {
+            return Err(Status::permission_denied(format!("{} is not an admin client", client.config.name)));
+        }
+
+        client.refill(Instant::now());
+        if client.tokens < 1.0 {
+            return Err(Status::resource_exhausted(format!("request rate quota exceeded for {}", client.config.name)));
+        }
+
+        client.tokens -= 1.0;
+        let quota = StreamQuota {
+            name: client.config.name.clone(),
+            max_concurrent_streams: client.config.max_concurrent_streams,
+            streams: Arc::clone(&client.streams),
+        };
+
+        request.extensions_mut().insert(ClientName(client.config.name.clone()));
+        request.extensions_mut().insert(quota);
+        Ok(request)
+    }
+}
+
+/// Client interceptor that adds an API key, if there is one, to every request.
+#[derive(Clone, Default)]
+pub struct ApiKey(Option<AsciiMetadataValue>);
+
+impl ApiKey {
+    pub fn new(key: Option<&str>) -> Result<Self, InvalidMetadataValue> {
+        Ok(ApiKey(key.map(str::parse).transpose()?))
+    }
+}
+
+impl Interceptor for ApiKey {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if let Some(key) = &self.0 {
+            request.metadata_mut().insert(API_KEY_HEADER, key.clone());
+        }
+        Ok(request)
+    }
+}
+
+/* ------------
+    TEST CASES
+   ------------*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::Code;
+
+    fn auth(requests_per_second: f64, burst: Option<f64>, max_concurrent_streams: usize) -> ApiKeyAuth {
+        ApiKeyAuth::new(&[ApiKeyConfig {
+            name: "dashboard".to_string(),
+            key: "secret".to_string(),
+            requests_per_second,
+            burst,
+            max_concurrent_streams,
+            admin: false,
+        }])
+    }
+
+    fn request(key: Option<&str>) -> Request<()> {
+        ApiKey::new(key).unwrap().call(Request::new(())).unwrap()
+    }
+
+    #[test]
+    fn test_no_keys_configured() {
+        let mut auth = ApiKeyAuth::default();
+        assert!(!auth.is_enabled());
+        assert!(auth.call(request(None)).is_ok());
+    }
+
+    #[test]
+    fn test_rejects_missing_and_unknown_keys() {
+        let mut auth = auth(10.0, None, 10);
+        assert_eq!(auth.call(request(None)).unwrap_err().code(), Code::Unauthenticated);
+        assert_eq!(auth.call(request(Some("wrong"))).unwrap_err().code(), Code::Unauthenticated);
+
+        let request = auth.call(request(Some("secret"))).unwrap();
+        assert_eq!(request.extensions().get::<ClientName>().unwrap().0, "dashboard");
+    }
+
+    #[test]
+    fn test_request_rate_quota() {
+        // Practically no refill, so only the burst is available.
+        let mut auth = auth(0.001, Some(2.0), 10);
+        assert!(auth.call(request(Some("secret"))).is_ok());
+        assert!(auth.call(request(Some("secret"))).is_ok());
+
+        let status = auth.call(request(Some("secret"))).unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+    }
+
+    #[test]
+    fn test_concurrent_stream_quota() {
+        let mut auth = auth(100.0, None, 1);
+        let quota = |request: Request<()>| request.extensions().get::<StreamQuota>().unwrap().clone();
+
+        // Unary calls take no stream slot, however many are in flight.
+        let first = auth.call(request(Some("secret"))).unwrap();
+        let second = auth.call(request(Some("secret"))).unwrap();
+
+        let open = quota(first).acquire().unwrap();
+        assert!(quota(second).acquire().unwrap_err().contains("dashboard"));
+
+        // Closing the first stream frees its slot.
+        drop(open);
+        assert!(quota(auth.call(request(Some("secret"))).unwrap()).acquire().is_ok());
+    }
+
+    #[test]
+    fn test_admin_only() {
+        let client = |name: &str, admin: bool| ApiKeyConfig {
+            name: name.to_string(),
+            key: name.to_string(),
+            requests_per_second: 100.0,
+            burst: None,
+            max_concurrent_streams: 1,
+            admin,
+        };
+        let auth = ApiKeyAuth::new(&[client("dashboard", false), client("ops", true)]);
+        let mut admin = auth.admin_only();
+
+        assert_eq!(admin.call(request(Some("dashboard"))).unwrap_err().code(), Code::PermissionDenied);
+        assert_eq!(admin.call(request(Some("wrong"))).unwrap_err().code(), Code::Unauthenticated);
+        assert!(admin.call(request(Some("ops"))).is_ok());
+        assert!(auth.clone().call(request(Some("dashboard"))).is_ok());
+    }
+}
@@ -1,6 +1,10 @@
 use std::env;
+use std::path::PathBuf;
+use tonic::codegen::InterceptedService;
 use tonic::transport::Channel;
 use tonic::Request;
+use ::grpc::auth::ApiKey;
+use ::grpc::tls::{connect, ClientTls};
 
 pub mod grpc {
     pub mod orderbook {
@@ -11,12 +15,14 @@ pub mod grpc {
 use crate::grpc::orderbook::order_book_client::OrderBookClient; 
 use crate::grpc::orderbook::GetTopOrdersRequest;
 
+pub type Client = OrderBookClient<InterceptedService<Channel, ApiKey>>;
+
 pub async fn print_top_orders(
-    mut client: OrderBookClient<Channel>,
+    mut client: Client,
     top: i32,
     pair: String,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let request = Request::new(GetTopOrdersRequest { top, pair });
+    let request = Request::new(GetTopOrdersRequest { top, pair, ..Default::default() });
 
     let response = client.get_top_orders(request).await?.into_inner();
 
@@ -31,6 +37,7 @@ pub async fn print_top_orders(
     }
 
     println!("Spread: {}", response.spread);
+    println!("Venues: {}", response.venues.join(", "));
 
     Ok(())
 }
@@ -38,19 +45,45 @@ pub async fn print_top_orders(
 
 #[tokio::main]
 pub async fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
+    // Usage: client <pair> [--addr <url>] [--api-key <key>] [--ca <path>] [--cert <path> --key <path>] [--domain <name>]
+    let mut pair = None;
+    let mut addr = None;
+    let mut api_key = None;
+    let mut tls = ClientTls::default();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| {
+            eprintln!("{} needs a value", arg);
+            std::process::exit(1);
+        });
+        match arg.as_str() {
+            "--addr" => addr = Some(value()),
+            "--api-key" => api_key = Some(value()),
+            "--ca" => tls.ca = Some(PathBuf::from(value())),
+            "--cert" => tls.cert = Some(PathBuf::from(value())),
+            "--key" => tls.key = Some(PathBuf::from(value())),
+            "--domain" => tls.domain = Some(value()),
+            _ => pair = Some(arg),
+        }
+    }
+    let Some(pair) = pair else {
         eprintln!("Please provide a trading pair as an argument");
         std::process::exit(1);
-    }
-    let pair = args[1].clone();
+    };
+
+    // Any TLS option switches the client to TLS, and to https by default.
+    let use_tls = tls.ca.is_some() || tls.cert.is_some() || tls.domain.is_some();
+    let addr = addr.unwrap_or_else(|| {
+        let scheme = if use_tls { "https" } else { "http" };
+        format!("{}://localhost:50051", scheme)
+    });
 
     println!("Wait Client is connecting with gRPC Server...");
     println!("Fetching Data...");
 
-    let channel = Channel::from_static("http://localhost:50051").connect().await.unwrap();
+    let channel = connect(addr, use_tls.then_some(&tls)).await.unwrap();
 
-    let client = OrderBookClient::new(channel);
+    let client = OrderBookClient::with_interceptor(channel, ApiKey::new(api_key.as_deref()).unwrap());
 
     print_top_orders(client, 10, pair).await.unwrap();
 }
@@ -66,7 +99,7 @@ mod tests {
     #[tokio::test]
     async fn test_print_top_orders() {
         let channel = Channel::from_static("http://localhost:50051").connect().await.unwrap();
-        let client = OrderBookClient::new(channel);
+        let client = OrderBookClient::with_interceptor(channel, ApiKey::default());
 
         let result = print_top_orders(client, 5, "ethbtc".to_string()).await;
         
@@ -0,0 +1,449 @@
+use exchanges::aggregator::Exchange;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+static DEFAULT_ADDR: &str = "0.0.0.0:50051";
+static DEFAULT_STALE_AFTER_MS: u64 = 30_000;
+static DEFAULT_PRICE_BAND_BPS: f64 = 0.0;
+static DEFAULT_MAX_AUTO_SUBSCRIBED_PAIRS: usize = 20;
+static DEFAULT_AUTO_SUBSCRIBE_IDLE_SECS: u64 = 600;
+
+/// Settings of the gRPC server, read from a TOML file passed with `--config`.
+///
+/// ```toml
+/// addr = "0.0.0.0:50051"
+/// pairs = ["ethbtc", "btcusdt"]
+/// arbitrage_log = "arbitrage.csv"
+/// stale_after_ms = 30000
+/// price_band_bps = 1000.0
+/// max_auto_subscribed_pairs = 20
+/// auto_subscribe_idle_secs = 600
+///
+/// [tls]
+/// cert = "certs/server.pem"
+/// key = "certs/server.key"
+/// client_ca = "certs/ca.pem"
+///
+/// [[api_keys]]
+/// name = "dashboard"
+/// key = "change-me"
+/// requests_per_second = 10.0
+/// max_concurrent_streams = 4
+///
+/// [[api_keys]]
+/// name = "ops"
+/// key = "change-me-too"
+/// requests_per_second = 1.0
+/// max_concurrent_streams = 1
+/// admin = true
+///
+/// [fees.bitstamp]
+/// maker_bps = 30.0
+/// taker_bps = 40.0
+///
+/// [[synthetic]]
+/// pair = "ethbtc"
+/// base_leg = "ethusd"
+/// quote_leg = "btcusd"
+///
+/// [[quote_groups]]
+/// currencies = ["usd", "usdt", "usdc"]
+/// rate_pairs = { usdt = "usdtusd" }
+///
+/// [index]
+/// max_deviation_bps = 50.0
+/// max_age_ms = 10000
+/// weights = { bitstamp = 0.5 }
+///
+/// [recorder]
+/// dir = "recordings"
+/// max_bytes = 104857600
+/// max_age_secs = 3600
+/// compress = true
+///
+/// [replay]
+/// dir = "recordings"
+/// speed = 10.0
+///
+/// [history]
+/// path = "history.db"
+/// interval_ms = 1000
+/// top = 10
+/// retention_hours = 168
+///
+/// [export]
+/// dir = "export"
+/// format = "parquet"
+/// interval_ms = 1000
+/// top = 10
+/// rows_per_file = 100000
+/// max_age_secs = 3600
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub addr: String,
+    pub pairs: Vec<String>,
+    pub tls: Option<TlsConfig>,
+    pub api_keys: Vec<ApiKeyConfig>,
+    pub fees: BTreeMap<String, FeeConfig>,
+    /// CSV file every arbitrage event is appended to.
+    pub arbitrage_log: Option<PathBuf>,
+    /// A venue whose book has not been updated for this long is left out of consolidated
+    /// views until it is again. 0 keeps every venue in.
+    pub stale_after_ms: u64,
+    /// Levels priced further than this many basis points from the reference price are
+    /// left out of consolidated views. 0, the default, keeps every level in.
+    pub price_band_bps: f64,
+    /// How many pairs besides the configured ones requests can have subscribed at once.
+    /// 0 serves the configured pairs only.
+    pub max_auto_subscribed_pairs: usize,
+    /// A pair subscribed on request is unsubscribed once no stream is open on it and no
+    /// request has asked for it for this long.
+    pub auto_subscribe_idle_secs: u64,
+    pub synthetic: Vec<SyntheticConfig>,
+    pub quote_groups: Vec<QuoteGroupConfig>,
+    pub index: IndexConfig,
+    /// Records every frame and book of every feed when set.
+    pub recorder: Option<RecorderConfig>,
+    /// Plays feeds back from recordings instead of connecting to the venues when set.
+    pub replay: Option<ReplayConfig>,
+    /// Keeps snapshots of every subscribed pair's book for `GetHistory` when set.
+    pub history: Option<HistoryConfig>,
+    /// Exports snapshots of every subscribed pair's books to Parquet or CSV when set.
+    pub export: Option<ExportConfig>,
+}
+
+/// Server certificate and key, in PEM. When `client_ca` is set, clients must present
+/// a certificate signed by it (mutual TLS).
+#[derive(Debug, Clone, Deserialize)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub client_ca: Option<PathBuf>,
+}
+
+/// A client allowed to call the API, and its quotas. `burst` is how many requests
+/// can be made at once after a quiet period, and defaults to `requests_per_second`.
+/// Only clients with `admin` set may call the Admin service.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiKeyConfig {
+    pub name: String,
+    pub key: String,
+    pub requests_per_second: f64,
+    pub burst: Option<f64>,
+    pub max_concurrent_streams: usize,
+    #[serde(default)]
+    pub admin: bool,
+}
+
+/// Fees of one exchange, in basis points. Exchanges without an entry are treated as free.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default)]
+pub struct FeeConfig {
+    pub maker_bps: f64,
+    pub taker_bps: f64,
+}
+
+/// A pair whose book is implied from `base_leg` (pair/common currency) and `quote_leg`
+/// (quote/common currency), e.g. `ethbtc` from `ethusd` and `btcusd`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SyntheticConfig {
+    pub pair: String,
+    pub base_leg: String,
+    pub quote_leg: String,
+}
+
+/// Quote currencies whose books can be merged. The first is the reference; `rate_pairs`
+/// maps a currency to a pair against the reference whose mid is its live rate.
+/// Currencies without one are taken at par.
+#[derive(Debug, Clone, Deserialize)]
+pub struct QuoteGroupConfig {
+    pub currencies: Vec<String>,
+    #[serde(default)]
+    pub rate_pairs: BTreeMap<String, String>,
+}
+
+/// How reference prices are computed. Venues weigh 1.0 unless `weights` says otherwise,
+/// and a weight of 0 leaves a venue out.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct IndexConfig {
+    pub weights: BTreeMap<String, f64>,
+    pub max_deviation_bps: f64,
+    pub max_age_ms: u64,
+}
+
+impl Default for IndexConfig {
+    fn default() -> Self {
+        IndexConfig { weights: BTreeMap::new(), max_deviation_bps: 50.0, max_age_ms: 10_000 }
+    }
+}
+
+/// Where feeds are recorded to. A feed moves on to a new file once its current one
+/// holds `max_bytes` (before compression) or is `max_age_secs` old.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RecorderConfig {
+    pub dir: PathBuf,
+    pub max_bytes: Option<u64>,
+    pub max_age_secs: Option<u64>,
+    #[serde(default)]
+    pub compress: bool,
+}
+
+/// Recordings to play back. They play as fast as they were recorded, `speed` times
+/// faster, or without pausing at all with `as_fast_as_possible`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReplayConfig {
+    pub dir: PathBuf,
+    pub speed: Option<f64>,
+    #[serde(default)]
+    pub as_fast_as_possible: bool,
+}
+
+/// Every `interval_ms`, the top `top` levels per side of each subscribed pair's
+/// consolidated book are stored in the SQLite database at `path`. Snapshots older than
+/// `retention_hours` are deleted; without it they are kept forever.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HistoryConfig {
+    pub path: PathBuf,
+    pub interval_ms: u64,
+    pub top: usize,
+    pub retention_hours: Option<u64>,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig { path: PathBuf::from("history.db"), interval_ms: 1_000, top: 10, retention_hours: None }
+    }
+}
+
+/// Every `interval_ms`, the top `top` levels per side of each venue's book and of the
+/// consolidated book are exported to `dir`, as `format` (`parquet` or `csv`). Parquet
+/// part files hold up to `rows_per_file` rows and are written at least every
+/// `max_age_secs`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ExportConfig {
+    pub dir: PathBuf,
+    pub format: String,
+    pub interval_ms: u64,
+    pub top: usize,
+    pub rows_per_file: usize,
+    pub max_age_secs: u64,
+}
+
+impl Default for ExportConfig {
+    fn default() -> Self {
+        ExportConfig {
+            dir: PathBuf::from("export"),
+            format: "parquet".to_string(),
+            interval_ms: 1_000,
+            top: 10,
+            rows_per_file: 100_000,
+            max_age_secs: 3_600,
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            addr: DEFAULT_ADDR.to_string(),
+            pairs: Vec::new(),
+            tls: None,
+            api_keys: Vec::new(),
+            fees: BTreeMap::new(),
+            arbitrage_log: None,
+            stale_after_ms: DEFAULT_STALE_AFTER_MS,
+            price_band_bps: DEFAULT_PRICE_BAND_BPS,
+            max_auto_subscribed_pairs: DEFAULT_MAX_AUTO_SUBSCRIBED_PAIRS,
+            auto_subscribe_idle_secs: DEFAULT_AUTO_SUBSCRIBE_IDLE_SECS,
+            synthetic: Vec::new(),
+            quote_groups: Vec::new(),
+            index: IndexConfig::default(),
+            recorder: None,
+            replay: None,
+            history: None,
+            export: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let contents = fs::read_to_string(path)?;
+        let config: ServerConfig = toml::from_str(&contents)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks what the TOML types cannot, such as that every `[fees]` entry names a
+    /// known exchange, so that a typo does not leave a venue free, and that no two
+    /// `[[api_keys]]` share a key.
+    pub fn validate(&self) -> Result<(), String> {
+        for exchange in self.fees.keys() {
+            exchange.parse::<Exchange>().map_err(|e| format!("[fees.{}]: {}", exchange, e))?;
+        }
+        let mut keys = HashSet::new();
+        for api_key in &self.api_keys {
+            if !keys.insert(api_key.key.as_str()) {
+                return Err(format!("[[api_keys]] {}: key is already used by another client", api_key.name));
+            }
+            if api_key.requests_per_second.is_nan() || api_key.requests_per_second <= 0.0 {
+                return Err(format!("[[api_keys]] {}: requests_per_second must be positive", api_key.name));
+            }
+        }
+        Ok(())
+    }
+}
+
+/* ------------
+    TEST CASES
+   ------------*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config: ServerConfig = toml::from_str(r#"
+            pairs = ["ethbtc"]
+            arbitrage_log = "arbitrage.csv"
+            stale_after_ms = 5000
+            price_band_bps = 0.0
+            max_auto_subscribed_pairs = 0
+
+            [tls]
+            cert = "server.pem"
+            key = "server.key"
+
+            [[api_keys]]
+            name = "dashboard"
+            key = "secret"
+            requests_per_second = 5.0
+            max_concurrent_streams = 2
+            admin = true
+
+            [fees.binance]
+            taker_bps = 10.0
+
+            [[synthetic]]
+            pair = "ethbtc"
+            base_leg = "ethusd"
+            quote_leg = "btcusd"
+
+            [[quote_groups]]
+            currencies = ["usd", "usdt", "usdc"]
+            rate_pairs = { usdt = "usdtusd" }
+
+            [index]
+            max_deviation_bps = 25.0
+            weights = { bitstamp = 0.5 }
+
+            [recorder]
+            dir = "recordings"
+            max_age_secs = 3600
+
+            [replay]
+            dir = "recordings"
+            speed = 10.0
+
+            [history]
+            retention_hours = 24
+
+            [export]
+            dir = "snapshots"
+            format = "csv"
+        "#).unwrap();
+
+        assert_eq!(config.addr, "0.0.0.0:50051");
+        assert_eq!(config.pairs, vec!["ethbtc"]);
+        assert_eq!(config.arbitrage_log, Some(PathBuf::from("arbitrage.csv")));
+        assert_eq!(config.stale_after_ms, 5_000);
+        assert_eq!(config.price_band_bps, 0.0);
+        assert_eq!(config.max_auto_subscribed_pairs, 0);
+        assert_eq!(config.auto_subscribe_idle_secs, 600);
+        let tls = config.tls.unwrap();
+        assert_eq!(tls.cert, PathBuf::from("server.pem"));
+        assert!(tls.client_ca.is_none());
+        assert_eq!(config.api_keys.len(), 1);
+        assert_eq!(config.api_keys[0].name, "dashboard");
+        assert_eq!(config.api_keys[0].burst, None);
+        assert_eq!(config.api_keys[0].max_concurrent_streams, 2);
+        assert!(config.api_keys[0].admin);
+        assert_eq!(config.fees["binance"].taker_bps, 10.0);
+        assert_eq!(config.fees["binance"].maker_bps, 0.0);
+        assert_eq!(config.synthetic[0].quote_leg, "btcusd");
+        assert_eq!(config.quote_groups[0].currencies.len(), 3);
+        assert_eq!(config.quote_groups[0].rate_pairs["usdt"], "usdtusd");
+        assert_eq!(config.index.max_deviation_bps, 25.0);
+        assert_eq!(config.index.max_age_ms, 10_000);
+        assert_eq!(config.index.weights["bitstamp"], 0.5);
+        let recorder = config.recorder.unwrap();
+        assert_eq!(recorder.dir, PathBuf::from("recordings"));
+        assert_eq!(recorder.max_bytes, None);
+        assert_eq!(recorder.max_age_secs, Some(3600));
+        assert!(!recorder.compress);
+        let replay = config.replay.unwrap();
+        assert_eq!(replay.speed, Some(10.0));
+        assert!(!replay.as_fast_as_possible);
+        let history = config.history.unwrap();
+        assert_eq!(history.path, PathBuf::from("history.db"));
+        assert_eq!(history.interval_ms, 1_000);
+        assert_eq!(history.retention_hours, Some(24));
+        let export = config.export.unwrap();
+        assert_eq!(export.dir, PathBuf::from("snapshots"));
+        assert_eq!(export.format, "csv");
+        assert_eq!(export.top, 10);
+    }
+
+    #[test]
+    fn test_empty_config() {
+        let config: ServerConfig = toml::from_str("").unwrap();
+        assert_eq!(config.addr, "0.0.0.0:50051");
+        assert!(config.pairs.is_empty());
+        assert_eq!(config.stale_after_ms, 30_000);
+        assert_eq!(config.price_band_bps, 0.0);
+        assert!(config.tls.is_none());
+        assert!(config.api_keys.is_empty());
+        assert!(config.fees.is_empty());
+        assert!(config.index.weights.is_empty());
+        assert!(config.recorder.is_none());
+        assert!(config.replay.is_none());
+        assert!(config.history.is_none());
+        assert!(config.export.is_none());
+    }
+
+    #[test]
+    fn test_unknown_fee_exchange_is_rejected() {
+        let config: ServerConfig = toml::from_str("[fees.Bitstamp]\ntaker_bps = 40.0").unwrap();
+        assert!(config.validate().is_ok());
+
+        let config: ServerConfig = toml::from_str("[fees.bitstmap]\ntaker_bps = 40.0").unwrap();
+        assert!(config.validate().unwrap_err().contains("bitstmap"));
+    }
+
+    #[test]
+    fn test_invalid_api_keys_are_rejected() {
+        let api_key = |name: &str, key: &str, requests_per_second: f64| format!(
+            "[[api_keys]]\nname = \"{}\"\nkey = \"{}\"\nrequests_per_second = {:?}\nmax_concurrent_streams = 1\n",
+            name, key, requests_per_second,
+        );
+
+        let config: ServerConfig = toml::from_str(&(api_key("dashboard", "secret", 5.0) + &api_key("ops", "other", 1.0))).unwrap();
+        assert!(config.validate().is_ok());
+        assert!(!config.api_keys[1].admin);
+
+        let config: ServerConfig = toml::from_str(&(api_key("dashboard", "secret", 5.0) + &api_key("ops", "secret", 1.0))).unwrap();
+        assert!(config.validate().unwrap_err().contains("ops"));
+
+        let config: ServerConfig = toml::from_str(&api_key("dashboard", "secret", 0.0)).unwrap();
+        assert!(config.validate().unwrap_err().contains("requests_per_second"));
+    }
+}
@@ -0,0 +1,192 @@
+use exc_orderbook::consolidated_book::{ConsolidatedBook, PriceLevel};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// One level of a stored snapshot, with the size each exchange contributes to it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StoredLevel {
+    pub price: f64,
+    pub size: f64,
+    pub venues: Vec<(String, f64)>,
+}
+
+impl From<&PriceLevel> for StoredLevel {
+    fn from(level: &PriceLevel) -> Self {
+        StoredLevel {
+            price: level.price,
+            size: level.size,
+            venues: level.venues.iter().map(|venue| (venue.exchange.clone(), venue.size)).collect(),
+        }
+    }
+}
+
+/// The top of the consolidated book of a pair at `time`, in milliseconds since the Unix epoch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub pair: String,
+    pub time: u64,
+    pub mid: Option<f64>,
+    pub spread: Option<f64>,
+    pub bids: Vec<StoredLevel>,
+    pub asks: Vec<StoredLevel>,
+}
+
+impl Snapshot {
+    /// The top `top` levels per side of `book`.
+    pub fn of(book: &ConsolidatedBook, top: usize, time: u64) -> Self {
+        Snapshot {
+            pair: book.pair.clone(),
+            time,
+            mid: book.mid(),
+            spread: book.spread(),
+            bids: book.bids.iter().take(top).map(StoredLevel::from).collect(),
+            asks: book.asks.iter().take(top).map(StoredLevel::from).collect(),
+        }
+    }
+}
+
+/// Consolidated-book snapshots kept in a SQLite database, one row per pair and time.
+/// Levels are stored as JSON.
+pub struct SnapshotStore {
+    connection: Mutex<Connection>,
+}
+
+impl SnapshotStore {
+    pub fn open(path: impl AsRef<Path>) -> rusqlite::Result<Self> {
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> rusqlite::Result<Self> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(connection: Connection) -> rusqlite::Result<Self> {
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS snapshots (
+                pair TEXT NOT NULL,
+                time INTEGER NOT NULL,
+                mid REAL,
+                spread REAL,
+                bids TEXT NOT NULL,
+                asks TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS snapshots_pair_time ON snapshots (pair, time);
+            CREATE INDEX IF NOT EXISTS snapshots_time ON snapshots (time);",
+        )?;
+        Ok(SnapshotStore { connection: Mutex::new(connection) })
+    }
+
+    pub fn insert(&self, snapshot: &Snapshot) -> Result<(), Box<dyn Error>> {
+        let bids = serde_json::to_string(&snapshot.bids)?;
+        let asks = serde_json::to_string(&snapshot.asks)?;
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO snapshots (pair, time, mid, spread, bids, asks) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![snapshot.pair, sql_integer(snapshot.time), snapshot.mid, snapshot.spread, bids, asks],
+        )?;
+        Ok(())
+    }
+
+    /// The snapshots of `pair` taken from `from` to `to`, both inclusive, oldest first,
+    /// and no more than `limit` of them. With a `resolution_ms` only the first snapshot of
+    /// each interval of that length is returned.
+    pub fn query(&self, pair: &str, from: u64, to: u64, resolution_ms: u64, limit: usize) -> Result<Vec<Snapshot>, Box<dyn Error>> {
+        let connection = self.connection.lock().unwrap();
+        // SQLite takes the other columns of a MIN() group from the row holding the minimum.
+        let mut statement = connection.prepare(
+            "SELECT MIN(time), mid, spread, bids, asks FROM snapshots
+             WHERE pair = ?1 AND time >= ?2 AND time <= ?3
+             GROUP BY CASE WHEN ?4 > 0 THEN time / ?4 ELSE rowid END
+             ORDER BY 1 LIMIT ?5",
+        )?;
+        let bounds = params![pair.to_lowercase(), sql_integer(from), sql_integer(to), sql_integer(resolution_ms), sql_integer(limit as u64)];
+        let rows = statement.query_map(bounds, |row| {
+            Ok((row.get::<_, i64>(0)?, row.get(1)?, row.get(2)?, row.get::<_, String>(3)?, row.get::<_, String>(4)?))
+        })?;
+
+        let mut snapshots: Vec<Snapshot> = Vec::new();
+        for row in rows {
+            let (time, mid, spread, bids, asks) = row?;
+            snapshots.push(Snapshot {
+                pair: pair.to_lowercase(),
+                time: time as u64,
+                mid,
+                spread,
+                bids: serde_json::from_str(&bids)?,
+                asks: serde_json::from_str(&asks)?,
+            });
+        }
+        Ok(snapshots)
+    }
+
+    /// Deletes every snapshot taken before `before`. Returns how many there were.
+    pub fn prune(&self, before: u64) -> rusqlite::Result<usize> {
+        self.connection.lock().unwrap().execute("DELETE FROM snapshots WHERE time < ?1", params![sql_integer(before)])
+    }
+}
+
+/// `value` as a SQLite integer, which is signed; larger values are taken as `i64::MAX`.
+fn sql_integer(value: u64) -> i64 {
+    value.min(i64::MAX as u64) as i64
+}
+
+/* ------------
+    TEST CASES
+   ------------*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exc_orderbook::combine_orderbook::Order;
+
+    fn book(bid: f64, ask: f64) -> ConsolidatedBook {
+        let order = |exchange: &str, price| Order { exchange: exchange.to_string(), pair: "ethbtc".to_string(), price, size: 1.0 };
+        ConsolidatedBook::new(vec![
+            (vec![order("binance", bid), order("binance", bid - 0.001)], vec![order("binance", ask)]),
+            (vec![order("Bitstamp", bid)], vec![]),
+        ], "ethbtc")
+    }
+
+    #[test]
+    fn test_store_and_query() {
+        let store = SnapshotStore::open_in_memory().unwrap();
+        for (i, time) in [1_000, 1_400, 2_100, 2_900, 3_000].into_iter().enumerate() {
+            let bid = 0.060 + i as f64 * 0.001;
+            store.insert(&Snapshot::of(&book(bid, bid + 0.002), 1, time)).unwrap();
+        }
+
+        let snapshots = store.query("ETHBTC", 1_000, 2_900, 0, 100).unwrap();
+        assert_eq!(snapshots.iter().map(|s| s.time).collect::<Vec<_>>(), vec![1_000, 1_400, 2_100, 2_900]);
+        let first = &snapshots[0];
+        assert_eq!(first.pair, "ethbtc");
+        assert_eq!(first.bids, vec![StoredLevel { price: 0.060, size: 2.0, venues: vec![("binance".to_string(), 1.0), ("Bitstamp".to_string(), 1.0)] }]);
+        assert_eq!(first.asks.len(), 1);
+        assert!((first.spread.unwrap() - 0.002).abs() < 1e-12);
+
+        // The first snapshot of each second.
+        let snapshots = store.query("ethbtc", 0, u64::MAX, 1_000, 100).unwrap();
+        assert_eq!(snapshots.iter().map(|s| s.time).collect::<Vec<_>>(), vec![1_000, 2_100, 3_000]);
+        assert!((snapshots[1].mid.unwrap() - 0.063).abs() < 1e-12);
+        let snapshots = store.query("ethbtc", 0, u64::MAX, 0, 2).unwrap();
+        assert_eq!(snapshots.iter().map(|s| s.time).collect::<Vec<_>>(), vec![1_000, 1_400]);
+
+        assert!(store.query("btcusd", 0, 10_000, 0, 100).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_prune_and_empty_book() {
+        let store = SnapshotStore::open_in_memory().unwrap();
+        store.insert(&Snapshot::of(&ConsolidatedBook::default(), 5, 1_000)).unwrap();
+        store.insert(&Snapshot::of(&book(0.06, 0.07), 5, 2_000)).unwrap();
+
+        let snapshots = store.query("", 0, 5_000, 0, 100).unwrap();
+        assert_eq!(snapshots.len(), 1);
+        assert!(snapshots[0].mid.is_none());
+        assert!(snapshots[0].bids.is_empty());
+
+        assert_eq!(store.prune(1_500).unwrap(), 1);
+        assert_eq!(store.query("ethbtc", 0, 5_000, 0, 100).unwrap().len(), 1);
+    }
+}
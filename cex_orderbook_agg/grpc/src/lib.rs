@@ -1,3 +1,9 @@
+pub mod auth;
+pub mod config;
+pub mod history;
+pub mod subscriptions;
+pub mod tls;
+
 pub mod orderbook {
     tonic::include_proto!("orderbook"); // The generated Rust code from the proto file.
 }
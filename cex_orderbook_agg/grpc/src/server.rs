@@ -1,114 +1,1819 @@
 use std::cmp::min;
-use std::sync::Arc;
-use std::sync::Mutex;
-use tokio::time::{sleep, Duration};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::time::{sleep, Duration, Instant};
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::{transport::Server, Request, Response, Status};
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
 
 use crate::orderbook::{
-    order_book_server::{OrderBook, OrderBookServer},
-    GetTopOrdersRequest, GetTopOrdersResponse, Order,
+    admin_server::{Admin, AdminServer},
+    get_levels_to_fill_request, order_book_server::{OrderBook, OrderBookServer},
+    pair_top_orders, ArbitrageEvent, ArbitrageEventKind, BookFeatures, Candle, CandleInterval, GetCandlesRequest, GetCandlesResponse, GetOrderLevelsRequest, GetOrderLevelsResponse, Nbbo, OrderLevel, NbboSide, NbboVenue, StreamCandlesRequest, StreamNbboRequest, BookFeaturesSnapshot, BookSnapshot, ConnectionState, CurvePoint, Depth, GetDepthWithinBpsRequest, GetDepthWithinBpsResponse,
+    GetLevelsToFillRequest, GetLevelsToFillResponse, GetTopOrdersBatchRequest, GetTopOrdersBatchResponse,
+    GetHistoryRequest, GetHistoryResponse, GetTopOrdersRequest, GetTopOrdersResponse, GetVenueStatusRequest, LevelStats, OrderSide,
+    SimulateMarketOrderRequest, SimulateMarketOrderResponse, StreamArbitrageRequest, StreamTradesRequest, Trade,
+    GetVenueStatusResponse, ListSubscriptionsRequest, ListSubscriptionsResponse, Order,
+    ResyncVenueRequest, ResyncVenueResponse, SetVenueEnabledRequest, SetVenueEnabledResponse,
+    PairTopOrders, PriceLevel, ReferencePrice, Side, StreamBookFeaturesRequest, StreamReferencePriceRequest, VenueMid, Subscription, SubscriptionRequest, SubscriptionResponse, Venue, VenueLevel,
+    BboFeedStatus, TradeFeedStatus, VenueDepth, VenueStatus,
 };
-use exchanges::binance::get_binance_order_book;
-use exchanges::bitstamp::get_bitstamp_order_book;
+use exchanges::aggregator::{self, Aggregator, Exchange, VenueBook};
+use exchanges::bbo::{self, BestPrice};
+use exchanges::candle::{self, CandleBuilder, Interval};
+use exchanges::export::{self, Exporter};
+use exchanges::l3::L3Level;
+use exchanges::recorder::{Recorder, RecorderConfig};
+use exchanges::replay::{Replay, ReplayConfig, Speed};
+use exchanges::status::{self, Clock};
+use exchanges::trade::{self, TradeSide};
+use exchanges::validation::RejectReason;
+use grpc::auth::{ApiKeyAuth, StreamPermit, StreamQuota};
+use grpc::history::{self, Snapshot, SnapshotStore};
+use grpc::subscriptions::{validate_pair, AutoSubscriptions};
+use grpc::config::{ExportConfig, FeeConfig, QuoteGroupConfig, ServerConfig, SyntheticConfig};
+use grpc::tls::server_tls_config;
 use exc_orderbook::combine_orderbook::combine_order_books;
+use exc_orderbook::analytics;
+use exc_orderbook::arbitrage::{self, ArbitrageTracker, EventKind};
+use exc_orderbook::consolidated_book::{self, ConsolidatedBook};
+use exc_orderbook::depth::{self, Target};
+use exc_orderbook::fees::{FeeSchedule, Fees};
+use exc_orderbook::index::{self, IndexConfig};
+use exc_orderbook::quote_equivalence::{self, QuoteGroup};
+use exc_orderbook::sweep;
+use exc_orderbook::synthetic::{self, SyntheticPair};
 
 
 pub mod orderbook {
     tonic::include_proto!("orderbook"); // The string specified here must match the proto package name
+
+    pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("orderbook_descriptor");
+}
+
+// How long a request waits for the first books of pairs it had to subscribe to.
+static WARMUP: Duration = Duration::from_secs(5);
+
+// How often pairs subscribed on request are checked for having gone unused.
+static EXPIRY_INTERVAL: Duration = Duration::from_secs(10);
+
+// The most pairs one GetTopOrdersBatch request can ask for.
+static MAX_BATCH_PAIRS: usize = 50;
+
+// A venue's book counts as fresh for health checks if it was updated this recently.
+static FRESH_BOOK_AGE: Duration = Duration::from_secs(10);
+
+// How often the books are checked for having gone stale.
+static STALENESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Whether there is a pair to serve and each has at least one venue with a fresh book,
+/// and no top-of-book feed is down. Stale venues do not count, however recent their
+/// book. The pairs are the configured ones still subscribed, or every subscribed pair
+/// when none were configured.
+fn is_serving(aggregator: &Aggregator, pairs: &[String]) -> bool {
+    let subscribed = aggregator.subscriptions();
+    let live: Vec<&String> = if pairs.is_empty() {
+        subscribed.keys().collect()
+    } else {
+        pairs.iter().filter(|pair| subscribed.contains_key(&pair.to_lowercase())).collect()
+    };
+    let bbo_down = aggregator.statuses(None).iter()
+        .filter_map(|venue| venue.bbo.as_ref())
+        .any(|bbo| bbo.state == status::ConnectionState::Disconnected);
+    !live.is_empty() && !bbo_down && live.iter().all(|pair| !aggregator.pair_books(pair).fresh(FRESH_BOOK_AGE).is_empty())
+}
+
+/// Records which books went stale, and which came back, every `STALENESS_INTERVAL`.
+async fn check_staleness(aggregator: Arc<Aggregator>) {
+    loop {
+        aggregator.check_staleness();
+        sleep(STALENESS_INTERVAL).await;
+    }
+}
+
+/// Keeps the `grpc.health.v1.Health` status of the server in line with the freshness of the configured pairs.
+async fn report_health(aggregator: Arc<Aggregator>, pairs: Vec<String>, mut health_reporter: HealthReporter) {
+    loop {
+        let status = if is_serving(&aggregator, &pairs) {
+            ServingStatus::Serving
+        } else {
+            ServingStatus::NotServing
+        };
+
+        health_reporter.set_service_status("", status).await;
+        health_reporter.set_service_status(<OrderBookServer<MyServer> as tonic::server::NamedService>::NAME, status).await;
+
+        sleep(Duration::from_secs(1)).await;
+    }
+}
+
+// How often the books are checked for arbitrage.
+static ARBITRAGE_INTERVAL: Duration = Duration::from_millis(250);
+
+// How often periodic streams send snapshots when the client does not say, and the shortest interval they accept.
+static SNAPSHOT_INTERVAL: Duration = Duration::from_secs(1);
+static MIN_SNAPSHOT_INTERVAL: Duration = Duration::from_millis(50);
+
+// How many levels per side book features cover when the client does not say.
+static FEATURE_LEVELS: usize = 10;
+
+// The most snapshots one GetHistory response carries.
+static MAX_HISTORY_SNAPSHOTS: usize = 10_000;
+
+// How many candles GetCandles returns when the client does not say, and at most.
+static CANDLE_LIMIT: usize = 100;
+static MAX_CANDLE_LIMIT: usize = 1_000;
+
+// How often candles whose interval is over are closed when no trade closes them first.
+static CANDLE_TICK: Duration = Duration::from_millis(100);
+
+// How many events a slow stream can fall behind before it misses some.
+static EVENT_BUFFER: usize = 1024;
+
+// Bumped whenever fields are added to `GetTopOrdersResponse` or `Order`.
+static SCHEMA_VERSION: u32 = 6;
+
+// Venues name themselves differently in their orders ("binance", "Bitstamp"); clients get one spelling.
+fn exchange_name(name: &str) -> String {
+    name.parse::<Exchange>().map(|exchange| exchange.to_string()).unwrap_or_else(|_| name.to_string())
+}
+
+/// How the prices of one venue's book were brought into the quote currency of a request.
+#[derive(Debug, Clone)]
+struct Conversion {
+    exchange: String,
+    quote_currency: String,
+    rate: f64,
+}
+
+/// Venue books keyed by `{exchange}:{pair}`, how each was converted, and the exchanges
+/// left out for being stale.
+type EquivalentBooks = (Vec<(String, VenueBook)>, HashMap<String, Conversion>, Vec<String>);
+
+fn to_proto_order(order: exc_orderbook::combine_orderbook::Order, side: Side, book: &VenueBook, conversion: Option<&Conversion>) -> Order {
+    Order {
+        id: format!("{}-{}", order.exchange, order.pair),
+        price: order.price,
+        size: order.size,
+        exchange: exchange_name(&order.exchange),
+        side: side as i32,
+        event_time: book.event_time.unwrap_or_default(),
+        receive_time: book.received_at.unwrap_or_default(),
+        synthetic: synthetic::is_synthetic(&order.exchange),
+        quote_currency: conversion.map(|conversion| conversion.quote_currency.clone()).unwrap_or_default(),
+        conversion_rate: conversion.map(|conversion| conversion.rate).unwrap_or_default(),
+    }
+}
+
+fn to_proto_level(level: consolidated_book::PriceLevel, conversions: &HashMap<String, Conversion>) -> PriceLevel {
+    PriceLevel {
+        price: level.price,
+        size: level.size,
+        venues: level.venues.into_iter()
+            .map(|venue| match conversions.get(&venue.exchange) {
+                Some(conversion) => VenueLevel {
+                    exchange: conversion.exchange.clone(),
+                    size: venue.size,
+                    synthetic: false,
+                    quote_currency: conversion.quote_currency.clone(),
+                    conversion_rate: conversion.rate,
+                },
+                None => VenueLevel {
+                    synthetic: synthetic::is_synthetic(&venue.exchange),
+                    exchange: exchange_name(&venue.exchange),
+                    size: venue.size,
+                    ..Default::default()
+                },
+            })
+            .collect(),
+    }
+}
+
+fn book_side(side: i32) -> Option<consolidated_book::Side> {
+    match Side::from_i32(side)? {
+        Side::Bid => Some(consolidated_book::Side::Bid),
+        Side::Ask => Some(consolidated_book::Side::Ask),
+        Side::Unspecified => None,
+    }
+}
+
+fn order_side(side: i32) -> Option<sweep::OrderSide> {
+    match OrderSide::from_i32(side)? {
+        OrderSide::Buy => Some(sweep::OrderSide::Buy),
+        OrderSide::Sell => Some(sweep::OrderSide::Sell),
+        OrderSide::Unspecified => None,
+    }
+}
+
+fn to_proto_venue_depth(venue: depth::VenueDepth) -> VenueDepth {
+    VenueDepth { exchange: exchange_name(&venue.exchange), size: venue.size, notional: venue.notional }
+}
+
+fn to_proto_depth(depth: depth::Depth) -> Depth {
+    Depth {
+        size: depth.size,
+        notional: depth.notional,
+        levels: depth.levels as u32,
+        venues: depth.venues.into_iter().map(to_proto_venue_depth).collect(),
+    }
+}
+
+fn to_proto_state(state: status::ConnectionState) -> ConnectionState {
+    match state {
+        status::ConnectionState::Disconnected => ConnectionState::Disconnected,
+        status::ConnectionState::Connecting => ConnectionState::Connecting,
+        status::ConnectionState::Connected => ConnectionState::Connected,
+    }
+}
+
+fn to_proto_trade_status(trades: status::FeedStatus) -> TradeFeedStatus {
+    TradeFeedStatus {
+        state: to_proto_state(trades.state) as i32,
+        last_trade_time: trades.last_message_at.unwrap_or_default(),
+        trade_rate: trades.message_rate,
+        last_trade_id: trades.last_sequence.unwrap_or_default(),
+        resync_count: trades.resync_count,
+        reconnect_count: trades.reconnect_count,
+        last_error: trades.last_error.unwrap_or_default(),
+        rejections: to_proto_rejections(&trades.rejections),
+    }
+}
+
+fn to_proto_bbo_status(bbo: status::FeedStatus) -> BboFeedStatus {
+    BboFeedStatus {
+        state: to_proto_state(bbo.state) as i32,
+        last_quote_time: bbo.last_message_at.unwrap_or_default(),
+        quote_rate: bbo.message_rate,
+        last_update_id: bbo.last_sequence.unwrap_or_default(),
+        reconnect_count: bbo.reconnect_count,
+        last_error: bbo.last_error.unwrap_or_default(),
+        rejections: to_proto_rejections(&bbo.rejections),
+    }
+}
+
+fn to_proto_rejections(rejections: &BTreeMap<RejectReason, u64>) -> HashMap<String, u64> {
+    rejections.iter().map(|(reason, count)| (reason.to_string(), *count)).collect()
+}
+
+fn to_proto_status(venue: aggregator::VenueStatus) -> VenueStatus {
+    VenueStatus {
+        exchange: venue.exchange.to_string(),
+        pair: venue.pair,
+        enabled: venue.enabled,
+        state: to_proto_state(venue.status.state) as i32,
+        last_message_time: venue.status.last_message_at.unwrap_or_default(),
+        message_rate: venue.status.message_rate,
+        last_sequence: venue.status.last_sequence.unwrap_or_default(),
+        resync_count: venue.status.resync_count,
+        reconnect_count: venue.status.reconnect_count,
+        last_error: venue.status.last_error.unwrap_or_default(),
+        stale: venue.status.stale_since.is_some(),
+        stale_since: venue.status.stale_since.unwrap_or_default(),
+        stale_count: venue.status.stale_count,
+        rejections: to_proto_rejections(&venue.status.rejections),
+        trades: venue.trades.map(to_proto_trade_status),
+        quote_rejections: to_proto_rejections(&venue.quote_rejections),
+        bbo: venue.bbo.map(to_proto_bbo_status),
+    }
+}
+
+fn synthetic_pair(config: &SyntheticConfig) -> SyntheticPair {
+    SyntheticPair {
+        pair: config.pair.to_lowercase(),
+        base_leg: config.base_leg.to_lowercase(),
+        quote_leg: config.quote_leg.to_lowercase(),
+    }
+}
+
+fn quote_group(config: &QuoteGroupConfig) -> QuoteGroup {
+    let currencies: Vec<&str> = config.currencies.iter().map(String::as_str).collect();
+    config.rate_pairs.iter()
+        .fold(QuoteGroup::new(&currencies), |group, (currency, pair)| group.with_rate_pair(currency, pair))
+}
+
+fn fee_schedule<'a>(fees: impl IntoIterator<Item = (&'a String, &'a FeeConfig)>) -> FeeSchedule {
+    let mut schedule = FeeSchedule::new();
+    for (exchange, config) in fees {
+        schedule.set(exchange, Fees { maker_bps: config.maker_bps, taker_bps: config.taker_bps });
+    }
+    schedule
+}
+
+fn index_config(config: &grpc::config::IndexConfig) -> IndexConfig {
+    IndexConfig {
+        weights: config.weights.iter().map(|(exchange, weight)| (exchange.to_lowercase(), *weight)).collect(),
+        max_deviation_bps: config.max_deviation_bps,
+        max_age: Duration::from_millis(config.max_age_ms),
+    }
+}
+
+fn to_proto_reference_price(price: index::ReferencePrice, time: u64) -> ReferencePrice {
+    ReferencePrice {
+        pair: price.pair,
+        mid: price.mid,
+        microprice: price.microprice,
+        weighted_median: price.weighted_median,
+        mean: price.mean,
+        venues: price.venues.into_iter()
+            .map(|venue| VenueMid { exchange: venue.exchange, mid: venue.mid, weight: venue.weight, outlier: venue.outlier })
+            .collect(),
+        stale: price.stale,
+        time,
+    }
+}
+
+fn to_proto_curve(curve: Vec<analytics::CurvePoint>) -> Vec<CurvePoint> {
+    curve.into_iter()
+        .map(|point| CurvePoint { price: point.price, distance_bps: point.distance_bps, size: point.size, notional: point.notional })
+        .collect()
+}
+
+fn to_proto_level_stats(stats: analytics::LevelStats) -> LevelStats {
+    LevelStats {
+        levels: stats.levels as u32,
+        total_size: stats.total_size,
+        mean_size: stats.mean_size,
+        max_size: stats.max_size,
+        mean_venues: stats.mean_venues,
+    }
+}
+
+fn to_proto_features(exchange: String, features: analytics::BookFeatures) -> BookFeatures {
+    BookFeatures {
+        exchange,
+        mid: features.mid.unwrap_or_default(),
+        spread: features.spread.unwrap_or_default(),
+        microprice: features.microprice.unwrap_or_default(),
+        weighted_mid: features.weighted_mid.unwrap_or_default(),
+        imbalance: features.imbalance.unwrap_or_default(),
+        bid_slope: features.bid_slope.unwrap_or_default(),
+        ask_slope: features.ask_slope.unwrap_or_default(),
+        bid_curve: to_proto_curve(features.bid_curve),
+        ask_curve: to_proto_curve(features.ask_curve),
+        bid_stats: Some(to_proto_level_stats(features.bid_stats)),
+        ask_stats: Some(to_proto_level_stats(features.ask_stats)),
+    }
+}
+
+/// Features of the consolidated book of `pair` and of each enabled venue's book, or
+/// `None` while no venue has a book.
+fn book_features(aggregator: &Aggregator, pair: &str, top: usize, now: u64) -> Option<BookFeaturesSnapshot> {
+    let books = aggregator.order_books(pair);
+    if books.values().all(|book| book.bids.is_empty() && book.asks.is_empty()) {
+        return None;
+    }
+
+    let venues = books.iter()
+        .map(|(exchange, book)| {
+            let venue_book = ConsolidatedBook::new(vec![(book.bids.clone(), book.asks.clone())], pair);
+            to_proto_features(exchange.to_string(), venue_book.features(top))
+        })
+        .collect();
+    let consolidated = ConsolidatedBook::new(books.into_values().map(|book| (book.bids, book.asks)), pair);
+
+    Some(BookFeaturesSnapshot {
+        pair: pair.to_string(),
+        time: now,
+        consolidated: Some(to_proto_features(String::new(), consolidated.features(top))),
+        venues,
+    })
+}
+
+fn to_proto_arbitrage(event: arbitrage::ArbitrageEvent) -> ArbitrageEvent {
+    let kind = match event.kind {
+        EventKind::Opened => ArbitrageEventKind::Opened,
+        EventKind::Updated => ArbitrageEventKind::Updated,
+        EventKind::Closed => ArbitrageEventKind::Closed,
+    };
+    let opportunity = event.opportunity;
+
+    ArbitrageEvent {
+        kind: kind as i32,
+        pair: opportunity.pair,
+        buy_exchange: exchange_name(&opportunity.buy_exchange),
+        sell_exchange: exchange_name(&opportunity.sell_exchange),
+        buy_price: opportunity.buy_price,
+        sell_price: opportunity.sell_price,
+        size: opportunity.size,
+        gross_profit: opportunity.gross_profit,
+        net_profit: opportunity.net_profit,
+        opened_at: event.opened_at,
+        duration_ms: event.duration_ms,
+    }
+}
+
+/// Looks for crossed books across the fresh venues of every subscribed pair.
+fn check_arbitrage(aggregator: &Aggregator, fees: &FeeSchedule, tracker: &mut ArbitrageTracker, now: u64) -> Vec<ArbitrageEvent> {
+    let mut events = Vec::new();
+    for pair in aggregator.subscriptions().keys() {
+        let snapshot = aggregator.pair_books(pair);
+        let fresh = snapshot.fresh(FRESH_BOOK_AGE);
+        let books = snapshot.books.into_iter()
+            .filter(|(exchange, _)| fresh.contains(exchange))
+            .map(|(_, book)| (book.bids, book.asks));
+        let opportunities = arbitrage::find_opportunities(&ConsolidatedBook::new(books, pair), fees);
+
+        events.extend(tracker.update(pair, opportunities, now).into_iter().map(to_proto_arbitrage));
+    }
+    events
+}
+
+/// The CSV file arbitrage events are appended to, kept open while the detector runs.
+struct ArbitrageLog {
+    file: tokio::io::BufWriter<tokio::fs::File>,
+}
+
+impl ArbitrageLog {
+    /// Opens `path` for appending, writing the header first if it is new or empty.
+    async fn open(path: &Path) -> std::io::Result<Self> {
+        let file = tokio::fs::OpenOptions::new().create(true).append(true).open(path).await?;
+        let is_empty = file.metadata().await?.len() == 0;
+        let mut log = ArbitrageLog { file: tokio::io::BufWriter::new(file) };
+        if is_empty {
+            log.file.write_all(b"time,kind,pair,buy_exchange,sell_exchange,buy_price,sell_price,size,gross_profit,net_profit,opened_at,duration_ms\n").await?;
+        }
+        Ok(log)
+    }
+
+    /// Buffers one row for `event`, seen at `now`; `flush` writes the buffered rows out.
+    async fn write(&mut self, event: &ArbitrageEvent, now: u64) -> std::io::Result<()> {
+        self.file.write_all(arbitrage_row(event, now).as_bytes()).await
+    }
+
+    async fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush().await
+    }
+}
+
+fn arbitrage_row(event: &ArbitrageEvent, now: u64) -> String {
+    format!(
+        "{},{},{},{},{},{},{},{},{},{},{},{}\n",
+        now,
+        event.kind().as_str_name(),
+        event.pair,
+        event.buy_exchange,
+        event.sell_exchange,
+        event.buy_price,
+        event.sell_price,
+        event.size,
+        event.gross_profit,
+        event.net_profit,
+        event.opened_at,
+        event.duration_ms,
+    )
+}
+
+fn to_proto_stored_level(level: history::StoredLevel) -> PriceLevel {
+    PriceLevel {
+        price: level.price,
+        size: level.size,
+        venues: level.venues.into_iter()
+            .map(|(exchange, size)| VenueLevel {
+                synthetic: synthetic::is_synthetic(&exchange),
+                exchange: exchange_name(&exchange),
+                size,
+                ..Default::default()
+            })
+            .collect(),
+    }
+}
+
+fn to_proto_snapshot(snapshot: history::Snapshot) -> BookSnapshot {
+    BookSnapshot {
+        pair: snapshot.pair,
+        time: snapshot.time,
+        mid: snapshot.mid.unwrap_or_default(),
+        spread: snapshot.spread.unwrap_or_default(),
+        bids: snapshot.bids.into_iter().map(to_proto_stored_level).collect(),
+        asks: snapshot.asks.into_iter().map(to_proto_stored_level).collect(),
+    }
+}
+
+fn to_proto_trade(trade: trade::Trade) -> Trade {
+    let aggressor = match trade.aggressor {
+        TradeSide::Buy => OrderSide::Buy,
+        TradeSide::Sell => OrderSide::Sell,
+    };
+    Trade {
+        exchange: trade.exchange.to_string(),
+        pair: trade.pair,
+        price: trade.price,
+        size: trade.size,
+        aggressor: aggressor as i32,
+        trade_id: trade.trade_id,
+        trade_time: trade.trade_time.unwrap_or_default(),
+        received_at: trade.received_at,
+    }
+}
+
+fn candle_interval(interval: i32) -> Option<Interval> {
+    match CandleInterval::from_i32(interval)? {
+        CandleInterval::Unspecified => None,
+        CandleInterval::OneSecond => Some(Interval::OneSecond),
+        CandleInterval::OneMinute => Some(Interval::OneMinute),
+        CandleInterval::FiveMinutes => Some(Interval::FiveMinutes),
+        CandleInterval::OneHour => Some(Interval::OneHour),
+    }
+}
+
+fn to_proto_candle(candle: candle::Candle) -> Candle {
+    let interval = match candle.interval {
+        Interval::OneSecond => CandleInterval::OneSecond,
+        Interval::OneMinute => CandleInterval::OneMinute,
+        Interval::FiveMinutes => CandleInterval::FiveMinutes,
+        Interval::OneHour => CandleInterval::OneHour,
+    };
+    Candle {
+        exchange: candle.exchange.map(|exchange| exchange.to_string()).unwrap_or_default(),
+        pair: candle.pair,
+        interval: interval as i32,
+        open_time: candle.open_time,
+        close_time: candle.close_time,
+        open: candle.open,
+        high: candle.high,
+        low: candle.low,
+        close: candle.close,
+        volume: candle.volume,
+        quote_volume: candle.quote_volume,
+        vwap: candle.vwap,
+        trade_count: candle.trades,
+        closed: candle.closed,
+    }
+}
+
+/// The exchange a candle request names, `None` for candles across every exchange.
+fn candle_exchange(exchange: &str) -> Result<Option<Exchange>, String> {
+    if exchange.is_empty() {
+        return Ok(None);
+    }
+    exchange.parse().map(Some)
+}
+
+/// Unsubscribes every venue from the pairs subscribed on request once they go unused.
+async fn expire_subscriptions(aggregator: Arc<Aggregator>, subscriptions: AutoSubscriptions) {
+    loop {
+        sleep(EXPIRY_INTERVAL.min(subscriptions.idle_ttl())).await;
+        for pair in subscriptions.expire(std::time::Instant::now()) {
+            for exchange in Exchange::ALL {
+                aggregator.unsubscribe(exchange, &pair);
+            }
+            aggregator.unsubscribe_l3(&pair);
+            println!("Unsubscribed {} after it went unused", pair);
+        }
+    }
+}
+
+/// Builds candles from the trade tape, publishing every candle a trade updates and
+/// every candle that closes by `clock` to `events`.
+async fn build_candles(mut trades: broadcast::Receiver<trade::Trade>, builder: Arc<Mutex<CandleBuilder>>, events: broadcast::Sender<candle::Candle>, clock: Clock) {
+    let mut tick = tokio::time::interval(CANDLE_TICK);
+    loop {
+        let candles = tokio::select! {
+            trade = trades.recv() => match trade {
+                Ok(trade) => builder.lock().unwrap().add(&trade),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    println!("Candles missed {} trades", missed);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            _ = tick.tick() => builder.lock().unwrap().close_elapsed(clock.now()),
+        };
+        for candle in candles {
+            let _ = events.send(candle);
+        }
+    }
+}
+
+fn to_proto_nbbo(nbbo: bbo::Nbbo) -> Nbbo {
+    let side = |best: Option<BestPrice>| best.map(|best| NbboSide {
+        price: best.price,
+        size: best.size,
+        venues: best.venues.into_iter().map(|(exchange, size)| NbboVenue { exchange: exchange.to_string(), size }).collect(),
+    });
+    Nbbo {
+        pair: nbbo.pair,
+        bid: side(nbbo.bid),
+        ask: side(nbbo.ask),
+        updated_at: nbbo.updated_at.unwrap_or_default(),
+    }
 }
 
-#[derive(Default)]
-pub struct MyServer {}
+fn to_proto_order_level(level: L3Level) -> OrderLevel {
+    OrderLevel {
+        price: level.price,
+        size: level.size,
+        order_count: level.orders as u32,
+        largest_order: level.largest_order,
+    }
+}
+
+/// Stores a snapshot of every subscribed pair's book every `interval`, and deletes those
+/// older than `retention`.
+async fn record_history(aggregator: Arc<Aggregator>, store: Arc<SnapshotStore>, interval: Duration, top: usize, retention: Option<Duration>) {
+    loop {
+        let now = aggregator.now();
+        let snapshots: Vec<Snapshot> = aggregator.subscriptions().into_keys()
+            .map(|pair| {
+                let order_books = aggregator.order_books(&pair);
+                ConsolidatedBook::new(order_books.into_values().map(|book| (book.bids, book.asks)), &pair)
+            })
+            .filter(|book| !book.bids.is_empty() || !book.asks.is_empty())
+            .map(|book| Snapshot::of(&book, top, now))
+            .collect();
+
+        // SQLite blocks, so it is kept off the async workers.
+        let store = Arc::clone(&store);
+        let written = tokio::task::spawn_blocking(move || {
+            for snapshot in &snapshots {
+                if let Err(e) = store.insert(snapshot) {
+                    println!("Could not store a snapshot of {}: {}", snapshot.pair, e);
+                }
+            }
+            if let Some(retention) = retention {
+                if let Err(e) = store.prune(now.saturating_sub(retention.as_millis() as u64)) {
+                    println!("Could not delete old snapshots: {}", e);
+                }
+            }
+        });
+        if let Err(e) = written.await {
+            println!("Could not store snapshots: {}", e);
+        }
+
+        sleep(interval).await;
+    }
+}
+
+/// Hands `exporter` the book of every enabled venue of every subscribed pair, and their
+/// consolidated book, as they stand at `now`.
+fn export_books(aggregator: &Aggregator, exporter: &mut Exporter, now: u64) -> Result<(), Box<dyn std::error::Error>> {
+    for pair in aggregator.subscriptions().into_keys() {
+        let venues: Vec<export::BookSnapshot> = aggregator.order_books(&pair).iter()
+            .map(|(exchange, book)| export::BookSnapshot::of_venue(exchange.name(), &pair, book, now))
+            .collect();
+        for venue in &venues {
+            exporter.write(venue)?;
+        }
+        exporter.write(&export::BookSnapshot::consolidated(&pair, &venues, now))?;
+    }
+    Ok(())
+}
+
+/// Exports the books every `interval`, writing out the partitions that are done as it
+/// goes, until `stop` is set. Everything still buffered is written out then.
+async fn export_snapshots(aggregator: Arc<Aggregator>, mut exporter: Exporter, interval: Duration, mut stop: watch::Receiver<bool>) {
+    while !*stop.borrow() {
+        let now = aggregator.now();
+        if let Err(e) = export_books(&aggregator, &mut exporter, now).and_then(|_| exporter.flush_due(now)) {
+            println!("Could not export snapshots: {}", e);
+        }
+        tokio::select! {
+            _ = sleep(interval) => {}
+            _ = stop.changed() => {}
+        }
+    }
+    if let Err(e) = exporter.flush() {
+        println!("Could not write out the last snapshots: {}", e);
+    }
+}
+
+fn exporter(config: &ExportConfig) -> Result<Exporter, String> {
+    Ok(Exporter::new(export::ExportConfig {
+        dir: config.dir.clone(),
+        format: config.format.parse()?,
+        top: config.top,
+        rows_per_file: config.rows_per_file.max(1),
+        max_age: Some(Duration::from_secs(config.max_age_secs)),
+    }))
+}
+
+/// Watches the books for arbitrage, logging every event and publishing it to `events`.
+async fn detect_arbitrage(aggregator: Arc<Aggregator>, fees: FeeSchedule, events: broadcast::Sender<ArbitrageEvent>, log_path: Option<PathBuf>) {
+    let mut tracker = ArbitrageTracker::new();
+    let mut log = None;
+    if let Some(path) = &log_path {
+        match ArbitrageLog::open(path).await {
+            Ok(opened) => log = Some(opened),
+            Err(e) => println!("Could not open the arbitrage log {}: {}", path.display(), e),
+        }
+    }
+    loop {
+        let now = aggregator.now();
+        let found = check_arbitrage(&aggregator, &fees, &mut tracker, now);
+        let logged = !found.is_empty();
+        for event in found {
+            println!(
+                "Arbitrage {} {}: buy {} @ {} sell {} @ {} size {} gross {} net {} after {}ms",
+                event.kind().as_str_name(), event.pair, event.buy_exchange, event.buy_price,
+                event.sell_exchange, event.sell_price, event.size, event.gross_profit, event.net_profit, event.duration_ms,
+            );
+            if let Some(log) = &mut log {
+                if let Err(e) = log.write(&event, now).await {
+                    println!("Could not log arbitrage event: {}", e);
+                }
+            }
+            // Nobody may be listening; that is fine.
+            let _ = events.send(event);
+        }
+        if let Some(log) = log.as_mut().filter(|_| logged) {
+            if let Err(e) = log.flush().await {
+                println!("Could not log arbitrage events: {}", e);
+            }
+        }
+
+        sleep(ARBITRAGE_INTERVAL).await;
+    }
+}
+
+/// A slot of the client's concurrent-stream quota for a streaming call, or `None` when no
+/// API keys are configured.
+fn stream_permit<T>(request: &mut Request<T>) -> Result<Option<StreamPermit>, String> {
+    request.extensions_mut().remove::<StreamQuota>().map(|quota| quota.acquire()).transpose()
+}
+
+/// Sends what `snapshot` makes of each of `pairs`, or of every subscribed pair when empty,
+/// every `interval` until the client goes away. Pairs it has nothing for yet are skipped.
+/// `guard`, such as the stream's permit and pair lease, is held until then.
+fn stream_snapshots<T, F, G>(aggregator: Arc<Aggregator>, pairs: Vec<String>, interval: Duration, guard: G, snapshot: F) -> ReceiverStream<Result<T, Status>>
+where
+    T: Send + 'static,
+    F: Fn(&Aggregator, &str, u64) -> Option<T> + Send + 'static,
+    G: Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel(EVENT_BUFFER);
+    tokio::spawn(async move {
+        let _guard = guard;
+        loop {
+            let pairs = if pairs.is_empty() { aggregator.subscriptions().into_keys().collect() } else { pairs.clone() };
+            let now = aggregator.now();
+            for pair in pairs {
+                if let Some(snapshot) = snapshot(&aggregator, &pair, now) {
+                    if sender.send(Ok(snapshot)).await.is_err() {
+                        return;
+                    }
+                }
+            }
+            sleep(interval).await;
+        }
+    });
+    ReceiverStream::new(receiver)
+}
+
+/// The interval a client asked for, in milliseconds, within what the server accepts.
+fn snapshot_interval(interval_ms: u32) -> Duration {
+    match interval_ms {
+        0 => SNAPSHOT_INTERVAL,
+        ms => Duration::from_millis(ms.into()).max(MIN_SNAPSHOT_INTERVAL),
+    }
+}
+
+/// Forwards the events of `events` that pass `filter` to a gRPC stream. `guard`, such as
+/// the stream's permit and pair lease, is held until the client goes away.
+fn forward_events<T, U, F, G>(mut events: broadcast::Receiver<T>, mut filter_map: F, guard: G) -> ReceiverStream<Result<U, Status>>
+where
+    T: Clone + Send + 'static,
+    U: Send + 'static,
+    F: FnMut(T) -> Option<U> + Send + 'static,
+    G: Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel(EVENT_BUFFER);
+    tokio::spawn(async move {
+        let _guard = guard;
+        loop {
+            // Stops as soon as the client goes away, rather than at the next event it wants.
+            let event = tokio::select! {
+                event = events.recv() => event,
+                _ = sender.closed() => break,
+            };
+            match event {
+                Ok(event) => {
+                    let Some(event) = filter_map(event) else { continue };
+                    if sender.send(Ok(event)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    println!("A stream fell behind and missed {} events", missed);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+    ReceiverStream::new(receiver)
+}
+
+/// The top-of-book feeds of the pairs of one `StreamNbbo` stream, let go once it closes.
+struct BboLease {
+    aggregator: Arc<Aggregator>,
+    pairs: Vec<String>,
+}
+
+impl BboLease {
+    fn new(aggregator: Arc<Aggregator>, pairs: &[String]) -> Self {
+        for pair in pairs {
+            for exchange in Exchange::ALL {
+                aggregator.subscribe_bbo(exchange, pair);
+            }
+        }
+        BboLease { aggregator, pairs: pairs.to_vec() }
+    }
+}
+
+impl Drop for BboLease {
+    fn drop(&mut self) {
+        for pair in &self.pairs {
+            for exchange in Exchange::ALL {
+                self.aggregator.unsubscribe_bbo(exchange, pair);
+            }
+        }
+    }
+}
+
+/// Why the pairs a request needs could not be subscribed.
+#[derive(Debug)]
+enum SubscribeError {
+    InvalidPair(String),
+    TooManyPairs(String),
+}
+
+impl From<SubscribeError> for Status {
+    fn from(error: SubscribeError) -> Self {
+        match error {
+            SubscribeError::InvalidPair(message) => Status::invalid_argument(message),
+            SubscribeError::TooManyPairs(message) => Status::resource_exhausted(message),
+        }
+    }
+}
+
+pub struct MyServer {
+    aggregator: Arc<Aggregator>,
+    auto_subscriptions: AutoSubscriptions,
+    fees: FeeSchedule,
+    synthetics: Vec<SyntheticPair>,
+    quote_groups: Vec<QuoteGroup>,
+    index: IndexConfig,
+    history: Option<Arc<SnapshotStore>>,
+    arbitrage: broadcast::Sender<ArbitrageEvent>,
+    candles: Arc<Mutex<CandleBuilder>>,
+    candle_events: broadcast::Sender<candle::Candle>,
+}
+
+impl Default for MyServer {
+    fn default() -> Self {
+        MyServer::new(Arc::default())
+    }
+}
+
+impl MyServer {
+    pub fn new(aggregator: Arc<Aggregator>) -> Self {
+        let (arbitrage, _) = broadcast::channel(EVENT_BUFFER);
+        let (candle_events, _) = broadcast::channel(EVENT_BUFFER);
+        Self {
+            aggregator,
+            auto_subscriptions: AutoSubscriptions::default(),
+            fees: FeeSchedule::new(),
+            synthetics: Vec::new(),
+            quote_groups: Vec::new(),
+            index: IndexConfig::default(),
+            history: None,
+            arbitrage,
+            candles: Arc::default(),
+            candle_events,
+        }
+    }
+
+    /// How many pairs requests can have subscribed, and how long they stay unused before
+    /// they are let go.
+    pub fn with_auto_subscriptions(mut self, auto_subscriptions: AutoSubscriptions) -> Self {
+        self.auto_subscriptions = auto_subscriptions;
+        self
+    }
+
+    /// Unsubscribes the pairs requests subscribed once they go unused, for as long as the
+    /// server runs.
+    pub fn expire_subscriptions(&self) -> impl std::future::Future<Output = ()> {
+        expire_subscriptions(Arc::clone(&self.aggregator), self.auto_subscriptions.clone())
+    }
+
+    /// Pairs whose books can be implied from two other pairs on request.
+    pub fn with_synthetics(mut self, synthetics: Vec<SyntheticPair>) -> Self {
+        self.synthetics = synthetics;
+        self
+    }
+
+    /// Quote currencies whose books can be merged on request, e.g. USD, USDT and USDC.
+    pub fn with_quote_groups(mut self, quote_groups: Vec<QuoteGroup>) -> Self {
+        self.quote_groups = quote_groups;
+        self
+    }
+
+    /// Venue weights, outlier band and staleness limit of reference prices.
+    pub fn with_index(mut self, index: IndexConfig) -> Self {
+        self.index = index;
+        self
+    }
+
+    /// Answers `GetHistory` from the snapshots in `store`.
+    pub fn with_history(mut self, store: Arc<SnapshotStore>) -> Self {
+        self.history = Some(store);
+        self
+    }
+
+    /// The channel `StreamArbitrage` clients listen on; the arbitrage detector publishes to it.
+    pub fn arbitrage_events(&self) -> broadcast::Sender<ArbitrageEvent> {
+        self.arbitrage.clone()
+    }
+
+    /// Builds the candles `StreamCandles` and `GetCandles` serve from the trades on the
+    /// aggregator's tape from now on, until the tape closes.
+    pub fn build_candles(&self) -> impl std::future::Future<Output = ()> {
+        build_candles(self.aggregator.trade_tape().subscribe(), Arc::clone(&self.candles), self.candle_events.clone(), self.aggregator.clock())
+    }
+
+    /// Prices routing and sweeps with these fees instead of treating every venue as free.
+    pub fn with_fees(mut self, fees: FeeSchedule) -> Self {
+        self.fees = fees;
+        self
+    }
+
+    /// Subscribes every venue to the `pairs` nobody subscribed to yet, and waits up to
+    /// `WARMUP` for their first books. They are kept live until they go unused.
+    async fn ensure_subscribed(&self, pairs: &[impl AsRef<str>]) -> Result<(), SubscribeError> {
+        for pair in pairs {
+            validate_pair(pair.as_ref()).map_err(SubscribeError::InvalidPair)?;
+        }
+        self.auto_subscriptions.touch(pairs);
+
+        let mut new_pairs: Vec<&str> = pairs.iter()
+            .map(AsRef::as_ref)
+            .filter(|pair| !self.aggregator.is_subscribed(pair))
+            .collect();
+        new_pairs.sort();
+        new_pairs.dedup();
+        if new_pairs.is_empty() {
+            return Ok(());
+        }
+        self.auto_subscriptions.admit(&new_pairs).map_err(SubscribeError::TooManyPairs)?;
+
+        for pair in &new_pairs {
+            for exchange in Exchange::ALL {
+                self.aggregator.subscribe(exchange, pair);
+            }
+        }
+
+        let enabled_venues = Exchange::ALL.iter()
+            .filter(|exchange| self.aggregator.is_venue_enabled(**exchange))
+            .count();
+        let deadline = Instant::now() + WARMUP;
+        while Instant::now() < deadline {
+            let warmed_up = new_pairs.iter().all(|pair| {
+                let books = self.aggregator.order_books(pair);
+                books.values().filter(|book| !book.bids.is_empty() || !book.asks.is_empty()).count() >= enabled_venues
+            });
+            if warmed_up {
+                break;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        Ok(())
+    }
+
+    /// The merged book of `pair` over every enabled venue.
+    fn consolidated_book(&self, pair: &str) -> ConsolidatedBook {
+        let order_books = self.aggregator.order_books(pair);
+        ConsolidatedBook::new(order_books.into_values().map(|book| (book.bids, book.asks)), pair)
+    }
+
+    /// The legs the configured synthetic pairs implying `pair` are built from.
+    fn synthetic_legs(&self, pair: &str) -> Vec<&str> {
+        let pair = pair.to_lowercase();
+        self.synthetics.iter()
+            .filter(|synthetic| synthetic.pair == pair)
+            .flat_map(|synthetic| [synthetic.base_leg.as_str(), synthetic.quote_leg.as_str()])
+            .collect()
+    }
+
+    /// The books the configured synthetic pairs imply for `pair`, by their exchange name.
+    fn synthetic_books(&self, pair: &str) -> Vec<(String, VenueBook)> {
+        let pair = pair.to_lowercase();
+        self.synthetics.iter()
+            .filter(|synthetic| synthetic.pair == pair)
+            .map(|synthetic| {
+                let base_leg = self.consolidated_book(&synthetic.base_leg);
+                let quote_leg = self.consolidated_book(&synthetic.quote_leg);
+                let (bids, asks) = synthetic.implied_book(&base_leg, &quote_leg);
+                (synthetic.exchange(), VenueBook { bids, asks, ..Default::default() })
+            })
+            .collect()
+    }
+
+    /// The quote-equivalence group `pair` is quoted in, if any.
+    fn quote_group(&self, pair: &str) -> Option<&QuoteGroup> {
+        let pair = pair.to_lowercase();
+        self.quote_groups.iter().find(|group| group.split_pair(&pair).is_some())
+    }
+
+    /// The books of `pair` and of the same base quoted in the other currencies of `group`,
+    /// converted into the quote currency of `pair`. They are keyed by `{exchange}:{pair}`,
+    /// so levels keep the venues of different pairs apart. Exchanges stale on any of the
+    /// pairs are listed too.
+    fn equivalent_books(&self, group: &QuoteGroup, pair: &str) -> EquivalentBooks {
+        let pair = pair.to_lowercase();
+        let target = group.split_pair(&pair).map(|(_, quote)| quote).unwrap_or_default();
+        let mid = |rate_pair: &str| self.consolidated_book(rate_pair).mid();
+
+        let mut books = Vec::new();
+        let mut conversions = HashMap::new();
+        let mut stale = Vec::new();
+        for source in group.equivalent_pairs(&pair) {
+            let Some((_, quote)) = group.split_pair(&source) else {
+                continue;
+            };
+            // Left out until its live rate is known.
+            let Some(rate) = group.rate(quote, target, mid) else {
+                continue;
+            };
+
+            let snapshot = self.aggregator.pair_books(&source);
+            stale.extend(snapshot.stale.iter().map(Exchange::to_string));
+            for (exchange, book) in snapshot.books {
+                let venue = format!("{}:{}", exchange, source);
+                conversions.insert(venue.clone(), Conversion { exchange: exchange.to_string(), quote_currency: quote.to_string(), rate });
+                books.push((venue, VenueBook {
+                    bids: quote_equivalence::convert(book.bids, rate),
+                    asks: quote_equivalence::convert(book.asks, rate),
+                    ..book
+                }));
+            }
+        }
+        stale.sort();
+        stale.dedup();
+        (books, conversions, stale)
+    }
+
+    /// Every pair `req` needs books of, once each.
+    fn request_pairs(&self, req: &GetTopOrdersRequest) -> Vec<String> {
+        let mut pairs = vec![req.pair.clone()];
+        if req.include_synthetic {
+            pairs.extend(self.synthetic_legs(&req.pair).into_iter().map(str::to_string));
+        }
+        if let Some(group) = self.quote_group(&req.pair).filter(|_| req.quote_equivalent) {
+            pairs.extend(group.equivalent_pairs(&req.pair));
+            pairs.extend(group.live_rate_pairs().into_iter().map(str::to_string));
+        }
+        pairs.sort();
+        pairs.dedup();
+        pairs
+    }
+
+    /// The consolidated top bids and asks asked for by `req`, from the books held right now.
+    /// Books implied by synthetic pairs and of quote-equivalent pairs are merged in on request.
+    fn top_orders(&self, req: &GetTopOrdersRequest) -> GetTopOrdersResponse {
+        let pair = req.pair.as_str();
+        let top = req.top as usize;
+
+        let (mut order_books, conversions, stale_venues) = match self.quote_group(pair).filter(|_| req.quote_equivalent) {
+            Some(group) => self.equivalent_books(group, pair),
+            None => {
+                let snapshot = self.aggregator.pair_books(pair);
+                let books = snapshot.books.into_iter()
+                    .map(|(exchange, book)| (exchange.to_string(), book))
+                    .collect();
+                (books, HashMap::new(), snapshot.stale.iter().map(Exchange::to_string).collect())
+            }
+        };
+        if req.include_synthetic {
+            order_books.extend(self.synthetic_books(pair));
+        }
+
+        let mut venues: Vec<String> = Vec::new();
+        for (venue, book) in &order_books {
+            let exchange = conversions.get(venue).map_or(venue, |conversion| &conversion.exchange);
+            if (!book.bids.is_empty() || !book.asks.is_empty()) && !venues.contains(exchange) {
+                venues.push(exchange.clone());
+            }
+        }
+
+        let mut asks: Vec<Order> = Vec::new();
+        let mut bids: Vec<Order> = Vec::new();
+        for (venue, book) in &order_books {
+            let conversion = conversions.get(venue);
+            let combined_asks = combine_order_books(vec![(book.asks.clone(), Vec::new())], pair);
+            let combined_bids = combine_order_books(vec![(book.bids.clone(), Vec::new())], pair);
+            asks.extend(combined_asks.into_iter().map(|order| to_proto_order(order, Side::Ask, book, conversion)));
+            bids.extend(combined_bids.into_iter().map(|order| to_proto_order(order, Side::Bid, book, conversion)));
+        }
+
+        asks.sort_by(|a, b| a.price.partial_cmp(&b.price).unwrap());
+        bids.sort_by(|a, b| b.price.partial_cmp(&a.price).unwrap());
+        
+        let top_asks = asks[..min(top, asks.len())].to_vec();
+        let top_bids = bids[..min(top, bids.len())].to_vec();
+        
+        let spread = match (asks.first(), bids.first()) {
+            (Some(best_ask), Some(best_bid)) => best_ask.price - best_bid.price,
+            _ => 0.0,
+        };
+
+        // Converted books merge under their `{exchange}:{pair}` key, and pay the fees of their exchange.
+        let mut fees = self.fees.clone();
+        for (venue, conversion) in &conversions {
+            fees.set(venue, self.fees.fees(&conversion.exchange));
+        }
+        let rename = |orders: Vec<exc_orderbook::combine_orderbook::Order>, venue: &String| -> Vec<_> {
+            orders.into_iter()
+                .map(|order| exc_orderbook::combine_orderbook::Order { exchange: venue.clone(), ..order })
+                .collect()
+        };
+        let books = order_books.into_iter().map(|(venue, book)| {
+            if conversions.contains_key(&venue) {
+                (rename(book.bids, &venue), rename(book.asks, &venue))
+            } else {
+                (book.bids, book.asks)
+            }
+        });
+
+        let consolidated = ConsolidatedBook::new(books, pair);
+        let effective = consolidated.fee_adjusted(&fees);
+        let effective_spread = effective.spread().unwrap_or_default();
+        let to_levels = |levels: Vec<consolidated_book::PriceLevel>| -> Vec<PriceLevel> {
+            levels.into_iter().take(top).map(|level| to_proto_level(level, &conversions)).collect()
+        };
+
+        GetTopOrdersResponse {
+            asks: top_asks,
+            bids: top_bids,
+            spread,
+            venues,
+            bid_levels: to_levels(consolidated.bids),
+            ask_levels: to_levels(consolidated.asks),
+            schema_version: SCHEMA_VERSION,
+            effective_spread,
+            effective_bid_levels: to_levels(effective.bids),
+            effective_ask_levels: to_levels(effective.asks),
+            stale_venues,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl OrderBook for MyServer {
+    async fn get_top_orders(
+        &self,
+        request: Request<GetTopOrdersRequest>,
+    ) -> Result<Response<GetTopOrdersResponse>, Status> {
+        let req = top_orders_request(request.into_inner()).map_err(Status::invalid_argument)?;
+
+        self.ensure_subscribed(&self.request_pairs(&req)).await?;
+
+        Ok(Response::new(self.top_orders(&req)))
+    }
+
+    async fn get_top_orders_batch(
+        &self,
+        request: Request<GetTopOrdersBatchRequest>,
+    ) -> Result<Response<GetTopOrdersBatchResponse>, Status> {
+        let requests = request.into_inner().pairs;
+        if requests.len() > MAX_BATCH_PAIRS {
+            return Err(Status::invalid_argument(format!("a batch can ask for at most {} pairs, got {}", MAX_BATCH_PAIRS, requests.len())));
+        }
+
+        let checked: Vec<Result<GetTopOrdersRequest, String>> = requests.iter().cloned().map(top_orders_request).collect();
+        // New pairs warm up together, so the batch waits at most once.
+        let pairs: Vec<String> = checked.iter().flatten().flat_map(|req| self.request_pairs(req)).collect();
+        self.ensure_subscribed(&pairs).await?;
+
+        let results = requests.iter().zip(checked)
+            .map(|(asked, checked)| {
+                let result = match checked {
+                    Err(error) => pair_top_orders::Result::Error(error),
+                    Ok(req) => {
+                        let orders = self.top_orders(&req);
+                        if orders.venues.is_empty() {
+                            pair_top_orders::Result::Error(format!("no venue has a book for {}", req.pair))
+                        } else {
+                            pair_top_orders::Result::Orders(orders)
+                        }
+                    }
+                };
+                PairTopOrders { pair: asked.pair.clone(), result: Some(result) }
+            })
+            .collect();
+
+        Ok(Response::new(GetTopOrdersBatchResponse { results }))
+    }
+
+    async fn get_depth_within_bps(
+        &self,
+        request: Request<GetDepthWithinBpsRequest>,
+    ) -> Result<Response<GetDepthWithinBpsResponse>, Status> {
+        let req = request.into_inner();
+        let side = book_side(req.side).ok_or_else(|| Status::invalid_argument("side must be BID or ASK"))?;
+        if req.bps.is_nan() || req.bps < 0.0 {
+            return Err(Status::invalid_argument(format!("bps must not be negative, got {}", req.bps)));
+        }
+
+        self.ensure_subscribed(&[req.pair.as_str()]).await?;
+
+        let book = self.consolidated_book(&req.pair);
+        let depth = book.depth_within_bps(side, req.bps)
+            .ok_or_else(|| Status::unavailable(format!("no two-sided book for {}", req.pair)))?;
+
+        Ok(Response::new(GetDepthWithinBpsResponse {
+            depth: Some(to_proto_depth(depth)),
+            mid: book.mid().unwrap_or_default(),
+        }))
+    }
+
+    async fn get_levels_to_fill(
+        &self,
+        request: Request<GetLevelsToFillRequest>,
+    ) -> Result<Response<GetLevelsToFillResponse>, Status> {
+        let req = request.into_inner();
+        let side = book_side(req.side).ok_or_else(|| Status::invalid_argument("side must be BID or ASK"))?;
+        let target = match req.target {
+            Some(get_levels_to_fill_request::Target::Quantity(quantity)) => Target::Quantity(quantity),
+            Some(get_levels_to_fill_request::Target::Notional(notional)) => Target::Notional(notional),
+            None => return Err(Status::invalid_argument("a quantity or notional target is required")),
+        };
+        let (Target::Quantity(amount) | Target::Notional(amount)) = target;
+        if !amount.is_finite() || amount <= 0.0 {
+            return Err(Status::invalid_argument(format!("the target must be a positive number, got {}", amount)));
+        }
+
+        self.ensure_subscribed(&[req.pair.as_str()]).await?;
+
+        let fill = self.consolidated_book(&req.pair).fee_adjusted(&self.fees).levels_to_fill(side, target);
+
+        Ok(Response::new(GetLevelsToFillResponse {
+            depth: Some(to_proto_depth(fill.depth)),
+            worst_price: fill.worst_price.unwrap_or_default(),
+            reached: fill.reached,
+        }))
+    }
+
+    async fn simulate_market_order(
+        &self,
+        request: Request<SimulateMarketOrderRequest>,
+    ) -> Result<Response<SimulateMarketOrderResponse>, Status> {
+        let req = request.into_inner();
+        let side = order_side(req.side).ok_or_else(|| Status::invalid_argument("side must be BUY or SELL"))?;
+        if !req.quantity.is_finite() || req.quantity <= 0.0 {
+            return Err(Status::invalid_argument(format!("quantity must be a positive number, got {}", req.quantity)));
+        }
+
+        self.ensure_subscribed(&[req.pair.as_str()]).await?;
+
+        let simulation = self.consolidated_book(&req.pair).simulate_market_order_with_fees(side, req.quantity, &self.fees);
+
+        Ok(Response::new(SimulateMarketOrderResponse {
+            fills: simulation.fills.into_iter().map(to_proto_venue_depth).collect(),
+            filled: simulation.filled,
+            notional: simulation.notional,
+            unfilled: simulation.unfilled,
+            vwap: simulation.vwap.unwrap_or_default(),
+            worst_price: simulation.worst_price.unwrap_or_default(),
+            mid: simulation.mid.unwrap_or_default(),
+            slippage_bps: simulation.slippage_bps.unwrap_or_default(),
+        }))
+    }
+
+    type StreamArbitrageStream = ReceiverStream<Result<ArbitrageEvent, Status>>;
+
+    async fn stream_arbitrage(
+        &self,
+        mut request: Request<StreamArbitrageRequest>,
+    ) -> Result<Response<Self::StreamArbitrageStream>, Status> {
+        let permit = stream_permit(&mut request).map_err(Status::resource_exhausted)?;
+        let pairs: Vec<String> = request.into_inner().pairs.iter().map(|pair| pair.to_lowercase()).collect();
+
+        let stream = forward_events(
+            self.arbitrage.subscribe(),
+            move |event: ArbitrageEvent| (pairs.is_empty() || pairs.contains(&event.pair)).then_some(event),
+            permit,
+        );
+        Ok(Response::new(stream))
+    }
+
+    type StreamReferencePriceStream = ReceiverStream<Result<ReferencePrice, Status>>;
+
+    async fn stream_reference_price(
+        &self,
+        mut request: Request<StreamReferencePriceRequest>,
+    ) -> Result<Response<Self::StreamReferencePriceStream>, Status> {
+        let permit = stream_permit(&mut request).map_err(Status::resource_exhausted)?;
+        let req = request.into_inner();
+        let pairs: Vec<String> = req.pairs.iter().map(|pair| pair.to_lowercase()).collect();
+        self.ensure_subscribed(&pairs).await?;
+        let lease = self.auto_subscriptions.lease(&pairs);
+
+        let config = self.index.clone();
+        let stream = stream_snapshots(Arc::clone(&self.aggregator), pairs, snapshot_interval(req.interval_ms), (permit, lease), move |aggregator, pair, now| {
+            aggregator.reference_price(pair, &config).map(|price| to_proto_reference_price(price, now))
+        });
+        Ok(Response::new(stream))
+    }
+
+    type StreamBookFeaturesStream = ReceiverStream<Result<BookFeaturesSnapshot, Status>>;
+
+    async fn stream_book_features(
+        &self,
+        mut request: Request<StreamBookFeaturesRequest>,
+    ) -> Result<Response<Self::StreamBookFeaturesStream>, Status> {
+        let permit = stream_permit(&mut request).map_err(Status::resource_exhausted)?;
+        let req = request.into_inner();
+        let pairs: Vec<String> = req.pairs.iter().map(|pair| pair.to_lowercase()).collect();
+        let top = match req.top {
+            0 => FEATURE_LEVELS,
+            top => top as usize,
+        };
+        self.ensure_subscribed(&pairs).await?;
+        let lease = self.auto_subscriptions.lease(&pairs);
+
+        let stream = stream_snapshots(Arc::clone(&self.aggregator), pairs, snapshot_interval(req.interval_ms), (permit, lease), move |aggregator, pair, now| {
+            book_features(aggregator, pair, top, now)
+        });
+        Ok(Response::new(stream))
+    }
+
+    async fn get_venue_status(
+        &self,
+        request: Request<GetVenueStatusRequest>,
+    ) -> Result<Response<GetVenueStatusResponse>, Status> {
+        let pair = request.into_inner().pair;
+        let pair = (!pair.is_empty()).then_some(pair.as_str());
+
+        let statuses = self.aggregator.statuses(pair).into_iter()
+            .map(to_proto_status)
+            .collect();
+
+        Ok(Response::new(GetVenueStatusResponse { statuses }))
+    }
+
+    async fn get_history(
+        &self,
+        request: Request<GetHistoryRequest>,
+    ) -> Result<Response<GetHistoryResponse>, Status> {
+        let req = request.into_inner();
+        let store = self.history.as_ref()
+            .ok_or_else(|| Status::failed_precondition("History is not enabled on this server"))?;
+        if req.pair.is_empty() {
+            return Err(Status::invalid_argument("pair is required"));
+        }
+        let to = if req.to == 0 { self.aggregator.now() } else { req.to };
+        if req.from > to {
+            return Err(Status::invalid_argument("from is after to"));
+        }
+
+        // One more than can be returned, to tell whether there are too many.
+        let store = Arc::clone(store);
+        let query = move || store.query(&req.pair, req.from, to, req.resolution_ms, MAX_HISTORY_SNAPSHOTS + 1).map_err(|e| e.to_string());
+        let snapshots = tokio::task::spawn_blocking(query).await
+            .map_err(|e| e.to_string())
+            .and_then(|result| result)
+            .map_err(|e| Status::internal(format!("Could not read history: {}", e)))?;
+        if snapshots.len() > MAX_HISTORY_SNAPSHOTS {
+            return Err(Status::invalid_argument(format!(
+                "More than {} snapshots match; narrow the range or use a coarser resolution",
+                MAX_HISTORY_SNAPSHOTS,
+            )));
+        }
+
+        Ok(Response::new(GetHistoryResponse { snapshots: snapshots.into_iter().map(to_proto_snapshot).collect() }))
+    }
+
+    type StreamTradesStream = ReceiverStream<Result<Trade, Status>>;
+
+    async fn stream_trades(
+        &self,
+        mut request: Request<StreamTradesRequest>,
+    ) -> Result<Response<Self::StreamTradesStream>, Status> {
+        let permit = stream_permit(&mut request).map_err(Status::resource_exhausted)?;
+        let req = request.into_inner();
+        let pairs: Vec<String> = req.pairs.iter().map(|pair| pair.to_lowercase()).collect();
+        let exchanges = req.exchanges.iter()
+            .map(|exchange| exchange.parse::<Exchange>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(Status::invalid_argument)?;
+        self.ensure_subscribed(&pairs).await?;
+        let lease = self.auto_subscriptions.lease(&pairs);
+
+        let stream = forward_events(
+            self.aggregator.trade_tape().subscribe(),
+            move |trade: trade::Trade| {
+                let wanted = (pairs.is_empty() || pairs.contains(&trade.pair))
+                    && (exchanges.is_empty() || exchanges.contains(&trade.exchange));
+                wanted.then(|| to_proto_trade(trade))
+            },
+            (permit, lease),
+        );
+        Ok(Response::new(stream))
+    }
+
+    type StreamCandlesStream = ReceiverStream<Result<Candle, Status>>;
+
+    async fn stream_candles(
+        &self,
+        mut request: Request<StreamCandlesRequest>,
+    ) -> Result<Response<Self::StreamCandlesStream>, Status> {
+        let permit = stream_permit(&mut request).map_err(Status::resource_exhausted)?;
+        let req = request.into_inner();
+        let interval = candle_interval(req.interval).ok_or_else(|| Status::invalid_argument("A candle interval is required"))?;
+        let exchange = candle_exchange(&req.exchange).map_err(Status::invalid_argument)?;
+        let pairs: Vec<String> = req.pairs.iter().map(|pair| pair.to_lowercase()).collect();
+        self.ensure_subscribed(&pairs).await?;
+        let lease = self.auto_subscriptions.lease(&pairs);
+
+        let closed_only = req.closed_only;
+        let stream = forward_events(
+            self.candle_events.subscribe(),
+            move |candle: candle::Candle| {
+                let wanted = candle.interval == interval
+                    && candle.exchange == exchange
+                    && (pairs.is_empty() || pairs.contains(&candle.pair))
+                    && (candle.closed || !closed_only);
+                wanted.then(|| to_proto_candle(candle))
+            },
+            (permit, lease),
+        );
+        Ok(Response::new(stream))
+    }
+
+    async fn get_candles(
+        &self,
+        request: Request<GetCandlesRequest>,
+    ) -> Result<Response<GetCandlesResponse>, Status> {
+        let req = request.into_inner();
+        let interval = candle_interval(req.interval).ok_or_else(|| Status::invalid_argument("A candle interval is required"))?;
+        let exchange = candle_exchange(&req.exchange).map_err(Status::invalid_argument)?;
+        let limit = match req.limit {
+            0 => CANDLE_LIMIT,
+            limit => (limit as usize).min(MAX_CANDLE_LIMIT),
+        };
+
+        let candles = self.candles.lock().unwrap().history(exchange, &req.pair, interval, limit);
+        Ok(Response::new(GetCandlesResponse { candles: candles.into_iter().map(to_proto_candle).collect() }))
+    }
+
+    type StreamNbboStream = ReceiverStream<Result<Nbbo, Status>>;
 
-#[tonic::async_trait]
-impl OrderBook for MyServer {
-    async fn get_top_orders(
+    async fn stream_nbbo(
         &self,
-        request: Request<GetTopOrdersRequest>,
-    ) -> Result<Response<GetTopOrdersResponse>, Status> {
+        mut request: Request<StreamNbboRequest>,
+    ) -> Result<Response<Self::StreamNbboStream>, Status> {
+        let permit = stream_permit(&mut request).map_err(Status::resource_exhausted)?;
+        let mut pairs: Vec<String> = request.into_inner().pairs.iter().map(|pair| pair.to_lowercase()).collect();
+        if pairs.is_empty() {
+            return Err(Status::invalid_argument("At least one pair is required"));
+        }
+        pairs.sort();
+        pairs.dedup();
+        // Bitstamp quotes come from its depth feeds, so the pairs need those too.
+        self.ensure_subscribed(&pairs).await?;
+        let lease = (self.auto_subscriptions.lease(&pairs), BboLease::new(Arc::clone(&self.aggregator), &pairs));
+
+        let aggregator = Arc::clone(&self.aggregator);
+        let mut last_sent: HashMap<String, bbo::Nbbo> = HashMap::new();
+        let stream = forward_events(
+            self.aggregator.bbo_book().subscribe(),
+            move |quote: bbo::Quote| {
+                if !pairs.contains(&quote.pair) {
+                    return None;
+                }
+                let nbbo = aggregator.nbbo(&quote.pair);
+                if last_sent.get(&quote.pair).is_some_and(|last| last.same_prices(&nbbo)) {
+                    return None;
+                }
+                last_sent.insert(quote.pair, nbbo.clone());
+                Some(to_proto_nbbo(nbbo))
+            },
+            (permit, lease),
+        );
+        Ok(Response::new(stream))
+    }
+
+    async fn get_order_levels(
+        &self,
+        request: Request<GetOrderLevelsRequest>,
+    ) -> Result<Response<GetOrderLevelsResponse>, Status> {
         let req = request.into_inner();
-        let top = req.top as usize;
-        let pair = req.pair;
-
-        let binance_order_book = Arc::new(Mutex::new((Vec::new(), Vec::new())));
-        let bitstamp_order_book = Arc::new(Mutex::new((Vec::new(), Vec::new())));
-      
-        let binance_handle = {
-            let binance_order_book = Arc::clone(&binance_order_book);
-            let pair = pair.clone();
-            tokio::spawn(async move {
-                get_binance_order_book(binance_order_book, &pair).await.unwrap();
-            })
+        let pair = req.pair.to_lowercase();
+        if pair.is_empty() {
+            return Err(Status::invalid_argument("A pair is required"));
+        }
+        // The order feed goes with the pair's other feeds once they expire.
+        self.ensure_subscribed(&[&pair]).await?;
+        self.aggregator.subscribe_l3(&pair);
+        let Some(book) = self.aggregator.l3_book(&pair) else {
+            return Err(Status::unavailable("Order-by-order books are not replayed"));
         };
-        
-        let bitstamp_handle = {
-            let bitstamp_order_book = Arc::clone(&bitstamp_order_book);
-            let pair = pair.clone();
-            tokio::spawn(async move {
-                get_bitstamp_order_book(bitstamp_order_book, &pair).await.unwrap();
-            })
+
+        let book = book.lock().unwrap();
+        let top = match req.top {
+            0 => usize::MAX,
+            top => top as usize,
         };
+        let levels = |side| book.levels(side).into_iter().take(top).map(to_proto_order_level).collect();
+        Ok(Response::new(GetOrderLevelsResponse {
+            exchange: book.exchange.clone(),
+            bids: levels(consolidated_book::Side::Bid),
+            asks: levels(consolidated_book::Side::Ask),
+            order_count: book.len() as u32,
+            last_event_time: book.last_event_time.unwrap_or_default(),
+            connected_at: book.connected_at.unwrap_or_default(),
+            skipped_events: book.skipped_events,
+        }))
+    }
+}
+
+/// The pair of an Admin request for an order-by-order feed, which only Bitstamp has.
+/// `req` with its pair lowercased, if its pair is valid and `top` is not negative.
+fn top_orders_request(mut req: GetTopOrdersRequest) -> Result<GetTopOrdersRequest, String> {
+    req.pair = req.pair.to_lowercase();
+    validate_pair(&req.pair)?;
+    if req.top < 0 {
+        return Err(format!("top must not be negative, got {}", req.top));
+    }
+    Ok(req)
+}
 
-        sleep(Duration::from_secs(5)).await;
+/// The exchange and lowercased pair an Admin subscription request is for, if both are
+/// valid. Only Bitstamp has order-by-order books.
+fn subscription_target(req: &SubscriptionRequest) -> Result<(Exchange, String), String> {
+    let exchange: Exchange = req.exchange.parse()?;
+    let pair = req.pair.to_lowercase();
+    validate_pair(&pair)?;
+    if req.order_levels && exchange != Exchange::Bitstamp {
+        return Err(format!("{} has no order-by-order feed", exchange));
+    }
+    Ok((exchange, pair))
+}
 
-        binance_handle.abort();
-        bitstamp_handle.abort();
+#[derive(Default)]
+pub struct MyAdmin {
+    aggregator: Arc<Aggregator>,
+    auto_subscriptions: AutoSubscriptions,
+}
 
-        let (binance_bids, binance_asks) = binance_order_book.lock().unwrap().clone();
-        let (bitstamp_bids, bitstamp_asks) = bitstamp_order_book.lock().unwrap().clone();
+impl MyAdmin {
+    pub fn new(aggregator: Arc<Aggregator>) -> Self {
+        Self { aggregator, auto_subscriptions: AutoSubscriptions::default() }
+    }
 
-        let combined_asks = combine_order_books(vec![(binance_asks, bitstamp_asks)], &pair);
-        let combined_bids = combine_order_books(vec![(binance_bids, bitstamp_bids)], &pair);
+    /// The pairs requests subscribed; subscribing one here keeps it from expiring.
+    pub fn with_auto_subscriptions(mut self, auto_subscriptions: AutoSubscriptions) -> Self {
+        self.auto_subscriptions = auto_subscriptions;
+        self
+    }
+}
 
-        let mut asks = combined_asks.into_iter()
-            .map(|order| Order {
-                id: format!("{}-{}", order.exchange, order.pair),
-                price: order.price,
-                size: order.size,
+#[tonic::async_trait]
+impl Admin for MyAdmin {
+    async fn list_subscriptions(
+        &self,
+        _request: Request<ListSubscriptionsRequest>,
+    ) -> Result<Response<ListSubscriptionsResponse>, Status> {
+        let subscriptions = self.aggregator.subscriptions().into_iter()
+            .map(|(pair, exchanges)| Subscription {
+                pair,
+                exchanges: exchanges.iter().map(|exchange| exchange.to_string()).collect(),
             })
-            .collect::<Vec<Order>>();
-        
-        let mut bids = combined_bids.into_iter()
-            .map(|order| Order {
-                id: format!("{}-{}", order.exchange, order.pair),
-                price: order.price,
-                size: order.size,
+            .collect();
+
+        let venues = Exchange::ALL.iter()
+            .map(|exchange| Venue {
+                exchange: exchange.to_string(),
+                enabled: self.aggregator.is_venue_enabled(*exchange),
             })
-            .collect::<Vec<Order>>();
-        
-        asks.sort_by(|a, b| a.price.partial_cmp(&b.price).unwrap());
-        bids.sort_by(|a, b| b.price.partial_cmp(&a.price).unwrap());
-        
-        let top_asks = asks[..min(top, asks.len())].to_vec();
-        let top_bids = bids[..min(top, bids.len())].to_vec();
-        
-        let spread = match (asks.first(), bids.first()) {
-            (Some(best_ask), Some(best_bid)) => best_ask.price - best_bid.price,
-            _ => 0.0,
+            .collect();
+
+        Ok(Response::new(ListSubscriptionsResponse { subscriptions, venues, order_level_pairs: self.aggregator.l3_pairs() }))
+    }
+
+    async fn subscribe(
+        &self,
+        request: Request<SubscriptionRequest>,
+    ) -> Result<Response<SubscriptionResponse>, Status> {
+        let req = request.into_inner();
+        let (exchange, pair) = subscription_target(&req).map_err(Status::invalid_argument)?;
+
+        let changed = if req.order_levels {
+            self.aggregator.subscribe_l3(&pair)
+        } else {
+            self.aggregator.subscribe(exchange, &pair)
         };
-            
-        let reply = GetTopOrdersResponse { asks: top_asks, bids: top_bids, spread };
-        Ok(Response::new(reply))
-    
-        
+        self.auto_subscriptions.forget(&pair);
+        Ok(Response::new(SubscriptionResponse { changed }))
+    }
+
+    async fn unsubscribe(
+        &self,
+        request: Request<SubscriptionRequest>,
+    ) -> Result<Response<SubscriptionResponse>, Status> {
+        let req = request.into_inner();
+        let (exchange, pair) = subscription_target(&req).map_err(Status::invalid_argument)?;
+
+        let changed = if req.order_levels {
+            self.aggregator.unsubscribe_l3(&pair)
+        } else {
+            self.aggregator.unsubscribe(exchange, &pair)
+        };
+        Ok(Response::new(SubscriptionResponse { changed }))
+    }
+
+    async fn resync_venue(
+        &self,
+        request: Request<ResyncVenueRequest>,
+    ) -> Result<Response<ResyncVenueResponse>, Status> {
+        let exchange: Exchange = request.into_inner().exchange.parse().map_err(Status::invalid_argument)?;
+
+        let restarted = self.aggregator.resync(exchange) as u32;
+        Ok(Response::new(ResyncVenueResponse { restarted }))
+    }
+
+    async fn set_venue_enabled(
+        &self,
+        request: Request<SetVenueEnabledRequest>,
+    ) -> Result<Response<SetVenueEnabledResponse>, Status> {
+        let req = request.into_inner();
+        let exchange: Exchange = req.exchange.parse().map_err(Status::invalid_argument)?;
+
+        self.aggregator.set_venue_enabled(exchange, req.enabled);
+        Ok(Response::new(SetVenueEnabledResponse {}))
     }
 }
 
 #[tokio::main]
 pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let addr = "0.0.0.0:50051".parse()?;
+    // Usage: server [--config <path>] [pair...]
+    let mut config = ServerConfig::default();
+    let mut pairs: Vec<String> = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => config = ServerConfig::load(args.next().ok_or("--config needs a path")?)?,
+            _ => pairs.push(arg),
+        }
+    }
+    // Pairs from the config and the command line are subscribed on every venue at startup,
+    // along with the legs of synthetic pairs and the pairs quote-equivalence rates come from.
+    pairs.extend(config.pairs.iter().cloned());
+    let synthetics: Vec<SyntheticPair> = config.synthetic.iter().map(synthetic_pair).collect();
+    let quote_groups: Vec<QuoteGroup> = config.quote_groups.iter().map(quote_group).collect();
+
+    let addr = config.addr.parse()?;
     println!("Server is running on -> {:?}", addr);
 
-    let order_book_server = OrderBookServer::new(MyServer::default());
+    let mut aggregator = Aggregator::new();
+    // Kept to complete the recordings on shutdown; clones share the same files.
+    let mut recorder = None;
+    if config.stale_after_ms > 0 {
+        aggregator = aggregator.with_stale_after(Duration::from_millis(config.stale_after_ms));
+    }
+    if config.price_band_bps > 0.0 {
+        aggregator = aggregator.with_price_band(config.price_band_bps).with_index(index_config(&config.index));
+    }
+    if let Some(recording) = &config.recorder {
+        println!("Recording feeds to {}", recording.dir.display());
+        let created = Recorder::new(RecorderConfig {
+            dir: recording.dir.clone(),
+            max_bytes: recording.max_bytes,
+            max_age: recording.max_age_secs.map(Duration::from_secs),
+            compress: recording.compress,
+        });
+        recorder = Some(created.clone());
+        aggregator = aggregator.with_recorder(created);
+    }
+    if let Some(replay) = &config.replay {
+        let speed = match (replay.as_fast_as_possible, replay.speed) {
+            (true, _) => Speed::AsFastAsPossible,
+            (false, Some(speed)) => Speed::Accelerated(speed),
+            (false, None) => Speed::Realtime,
+        };
+        let replay = Replay::open(ReplayConfig { dir: replay.dir.clone(), speed, start: None })?;
+        println!("Replaying {} recorded feeds instead of connecting to the exchanges", replay.feeds().len());
+        // Every recorded pair is served, on top of the configured ones.
+        pairs.extend(replay.feeds().into_iter().map(|(_, pair)| pair));
+        pairs.sort();
+        pairs.dedup();
+        aggregator = aggregator.with_replay(replay);
+    }
+    let aggregator = Arc::new(aggregator);
+    let legs = synthetics.iter().flat_map(|synthetic| [synthetic.base_leg.as_str(), synthetic.quote_leg.as_str()]);
+    let rate_pairs = quote_groups.iter().flat_map(QuoteGroup::live_rate_pairs);
+    for pair in pairs.iter().map(String::as_str).chain(legs).chain(rate_pairs) {
+        for exchange in Exchange::ALL {
+            aggregator.subscribe(exchange, pair);
+        }
+    }
+    aggregator.start_replay();
+
+    // Health reports NOT_SERVING until each configured pair has a fresh book on at least one venue.
+    let (mut health_reporter, health_server) = tonic_health::server::health_reporter();
+    health_reporter.set_service_status("", ServingStatus::NotServing).await;
+    health_reporter.set_not_serving::<OrderBookServer<MyServer>>().await;
+    tokio::spawn(check_staleness(Arc::clone(&aggregator)));
+    tokio::spawn(report_health(Arc::clone(&aggregator), pairs, health_reporter));
+
+    let reflection_server = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(orderbook::FILE_DESCRIPTOR_SET)
+        .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
+        .build()?;
+
+    // Health and reflection stay open to load balancers; the API itself needs a key once any is configured.
+    let auth = ApiKeyAuth::new(&config.api_keys);
+    if auth.is_enabled() {
+        println!("API key authentication enabled for {} clients", config.api_keys.len());
+    }
+
+    let fees = fee_schedule(&config.fees);
+    let auto_subscriptions = AutoSubscriptions::new(config.max_auto_subscribed_pairs, Duration::from_secs(config.auto_subscribe_idle_secs));
+    let server = MyServer::new(Arc::clone(&aggregator))
+        .with_auto_subscriptions(auto_subscriptions.clone())
+        .with_fees(fees.clone())
+        .with_synthetics(synthetics)
+        .with_quote_groups(quote_groups)
+        .with_index(index_config(&config.index));
+    tokio::spawn(server.build_candles());
+    tokio::spawn(server.expire_subscriptions());
+    tokio::spawn(detect_arbitrage(Arc::clone(&aggregator), fees, server.arbitrage_events(), config.arbitrage_log.clone()));
+    let server = match &config.history {
+        Some(history) => {
+            let store = Arc::new(SnapshotStore::open(&history.path)?);
+            println!("Storing snapshots every {}ms in {}", history.interval_ms, history.path.display());
+            tokio::spawn(record_history(
+                Arc::clone(&aggregator),
+                Arc::clone(&store),
+                Duration::from_millis(history.interval_ms.max(1)),
+                history.top,
+                history.retention_hours.map(|hours| Duration::from_secs(hours * 3600)),
+            ));
+            server.with_history(store)
+        }
+        None => server,
+    };
+    let (stop_export, stop) = watch::channel(false);
+    let export = match &config.export {
+        Some(export) => {
+            println!("Exporting snapshots every {}ms to {} as {}", export.interval_ms, export.dir.display(), export.format);
+            Some(tokio::spawn(export_snapshots(Arc::clone(&aggregator), exporter(export)?, Duration::from_millis(export.interval_ms.max(1)), stop)))
+        }
+        None => None,
+    };
+    let order_book_server = OrderBookServer::with_interceptor(server, auth.clone());
+    let admin_server = AdminServer::with_interceptor(MyAdmin::new(aggregator).with_auto_subscriptions(auto_subscriptions), auth.admin_only());
 
-    Server::builder()
+    let mut builder = Server::builder();
+    if let Some(tls) = &config.tls {
+        builder = builder.tls_config(server_tls_config(tls)?)?;
+        println!("TLS enabled{}", if tls.client_ca.is_some() { ", client certificates required" } else { "" });
+    }
+
+    builder
+        .add_service(health_server)
+        .add_service(reflection_server)
         .add_service(order_book_server)
-        .serve(addr)
+        .add_service(admin_server)
+        .serve_with_shutdown(addr, shutdown_signal())
         .await?;
 
+    println!("Shutting down");
+    if let Some(export) = export {
+        let _ = stop_export.send(true);
+        export.await?;
+    }
+    if let Some(recorder) = &recorder {
+        recorder.flush()?;
+    }
     Ok(())
 }
 
+/// Resolves once the server is asked to stop, by Ctrl-C or, on Unix, SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(e) => {
+                println!("Could not listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+                return;
+            }
+        };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
 /* ------------
     TEST CASES    
    ------------*/
@@ -116,12 +1821,16 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use exchanges::feed::{apply_update, FeedUpdate};
+    use exchanges::l3::{L3Book, OrderEvent, OrderEventKind};
+    use exchanges::test_util::{set_book, venue_order};
+    use std::collections::BTreeMap;
     use tokio::runtime::Runtime;
 
     #[test]
     fn test_get_top_orders() {
         let server = MyServer::default();
-        let request = Request::new(GetTopOrdersRequest { top: 5, pair: String::from("ethbtc") }); // Replace "ethbtc" with a desired trading pair
+        let request = Request::new(GetTopOrdersRequest { top: 5, pair: String::from("ethbtc"), ..Default::default() }); // Replace "ethbtc" with a desired trading pair
 
         let rt = Runtime::new().unwrap();
 
@@ -142,4 +1851,818 @@ mod tests {
             },
         }
     }
+
+    #[tokio::test]
+    async fn test_get_top_orders_batch() {
+        let aggregator = Arc::new(Aggregator::new());
+        for pair in ["ethbtc", "btcusd"] {
+            set_book(&aggregator, Exchange::Binance, pair, &[], &[]);
+            set_book(&aggregator, Exchange::Bitstamp, pair, &[], &[]);
+        }
+        let server = MyServer::new(Arc::clone(&aggregator));
+
+        set_book(&aggregator, Exchange::Binance, "ethbtc", &[(0.06, 1.0), (0.05, 1.0), (0.04, 1.0)], &[(0.07, 1.0)]);
+
+        let pairs = vec![
+            GetTopOrdersRequest { pair: "ethbtc".to_string(), top: 2, ..Default::default() },
+            GetTopOrdersRequest { pair: "btcusd".to_string(), top: 5, ..Default::default() },
+            GetTopOrdersRequest { pair: String::new(), top: 5, ..Default::default() },
+            GetTopOrdersRequest { pair: "ethbtc".to_string(), top: -1, ..Default::default() },
+        ];
+        let request = Request::new(GetTopOrdersBatchRequest { pairs });
+        let results = server.get_top_orders_batch(request).await.unwrap().into_inner().results;
+        assert_eq!(results.len(), 4);
+
+        assert_eq!(results[0].pair, "ethbtc");
+        match &results[0].result {
+            Some(pair_top_orders::Result::Orders(orders)) => {
+                assert_eq!(orders.bids.len(), 2);
+                assert_eq!(orders.asks.len(), 1);
+            }
+            other => panic!("Expected orders for ethbtc, got {:?}", other),
+        }
+
+        // Subscribed but without any book yet.
+        assert_eq!(results[1].pair, "btcusd");
+        assert!(matches!(results[1].result, Some(pair_top_orders::Result::Error(_))));
+        assert!(matches!(results[2].result, Some(pair_top_orders::Result::Error(_))));
+        assert!(matches!(results[3].result, Some(pair_top_orders::Result::Error(_))));
+    }
+
+    #[tokio::test]
+    async fn test_requested_pairs_are_checked() {
+        let aggregator = Arc::new(Aggregator::new());
+        let server = MyServer::new(Arc::clone(&aggregator))
+            .with_auto_subscriptions(AutoSubscriptions::new(0, Duration::from_secs(60)));
+
+        let request = |pair: &str| Request::new(GetTopOrdersRequest { pair: pair.to_string(), top: 5, ..Default::default() });
+        let status = server.get_top_orders(request("eth/btc")).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        let status = server.get_top_orders(request("")).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        let negative = Request::new(GetTopOrdersRequest { pair: "ethbtc".to_string(), top: -1, ..Default::default() });
+        assert_eq!(server.get_top_orders(negative).await.unwrap_err().code(), tonic::Code::InvalidArgument);
+        // Only configured pairs are served, and none is.
+        let status = server.get_top_orders(request("xrpbtc")).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+        assert!(!aggregator.is_subscribed("xrpbtc"));
+
+        let pairs = vec![GetTopOrdersRequest { pair: "ethbtc".to_string(), top: 1, ..Default::default() }; MAX_BATCH_PAIRS + 1];
+        let status = server.get_top_orders_batch(Request::new(GetTopOrdersBatchRequest { pairs })).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_depth_queries() {
+        let aggregator = Arc::new(Aggregator::new());
+        let server = MyServer::new(Arc::clone(&aggregator));
+
+        set_book(&aggregator, Exchange::Binance, "ethbtc", &[(99.0, 1.0)], &[(101.0, 1.0), (105.0, 1.0)]);
+        set_book(&aggregator, Exchange::Bitstamp, "ethbtc", &[(99.0, 2.0)], &[(101.0, 2.0)]);
+
+        let request = Request::new(GetDepthWithinBpsRequest { pair: "ethbtc".to_string(), side: Side::Ask as i32, bps: 100.0 });
+        let response = server.get_depth_within_bps(request).await.unwrap().into_inner();
+        assert_eq!(response.mid, 100.0);
+        let depth = response.depth.unwrap();
+        assert_eq!(depth.levels, 1);
+        assert_eq!(depth.size, 3.0);
+        assert_eq!(depth.venues[1].exchange, "bitstamp");
+        assert_eq!(depth.venues[1].notional, 202.0);
+
+        let request = Request::new(GetLevelsToFillRequest {
+            pair: "ethbtc".to_string(),
+            side: Side::Ask as i32,
+            target: Some(get_levels_to_fill_request::Target::Notional(350.0)),
+        });
+        let response = server.get_levels_to_fill(request).await.unwrap().into_inner();
+        assert!(response.reached);
+        assert_eq!(response.worst_price, 105.0);
+        assert_eq!(response.depth.unwrap().levels, 2);
+
+        let request = Request::new(GetLevelsToFillRequest { pair: "ethbtc".to_string(), side: Side::Unspecified as i32, target: None });
+        assert_eq!(server.get_levels_to_fill(request).await.unwrap_err().code(), tonic::Code::InvalidArgument);
+        for target in [get_levels_to_fill_request::Target::Quantity(f64::NAN), get_levels_to_fill_request::Target::Quantity(-1.0), get_levels_to_fill_request::Target::Notional(f64::INFINITY)] {
+            let request = Request::new(GetLevelsToFillRequest { pair: "ethbtc".to_string(), side: Side::Ask as i32, target: Some(target) });
+            assert_eq!(server.get_levels_to_fill(request).await.unwrap_err().code(), tonic::Code::InvalidArgument);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_simulate_market_order() {
+        let aggregator = Arc::new(Aggregator::new());
+        let server = MyServer::new(Arc::clone(&aggregator));
+
+        set_book(&aggregator, Exchange::Binance, "ethbtc", &[(99.0, 1.0)], &[(101.0, 1.0)]);
+        set_book(&aggregator, Exchange::Bitstamp, "ethbtc", &[(98.0, 2.0)], &[(103.0, 2.0)]);
+
+        let request = Request::new(SimulateMarketOrderRequest { pair: "ethbtc".to_string(), side: OrderSide::Buy as i32, quantity: 4.0 });
+        let response = server.simulate_market_order(request).await.unwrap().into_inner();
+        assert_eq!(response.filled, 3.0);
+        assert_eq!(response.unfilled, 1.0);
+        assert_eq!(response.worst_price, 103.0);
+        assert_eq!(response.vwap, 307.0 / 3.0);
+        assert_eq!(response.fills.len(), 2);
+        assert_eq!(response.fills[1].exchange, "bitstamp");
+        assert_eq!(response.fills[1].size, 2.0);
+
+        for quantity in [0.0, f64::NAN, f64::INFINITY] {
+            let request = Request::new(SimulateMarketOrderRequest { pair: "ethbtc".to_string(), side: OrderSide::Sell as i32, quantity });
+            assert_eq!(server.simulate_market_order(request).await.unwrap_err().code(), tonic::Code::InvalidArgument);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fee_adjusted_top_orders() {
+        let aggregator = Arc::new(Aggregator::new());
+        let fees = BTreeMap::from([
+            ("binance".to_string(), FeeConfig { maker_bps: 0.0, taker_bps: 10.0 }),
+            ("bitstamp".to_string(), FeeConfig { maker_bps: 0.0, taker_bps: 50.0 }),
+        ]);
+        let server = MyServer::new(Arc::clone(&aggregator)).with_fees(fee_schedule(&fees));
+
+        set_book(&aggregator, Exchange::Binance, "ethbtc", &[(100.0, 1.0)], &[(101.0, 1.0)]);
+        set_book(&aggregator, Exchange::Bitstamp, "ethbtc", &[(100.0, 1.0)], &[(101.0, 1.0)]);
+
+        let request = Request::new(GetTopOrdersRequest { top: 5, pair: String::from("ethbtc"), ..Default::default() });
+        let response = server.get_top_orders(request).await.unwrap().into_inner();
+        assert_eq!(response.spread, 1.0);
+        assert!((response.effective_spread - 1.201).abs() < 1e-9);
+        assert_eq!(response.ask_levels.len(), 1);
+        assert_eq!(response.effective_ask_levels.len(), 2);
+        assert_eq!(response.effective_ask_levels[0].venues[0].exchange, "binance");
+
+        // The sweep takes the cheaper venue first even though both show the same price.
+        let request = Request::new(SimulateMarketOrderRequest { pair: "ethbtc".to_string(), side: OrderSide::Buy as i32, quantity: 1.0 });
+        let response = server.simulate_market_order(request).await.unwrap().into_inner();
+        assert_eq!(response.fills.len(), 1);
+        assert_eq!(response.fills[0].exchange, "binance");
+        assert!((response.vwap - 101.101).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn test_arbitrage_stream() {
+        let aggregator = Arc::new(Aggregator::new());
+        let server = MyServer::new(Arc::clone(&aggregator));
+
+        set_book(&aggregator, Exchange::Binance, "ethbtc", &[(99.0, 1.0)], &[(100.0, 1.0)]);
+        set_book(&aggregator, Exchange::Bitstamp, "ethbtc", &[(101.0, 2.0)], &[(102.0, 1.0)]);
+        for exchange in Exchange::ALL {
+            aggregator.status(exchange, "ethbtc").unwrap().lock().unwrap().record_message(status::now_millis(), None, None);
+        }
+
+        let request = Request::new(StreamArbitrageRequest { pairs: vec!["ETHBTC".to_string()] });
+        let mut stream = server.stream_arbitrage(request).await.unwrap().into_inner().into_inner();
+
+        let mut tracker = ArbitrageTracker::new();
+        let events = check_arbitrage(&aggregator, &FeeSchedule::new(), &mut tracker, 1_000);
+        assert_eq!(events.len(), 1);
+        for event in events {
+            server.arbitrage_events().send(event).unwrap();
+        }
+
+        let event = stream.recv().await.unwrap().unwrap();
+        assert_eq!(event.kind(), ArbitrageEventKind::Opened);
+        assert_eq!(event.buy_exchange, "binance");
+        assert_eq!(event.sell_exchange, "bitstamp");
+        assert_eq!(event.size, 1.0);
+        assert_eq!(event.gross_profit, 1.0);
+
+        // Once Bitstamp drops out the books no longer cross.
+        aggregator.set_venue_enabled(Exchange::Bitstamp, false);
+        let events = check_arbitrage(&aggregator, &FeeSchedule::new(), &mut tracker, 1_750);
+        assert_eq!(events[0].kind(), ArbitrageEventKind::Closed);
+        assert_eq!(events[0].duration_ms, 750);
+    }
+
+    #[tokio::test]
+    async fn test_arbitrage_log() {
+        let path = std::env::temp_dir().join(format!("server-arbitrage-{}.csv", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let event = ArbitrageEvent {
+            kind: ArbitrageEventKind::Opened as i32,
+            pair: "ethbtc".to_string(),
+            buy_exchange: "binance".to_string(),
+            sell_exchange: "bitstamp".to_string(),
+            buy_price: 100.0,
+            sell_price: 101.0,
+            size: 1.0,
+            gross_profit: 1.0,
+            net_profit: 0.5,
+            opened_at: 1_000,
+            duration_ms: 0,
+        };
+
+        let mut log = ArbitrageLog::open(&path).await.unwrap();
+        log.write(&event, 1_000).await.unwrap();
+        log.flush().await.unwrap();
+        drop(log);
+        // Reopening appends without a second header.
+        let mut log = ArbitrageLog::open(&path).await.unwrap();
+        log.write(&ArbitrageEvent { kind: ArbitrageEventKind::Closed as i32, duration_ms: 750, ..event }, 1_750).await.unwrap();
+        log.flush().await.unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("time,kind,pair"));
+        assert_eq!(lines[1], "1000,OPENED,ethbtc,binance,bitstamp,100,101,1,1,0.5,1000,0");
+        assert_eq!(lines[2], "1750,CLOSED,ethbtc,binance,bitstamp,100,101,1,1,0.5,1000,750");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reference_price_stream() {
+        let aggregator = Arc::new(Aggregator::new());
+        let server = MyServer::new(Arc::clone(&aggregator));
+
+        set_book(&aggregator, Exchange::Binance, "ethbtc", &[(0.0600, 1.0)], &[(0.0602, 1.0)]);
+        set_book(&aggregator, Exchange::Bitstamp, "ethbtc", &[(0.0500, 1.0)], &[(0.0510, 1.0)]);
+        // Bitstamp has not been heard from, so it is stale.
+        aggregator.status(Exchange::Binance, "ethbtc").unwrap().lock().unwrap().record_message(status::now_millis(), None, None);
+
+        let request = Request::new(StreamReferencePriceRequest { pairs: vec!["ETHBTC".to_string()], interval_ms: 10 });
+        let mut stream = server.stream_reference_price(request).await.unwrap().into_inner().into_inner();
+
+        let price = stream.recv().await.unwrap().unwrap();
+        assert_eq!(price.pair, "ethbtc");
+        assert!((price.mid - 0.0601).abs() < 1e-12);
+        assert_eq!(price.weighted_median, price.venues[0].mid);
+        assert_eq!(price.venues.len(), 1);
+        assert_eq!(price.venues[0].exchange, "binance");
+        assert_eq!(price.stale, vec!["bitstamp"]);
+        assert!(price.time > 0);
+    }
+
+    #[tokio::test]
+    async fn test_book_features_stream() {
+        let aggregator = Arc::new(Aggregator::new());
+        let server = MyServer::new(Arc::clone(&aggregator));
+
+        set_book(&aggregator, Exchange::Binance, "ethbtc", &[(99.0, 3.0), (98.0, 1.0)], &[(101.0, 1.0)]);
+        set_book(&aggregator, Exchange::Bitstamp, "ethbtc", &[(99.0, 1.0)], &[(102.0, 1.0)]);
+
+        let request = Request::new(StreamBookFeaturesRequest { pairs: vec!["ethbtc".to_string()], top: 1, interval_ms: 10 });
+        let mut stream = server.stream_book_features(request).await.unwrap().into_inner().into_inner();
+
+        let snapshot = stream.recv().await.unwrap().unwrap();
+        assert_eq!(snapshot.pair, "ethbtc");
+        let consolidated = snapshot.consolidated.unwrap();
+        assert_eq!(consolidated.exchange, "");
+        assert_eq!(consolidated.mid, 100.0);
+        assert_eq!(consolidated.imbalance, 0.6);
+        assert_eq!(consolidated.bid_curve.len(), 1);
+        assert_eq!(consolidated.bid_stats.unwrap().levels, 2);
+
+        assert_eq!(snapshot.venues.len(), 2);
+        assert_eq!(snapshot.venues[1].exchange, "bitstamp");
+        assert_eq!(snapshot.venues[1].mid, 100.5);
+        assert_eq!(snapshot.venues[1].imbalance, 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_get_history() {
+        let server = MyServer::default();
+        let request = || Request::new(GetHistoryRequest { pair: "ethbtc".to_string(), from: 0, to: 5_000, resolution_ms: 0 });
+        assert_eq!(server.get_history(request()).await.unwrap_err().code(), tonic::Code::FailedPrecondition);
+
+        let book = ConsolidatedBook::new(vec![(vec![venue_order(Exchange::Bitstamp, "ethbtc", 0.06, 1.0)], vec![venue_order(Exchange::Binance, "ethbtc", 0.07, 1.0)])], "ethbtc");
+        let store = Arc::new(SnapshotStore::open_in_memory().unwrap());
+        store.insert(&Snapshot::of(&book, 5, 1_000)).unwrap();
+        store.insert(&Snapshot::of(&book, 5, 1_500)).unwrap();
+        store.insert(&Snapshot::of(&book, 5, 9_000)).unwrap();
+        let server = server.with_history(store);
+
+        let response = server.get_history(request()).await.unwrap().into_inner();
+        assert_eq!(response.snapshots.len(), 2);
+        let snapshot = &response.snapshots[0];
+        assert_eq!(snapshot.time, 1_000);
+        assert!((snapshot.spread - 0.01).abs() < 1e-12);
+        assert_eq!(snapshot.bids[0].venues[0].exchange, "bitstamp");
+        assert_eq!(snapshot.asks[0].price, 0.07);
+
+        let coarse = Request::new(GetHistoryRequest { pair: "ETHBTC".to_string(), from: 0, to: 0, resolution_ms: 60_000 });
+        assert_eq!(server.get_history(coarse).await.unwrap().into_inner().snapshots.len(), 1);
+        let everything = Request::new(GetHistoryRequest { pair: "ethbtc".to_string(), from: 0, to: u64::MAX, resolution_ms: 0 });
+        assert_eq!(server.get_history(everything).await.unwrap().into_inner().snapshots.len(), 3);
+
+        let backwards = Request::new(GetHistoryRequest { pair: "ethbtc".to_string(), from: 5_000, to: 1_000, resolution_ms: 0 });
+        assert_eq!(server.get_history(backwards).await.unwrap_err().code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_trade_stream() {
+        let aggregator = Arc::new(Aggregator::new());
+        aggregator.subscribe(Exchange::Binance, "ethbtc");
+        aggregator.subscribe(Exchange::Bitstamp, "ethbtc");
+        let server = MyServer::new(Arc::clone(&aggregator));
+
+        let request = Request::new(StreamTradesRequest { pairs: vec!["ETHBTC".to_string()], exchanges: vec!["Bitstamp".to_string()] });
+        let mut stream = server.stream_trades(request).await.unwrap().into_inner().into_inner();
+
+        let trade = |exchange, trade_id| trade::Trade {
+            exchange,
+            pair: "ethbtc".to_string(),
+            price: 0.06,
+            size: 2.0,
+            aggressor: TradeSide::Sell,
+            trade_id,
+            trade_time: None,
+            received_at: 1_000,
+        };
+        let tape = aggregator.trade_tape();
+        tape.publish(trade(Exchange::Binance, 1));
+        tape.publish(trade::Trade { pair: "btcusd".to_string(), ..trade(Exchange::Bitstamp, 2) });
+        tape.publish(trade(Exchange::Bitstamp, 3));
+
+        let received = stream.recv().await.unwrap().unwrap();
+        assert_eq!(received.trade_id, 3);
+        assert_eq!(received.exchange, "bitstamp");
+        assert_eq!(received.aggressor(), OrderSide::Sell);
+        assert_eq!(received.trade_time, 0);
+
+        let unknown = Request::new(StreamTradesRequest { exchanges: vec!["kraken".to_string()], ..Default::default() });
+        assert_eq!(server.stream_trades(unknown).await.unwrap_err().code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_candles() {
+        let aggregator = Arc::new(Aggregator::new());
+        aggregator.subscribe(Exchange::Binance, "ethbtc");
+        aggregator.subscribe(Exchange::Bitstamp, "ethbtc");
+        let server = MyServer::new(Arc::clone(&aggregator));
+        tokio::spawn(server.build_candles());
+
+        let request = Request::new(StreamCandlesRequest { pairs: vec!["ethbtc".to_string()], interval: CandleInterval::OneHour as i32, ..Default::default() });
+        let mut stream = server.stream_candles(request).await.unwrap().into_inner().into_inner();
+        let closed_only = Request::new(StreamCandlesRequest { interval: CandleInterval::OneSecond as i32, closed_only: true, ..Default::default() });
+        let mut closed = server.stream_candles(closed_only).await.unwrap().into_inner().into_inner();
+
+        let now = status::now_millis();
+        let trade = |exchange, price, size| trade::Trade {
+            exchange,
+            pair: "ethbtc".to_string(),
+            price,
+            size,
+            aggressor: TradeSide::Buy,
+            trade_id: 1,
+            trade_time: Some(now),
+            received_at: now,
+        };
+        let tape = aggregator.trade_tape();
+        tape.publish(trade(Exchange::Binance, 10.0, 1.0));
+        tape.publish(trade(Exchange::Bitstamp, 13.0, 2.0));
+
+        let first = stream.recv().await.unwrap().unwrap();
+        assert_eq!((first.exchange.as_str(), first.close, first.trade_count), ("", 10.0, 1));
+        let second = stream.recv().await.unwrap().unwrap();
+        assert_eq!((second.high, second.volume, second.vwap, second.trade_count), (13.0, 3.0, 12.0, 2));
+        assert!(!second.closed);
+
+        // The consolidated second closes within a tick of its end.
+        let candle = closed.recv().await.unwrap().unwrap();
+        assert!(candle.closed);
+        assert_eq!(candle.interval(), CandleInterval::OneSecond);
+        assert_eq!(candle.trade_count, 2);
+
+        let request = |exchange: &str, interval: CandleInterval| Request::new(GetCandlesRequest {
+            pair: "ETHBTC".to_string(),
+            exchange: exchange.to_string(),
+            interval: interval as i32,
+            limit: 0,
+        });
+        let candles = server.get_candles(request("bitstamp", CandleInterval::OneMinute)).await.unwrap().into_inner().candles;
+        assert_eq!(candles.len(), 1);
+        assert_eq!((candles[0].exchange.as_str(), candles[0].open), ("bitstamp", 13.0));
+        assert_eq!(server.get_candles(request("", CandleInterval::Unspecified)).await.unwrap_err().code(), tonic::Code::InvalidArgument);
+        assert_eq!(server.get_candles(request("kraken", CandleInterval::OneHour)).await.unwrap_err().code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_nbbo_stream() {
+        let aggregator = Arc::new(Aggregator::new());
+        for exchange in Exchange::ALL {
+            aggregator.subscribe(exchange, "ethbtc");
+        }
+        let server = MyServer::new(Arc::clone(&aggregator));
+        let request = Request::new(StreamNbboRequest { pairs: vec!["ETHBTC".to_string()] });
+        let mut stream = server.stream_nbbo(request).await.unwrap().into_inner().into_inner();
+
+        let quote = |exchange, bid, received_at| bbo::Quote {
+            exchange,
+            pair: "ethbtc".to_string(),
+            bid: Some((bid, 1.0)),
+            ask: Some((0.07, 2.0)),
+            update_id: None,
+            event_time: None,
+            received_at,
+        };
+        let book = aggregator.bbo_book();
+        book.update(quote(Exchange::Binance, 0.06, 1)).unwrap();
+        let first = stream.recv().await.unwrap().unwrap();
+        assert_eq!((first.bid.unwrap().price, first.updated_at), (0.06, 1));
+
+        // Same prices and sizes, then another pair: nothing to send for either.
+        book.update(quote(Exchange::Binance, 0.06, 2)).unwrap();
+        book.update(bbo::Quote { pair: "btcusd".to_string(), ..quote(Exchange::Binance, 0.06, 3) }).unwrap();
+        book.update(quote(Exchange::Bitstamp, 0.06, 4)).unwrap();
+        let second = stream.recv().await.unwrap().unwrap();
+        let bid = second.bid.unwrap();
+        assert_eq!((bid.size, bid.venues.len(), second.updated_at), (2.0, 2, 4));
+        assert_eq!(bid.venues[1].exchange, "bitstamp");
+        assert_eq!(second.ask.unwrap().size, 4.0);
+
+        let empty = Request::new(StreamNbboRequest { pairs: vec![] });
+        assert_eq!(server.stream_nbbo(empty).await.unwrap_err().code(), tonic::Code::InvalidArgument);
+        let invalid = Request::new(StreamNbboRequest { pairs: vec!["eth/btc".to_string()] });
+        assert_eq!(server.stream_nbbo(invalid).await.unwrap_err().code(), tonic::Code::InvalidArgument);
+
+        // The Binance top-of-book feed stops, and its quote goes, with the last stream on it.
+        drop(stream);
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while book.quotes("ethbtc").iter().any(|quote| quote.exchange == Exchange::Binance) {
+                sleep(Duration::from_millis(10)).await;
+            }
+        }).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stale_venue_is_reported() {
+        let aggregator = Arc::new(Aggregator::new().with_stale_after(Duration::from_secs(30)));
+        let server = MyServer::new(Arc::clone(&aggregator));
+        set_book(&aggregator, Exchange::Binance, "ethbtc", &[(0.06, 1.0)], &[(0.07, 1.0)]);
+        set_book(&aggregator, Exchange::Bitstamp, "ethbtc", &[(0.08, 1.0)], &[(0.05, 1.0)]);
+        let now = status::now_millis();
+        aggregator.status(Exchange::Binance, "ethbtc").unwrap().lock().unwrap().record_message(now, None, None);
+        aggregator.status(Exchange::Bitstamp, "ethbtc").unwrap().lock().unwrap().record_message(now - 60_000, None, None);
+
+        let request = Request::new(GetTopOrdersRequest { pair: "ethbtc".to_string(), top: 5, ..Default::default() });
+        let response = server.get_top_orders(request).await.unwrap().into_inner();
+        assert_eq!(response.venues, vec!["binance"]);
+        assert_eq!(response.stale_venues, vec!["bitstamp"]);
+        assert!(response.spread > 0.0);
+
+        aggregator.check_staleness();
+        let request = Request::new(GetVenueStatusRequest { pair: "ethbtc".to_string() });
+        let statuses = server.get_venue_status(request).await.unwrap().into_inner().statuses;
+        let bitstamp = statuses.iter().find(|status| status.exchange == "bitstamp").unwrap();
+        assert!(bitstamp.stale && bitstamp.stale_since > 0);
+        assert_eq!(bitstamp.stale_count, 1);
+        assert!(!statuses.iter().find(|status| status.exchange == "binance").unwrap().stale);
+    }
+
+    #[tokio::test]
+    async fn test_rejections_are_reported() {
+        let aggregator = Arc::new(Aggregator::new().with_price_band(1_000.0));
+        set_book(&aggregator, Exchange::Binance, "ethbtc", &[], &[]);
+        let server = MyServer::new(Arc::clone(&aggregator));
+        // A second venue agreeing with Binance, for the band to be anchored on.
+        set_book(&aggregator, Exchange::Bitstamp, "ethbtc", &[(0.0601, 1.0)], &[(0.0609, 1.0)]);
+        let binance = |price, size| venue_order(Exchange::Binance, "ethbtc", price, size);
+        let update = FeedUpdate {
+            bids: vec![binance(0.06, 1.0), binance(0.06, f64::NAN), binance(0.03, 1.0)],
+            asks: vec![binance(0.061, 1.0)],
+            ..Default::default()
+        };
+        let order_book = aggregator.order_book(Exchange::Binance, "ethbtc").unwrap();
+        let status = aggregator.status(Exchange::Binance, "ethbtc").unwrap();
+        apply_update("binance", "ethbtc", status::now_millis(), update, &order_book, &status, None).unwrap();
+
+        let request = Request::new(GetTopOrdersRequest { pair: "ethbtc".to_string(), top: 5, ..Default::default() });
+        let response = server.get_top_orders(request).await.unwrap().into_inner();
+        assert_eq!(response.bids.iter().map(|order| order.price).collect::<Vec<_>>(), vec![0.0601, 0.06]);
+
+        let crossed = bbo::Quote {
+            exchange: Exchange::Binance,
+            pair: "ethbtc".to_string(),
+            bid: Some((0.062, 1.0)),
+            ask: Some((0.061, 1.0)),
+            update_id: None,
+            event_time: None,
+            received_at: 1,
+        };
+        assert!(aggregator.bbo_book().update(crossed).is_err());
+        aggregator.subscribe_bbo_without_feed(Exchange::Binance, "ethbtc");
+        aggregator.bbo_status(Exchange::Binance, "ethbtc").unwrap().lock().unwrap().record_rejections(RejectReason::MalformedFrame, 1);
+
+        let request = Request::new(GetVenueStatusRequest { pair: "ethbtc".to_string() });
+        let statuses = server.get_venue_status(request).await.unwrap().into_inner().statuses;
+        let rejections = &statuses[0].rejections;
+        assert_eq!((rejections["invalid_size"], rejections["outside_band"]), (1, 1));
+        assert!(!rejections.contains_key("crossed_book"));
+        assert_eq!(statuses[0].quote_rejections["crossed_book"], 1);
+        assert!(statuses[0].trades.as_ref().unwrap().rejections.is_empty());
+        assert_eq!(statuses[0].bbo.as_ref().unwrap().rejections["malformed_frame"], 1);
+        assert!(statuses[1].bbo.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_get_order_levels() {
+        let aggregator = Arc::new(Aggregator::new());
+        for exchange in Exchange::ALL {
+            aggregator.subscribe(exchange, "ethbtc");
+        }
+        let server = MyServer::new(Arc::clone(&aggregator)).with_auto_subscriptions(AutoSubscriptions::new(0, Duration::from_secs(60)));
+        let request = || Request::new(GetOrderLevelsRequest { pair: "ETHBTC".to_string(), top: 1 });
+        let response = server.get_order_levels(request()).await.unwrap().into_inner();
+        assert!(response.bids.is_empty() && response.order_count == 0);
+
+        let event = |id, side, price, size| OrderEvent { kind: OrderEventKind::Created, id, side, price, size, time: Some(id) };
+        {
+            let book = aggregator.l3_book("ethbtc").unwrap();
+            let mut book = book.lock().unwrap();
+            // Stand in for the live feed: whatever it received would otherwise be mixed in.
+            *book = L3Book::new("bitstamp", "ethbtc");
+            book.connected_at = Some(1);
+            book.skipped_events = 2;
+            book.apply(&event(1, consolidated_book::Side::Bid, 0.06, 1.0));
+            book.apply(&event(2, consolidated_book::Side::Bid, 0.06, 3.0));
+            book.apply(&event(3, consolidated_book::Side::Bid, 0.059, 2.0));
+            book.apply(&event(4, consolidated_book::Side::Ask, 0.061, 0.5));
+        }
+
+        let response = server.get_order_levels(request()).await.unwrap().into_inner();
+        assert_eq!(response.exchange, "bitstamp");
+        assert_eq!(response.bids, vec![OrderLevel { price: 0.06, size: 4.0, order_count: 2, largest_order: 3.0 }]);
+        assert_eq!(response.asks[0].order_count, 1);
+        assert_eq!((response.order_count, response.last_event_time), (4, 4));
+        assert_eq!((response.connected_at, response.skipped_events), (1, 2));
+
+        let empty = Request::new(GetOrderLevelsRequest::default());
+        assert_eq!(server.get_order_levels(empty).await.unwrap_err().code(), tonic::Code::InvalidArgument);
+        // Pairs nobody subscribed are not started for a look at their orders.
+        let unsubscribed = Request::new(GetOrderLevelsRequest { pair: "btcusd".to_string(), top: 1 });
+        assert_eq!(server.get_order_levels(unsubscribed).await.unwrap_err().code(), tonic::Code::ResourceExhausted);
+        assert_eq!(aggregator.l3_pairs(), vec!["ethbtc"]);
+    }
+
+    #[tokio::test]
+    async fn test_export_books() {
+        let aggregator = Aggregator::new();
+        set_book(&aggregator, Exchange::Binance, "ethbtc", &[(0.06, 1.0)], &[(0.07, 1.0)]);
+        set_book(&aggregator, Exchange::Bitstamp, "ethbtc", &[(0.06, 1.0)], &[]);
+
+        let dir = std::env::temp_dir().join(format!("server-export-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = ExportConfig { dir: dir.clone(), format: "csv".to_string(), ..Default::default() };
+        let mut csv = exporter(&config).unwrap();
+        export_books(&aggregator, &mut csv, 1_000).unwrap();
+
+        let csv = std::fs::read_to_string(dir.join("pair=ethbtc/date=1970-01-01/snapshots.csv")).unwrap();
+        let rows: Vec<&str> = csv.lines().skip(1).collect();
+        assert_eq!(rows, vec![
+            "1000,ethbtc,binance,bid,0,0.06,1,,",
+            "1000,ethbtc,binance,ask,0,0.07,1,,",
+            "1000,ethbtc,bitstamp,bid,0,0.06,1,,",
+            "1000,ethbtc,consolidated,bid,0,0.06,2,,",
+            "1000,ethbtc,consolidated,ask,0,0.07,1,,",
+        ]);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(exporter(&ExportConfig { format: "xml".to_string(), ..Default::default() }).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_synthetic_top_orders() {
+        let aggregator = Arc::new(Aggregator::new());
+        for pair in ["ethbtc", "ethusd", "btcusd"] {
+            set_book(&aggregator, Exchange::Binance, pair, &[], &[]);
+            set_book(&aggregator, Exchange::Bitstamp, pair, &[], &[]);
+        }
+        let config = SyntheticConfig { pair: "ETHBTC".to_string(), base_leg: "ethusd".to_string(), quote_leg: "btcusd".to_string() };
+        let server = MyServer::new(Arc::clone(&aggregator)).with_synthetics(vec![synthetic_pair(&config)]);
+
+        set_book(&aggregator, Exchange::Binance, "ethbtc", &[(0.049, 1.0)], &[(0.051, 1.0)]);
+        set_book(&aggregator, Exchange::Bitstamp, "ethusd", &[(2000.0, 1.0)], &[(2010.0, 1.0)]);
+        set_book(&aggregator, Exchange::Bitstamp, "btcusd", &[(40000.0, 1.0)], &[(40000.0, 1.0)]);
+
+        let request = Request::new(GetTopOrdersRequest { pair: "ethbtc".to_string(), top: 5, ..Default::default() });
+        let response = server.get_top_orders(request).await.unwrap().into_inner();
+        assert_eq!(response.venues, vec!["binance"]);
+        assert!(response.bids.iter().all(|order| !order.synthetic));
+
+        let request = Request::new(GetTopOrdersRequest { pair: "ethbtc".to_string(), top: 5, include_synthetic: true, ..Default::default() });
+        let response = server.get_top_orders(request).await.unwrap().into_inner();
+        assert_eq!(response.venues, vec!["binance", "synthetic:ethusd/btcusd"]);
+        assert_eq!(response.bids[0].price, 0.05);
+        assert!(response.bids[0].synthetic);
+        assert_eq!(response.bid_levels[0].venues[0].exchange, "synthetic:ethusd/btcusd");
+        assert!(response.bid_levels[0].venues[0].synthetic);
+        assert!(!response.bid_levels[1].venues[0].synthetic);
+    }
+
+    #[tokio::test]
+    async fn test_quote_equivalent_top_orders() {
+        let aggregator = Arc::new(Aggregator::new());
+        for pair in ["btcusd", "btcusdt", "btcusdc", "usdtusd"] {
+            set_book(&aggregator, Exchange::Binance, pair, &[], &[]);
+            set_book(&aggregator, Exchange::Bitstamp, pair, &[], &[]);
+        }
+        let config = QuoteGroupConfig {
+            currencies: vec!["usd".to_string(), "usdt".to_string(), "usdc".to_string()],
+            rate_pairs: BTreeMap::from([("usdt".to_string(), "usdtusd".to_string())]),
+        };
+        let server = MyServer::new(Arc::clone(&aggregator)).with_quote_groups(vec![quote_group(&config)]);
+
+        set_book(&aggregator, Exchange::Bitstamp, "btcusd", &[(39900.0, 1.0)], &[(40100.0, 1.0)]);
+        set_book(&aggregator, Exchange::Binance, "btcusdt", &[(40000.0, 2.0)], &[(40200.0, 2.0)]);
+        set_book(&aggregator, Exchange::Bitstamp, "usdtusd", &[(0.5, 1.0)], &[(1.5, 1.0)]);
+
+        let request = Request::new(GetTopOrdersRequest { pair: "btcusd".to_string(), top: 5, ..Default::default() });
+        let response = server.get_top_orders(request).await.unwrap().into_inner();
+        assert_eq!(response.venues, vec!["bitstamp"]);
+
+        // USDT trades at par with USD here, per the usdtusd mid.
+        let request = Request::new(GetTopOrdersRequest { pair: "btcusd".to_string(), top: 5, quote_equivalent: true, ..Default::default() });
+        let response = server.get_top_orders(request).await.unwrap().into_inner();
+        assert_eq!(response.venues, vec!["bitstamp", "binance"]);
+        assert_eq!(response.bid_levels[0].price, 40000.0);
+        let binance = &response.bid_levels[0].venues[0];
+        assert_eq!(binance.exchange, "binance");
+        assert_eq!(binance.quote_currency, "usdt");
+        assert_eq!(binance.conversion_rate, 1.0);
+        assert_eq!(response.bids[1].quote_currency, "usd");
+        assert!((response.spread - 100.0).abs() < 1e-9);
+
+        set_book(&aggregator, Exchange::Bitstamp, "usdtusd", &[(0.99, 1.0)], &[(0.99, 1.0)]);
+        let request = Request::new(GetTopOrdersRequest { pair: "btcusd".to_string(), top: 5, quote_equivalent: true, ..Default::default() });
+        let response = server.get_top_orders(request).await.unwrap().into_inner();
+        assert_eq!(response.bid_levels[0].price, 39900.0);
+        assert_eq!(response.bid_levels[1].venues[0].conversion_rate, 0.99);
+        assert!((response.bid_levels[1].price - 39600.0).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn test_get_venue_status() {
+        let aggregator = Arc::new(Aggregator::new());
+        aggregator.subscribe(Exchange::Binance, "ethbtc");
+        aggregator.subscribe(Exchange::Bitstamp, "btcusd");
+        aggregator.set_venue_enabled(Exchange::Bitstamp, false);
+        let server = MyServer::new(Arc::clone(&aggregator));
+
+        let request = Request::new(GetVenueStatusRequest { pair: String::new() });
+        let statuses = server.get_venue_status(request).await.unwrap().into_inner().statuses;
+        assert_eq!(statuses.len(), 2);
+
+        let request = Request::new(GetVenueStatusRequest { pair: "btcusd".to_string() });
+        let statuses = server.get_venue_status(request).await.unwrap().into_inner().statuses;
+        assert_eq!(statuses.len(), 1);
+        assert_eq!(statuses[0].exchange, "bitstamp");
+        assert!(!statuses[0].enabled);
+        assert_eq!(statuses[0].state(), ConnectionState::Disconnected);
+        assert_eq!(statuses[0].last_message_time, 0);
+        assert!(statuses[0].trades.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_get_top_orders_reports_venues() {
+        let aggregator = Arc::new(Aggregator::new());
+        set_book(&aggregator, Exchange::Bitstamp, "ethbtc", &[], &[]);
+        let server = MyServer::new(Arc::clone(&aggregator));
+
+        set_book(&aggregator, Exchange::Binance, "ethbtc", &[(0.06, 1.0)], &[(0.07, 1.0)]);
+
+        let request = Request::new(GetTopOrdersRequest { top: 5, pair: String::from("ETHBTC"), ..Default::default() });
+        let response = server.get_top_orders(request).await.unwrap().into_inner();
+        assert_eq!(response.venues, vec!["binance"]);
+        assert_eq!(response.bids.len(), 1);
+        assert_eq!(response.asks.len(), 1);
+        assert!((response.spread - 0.01).abs() < 1e-12);
+    }
+
+    #[tokio::test]
+    async fn test_get_top_orders_schema() {
+        let aggregator = Arc::new(Aggregator::new());
+        let server = MyServer::new(Arc::clone(&aggregator));
+
+        set_book(&aggregator, Exchange::Binance, "ethbtc", &[(0.06, 1.0)], &[(0.07, 1.0)]);
+        set_book(&aggregator, Exchange::Bitstamp, "ethbtc", &[(0.06, 2.0), (0.05, 1.0)], &[]);
+        aggregator.status(Exchange::Bitstamp, "ethbtc").unwrap().lock().unwrap().record_message(1_000, Some(990), None);
+
+        let request = Request::new(GetTopOrdersRequest { top: 5, pair: String::from("ethbtc"), ..Default::default() });
+        let response = server.get_top_orders(request).await.unwrap().into_inner();
+        assert_eq!(response.schema_version, 6);
+
+        let bitstamp_bid = response.bids.iter().find(|order| order.exchange == "bitstamp").unwrap();
+        assert_eq!(bitstamp_bid.id, "bitstamp-ethbtc");
+        assert_eq!(bitstamp_bid.side(), Side::Bid);
+        assert_eq!(bitstamp_bid.event_time, 990);
+        assert_eq!(bitstamp_bid.receive_time, 1_000);
+        assert_eq!(response.asks[0].side(), Side::Ask);
+
+        assert_eq!(response.bid_levels.len(), 2);
+        assert_eq!(response.bid_levels[0].price, 0.06);
+        assert_eq!(response.bid_levels[0].size, 3.0);
+        let venues: Vec<_> = response.bid_levels[0].venues.iter().map(|venue| (venue.exchange.as_str(), venue.size)).collect();
+        assert_eq!(venues, vec![("binance", 1.0), ("bitstamp", 2.0)]);
+        assert_eq!(response.ask_levels.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_is_serving() {
+        let aggregator = Aggregator::new();
+        // Nothing to serve yet.
+        assert!(!is_serving(&aggregator, &[]));
+        let pairs = vec!["ethbtc".to_string(), "btcusd".to_string()];
+        for pair in &pairs {
+            set_book(&aggregator, Exchange::Binance, pair, &[], &[]);
+            set_book(&aggregator, Exchange::Bitstamp, pair, &[], &[]);
+        }
+        assert!(!is_serving(&aggregator, &pairs));
+
+        let receive = |exchange, pair: &str| {
+            set_book(&aggregator, exchange, pair, &[(1.0, 1.0)], &[(1.0, 1.0)]);
+            aggregator.status(exchange, pair).unwrap().lock().unwrap().record_message(status::now_millis(), None, None);
+        };
+
+        receive(Exchange::Binance, "ethbtc");
+        assert!(!is_serving(&aggregator, &pairs));
+        assert!(!is_serving(&aggregator, &[]));
+
+        // A configured pair unsubscribed through Admin no longer holds the server back.
+        aggregator.unsubscribe(Exchange::Binance, "btcusd");
+        aggregator.unsubscribe(Exchange::Bitstamp, "btcusd");
+        assert!(is_serving(&aggregator, &pairs));
+
+        set_book(&aggregator, Exchange::Bitstamp, "btcusd", &[], &[]);
+        receive(Exchange::Bitstamp, "btcusd");
+        assert!(is_serving(&aggregator, &pairs));
+        assert!(is_serving(&aggregator, &[]));
+
+        // A top-of-book feed that is down holds the server back until it connects again.
+        aggregator.subscribe_bbo_without_feed(Exchange::Binance, "ethbtc");
+        assert!(!is_serving(&aggregator, &pairs));
+        aggregator.bbo_status(Exchange::Binance, "ethbtc").unwrap().lock().unwrap().connected();
+        assert!(is_serving(&aggregator, &pairs));
+
+        // A stale book does not serve its pair, however recent it is.
+        let stale = Aggregator::new().with_stale_after(Duration::from_secs(1));
+        set_book(&stale, Exchange::Binance, "ethbtc", &[(1.0, 1.0)], &[(1.0, 1.0)]);
+        stale.status(Exchange::Binance, "ethbtc").unwrap().lock().unwrap().record_message(status::now_millis() - 5_000, None, None);
+        assert!(!is_serving(&stale, &[]));
+    }
+
+    #[tokio::test]
+    async fn test_admin_subscriptions() {
+        let aggregator = Arc::new(Aggregator::new());
+        let admin = MyAdmin::new(Arc::clone(&aggregator));
+
+        let request = Request::new(SubscriptionRequest { exchange: "Binance".to_string(), pair: "ethbtc".to_string(), order_levels: false });
+        assert!(admin.subscribe(request).await.unwrap().into_inner().changed);
+
+        let request = Request::new(SubscriptionRequest { exchange: "binance".to_string(), pair: "ethbtc".to_string(), order_levels: false });
+        assert!(!admin.subscribe(request).await.unwrap().into_inner().changed);
+
+        let request = Request::new(SetVenueEnabledRequest { exchange: "bitstamp".to_string(), enabled: false });
+        admin.set_venue_enabled(request).await.unwrap();
+
+        let response = admin.list_subscriptions(Request::new(ListSubscriptionsRequest {})).await.unwrap().into_inner();
+        assert_eq!(response.subscriptions.len(), 1);
+        assert_eq!(response.subscriptions[0].pair, "ethbtc");
+        assert_eq!(response.subscriptions[0].exchanges, vec!["binance"]);
+        assert!(response.venues.iter().any(|venue| venue.exchange == "bitstamp" && !venue.enabled));
+
+        let request = Request::new(ResyncVenueRequest { exchange: "binance".to_string() });
+        assert_eq!(admin.resync_venue(request).await.unwrap().into_inner().restarted, 1);
+
+        let request = Request::new(SubscriptionRequest { exchange: "binance".to_string(), pair: "ethbtc".to_string(), order_levels: false });
+        assert!(admin.unsubscribe(request).await.unwrap().into_inner().changed);
+        assert!(aggregator.subscriptions().is_empty());
+
+        let request = |exchange: &str| Request::new(SubscriptionRequest { exchange: exchange.to_string(), pair: "ethbtc".to_string(), order_levels: true });
+        assert_eq!(admin.subscribe(request("binance")).await.unwrap_err().code(), tonic::Code::InvalidArgument);
+        assert!(admin.subscribe(request("bitstamp")).await.unwrap().into_inner().changed);
+        let response = admin.list_subscriptions(Request::new(ListSubscriptionsRequest {})).await.unwrap().into_inner();
+        assert_eq!(response.order_level_pairs, vec!["ethbtc"]);
+        assert!(admin.unsubscribe(request("bitstamp")).await.unwrap().into_inner().changed);
+        assert!(aggregator.l3_pairs().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_admin_rejects_unknown_exchange() {
+        let admin = MyAdmin::default();
+
+        let request = Request::new(SubscriptionRequest { exchange: "kraken".to_string(), pair: "ethbtc".to_string(), order_levels: false });
+        let status = admin.subscribe(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        let request = Request::new(SubscriptionRequest { exchange: "binance".to_string(), pair: "eth/btc".to_string(), order_levels: false });
+        assert_eq!(admin.unsubscribe(request).await.unwrap_err().code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_admin_pins_auto_subscribed_pair() {
+        let aggregator = Arc::new(Aggregator::new());
+        set_book(&aggregator, Exchange::Binance, "ethbtc", &[], &[]);
+        let auto_subscriptions = AutoSubscriptions::new(1, Duration::from_secs(60));
+        auto_subscriptions.admit(&["ethbtc"]).unwrap();
+        let admin = MyAdmin::new(Arc::clone(&aggregator)).with_auto_subscriptions(auto_subscriptions.clone());
+
+        let request = Request::new(SubscriptionRequest { exchange: "Binance".to_string(), pair: "ETHBTC".to_string(), order_levels: false });
+        assert!(!admin.subscribe(request).await.unwrap().into_inner().changed);
+        assert!(auto_subscriptions.pairs().is_empty());
+
+        let request = Request::new(SubscriptionRequest { exchange: "binance".to_string(), pair: "ETHBTC".to_string(), order_levels: false });
+        assert!(admin.unsubscribe(request).await.unwrap().into_inner().changed);
+        assert!(aggregator.subscriptions().is_empty());
+    }
 }
@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+static DEFAULT_MAX_PAIRS: usize = 20;
+static DEFAULT_IDLE_TTL: Duration = Duration::from_secs(600);
+
+/// The shortest and longest pair names accepted, e.g. "btcusd" or "1000shibusdt".
+const MIN_PAIR_LEN: usize = 3;
+const MAX_PAIR_LEN: usize = 20;
+
+/// Checks that `pair` could be listed by a venue: 3 to 20 lowercase ASCII letters or digits.
+pub fn validate_pair(pair: &str) -> Result<(), String> {
+    if pair.is_empty() {
+        return Err("pair must not be empty".to_string());
+    }
+    if !(MIN_PAIR_LEN..=MAX_PAIR_LEN).contains(&pair.len()) {
+        return Err(format!("pair must be {} to {} characters long, got {:?}", MIN_PAIR_LEN, MAX_PAIR_LEN, pair));
+    }
+    if !pair.bytes().all(|byte| byte.is_ascii_lowercase() || byte.is_ascii_digit()) {
+        return Err(format!("pair must be lowercase letters and digits, got {:?}", pair));
+    }
+    Ok(())
+}
+
+struct Usage {
+    last_used: Instant,
+    streams: usize,
+}
+
+/// Pairs subscribed because a request asked for them, rather than by configuration or
+/// Admin. At most `max_pairs` are held at once, and each is let go once no stream is open
+/// on it and no request has asked for it for `idle_ttl`.
+#[derive(Clone)]
+pub struct AutoSubscriptions {
+    pairs: Arc<Mutex<HashMap<String, Usage>>>,
+    max_pairs: usize,
+    idle_ttl: Duration,
+}
+
+impl Default for AutoSubscriptions {
+    fn default() -> Self {
+        AutoSubscriptions::new(DEFAULT_MAX_PAIRS, DEFAULT_IDLE_TTL)
+    }
+}
+
+impl AutoSubscriptions {
+    /// With `max_pairs` at 0 no pair is subscribed on request.
+    pub fn new(max_pairs: usize, idle_ttl: Duration) -> Self {
+        AutoSubscriptions { pairs: Arc::default(), max_pairs, idle_ttl }
+    }
+
+    pub fn idle_ttl(&self) -> Duration {
+        self.idle_ttl
+    }
+
+    /// Takes on `pairs`, none of which is subscribed yet, if there is room for all of them.
+    pub fn admit(&self, pairs: &[&str]) -> Result<(), String> {
+        let mut held = self.pairs.lock().unwrap();
+        let new = pairs.iter().filter(|pair| !held.contains_key(**pair)).count();
+        if held.len() + new > self.max_pairs {
+            return Err(match self.max_pairs {
+                0 => format!("{} not subscribed, and this server only serves the pairs it is configured with", pairs.join(", ")),
+                max => format!("this server subscribes at most {} pairs on request; {} would take it over", max, pairs.join(", ")),
+            });
+        }
+
+        let now = Instant::now();
+        for pair in pairs {
+            held.entry(pair.to_string()).or_insert(Usage { last_used: now, streams: 0 }).last_used = now;
+        }
+        Ok(())
+    }
+
+    /// Marks `pairs` as used now. Pairs not subscribed on request are left alone.
+    pub fn touch(&self, pairs: &[impl AsRef<str>]) {
+        let now = Instant::now();
+        let mut held = self.pairs.lock().unwrap();
+        for pair in pairs {
+            if let Some(usage) = held.get_mut(pair.as_ref()) {
+                usage.last_used = now;
+            }
+        }
+    }
+
+    /// Keeps `pairs` from expiring until the lease is dropped.
+    pub fn lease(&self, pairs: &[String]) -> PairLease {
+        let mut held = self.pairs.lock().unwrap();
+        for pair in pairs {
+            if let Some(usage) = held.get_mut(pair) {
+                usage.streams += 1;
+            }
+        }
+        PairLease { subscriptions: self.clone(), pairs: pairs.to_vec() }
+    }
+
+    /// Stops tracking `pair`, e.g. once Admin subscribes it, so that it never expires.
+    pub fn forget(&self, pair: &str) -> bool {
+        self.pairs.lock().unwrap().remove(pair).is_some()
+    }
+
+    /// Stops tracking the pairs with no open stream that have not been used for `idle_ttl`
+    /// at `now`, and returns them.
+    pub fn expire(&self, now: Instant) -> Vec<String> {
+        let mut held = self.pairs.lock().unwrap();
+        let expired: Vec<String> = held.iter()
+            .filter(|(_, usage)| usage.streams == 0 && now.saturating_duration_since(usage.last_used) >= self.idle_ttl)
+            .map(|(pair, _)| pair.clone())
+            .collect();
+        for pair in &expired {
+            held.remove(pair);
+        }
+        expired
+    }
+
+    /// The pairs subscribed on request right now.
+    pub fn pairs(&self) -> Vec<String> {
+        let mut pairs: Vec<String> = self.pairs.lock().unwrap().keys().cloned().collect();
+        pairs.sort();
+        pairs
+    }
+}
+
+/// Keeps the pairs of an open stream from expiring until dropped.
+pub struct PairLease {
+    subscriptions: AutoSubscriptions,
+    pairs: Vec<String>,
+}
+
+impl Drop for PairLease {
+    fn drop(&mut self) {
+        let now = Instant::now();
+        let mut held = self.subscriptions.pairs.lock().unwrap();
+        for pair in &self.pairs {
+            if let Some(usage) = held.get_mut(pair) {
+                usage.streams = usage.streams.saturating_sub(1);
+                usage.last_used = now;
+            }
+        }
+    }
+}
+
+/* ------------
+    TEST CASES
+   ------------*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_pair() {
+        assert!(validate_pair("ethbtc").is_ok());
+        assert!(validate_pair("1000shibusdt").is_ok());
+        assert!(validate_pair("").is_err());
+        assert!(validate_pair("eb").is_err());
+        assert!(validate_pair("ETHBTC").is_err());
+        assert!(validate_pair("eth/btc").is_err());
+        assert!(validate_pair(&"a".repeat(MAX_PAIR_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn test_auto_subscriptions_are_capped_and_expire() {
+        let subscriptions = AutoSubscriptions::new(2, Duration::from_secs(60));
+        subscriptions.admit(&["ethbtc", "ltcbtc"]).unwrap();
+        assert!(subscriptions.admit(&["xrpbtc"]).is_err());
+        // Pairs already held do not count twice.
+        subscriptions.admit(&["ethbtc"]).unwrap();
+
+        let lease = subscriptions.lease(&["ethbtc".to_string()]);
+        let later = Instant::now() + Duration::from_secs(61);
+        assert_eq!(subscriptions.expire(later), vec!["ltcbtc"]);
+        assert!(subscriptions.expire(later).is_empty());
+
+        drop(lease);
+        assert!(subscriptions.expire(Instant::now()).is_empty());
+        assert_eq!(subscriptions.expire(Instant::now() + Duration::from_secs(61)), vec!["ethbtc"]);
+        assert!(subscriptions.pairs().is_empty());
+
+        assert!(AutoSubscriptions::new(0, Duration::from_secs(60)).admit(&["ethbtc"]).is_err());
+
+        subscriptions.admit(&["ethbtc"]).unwrap();
+        assert!(subscriptions.forget("ethbtc"));
+        assert!(subscriptions.expire(Instant::now() + Duration::from_secs(61)).is_empty());
+    }
+}
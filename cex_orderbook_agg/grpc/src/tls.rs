@@ -0,0 +1,175 @@
+use crate::config::TlsConfig;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Identity, ServerTlsConfig};
+
+/// TLS settings of a client. `ca` verifies the server; `cert` and `key` are only
+/// needed when the server requires client certificates.
+#[derive(Debug, Clone, Default)]
+pub struct ClientTls {
+    pub ca: Option<PathBuf>,
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub domain: Option<String>,
+}
+
+pub fn server_tls_config(config: &TlsConfig) -> Result<ServerTlsConfig, Box<dyn Error>> {
+    let cert = fs::read(&config.cert)?;
+    let key = fs::read(&config.key)?;
+    let mut tls = ServerTlsConfig::new().identity(Identity::from_pem(cert, key));
+
+    if let Some(client_ca) = &config.client_ca {
+        tls = tls.client_ca_root(Certificate::from_pem(fs::read(client_ca)?));
+    }
+
+    Ok(tls)
+}
+
+pub fn client_tls_config(options: &ClientTls) -> Result<ClientTlsConfig, Box<dyn Error>> {
+    let mut tls = ClientTlsConfig::new();
+
+    if let Some(ca) = &options.ca {
+        tls = tls.ca_certificate(Certificate::from_pem(fs::read(ca)?));
+    }
+    match (&options.cert, &options.key) {
+        (Some(cert), Some(key)) => tls = tls.identity(Identity::from_pem(fs::read(cert)?, fs::read(key)?)),
+        (None, None) => {}
+        _ => return Err("a client certificate needs both a cert and a key".into()),
+    }
+    if let Some(domain) = &options.domain {
+        tls = tls.domain_name(domain);
+    }
+
+    Ok(tls)
+}
+
+/// Connects to `addr`, over TLS if `tls` is given.
+pub async fn connect(addr: String, tls: Option<&ClientTls>) -> Result<Channel, Box<dyn Error>> {
+    let mut endpoint = Channel::from_shared(addr)?;
+    if let Some(tls) = tls {
+        endpoint = endpoint.tls_config(client_tls_config(tls)?)?;
+    }
+    Ok(endpoint.connect().await?)
+}
+
+/* ------------
+    TEST CASES
+   ------------*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, Certificate as RcgenCertificate, CertificateParams, IsCa};
+    use std::path::Path;
+    use tokio::net::TcpListener;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::transport::Server;
+    use tonic_health::pb::health_client::HealthClient;
+    use tonic_health::pb::HealthCheckRequest;
+
+    struct Certs {
+        dir: PathBuf,
+    }
+
+    impl Certs {
+        // Writes a CA, a server certificate for `localhost` and a client certificate, all signed by the CA.
+        fn generate(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("cex_agg_tls_{}_{}", name, std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+
+            let mut ca_params = CertificateParams::new(Vec::new());
+            ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let ca = RcgenCertificate::from_params(ca_params).unwrap();
+            fs::write(dir.join("ca.pem"), ca.serialize_pem().unwrap()).unwrap();
+
+            for (file, names) in [("server", vec!["localhost".to_string()]), ("client", vec!["client".to_string()])] {
+                let cert = RcgenCertificate::from_params(CertificateParams::new(names)).unwrap();
+                fs::write(dir.join(format!("{}.pem", file)), cert.serialize_pem_with_signer(&ca).unwrap()).unwrap();
+                fs::write(dir.join(format!("{}.key", file)), cert.serialize_private_key_pem()).unwrap();
+            }
+
+            Certs { dir }
+        }
+
+        fn path(&self, file: &str) -> PathBuf {
+            self.dir.join(file)
+        }
+    }
+
+    impl Drop for Certs {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    // Serves the standard health service over TLS and returns its address.
+    async fn serve(config: &TlsConfig) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("https://localhost:{}", listener.local_addr().unwrap().port());
+        let (_, health_server) = tonic_health::server::health_reporter();
+        let server = Server::builder()
+            .tls_config(server_tls_config(config).unwrap())
+            .unwrap()
+            .add_service(health_server)
+            .serve_with_incoming(TcpListenerStream::new(listener));
+        tokio::spawn(server);
+        addr
+    }
+
+    async fn check(addr: String, tls: &ClientTls) -> Result<(), Box<dyn Error>> {
+        let channel = connect(addr, Some(tls)).await?;
+        HealthClient::new(channel)
+            .check(HealthCheckRequest { service: String::new() })
+            .await?;
+        Ok(())
+    }
+
+    fn server_config(certs: &Certs, client_ca: Option<&Path>) -> TlsConfig {
+        TlsConfig {
+            cert: certs.path("server.pem"),
+            key: certs.path("server.key"),
+            client_ca: client_ca.map(Path::to_path_buf),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tls() {
+        let certs = Certs::generate("tls");
+        let addr = serve(&server_config(&certs, None)).await;
+
+        let tls = ClientTls { ca: Some(certs.path("ca.pem")), ..Default::default() };
+        assert!(check(addr.clone(), &tls).await.is_ok());
+
+        // A plaintext client cannot talk to a TLS server.
+        let channel = connect(addr.replace("https", "http"), None).await;
+        if let Ok(channel) = channel {
+            let response = HealthClient::new(channel).check(HealthCheckRequest { service: String::new() }).await;
+            assert!(response.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mutual_tls() {
+        let certs = Certs::generate("mtls");
+        let ca = certs.path("ca.pem");
+        let addr = serve(&server_config(&certs, Some(&ca))).await;
+
+        let tls = ClientTls {
+            ca: Some(ca.clone()),
+            cert: Some(certs.path("client.pem")),
+            key: Some(certs.path("client.key")),
+            domain: Some("localhost".to_string()),
+        };
+        assert!(check(addr.clone(), &tls).await.is_ok());
+
+        let without_identity = ClientTls { ca: Some(ca), ..Default::default() };
+        assert!(check(addr, &without_identity).await.is_err());
+    }
+
+    #[test]
+    fn test_client_identity_needs_cert_and_key() {
+        let tls = ClientTls { cert: Some(PathBuf::from("client.pem")), ..Default::default() };
+        assert!(client_tls_config(&tls).is_err());
+    }
+}
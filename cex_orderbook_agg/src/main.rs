@@ -1,37 +1,102 @@
 use exc_orderbook::combine_orderbook::combine_order_books;
 use exchanges::binance::get_binance_order_book;
 use exchanges::bitstamp::get_bitstamp_order_book;
+use exchanges::aggregator::Exchange;
+use exchanges::export::{export_recordings, ExportConfig, Exporter};
+use exchanges::recorder::{Recorder, RecorderConfig};
+use exchanges::replay::{Replay, ReplayConfig, Speed};
+use exchanges::status::FeedStatus;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::env;
-use tokio;
+use std::error::Error;
 use tokio::time::{sleep, Duration};
 
 #[tokio::main]
 async fn main() {
-    let args: Vec<String> = env::args().collect();
-    let trading_pair: Arc<String> = if args.len() > 1 {
-        Arc::new(args[1].clone())
-    } else {
-        Arc::new(String::from("ethbtc"))
-    };
+    // Usage: cex_orderbook_agg [pair] [--record <dir>] [--compress] [--replay <dir>] [--speed <factor>|max]
+    //        cex_orderbook_agg export <recordings> <out> [--format parquet|csv] [--top <levels>] [--rows-per-file <rows>]
+    if env::args().nth(1).as_deref() == Some("export") {
+        if let Err(e) = export(env::args().skip(2).collect()) {
+            println!("Export failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let mut trading_pair = String::from("ethbtc");
+    let mut record_config: Option<RecorderConfig> = None;
+    let mut compress = false;
+    let mut replay_dir: Option<PathBuf> = None;
+    let mut speed = Speed::Realtime;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--record" => {
+                let dir = args.next().expect("--record needs a directory");
+                record_config = Some(RecorderConfig { dir: PathBuf::from(dir), ..Default::default() });
+            }
+            "--compress" => compress = true,
+            "--replay" => replay_dir = Some(PathBuf::from(args.next().expect("--replay needs a directory"))),
+            "--speed" => {
+                speed = match args.next().expect("--speed needs a factor or max").as_str() {
+                    "max" => Speed::AsFastAsPossible,
+                    factor => match factor.parse::<f64>() {
+                        Ok(factor) if factor.is_finite() && factor > 0.0 => Speed::Accelerated(factor),
+                        _ => panic!("--speed needs a positive number or max, got {}", factor),
+                    },
+                };
+            }
+            _ => trading_pair = arg,
+        }
+    }
+    let trading_pair = Arc::new(trading_pair);
+    let recorder = record_config.map(|config| {
+        println!("Recording to {}", config.dir.display());
+        Recorder::new(RecorderConfig { compress, ..config })
+    });
+    // With --replay, the feeds are played back from recordings instead of the exchanges.
+    let replay = replay_dir.map(|dir| Replay::open(ReplayConfig { dir, speed, start: None }).expect("Can't open the recordings"));
 
     let binance_order_book = Arc::new(Mutex::new((Vec::new(), Vec::new())));
     let bitstamp_order_book = Arc::new(Mutex::new((Vec::new(), Vec::new())));
+    let binance_status = FeedStatus::new_shared();
+    let bitstamp_status = FeedStatus::new_shared();
 
     // Clone before moving into async blocks
     let binance_order_book_clone = Arc::clone(&binance_order_book);
     let bitstamp_order_book_clone = Arc::clone(&bitstamp_order_book);
+    let binance_status_clone = Arc::clone(&binance_status);
+    let bitstamp_status_clone = Arc::clone(&bitstamp_status);
     let trading_pair_clone1 = Arc::clone(&trading_pair);
     let trading_pair_clone2 = Arc::clone(&trading_pair);
+    let binance_recorder = recorder.clone();
+    let bitstamp_recorder = recorder.clone();
 
     // Spawn tasks
-    let binance_handle = tokio::spawn(async move {
-        get_binance_order_book(binance_order_book_clone, &trading_pair_clone1).await.unwrap();
-    });
+    let binance_handle = match &replay {
+        Some(replay) => {
+            let mut feed = replay.attach(Exchange::Binance, &trading_pair_clone1, binance_order_book_clone, binance_status_clone, binance_recorder);
+            tokio::spawn(async move { feed.finished().await.unwrap() })
+        }
+        None => tokio::spawn(async move {
+            get_binance_order_book(binance_order_book_clone, binance_status_clone, &trading_pair_clone1, binance_recorder).await.unwrap()
+        }),
+    };
 
-    let bitstamp_handle = tokio::spawn(async move {
-        get_bitstamp_order_book(bitstamp_order_book_clone, &trading_pair_clone2).await.unwrap();
-    });
+    let bitstamp_handle = match &replay {
+        Some(replay) => {
+            let mut feed = replay.attach(Exchange::Bitstamp, &trading_pair_clone2, bitstamp_order_book_clone, bitstamp_status_clone, bitstamp_recorder);
+            tokio::spawn(async move { feed.finished().await.unwrap() })
+        }
+        None => tokio::spawn(async move {
+            get_bitstamp_order_book(bitstamp_order_book_clone, bitstamp_status_clone, &trading_pair_clone2, bitstamp_recorder, None).await.unwrap()
+        }),
+    };
+    // Both feeds are attached, so the replay plays them from their first frames.
+    if let Some(replay) = &replay {
+        replay.start();
+    }
 
     // Give it some time to collect data.
     sleep(Duration::from_secs(10)).await;
@@ -39,13 +104,21 @@ async fn main() {
     // Cancel the tasks.
     binance_handle.abort();
     bitstamp_handle.abort();
+    if let Some(recorder) = &recorder {
+        if let Err(e) = recorder.flush() {
+            println!("Could not finish the recording: {}", e);
+        }
+    }
+
+    println!("Binance feed: {:?}", binance_status.lock().unwrap());
+    println!("Bitstamp feed: {:?}", bitstamp_status.lock().unwrap());
 
     // Extract the fetched order books
     let binance_order_book = binance_order_book.lock().unwrap().clone();
     let bitstamp_order_book = bitstamp_order_book.lock().unwrap().clone();
 
     // Combine the order books
-    let combined_order_books = combine_order_books(vec![binance_order_book, bitstamp_order_book], &*trading_pair);
+    let combined_order_books = combine_order_books(vec![binance_order_book, bitstamp_order_book], &trading_pair);
 
     println!("Combined order book:");
     for order in &combined_order_books {
@@ -81,3 +154,25 @@ async fn main() {
     };
     println!("Spread: {}", spread);
 }
+
+/// Converts the recordings in one directory into Parquet or CSV snapshots in another.
+fn export(args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let mut dirs = Vec::new();
+    let mut config = ExportConfig::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--format" => config.format = value()?.parse()?,
+            "--top" => config.top = value()?.parse()?,
+            "--rows-per-file" => config.rows_per_file = value()?.parse()?,
+            _ => dirs.push(PathBuf::from(arg)),
+        }
+    }
+    let [recordings, out] = <[PathBuf; 2]>::try_from(dirs).map_err(|_| "export needs a recordings directory and an output directory")?;
+
+    let mut exporter = Exporter::new(ExportConfig { dir: out, ..config });
+    let written = export_recordings(&recordings, &mut exporter)?;
+    println!("Exported {} snapshots from {}", written, recordings.display());
+    Ok(())
+}
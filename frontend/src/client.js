@@ -21,12 +21,18 @@ const orderbookProto = grpc.loadPackageDefinition(packageDefinition).orderbook;
 // Create a new client instance
 const client = new orderbookProto.OrderBook('localhost:50051', grpc.credentials.createInsecure());
 
+// Servers with API keys configured expect one in the `x-api-key` metadata entry
+const metadata = new grpc.Metadata();
+if (process.env.API_KEY) {
+    metadata.set('x-api-key', process.env.API_KEY);
+}
+
 function getTopOrders(pair) {
     // Define the request
     const request = { top: 10, pair: pair }; // Request top 10 orders for the given pair
 
     // Make the request
-    client.getTopOrders(request, (error, response) => {
+    client.getTopOrders(request, metadata, (error, response) => {
         if (error) {
             console.log('Error:', error);
         } else {